@@ -0,0 +1,329 @@
+//! Configuration file of the daemon
+//!
+//! A TOML file describes one or more mounts served by a single process, the options of each
+//! mount are validated the same way as the `-o` arguments on the command line:
+//!
+//! ```toml
+//! log_level = "info"
+//!
+//! [[mount]]
+//! source = "/srv/data"
+//! mountpoint = "/mnt/data"
+//! options = ["ro", "allow_other"]
+//! cache_budget = 67108864 # bytes of file data held in memory
+//! attr_ttl = 1.0 # seconds
+//! entry_ttl = 1.0 # seconds
+//! trace = "/var/tmp/data.trace" # record requests and replies for `fuse_ll replay`
+//! metrics_socket = "/run/fuse_ll/data.sock" # serve metrics in the Prometheus text format
+//! metrics_interval = 60.0 # seconds between metrics written to the log
+//! control_dir = true # inspect and steer the mount through /mnt/data/.fuse_ll
+//! ```
+
+use log::LevelFilter;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::fuse::{self, MountConfig};
+use super::memfs::MemoryFilesystemConfig;
+
+/// The content of a configuration file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Log level of the daemon, one of `off`, `error`, `warn`, `info`, `debug` and `trace`
+    pub log_level: Option<String>,
+    /// The mounts served by the daemon
+    #[serde(default, rename = "mount")]
+    pub mounts: Vec<MountEntry>,
+}
+
+/// A mount as given on the command line or in a configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MountEntry {
+    /// Directory mirrored by the filesystem
+    pub source: PathBuf,
+    /// Directory to mount the filesystem on, may be omitted with `overlay_self`
+    pub mountpoint: Option<PathBuf>,
+    /// Mount the filesystem over the source directory itself
+    #[serde(default)]
+    pub overlay_self: bool,
+    /// Mount options, each of them may hold several comma separated options
+    #[serde(default)]
+    pub options: Vec<String>,
+    /// Perform mutating operations with the uid and gid of the caller
+    #[serde(default)]
+    pub switch_credentials: bool,
+    /// Max bytes of file data held in memory
+    pub cache_budget: Option<u64>,
+    /// Seconds the kernel may cache file attributes
+    pub attr_ttl: Option<f64>,
+    /// Seconds the kernel may cache name lookups
+    pub entry_ttl: Option<f64>,
+    /// File to record the requests and replies of the mount to
+    pub trace: Option<PathBuf>,
+    /// Unix socket to serve the metrics of the mount on
+    pub metrics_socket: Option<PathBuf>,
+    /// Seconds between metrics of the mount written to the log
+    pub metrics_interval: Option<f64>,
+    /// Serve the virtual control directory at the root of the mount
+    #[serde(default)]
+    pub control_dir: bool,
+}
+
+/// A validated mount with resolved paths
+#[derive(Debug)]
+pub struct Mount {
+    /// Full path of the source directory
+    pub source: PathBuf,
+    /// Full path of the mount point
+    pub mountpoint: PathBuf,
+    /// Mount options, each of them a single option
+    pub options: Vec<String>,
+    /// Options of the filesystem
+    pub fs_config: MemoryFilesystemConfig,
+    /// Full path of the trace file, if any
+    pub trace: Option<PathBuf>,
+    /// Full path of the metrics socket, if any
+    pub metrics_socket: Option<PathBuf>,
+    /// Interval of logging the metrics, if any
+    pub metrics_interval: Option<Duration>,
+}
+
+impl Config {
+    /// Read and validate a configuration file
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read config file {:?}: {}", path, e))?;
+        Config::parse(&content).map_err(|e| format!("invalid config file {:?}: {}", path, e))
+    }
+
+    /// Parse and validate the content of a configuration file
+    pub fn parse(content: &str) -> Result<Config, String> {
+        let config: Config = toml::from_str(content).map_err(|e| e.to_string())?;
+        if config.mounts.is_empty() {
+            return Err(String::from("no mount is given"));
+        }
+        config.log_level()?;
+        for mount in &config.mounts {
+            mount.validate()?;
+        }
+        Ok(config)
+    }
+
+    /// Return the log level, if any
+    pub fn log_level(&self) -> Result<Option<LevelFilter>, String> {
+        self.log_level
+            .as_ref()
+            .map(|level| {
+                level
+                    .parse()
+                    .map_err(|_| format!("invalid log level \"{}\"", level))
+            })
+            .transpose()
+    }
+
+    /// Resolve all mounts, no mount point may be used twice
+    pub fn resolve(&self) -> Result<Vec<Mount>, String> {
+        let mounts = self
+            .mounts
+            .iter()
+            .map(MountEntry::resolve)
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut mountpoints = HashSet::new();
+        for mount in &mounts {
+            if !mountpoints.insert(&mount.mountpoint) {
+                return Err(format!(
+                    "the mount point {:?} is used by more than one mount",
+                    mount.mountpoint
+                ));
+            }
+        }
+        Ok(mounts)
+    }
+}
+
+fn parse_ttl(name: &str, ttl: Option<f64>, default: Duration) -> Result<Duration, String> {
+    match ttl {
+        None => Ok(default),
+        Some(secs) if secs.is_finite() && secs >= 0.0 => Ok(Duration::from_secs_f64(secs)),
+        Some(secs) => Err(format!(
+            "invalid {} {}, expect non-negative seconds",
+            name, secs
+        )),
+    }
+}
+
+/// Make a path absolute without resolving it, the file may not exist yet
+fn absolute_path(name: &str, path: Option<&Path>) -> Result<Option<PathBuf>, String> {
+    match path {
+        Some(path) => env::current_dir()
+            .map(|dir| Some(dir.join(path)))
+            .map_err(|e| format!("failed to resolve {} {:?}: {}", name, path, e)),
+        None => Ok(None),
+    }
+}
+
+impl MountEntry {
+    /// Split the options into single options
+    fn split_options(&self) -> Vec<&str> {
+        self.options
+            .iter()
+            .flat_map(|o| o.split(','))
+            .filter(|o| !o.is_empty())
+            .collect()
+    }
+
+    /// Validate the options and build the filesystem options, without touching the paths
+    fn validate(&self) -> Result<MemoryFilesystemConfig, String> {
+        for option in &self.options {
+            fuse::options_validator(option.clone())?;
+        }
+        // options of different strings may still conflict with each other
+        let mount_config = MountConfig::parse(&self.split_options())?;
+        if self.mountpoint.is_none() && !self.overlay_self {
+            return Err(format!(
+                "no mount point is given for source {:?}",
+                self.source
+            ));
+        }
+
+        let default = MemoryFilesystemConfig::default();
+        let mut config = MemoryFilesystemConfig {
+            default_permissions: mount_config.default_permissions,
+            switch_credentials: self.switch_credentials,
+            attr_ttl: parse_ttl("attr_ttl", self.attr_ttl, default.attr_ttl)?,
+            entry_ttl: parse_ttl("entry_ttl", self.entry_ttl, default.entry_ttl)?,
+            cache_budget: self.cache_budget,
+            control_dir: self.control_dir,
+            ..default
+        };
+        for spec in &mount_config.uid_map {
+            config.uid_map.add_range(spec)?;
+        }
+        for spec in &mount_config.gid_map {
+            config.gid_map.add_range(spec)?;
+        }
+        self.metrics_interval()?;
+        Ok(config)
+    }
+
+    /// Return the interval of logging the metrics, if any
+    fn metrics_interval(&self) -> Result<Option<Duration>, String> {
+        match self.metrics_interval {
+            None => Ok(None),
+            Some(secs) if secs.is_finite() && secs > 0.0 => Ok(Some(Duration::from_secs_f64(secs))),
+            Some(secs) => Err(format!(
+                "invalid metrics_interval {}, expect positive seconds",
+                secs
+            )),
+        }
+    }
+
+    /// Validate the entry and resolve its paths, the daemon changes its working directory,
+    /// so the paths are resolved beforehand
+    pub fn resolve(&self) -> Result<Mount, String> {
+        let fs_config = self.validate()?;
+        let source = fs::canonicalize(&self.source)
+            .map_err(|e| format!("failed to resolve source {:?}: {}", self.source, e))?;
+        if !source.is_dir() {
+            return Err(format!("the source {:?} is not a directory", source));
+        }
+        // without a separate mount point, the filesystem is mounted over its source directory
+        let mountpoint = match self.mountpoint {
+            Some(ref mountpoint) => fs::canonicalize(mountpoint)
+                .map_err(|e| format!("failed to resolve mount point {:?}: {}", mountpoint, e))?,
+            None => source.clone(),
+        };
+        if source == mountpoint && !self.overlay_self {
+            return Err(format!(
+                "the source {:?} is the mount point, pass --overlay-self or set overlay_self to mount over it",
+                source
+            ));
+        }
+        if source != mountpoint && self.overlay_self {
+            return Err(format!(
+                "the source {:?} is not the mount point {:?} of an overlay_self mount",
+                source, mountpoint
+            ));
+        }
+        Ok(Mount {
+            source,
+            mountpoint,
+            options: self.split_options().into_iter().map(String::from).collect(),
+            fs_config,
+            trace: absolute_path("trace file", self.trace.as_deref())?,
+            metrics_socket: absolute_path("metrics socket", self.metrics_socket.as_deref())?,
+            metrics_interval: self.metrics_interval()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        let config = Config::parse(
+            r#"
+            log_level = "debug"
+
+            [[mount]]
+            source = "/srv/a"
+            mountpoint = "/mnt/a"
+            options = ["ro,allow_other", "fsname=a"]
+            cache_budget = 4096
+            attr_ttl = 0.5
+            trace = "a.trace"
+            metrics_socket = "a.sock"
+            metrics_interval = 30.0
+            control_dir = true
+
+            [[mount]]
+            source = "/srv/b"
+            overlay_self = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.log_level(), Ok(Some(LevelFilter::Debug)));
+        assert_eq!(config.mounts.len(), 2);
+        let a = &config.mounts[0];
+        assert_eq!(a.split_options(), vec!["ro", "allow_other", "fsname=a"]);
+        let fs_config = a.validate().unwrap();
+        assert_eq!(fs_config.cache_budget, Some(4096));
+        assert_eq!(fs_config.attr_ttl, Duration::from_millis(500));
+        assert_eq!(fs_config.entry_ttl, Duration::from_secs(1));
+        assert!(fs_config.control_dir);
+        assert_eq!(a.trace, Some(PathBuf::from("a.trace")));
+        assert_eq!(a.metrics_socket, Some(PathBuf::from("a.sock")));
+        assert_eq!(a.metrics_interval(), Ok(Some(Duration::from_secs(30))));
+        assert!(config.mounts[1].overlay_self);
+    }
+
+    #[test]
+    fn invalid_config() {
+        let mount = |body: &str| format!("[[mount]]\nsource = \"/srv\"\n{}", body);
+        // no mount, unknown fields and bad log levels
+        assert!(Config::parse("log_level = \"info\"").is_err());
+        assert!(Config::parse(&mount("mountpoint = \"/mnt\"\nfoo = 1")).is_err());
+        assert!(Config::parse(&format!(
+            "log_level = \"loud\"\n{}",
+            mount("mountpoint = \"/mnt\"")
+        ))
+        .is_err());
+        // missing mount point, invalid and conflicting options, negative ttls
+        assert!(Config::parse(&mount("")).is_err());
+        assert!(Config::parse(&mount("mountpoint = \"/mnt\"\noptions = [\"bogus\"]")).is_err());
+        assert!(
+            Config::parse(&mount("mountpoint = \"/mnt\"\noptions = [\"ro\", \"rw\"]")).is_err()
+        );
+        assert!(Config::parse(&mount("mountpoint = \"/mnt\"\nentry_ttl = -1.0")).is_err());
+        assert!(Config::parse(&mount("mountpoint = \"/mnt\"\nmetrics_interval = 0.0")).is_err());
+        assert!(Config::parse(&mount("mountpoint = \"/mnt\"\noptions = [\"ro\"]")).is_ok());
+    }
+}
@@ -0,0 +1,94 @@
+//! Daemonization of the filesystem process
+//!
+//! The process forks only after the filesystem has been mounted, the parent process waits
+//! until the child has detached from the terminal and then exits, so that its exit status
+//! tells whether mounting succeeded.
+
+use log::{debug, error};
+use nix::fcntl::{self, OFlag};
+use nix::sys::stat::Mode;
+use nix::unistd::{self, ForkResult};
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// A file holding the process id, removed when dropped
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Write the id of the current process to the file, a relative path is resolved against
+    /// the current directory so that the file is still removed after changing the directory
+    pub fn create(path: &Path) -> io::Result<PidFile> {
+        let path = env::current_dir()?.join(path);
+        fs::write(&path, format!("{}\n", process::id()))?;
+        debug!("wrote pid {} to {:?}", process::id(), path);
+        Ok(PidFile { path })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            error!("failed to remove the pid file {:?}: {}", self.path, e);
+        }
+    }
+}
+
+fn nix_to_io_error(err: nix::Error) -> io::Error {
+    match err.as_errno() {
+        Some(errno) => io::Error::from_raw_os_error(errno as i32),
+        None => io::Error::other(err),
+    }
+}
+
+/// Fork and detach the child process from the terminal, the parent process exits with
+/// status 0 once the child is set up, or 1 if the child failed to set up. Only returns
+/// in the child, which writes its process id to `pidfile` if given.
+pub fn daemonize(pidfile: Option<&Path>) -> io::Result<Option<PidFile>> {
+    let (read_fd, write_fd) = unistd::pipe().map_err(nix_to_io_error)?;
+    match unistd::fork().map_err(nix_to_io_error)? {
+        ForkResult::Parent { child } => {
+            let _ = unistd::close(write_fd);
+            // the child writes one byte when ready, or closes the pipe when it failed
+            let mut buf = [0_u8; 1];
+            let status = match unistd::read(read_fd, &mut buf) {
+                Ok(1) => 0,
+                _ => 1,
+            };
+            debug!("daemon {} started, exit with status {}", child, status);
+            process::exit(status);
+        }
+        ForkResult::Child => {
+            let _ = unistd::close(read_fd);
+            let result = detach(pidfile);
+            if result.is_ok() {
+                let _ = unistd::write(write_fd, &[0]);
+            }
+            let _ = unistd::close(write_fd);
+            result
+        }
+    }
+}
+
+/// Start a new session, write the pid file, change to the root directory and redirect the
+/// standard streams to `/dev/null`
+fn detach(pidfile: Option<&Path>) -> io::Result<Option<PidFile>> {
+    unistd::setsid().map_err(nix_to_io_error)?;
+    let pidfile = pidfile.map(PidFile::create).transpose()?;
+    unistd::chdir("/").map_err(nix_to_io_error)?;
+    let null_fd: RawFd =
+        fcntl::open("/dev/null", OFlag::O_RDWR, Mode::empty()).map_err(nix_to_io_error)?;
+    for fd in 0..=2 {
+        unistd::dup2(null_fd, fd).map_err(nix_to_io_error)?;
+    }
+    if null_fd > 2 {
+        let _ = unistd::close(null_fd);
+    }
+    Ok(pidfile)
+}
@@ -22,6 +22,8 @@
 #![allow(missing_docs)]
 
 use std::convert::TryFrom;
+use std::mem;
+use std::os::raw::c_int;
 
 pub const FUSE_KERNEL_VERSION: u32 = 7;
 
@@ -929,3 +931,19 @@ pub struct fuse_notify_retrieve_in {
     pub dummy3: u64,
     pub dummy4: u64,
 }
+
+/// Return the bytes of a header or argument structure of this module
+#[allow(unsafe_code)]
+pub fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+/// Decode a structure of this module at the start of the data, too little data is `EIO`
+#[allow(unsafe_code)]
+pub fn from_bytes<T>(data: &[u8]) -> Result<T, c_int> {
+    if data.len() < mem::size_of::<T>() {
+        return Err(libc::EIO);
+    }
+    // the structures consist of integers only, any bytes are a valid value
+    Ok(unsafe { std::ptr::read_unaligned(data.as_ptr() as *const T) })
+}
@@ -0,0 +1,880 @@
+//! Asynchronous filesystem trait
+//!
+//! Filesystems backed by asynchronous clients, e.g. object storage or databases, implement
+//! `AsyncFilesystem`, whose methods return futures. An `AsyncSession` polls the futures of many
+//! requests concurrently on one thread, so a slow backend call does not block other requests.
+//! Synchronous `Filesystem` implementations run in an `AsyncSession` with `SyncAdapter`.
+
+use libc::ENOSYS;
+use std::cell::RefCell;
+use std::ffi::OsStr;
+use std::os::raw::c_int;
+use std::path::Path;
+use std::time::SystemTime;
+
+#[cfg(target_os = "macos")]
+use super::reply::ReplyXTimes;
+use super::reply::{
+    ReplyAttr, ReplyBmap, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
+    ReplyLock, ReplyOpen, ReplyStatfs, ReplyStatfsParam, ReplyWrite, ReplyXattr,
+};
+use super::request::Request;
+use super::Filesystem;
+
+/// Asynchronous filesystem trait.
+///
+/// The same as `Filesystem`, except that the methods take `&self` and return futures, which
+/// run concurrently on the thread of the session, so the filesystem uses interior mutability
+/// for its state. Replies are sent with the reply objects like in `Filesystem`.
+// the futures are polled by the single threaded session, so they need not be `Send`
+#[allow(async_fn_in_trait, clippy::too_many_arguments)]
+pub trait AsyncFilesystem {
+    /// Initialize filesystem.
+    /// Called before any other filesystem method.
+    async fn init(&self, _req: &Request<'_>) -> Result<(), c_int> {
+        Ok(())
+    }
+
+    /// Clean up filesystem.
+    /// Called on filesystem exit.
+    async fn destroy(&self, _req: &Request<'_>) {}
+
+    /// Look up a directory entry by name and get its attributes.
+    async fn lookup(&self, _req: &Request<'_>, _parent: u64, _name: &OsStr, reply: ReplyEntry) {
+        reply.error(ENOSYS);
+    }
+
+    /// Forget about an inode.
+    /// The nlookup parameter indicates the number of lookups previously performed on
+    /// this inode. If the filesystem implements inode lifetimes, it is recommended that
+    /// inodes acquire a single reference on each lookup, and lose nlookup references on
+    /// each forget. The filesystem may ignore forget calls, if the inodes don't need to
+    /// have a limited lifetime. On unmount it is not guaranteed, that all referenced
+    /// inodes will receive a forget message.
+    async fn forget(&self, _req: &Request<'_>, _ino: u64, _nlookup: u64) {}
+
+    /// Get file attributes.
+    async fn getattr(&self, _req: &Request<'_>, _ino: u64, reply: ReplyAttr) {
+        reply.error(ENOSYS);
+    }
+
+    /// Set file attributes.
+    async fn setattr(
+        &self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        _size: Option<u64>,
+        _atime: Option<SystemTime>,
+        _mtime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        reply.error(ENOSYS);
+    }
+
+    /// Read symbolic link.
+    async fn readlink(&self, _req: &Request<'_>, _ino: u64, reply: ReplyData) {
+        reply.error(ENOSYS);
+    }
+
+    /// Create file node.
+    /// Create a regular file, character device, block device, fifo or socket node.
+    async fn mknod(
+        &self,
+        _req: &Request<'_>,
+        _parent: u64,
+        _name: &OsStr,
+        _mode: u32,
+        _rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        reply.error(ENOSYS);
+    }
+
+    /// Create a directory.
+    async fn mkdir(
+        &self,
+        _req: &Request<'_>,
+        _parent: u64,
+        _name: &OsStr,
+        _mode: u32,
+        reply: ReplyEntry,
+    ) {
+        reply.error(ENOSYS);
+    }
+
+    /// Remove a file.
+    async fn unlink(&self, _req: &Request<'_>, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
+        reply.error(ENOSYS);
+    }
+
+    /// Remove a directory.
+    async fn rmdir(&self, _req: &Request<'_>, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
+        reply.error(ENOSYS);
+    }
+
+    /// Create a symbolic link.
+    async fn symlink(
+        &self,
+        _req: &Request<'_>,
+        _parent: u64,
+        _name: &OsStr,
+        _link: &Path,
+        reply: ReplyEntry,
+    ) {
+        reply.error(ENOSYS);
+    }
+
+    /// Rename a file.
+    async fn rename(
+        &self,
+        _req: &Request<'_>,
+        _parent: u64,
+        _name: &OsStr,
+        _newparent: u64,
+        _newname: &OsStr,
+        reply: ReplyEmpty,
+    ) {
+        reply.error(ENOSYS);
+    }
+
+    /// Create a hard link.
+    async fn link(
+        &self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _newparent: u64,
+        _newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        reply.error(ENOSYS);
+    }
+
+    /// Open a file.
+    /// Open flags (with the exception of O_CREAT, O_EXCL, O_NOCTTY and O_TRUNC) are
+    /// available in flags. Filesystem may store an arbitrary file handle (pointer, index,
+    /// etc) in fh, and use this in other all other file operations (read, write, flush,
+    /// release, fsync). Filesystem may also implement stateless file I/O and not store
+    /// anything in fh. There are also some flags (direct_io, keep_cache) which the
+    /// filesystem may set, to change the way the file is opened. See fuse_file_info
+    /// structure in <fuse_common.h> for more details.
+    async fn open(&self, _req: &Request<'_>, _ino: u64, _flags: u32, reply: ReplyOpen) {
+        reply.opened(0, 0);
+    }
+
+    /// Read data.
+    /// Read should send exactly the number of bytes requested except on EOF or error,
+    /// otherwise the rest of the data will be substituted with zeroes. An exception to
+    /// this is when the file has been opened in 'direct_io' mode, in which case the
+    /// return value of the read system call will reflect the return value of this
+    /// operation. fh will contain the value set by the open method, or will be undefined
+    /// if the open method didn't set any value.
+    async fn read(
+        &self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        _size: u32,
+        reply: ReplyData,
+    ) {
+        reply.error(ENOSYS);
+    }
+
+    /// Write data.
+    /// Write should return exactly the number of bytes requested except on error. An
+    /// exception to this is when the file has been opened in 'direct_io' mode, in
+    /// which case the return value of the write system call will reflect the return
+    /// value of this operation. fh will contain the value set by the open method, or
+    /// will be undefined if the open method didn't set any value.
+    async fn write(
+        &self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        _data: &[u8],
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        reply.error(ENOSYS);
+    }
+
+    /// Flush method.
+    /// This is called on each close() of the opened file. Since file descriptors can
+    /// be duplicated (dup, dup2, fork), for one open call there may be many flush
+    /// calls. Filesystems shouldn't assume that flush will always be called after some
+    /// writes, or that if will be called at all. fh will contain the value set by the
+    /// open method, or will be undefined if the open method didn't set any value.
+    /// NOTE: the name of the method is misleading, since (unlike fsync) the filesystem
+    /// is not forced to flush pending writes. One reason to flush data, is if the
+    /// filesystem wants to return write errors. If the filesystem supports file locking
+    /// operations (setlk, getlk) it should remove all locks belonging to 'lock_owner'.
+    async fn flush(
+        &self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        _lock_owner: u64,
+        reply: ReplyEmpty,
+    ) {
+        reply.error(ENOSYS);
+    }
+
+    /// Release an open file.
+    /// Release is called when there are no more references to an open file: all file
+    /// descriptors are closed and all memory mappings are unmapped. For every open
+    /// call there will be exactly one release call. The filesystem may reply with an
+    /// error, but error values are not returned to close() or munmap() which triggered
+    /// the release. fh will contain the value set by the open method, or will be undefined
+    /// if the open method didn't set any value. flags will contain the same flags as for
+    /// open.
+    async fn release(
+        &self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        reply.ok();
+    }
+
+    /// Synchronize file contents.
+    /// If the datasync parameter is non-zero, then only the user data should be flushed,
+    /// not the meta data.
+    async fn fsync(
+        &self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        _datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        reply.error(ENOSYS);
+    }
+
+    /// Open a directory.
+    /// Filesystem may store an arbitrary file handle (pointer, index, etc) in fh, and
+    /// use this in other all other directory stream operations (readdir, releasedir,
+    /// fsyncdir). Filesystem may also implement stateless directory I/O and not store
+    /// anything in fh, though that makes it impossible to implement standard conforming
+    /// directory stream operations in case the contents of the directory can change
+    /// between opendir and releasedir.
+    async fn opendir(&self, _req: &Request<'_>, _ino: u64, _flags: u32, reply: ReplyOpen) {
+        reply.opened(0, 0);
+    }
+
+    /// Read directory.
+    /// Send a buffer filled using buffer.fill(), with size not exceeding the
+    /// requested size. Send an empty buffer on end of stream. fh will contain the
+    /// value set by the opendir method, or will be undefined if the opendir method
+    /// didn't set any value.
+    async fn readdir(
+        &self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        reply: ReplyDirectory,
+    ) {
+        reply.error(ENOSYS);
+    }
+
+    /// Release an open directory.
+    /// For every opendir call there will be exactly one releasedir call. fh will
+    /// contain the value set by the opendir method, or will be undefined if the
+    /// opendir method didn't set any value.
+    async fn releasedir(
+        &self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        _flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        reply.ok();
+    }
+
+    /// Synchronize directory contents.
+    /// If the datasync parameter is set, then only the directory contents should
+    /// be flushed, not the meta data. fh will contain the value set by the opendir
+    /// method, or will be undefined if the opendir method didn't set any value.
+    async fn fsyncdir(
+        &self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        _datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        reply.error(ENOSYS);
+    }
+
+    /// Get file system statistics.
+    async fn statfs(&self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        reply.statfs(&ReplyStatfsParam {
+            blocks: 0,
+            bfree: 0,
+            bavail: 0,
+            files: 0,
+            ffree: 0,
+            bsize: 512,
+            namelen: 255,
+            frsize: 0,
+        });
+    }
+
+    /// Set an extended attribute.
+    async fn setxattr(
+        &self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _name: &OsStr,
+        _value: &[u8],
+        _flags: u32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        reply.error(ENOSYS);
+    }
+
+    /// Get an extended attribute.
+    /// If `size` is 0, the size of the value should be sent with `reply.size()`.
+    /// If `size` is not 0, and the value fits, send it with `reply.data()`, or
+    /// `reply.error(ERANGE)` if it doesn't.
+    async fn getxattr(
+        &self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _name: &OsStr,
+        _size: u32,
+        reply: ReplyXattr,
+    ) {
+        reply.error(ENOSYS);
+    }
+
+    /// List extended attribute names.
+    /// If `size` is 0, the size of the value should be sent with `reply.size()`.
+    /// If `size` is not 0, and the value fits, send it with `reply.data()`, or
+    /// `reply.error(ERANGE)` if it doesn't.
+    async fn listxattr(&self, _req: &Request<'_>, _ino: u64, _size: u32, reply: ReplyXattr) {
+        reply.error(ENOSYS);
+    }
+
+    /// Remove an extended attribute.
+    async fn removexattr(&self, _req: &Request<'_>, _ino: u64, _name: &OsStr, reply: ReplyEmpty) {
+        reply.error(ENOSYS);
+    }
+
+    /// Check file access permissions.
+    /// This will be called for the access() system call. If the 'default_permissions'
+    /// mount option is given, this method is not called. This method is not called
+    /// under Linux kernel versions 2.4.x
+    async fn access(&self, _req: &Request<'_>, _ino: u64, _mask: u32, reply: ReplyEmpty) {
+        reply.error(ENOSYS);
+    }
+
+    /// Create and open a file.
+    /// If the file does not exist, first create it with the specified mode, and then
+    /// open it. Open flags (with the exception of O_NOCTTY) are available in flags.
+    /// Filesystem may store an arbitrary file handle (pointer, index, etc) in fh,
+    /// and use this in other all other file operations (read, write, flush, release,
+    /// fsync). There are also some flags (direct_io, keep_cache) which the
+    /// filesystem may set, to change the way the file is opened. See fuse_file_info
+    /// structure in <fuse_common.h> for more details. If this method is not
+    /// implemented or under Linux kernel versions earlier than 2.6.15, the mknod()
+    /// and open() methods will be called instead.
+    async fn create(
+        &self,
+        _req: &Request<'_>,
+        _parent: u64,
+        _name: &OsStr,
+        _mode: u32,
+        _flags: u32,
+        reply: ReplyCreate,
+    ) {
+        reply.error(ENOSYS);
+    }
+
+    /// Test for a POSIX file lock.
+    async fn getlk(
+        &self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        _lock_owner: u64,
+        _start: u64,
+        _end: u64,
+        _typ: u32,
+        _pid: u32,
+        reply: ReplyLock,
+    ) {
+        reply.error(ENOSYS);
+    }
+
+    /// Acquire, modify or release a POSIX file lock.
+    /// For POSIX threads (NPTL) there's a 1-1 relation between pid and owner, but
+    /// otherwise this is not always the case.  For checking lock ownership,
+    /// 'fi->owner' must be used. The l_pid field in 'struct flock' should only be
+    /// used to fill in this field in getlk(). Note: if the locking methods are not
+    /// implemented, the kernel will still allow file locking to work locally.
+    /// Hence these are only interesting for network filesystems and similar.
+    async fn setlk(
+        &self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        _lock_owner: u64,
+        _start: u64,
+        _end: u64,
+        _typ: u32,
+        _pid: u32,
+        _sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        reply.error(ENOSYS);
+    }
+
+    /// Map block index within file to block index within device.
+    /// Note: This makes sense only for block device backed filesystems mounted
+    /// with the 'blkdev' option
+    async fn bmap(
+        &self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _blocksize: u32,
+        _idx: u64,
+        reply: ReplyBmap,
+    ) {
+        reply.error(ENOSYS);
+    }
+
+    /// macOS only: Rename the volume. Set fuse_init_out.flags during init to
+    /// FUSE_VOL_RENAME to enable
+    #[cfg(target_os = "macos")]
+    async fn setvolname(&self, _req: &Request<'_>, _name: &OsStr, reply: ReplyEmpty) {
+        reply.error(ENOSYS);
+    }
+
+    /// macOS only (undocumented)
+    #[cfg(target_os = "macos")]
+    async fn exchange(
+        &self,
+        _req: &Request<'_>,
+        _parent: u64,
+        _name: &OsStr,
+        _newparent: u64,
+        _newname: &OsStr,
+        _options: u64,
+        reply: ReplyEmpty,
+    ) {
+        reply.error(ENOSYS);
+    }
+
+    /// macOS only: Query extended times (bkuptime and crtime). Set fuse_init_out.flags
+    /// during init to FUSE_XTIMES to enable
+    #[cfg(target_os = "macos")]
+    async fn getxtimes(&self, _req: &Request<'_>, _ino: u64, reply: ReplyXTimes) {
+        reply.error(ENOSYS);
+    }
+}
+
+/// Adapter to run a synchronous `Filesystem` in an `AsyncSession`. The filesystem methods
+/// complete before their futures are polled the first time, so the requests are served one
+/// after another like in `Session`.
+#[derive(Debug)]
+pub struct SyncAdapter<FS: Filesystem> {
+    filesystem: RefCell<FS>,
+}
+
+impl<FS: Filesystem> SyncAdapter<FS> {
+    /// Wrap a synchronous filesystem
+    pub fn new(filesystem: FS) -> SyncAdapter<FS> {
+        SyncAdapter {
+            filesystem: RefCell::new(filesystem),
+        }
+    }
+
+    /// Return the wrapped filesystem
+    pub fn into_inner(self) -> FS {
+        self.filesystem.into_inner()
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+impl<FS: Filesystem> AsyncFilesystem for SyncAdapter<FS> {
+    async fn init(&self, req: &Request<'_>) -> Result<(), c_int> {
+        self.filesystem.borrow_mut().init(req)
+    }
+
+    async fn destroy(&self, req: &Request<'_>) {
+        self.filesystem.borrow_mut().destroy(req)
+    }
+
+    async fn lookup(&self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        self.filesystem
+            .borrow_mut()
+            .lookup(req, parent, name, reply)
+    }
+
+    async fn forget(&self, req: &Request<'_>, ino: u64, nlookup: u64) {
+        self.filesystem.borrow_mut().forget(req, ino, nlookup)
+    }
+
+    async fn getattr(&self, req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        self.filesystem.borrow_mut().getattr(req, ino, reply)
+    }
+
+    async fn setattr(
+        &self,
+        req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+        fh: Option<u64>,
+        crtime: Option<SystemTime>,
+        chgtime: Option<SystemTime>,
+        bkuptime: Option<SystemTime>,
+        flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        self.filesystem.borrow_mut().setattr(
+            req, ino, mode, uid, gid, size, atime, mtime, fh, crtime, chgtime, bkuptime, flags,
+            reply,
+        )
+    }
+
+    async fn readlink(&self, req: &Request<'_>, ino: u64, reply: ReplyData) {
+        self.filesystem.borrow_mut().readlink(req, ino, reply)
+    }
+
+    async fn mknod(
+        &self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        self.filesystem
+            .borrow_mut()
+            .mknod(req, parent, name, mode, rdev, reply)
+    }
+
+    async fn mkdir(
+        &self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        reply: ReplyEntry,
+    ) {
+        self.filesystem
+            .borrow_mut()
+            .mkdir(req, parent, name, mode, reply)
+    }
+
+    async fn unlink(&self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.filesystem
+            .borrow_mut()
+            .unlink(req, parent, name, reply)
+    }
+
+    async fn rmdir(&self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.filesystem.borrow_mut().rmdir(req, parent, name, reply)
+    }
+
+    async fn symlink(
+        &self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        self.filesystem
+            .borrow_mut()
+            .symlink(req, parent, name, link, reply)
+    }
+
+    async fn rename(
+        &self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEmpty,
+    ) {
+        self.filesystem
+            .borrow_mut()
+            .rename(req, parent, name, newparent, newname, reply)
+    }
+
+    async fn link(
+        &self,
+        req: &Request<'_>,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        self.filesystem
+            .borrow_mut()
+            .link(req, ino, newparent, newname, reply)
+    }
+
+    async fn open(&self, req: &Request<'_>, ino: u64, flags: u32, reply: ReplyOpen) {
+        self.filesystem.borrow_mut().open(req, ino, flags, reply)
+    }
+
+    async fn read(
+        &self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        reply: ReplyData,
+    ) {
+        self.filesystem
+            .borrow_mut()
+            .read(req, ino, fh, offset, size, reply)
+    }
+
+    async fn write(
+        &self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        flags: u32,
+        reply: ReplyWrite,
+    ) {
+        self.filesystem
+            .borrow_mut()
+            .write(req, ino, fh, offset, data, flags, reply)
+    }
+
+    async fn flush(
+        &self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        reply: ReplyEmpty,
+    ) {
+        self.filesystem
+            .borrow_mut()
+            .flush(req, ino, fh, lock_owner, reply)
+    }
+
+    async fn release(
+        &self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        flags: u32,
+        lock_owner: u64,
+        flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.filesystem
+            .borrow_mut()
+            .release(req, ino, fh, flags, lock_owner, flush, reply)
+    }
+
+    async fn fsync(&self, req: &Request<'_>, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        self.filesystem
+            .borrow_mut()
+            .fsync(req, ino, fh, datasync, reply)
+    }
+
+    async fn opendir(&self, req: &Request<'_>, ino: u64, flags: u32, reply: ReplyOpen) {
+        self.filesystem.borrow_mut().opendir(req, ino, flags, reply)
+    }
+
+    async fn readdir(
+        &self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        reply: ReplyDirectory,
+    ) {
+        self.filesystem
+            .borrow_mut()
+            .readdir(req, ino, fh, offset, reply)
+    }
+
+    async fn releasedir(
+        &self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        self.filesystem
+            .borrow_mut()
+            .releasedir(req, ino, fh, flags, reply)
+    }
+
+    async fn fsyncdir(
+        &self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.filesystem
+            .borrow_mut()
+            .fsyncdir(req, ino, fh, datasync, reply)
+    }
+
+    async fn statfs(&self, req: &Request<'_>, ino: u64, reply: ReplyStatfs) {
+        self.filesystem.borrow_mut().statfs(req, ino, reply)
+    }
+
+    async fn setxattr(
+        &self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32,
+        position: u32,
+        reply: ReplyEmpty,
+    ) {
+        self.filesystem
+            .borrow_mut()
+            .setxattr(req, ino, name, value, flags, position, reply)
+    }
+
+    async fn getxattr(
+        &self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        self.filesystem
+            .borrow_mut()
+            .getxattr(req, ino, name, size, reply)
+    }
+
+    async fn listxattr(&self, req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        self.filesystem
+            .borrow_mut()
+            .listxattr(req, ino, size, reply)
+    }
+
+    async fn removexattr(&self, req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.filesystem
+            .borrow_mut()
+            .removexattr(req, ino, name, reply)
+    }
+
+    async fn access(&self, req: &Request<'_>, ino: u64, mask: u32, reply: ReplyEmpty) {
+        self.filesystem.borrow_mut().access(req, ino, mask, reply)
+    }
+
+    async fn create(
+        &self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        flags: u32,
+        reply: ReplyCreate,
+    ) {
+        self.filesystem
+            .borrow_mut()
+            .create(req, parent, name, mode, flags, reply)
+    }
+
+    async fn getlk(
+        &self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: u32,
+        pid: u32,
+        reply: ReplyLock,
+    ) {
+        self.filesystem
+            .borrow_mut()
+            .getlk(req, ino, fh, lock_owner, start, end, typ, pid, reply)
+    }
+
+    async fn setlk(
+        &self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: u32,
+        pid: u32,
+        sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.filesystem
+            .borrow_mut()
+            .setlk(req, ino, fh, lock_owner, start, end, typ, pid, sleep, reply)
+    }
+
+    async fn bmap(&self, req: &Request<'_>, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
+        self.filesystem
+            .borrow_mut()
+            .bmap(req, ino, blocksize, idx, reply)
+    }
+
+    #[cfg(target_os = "macos")]
+    async fn setvolname(&self, req: &Request<'_>, name: &OsStr, reply: ReplyEmpty) {
+        self.filesystem.borrow_mut().setvolname(req, name, reply)
+    }
+
+    #[cfg(target_os = "macos")]
+    async fn exchange(
+        &self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        options: u64,
+        reply: ReplyEmpty,
+    ) {
+        self.filesystem
+            .borrow_mut()
+            .exchange(req, parent, name, newparent, newname, options, reply)
+    }
+
+    #[cfg(target_os = "macos")]
+    async fn getxtimes(&self, req: &Request<'_>, ino: u64, reply: ReplyXTimes) {
+        self.filesystem.borrow_mut().getxtimes(req, ino, reply)
+    }
+}
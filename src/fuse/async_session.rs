@@ -0,0 +1,462 @@
+//! Asynchronous filesystem session
+//!
+//! An asynchronous session runs an `AsyncFilesystem` while it is mounted. The session loop
+//! reads all pending requests from the non-blocking channel, spawns a task for each of them
+//! and polls the tasks woken since, then waits with poll(2) until another request arrives or
+//! a task is woken, e.g. by a backend client completing on another thread.
+
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::AtomicUsize;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Wake, Waker};
+
+use libc::{EAGAIN, EINTR, ENODEV, ENOENT};
+use log::{info, warn};
+use nix::errno::Errno;
+use nix::fcntl::{self, FcntlArg, FdFlag, OFlag};
+use nix::poll::{self, PollFd, PollFlags};
+use nix::unistd;
+
+use super::async_fs::AsyncFilesystem;
+use super::channel::Channel;
+use super::ll_request::RequestError;
+use super::mount::{MountConfig, MountOption, UnmountMode};
+use super::request::Request;
+use super::session::{destroy_request, Unmounter, BUFFER_SIZE};
+use super::transport::{ChannelSender, Transport};
+
+/// The asynchronous session data structure
+#[derive(Debug)]
+pub struct AsyncSession<FS: AsyncFilesystem> {
+    /// Filesystem operation implementations
+    pub filesystem: FS,
+    /// Communication channel to the kernel driver
+    ch: Channel,
+    /// Sender of replies over the channel
+    sender: ChannelSender,
+    /// The number of requests not replied yet
+    in_flight: Arc<AtomicUsize>,
+    /// FUSE protocol major version
+    pub(super) proto_major: Cell<u32>,
+    /// FUSE protocol minor version
+    pub(super) proto_minor: Cell<u32>,
+    /// True if the filesystem is initialized (init operation done)
+    pub(super) initialized: Cell<bool>,
+    /// True if the filesystem was destroyed (destroy operation done)
+    pub(super) destroyed: Cell<bool>,
+    /// True if only the owner of the session and root may access the filesystem (allow_root)
+    pub allow_root: bool,
+    /// The user who mounted the filesystem
+    pub owner: u32,
+    /// Number of requests with an operation unknown to the ABI in use, replied with `ENOSYS`
+    unknown_requests: Cell<u64>,
+    /// Number of requests too short or with corrupt arguments, replied with `EIO`
+    malformed_requests: Cell<u64>,
+}
+
+impl<FS: AsyncFilesystem> AsyncSession<FS> {
+    /// Create a new session by mounting the given filesystem to the given mountpoint,
+    /// fails with `InvalidInput` if the options are invalid or conflict with each other
+    pub fn new(
+        filesystem: FS,
+        mountpoint: &Path,
+        options: &[&str],
+    ) -> io::Result<AsyncSession<FS>> {
+        let config = MountConfig::parse(options)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        AsyncSession::with_config(filesystem, mountpoint, config)
+    }
+
+    /// Create a new session by mounting the given filesystem to the given mountpoint with
+    /// typed options, fails with `InvalidInput` if the options conflict with each other
+    pub fn with_options(
+        filesystem: FS,
+        mountpoint: &Path,
+        options: &[MountOption],
+    ) -> io::Result<AsyncSession<FS>> {
+        let config = MountConfig::from_options(options)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        AsyncSession::with_config(filesystem, mountpoint, config)
+    }
+
+    fn with_config(
+        filesystem: FS,
+        mountpoint: &Path,
+        config: MountConfig,
+    ) -> io::Result<AsyncSession<FS>> {
+        info!("mounting {:?}", mountpoint);
+        let ch = Channel::new(mountpoint, &config)?;
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        Ok(AsyncSession {
+            filesystem,
+            sender: ChannelSender::new(ch.sender(), Arc::clone(&in_flight)),
+            ch,
+            in_flight,
+            proto_major: Cell::new(0),
+            proto_minor: Cell::new(0),
+            initialized: Cell::new(false),
+            destroyed: Cell::new(false),
+            allow_root: config.allow_root,
+            owner: unistd::geteuid().as_raw(),
+            unknown_requests: Cell::new(0),
+            malformed_requests: Cell::new(0),
+        })
+    }
+
+    /// Return path of the mounted filesystem
+    pub fn mountpoint(&self) -> &Path {
+        self.ch.mountpoint()
+    }
+
+    /// FUSE protocol version negotiated with the kernel, 0.0 before init
+    pub fn proto_version(&self) -> (u32, u32) {
+        (self.proto_major.get(), self.proto_minor.get())
+    }
+
+    /// Number of requests with an operation unknown to the ABI in use, replied with `ENOSYS`
+    pub fn unknown_requests(&self) -> u64 {
+        self.unknown_requests.get()
+    }
+
+    /// Number of requests too short or with corrupt arguments, replied with `EIO`
+    pub fn malformed_requests(&self) -> u64 {
+        self.malformed_requests.get()
+    }
+
+    /// Unmount the filesystem, a busy mount point fails with `EBUSY` in `UnmountMode::Normal`
+    pub fn unmount(&mut self, mode: UnmountMode) -> io::Result<()> {
+        self.ch.unmount(mode)
+    }
+
+    /// Return a handle to unmount the filesystem from another thread while the session runs
+    pub fn unmounter(&self) -> Unmounter {
+        Unmounter::new(self.ch.mountpoint(), &self.in_flight)
+    }
+
+    /// Run the session loop that receives kernel requests and polls the futures of the
+    /// filesystem methods for them concurrently on the calling thread. Unlike `Session`, a
+    /// buffer is allocated for each request in flight.
+    pub fn run(&mut self) -> io::Result<()> {
+        self.ch.set_nonblocking()?;
+        self.ch.start();
+        self.serve()?;
+        // the tasks not completed yet were dropped by `serve`, their replies fail with the
+        // channel gone
+        self.ch.set_unmounted();
+        self.destroy();
+        Ok(())
+    }
+
+    /// Serve requests until the filesystem is unmounted
+    fn serve(&self) -> io::Result<()> {
+        let executor = Executor::new()?;
+        // Buffer for receiving requests from the kernel, each task owns a copy of its request
+        let mut buffer: Vec<u8> = vec![0_u8; BUFFER_SIZE];
+
+        loop {
+            // Read all pending requests, the kernel driver makes sure that we get exactly
+            // one request per read
+            loop {
+                match self.ch.receive(&mut buffer) {
+                    Ok(()) => executor.spawn(self.handle(buffer.to_vec())),
+                    Err(err) => match err.raw_os_error() {
+                        // No request pending, poll the tasks
+                        Some(EAGAIN) => break,
+                        // Operation interrupted. Accordingly to FUSE, this is safe to retry
+                        Some(ENOENT) => continue,
+                        // Interrupted system call, retry
+                        Some(EINTR) => continue,
+                        // Filesystem was unmounted, quit the loop
+                        Some(ENODEV) => return Ok(()),
+                        // Unhandled error
+                        _ => return Err(err),
+                    },
+                }
+            }
+            executor.run_ready();
+            executor.wait(Some(self.ch.fd()))?;
+        }
+    }
+
+    /// Handle a single request
+    async fn handle(&self, data: Vec<u8>) {
+        match Request::new(self.sender.clone(), &data) {
+            // Dispatch request
+            Ok(req) => req.dispatch_async(self).await,
+            // Reply an error to an invalid request and keep serving
+            Err(err) => {
+                let counter = match err {
+                    RequestError::UnknownOperation(_) => &self.unknown_requests,
+                    _ => &self.malformed_requests,
+                };
+                counter.set(counter.get() + 1);
+                Request::reply_invalid(self.sender.clone(), &data, &err);
+            }
+        }
+    }
+
+    /// Call destroy of the filesystem if the kernel did not send a destroy request before
+    /// the filesystem was unmounted, which it only does for fuseblk mounts
+    fn destroy(&self) {
+        if !self.initialized.get() || self.destroyed.get() {
+            return;
+        }
+        let data = destroy_request(self.owner);
+        if let Ok(req) = Request::new(self.sender.clone(), &data) {
+            info!("destroying filesystem after unmount");
+            if let Err(err) = block_on(self.filesystem.destroy(&req)) {
+                warn!("failed to wait for destroy of the filesystem: {}", err);
+            }
+        }
+        self.destroyed.set(true);
+    }
+}
+
+impl<FS: AsyncFilesystem> Drop for AsyncSession<FS> {
+    fn drop(&mut self) {
+        if self.unknown_requests.get() > 0 || self.malformed_requests.get() > 0 {
+            warn!(
+                "received {} unknown and {} malformed requests while mounted at {}",
+                self.unknown_requests.get(),
+                self.malformed_requests.get(),
+                self.mountpoint().display()
+            );
+        }
+        info!("umounted {}", self.mountpoint().display());
+    }
+}
+
+/// Run a future to completion on the calling thread
+fn block_on<F: Future<Output = ()>>(future: F) -> io::Result<()> {
+    let executor = Executor::new()?;
+    executor.spawn(future);
+    loop {
+        executor.run_ready();
+        if executor.is_empty() {
+            return Ok(());
+        }
+        executor.wait(None)?;
+    }
+}
+
+/// The ids of the tasks to poll, shared with the wakers of the tasks, which may be woken on
+/// other threads. Waking a task writes to a pipe to end the wait of the session loop.
+#[derive(Debug)]
+struct ReadyQueue {
+    ids: Mutex<VecDeque<usize>>,
+    wake_read: RawFd,
+    wake_write: RawFd,
+}
+
+impl ReadyQueue {
+    fn new() -> io::Result<ReadyQueue> {
+        let (wake_read, wake_write) = unistd::pipe().map_err(|_| io::Error::last_os_error())?;
+        let queue = ReadyQueue {
+            ids: Mutex::new(VecDeque::new()),
+            wake_read,
+            wake_write,
+        };
+        for &fd in &[wake_read, wake_write] {
+            fcntl::fcntl(fd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK))
+                .map_err(|_| io::Error::last_os_error())?;
+            fcntl::fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))
+                .map_err(|_| io::Error::last_os_error())?;
+        }
+        Ok(queue)
+    }
+
+    /// Queue a task, waking the session loop if `notify` is set
+    fn push(&self, id: usize, notify: bool) {
+        self.ids.lock().unwrap().push_back(id);
+        if notify {
+            // a full pipe wakes the session loop as well
+            let _ = unistd::write(self.wake_write, &[0]);
+        }
+    }
+
+    fn pop(&self) -> Option<usize> {
+        self.ids.lock().unwrap().pop_front()
+    }
+
+    fn len(&self) -> usize {
+        self.ids.lock().unwrap().len()
+    }
+
+    /// Read all wake ups written to the pipe
+    fn drain(&self) {
+        let mut buf = [0_u8; 64];
+        while let Ok(n) = unistd::read(self.wake_read, &mut buf) {
+            if n == 0 {
+                break;
+            }
+        }
+    }
+}
+
+impl Drop for ReadyQueue {
+    fn drop(&mut self) {
+        let _ = unistd::close(self.wake_read);
+        let _ = unistd::close(self.wake_write);
+    }
+}
+
+/// Waker of a task, queues the task to be polled again
+struct TaskWaker {
+    id: usize,
+    queue: Arc<ReadyQueue>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.queue.push(self.id, true);
+    }
+}
+
+/// A request being handled
+type Task<'a> = Pin<Box<dyn Future<Output = ()> + 'a>>;
+
+/// A single threaded executor of the tasks of a session
+struct Executor<'a> {
+    tasks: RefCell<HashMap<usize, Task<'a>>>,
+    next_id: Cell<usize>,
+    queue: Arc<ReadyQueue>,
+}
+
+impl<'a> Executor<'a> {
+    fn new() -> io::Result<Executor<'a>> {
+        Ok(Executor {
+            tasks: RefCell::new(HashMap::new()),
+            next_id: Cell::new(0),
+            queue: Arc::new(ReadyQueue::new()?),
+        })
+    }
+
+    /// Add a task, which is polled first by the next `run_ready`
+    fn spawn<F: Future<Output = ()> + 'a>(&self, future: F) {
+        let id = self.next_id.get();
+        self.next_id.set(id.wrapping_add(1));
+        self.tasks.borrow_mut().insert(id, Box::pin(future));
+        self.queue.push(id, false);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.tasks.borrow().is_empty()
+    }
+
+    /// Poll the tasks woken so far once, tasks woken while polling are polled by the next
+    /// call, so a task waking itself does not starve the session loop
+    fn run_ready(&self) {
+        for _ in 0..self.queue.len() {
+            let id = match self.queue.pop() {
+                Some(id) => id,
+                None => break,
+            };
+            // a task may be woken several times before it is polled, or after it completed
+            let mut task = match self.tasks.borrow_mut().remove(&id) {
+                Some(task) => task,
+                None => continue,
+            };
+            let waker = Waker::from(Arc::new(TaskWaker {
+                id,
+                queue: Arc::clone(&self.queue),
+            }));
+            let mut cx = Context::from_waker(&waker);
+            if task.as_mut().poll(&mut cx).is_pending() {
+                self.tasks.borrow_mut().insert(id, task);
+            }
+        }
+    }
+
+    /// Wait until a task is woken or the given file descriptor is readable, returns at once
+    /// if any task is ready
+    fn wait(&self, fd: Option<RawFd>) -> io::Result<()> {
+        if self.queue.len() > 0 {
+            return Ok(());
+        }
+        let mut fds = vec![PollFd::new(self.queue.wake_read, PollFlags::POLLIN)];
+        if let Some(fd) = fd {
+            fds.push(PollFd::new(fd, PollFlags::POLLIN));
+        }
+        match poll::poll(&mut fds, -1) {
+            Ok(_) => {}
+            Err(err) if err.as_errno() == Some(Errno::EINTR) => {}
+            Err(_) => return Err(io::Error::last_os_error()),
+        }
+        self.queue.drain();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{block_on, Executor};
+    use std::cell::Cell;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll, Waker};
+    use std::thread;
+    use std::time::Duration;
+
+    /// A future completed by another thread
+    #[derive(Default)]
+    struct Remote {
+        state: Arc<Mutex<(bool, Option<Waker>)>>,
+    }
+
+    impl Future for Remote {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            let mut state = self.state.lock().unwrap();
+            if state.0 {
+                return Poll::Ready(());
+            }
+            state.1 = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn wake_from_other_thread() {
+        let remote = Remote::default();
+        let state = Arc::clone(&remote.state);
+        let done = Cell::new(0);
+        let executor = Executor::new().unwrap();
+        executor.spawn(async {
+            remote.await;
+            done.set(done.get() + 1);
+        });
+        executor.spawn(async { done.set(done.get() + 1) });
+        executor.run_ready();
+        assert_eq!(done.get(), 1);
+        assert!(!executor.is_empty());
+
+        let waker = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            let mut state = state.lock().unwrap();
+            state.0 = true;
+            state.1.take().unwrap().wake();
+        });
+        while !executor.is_empty() {
+            executor.wait(None).unwrap();
+            executor.run_ready();
+        }
+        waker.join().unwrap();
+        assert_eq!(done.get(), 2);
+
+        let ran = Cell::new(false);
+        block_on(async { ran.set(true) }).unwrap();
+        assert!(ran.get());
+    }
+}
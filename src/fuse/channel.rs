@@ -3,17 +3,30 @@
 //! Raw communication channel to the FUSE kernel driver.
 
 // use libc::{c_void, size_t};
-use log::{debug, error};
+use log::{debug, error, info};
+use nix::errno::Errno;
+use nix::fcntl::{self, FcntlArg, OFlag};
 use nix::sys::uio::{self, IoVec};
 use nix::unistd;
+use std::array;
+#[cfg(target_os = "linux")]
+use std::cell::RefCell;
 use std::ffi::{CString, OsStr};
 use std::io;
 use std::os::raw::{c_char, c_int};
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
 use std::path::{Path, PathBuf};
 
-use super::mount;
-use super::reply::ReplySender;
+#[cfg(target_os = "linux")]
+use super::mount::AutoUnmount;
+use super::mount::{self, MountConfig, UnmountMode};
+use super::reply::{self, MAX_REPLY_SLICES};
+#[cfg(target_os = "linux")]
+use super::splice::{self, RequestPipe, SpliceError};
+#[cfg(target_os = "linux")]
+use super::transport::SplicedData;
+use super::transport::{Transport, TransportSender};
 
 #[repr(C)]
 #[derive(Debug)]
@@ -41,6 +54,14 @@ fn with_fuse_args<T, F: FnOnce(&fuse_args) -> T>(options: &[&OsStr], f: F) -> T
 pub struct Channel {
     mountpoint: PathBuf,
     fd: c_int,
+    /// True until the mount point is unmounted explicitly
+    mounted: bool,
+    /// Pipe to splice requests into, none once it failed to be set up
+    #[cfg(target_os = "linux")]
+    request_pipe: RefCell<Option<RequestPipe>>,
+    /// The fusermount process of an `auto_unmount` mount, dropped on unmount
+    #[cfg(target_os = "linux")]
+    auto_unmount: Option<AutoUnmount>,
 }
 
 impl Channel {
@@ -48,27 +69,51 @@ impl Channel {
     /// given path. The kernel driver will delegate filesystem operations of
     /// the given path to the channel. If the channel is dropped, the path is
     /// unmounted.
-    pub fn new(mountpoint: &Path, options: &[&str]) -> io::Result<Channel> {
+    pub fn new(mountpoint: &Path, config: &MountConfig) -> io::Result<Channel> {
         // let mnt = CString::new(mountpoint.as_os_str().as_bytes())?;
         // let fd = unsafe { fuse_mount_compat25(mnt.as_ptr(), args) };
-        let fd = mount::mount(mountpoint, options);
-        if fd < 0 {
-            Err(io::Error::last_os_error())
-        } else {
-            Ok(Channel {
-                mountpoint: mountpoint.into(),
-                fd,
-            })
-        }
+        let options = config.to_options();
+        let options = options.iter().map(String::as_str).collect::<Vec<_>>();
+        #[cfg(target_os = "linux")]
+        let (fd, auto_unmount) = mount::mount(mountpoint, &options)?;
+        #[cfg(target_os = "macos")]
+        let fd = mount::mount(mountpoint, &options)?;
+        Ok(Channel {
+            mountpoint: mountpoint.into(),
+            fd,
+            mounted: true,
+            #[cfg(target_os = "linux")]
+            request_pipe: RefCell::new(Some(RequestPipe::default())),
+            #[cfg(target_os = "linux")]
+            auto_unmount,
+        })
     }
 
+    /// Return the file descriptor of the channel, e.g. to wait for requests with poll(2)
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    /// Make `receive` fail with `EAGAIN` instead of blocking if no request is pending
+    pub fn set_nonblocking(&self) -> io::Result<()> {
+        let flags =
+            fcntl::fcntl(self.fd, FcntlArg::F_GETFL).map_err(|_| io::Error::last_os_error())?;
+        let flags = OFlag::from_bits_truncate(flags) | OFlag::O_NONBLOCK;
+        fcntl::fcntl(self.fd, FcntlArg::F_SETFL(flags)).map_err(|_| io::Error::last_os_error())?;
+        Ok(())
+    }
+}
+
+impl Transport for Channel {
+    type Sender = DeviceSender;
+
     /// Return path of the mounted filesystem
-    pub fn mountpoint(&self) -> &Path {
+    fn mountpoint(&self) -> &Path {
         &self.mountpoint.as_ref()
     }
 
     /// Receives data up to the capacity of the given buffer (can block).
-    pub fn receive(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
+    fn receive(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
         // let rc = unsafe {
         //     libc::read(
         //         self.fd,
@@ -97,21 +142,80 @@ impl Channel {
                 Ok(())
             }
             Err(e) => {
-                error!("receive failed, the error is: {:?}", e);
+                if e.as_errno() == Some(Errno::EAGAIN) {
+                    debug!("receive found no pending request");
+                } else {
+                    error!("receive failed, the error is: {:?}", e);
+                }
                 Err(io::Error::last_os_error())
             }
         }
     }
 
+    /// Receives a request like `receive`, but splices it into a pipe and leaves the data of
+    /// a write request there. Falls back to `receive` if the pipe cannot be set up, e.g.
+    /// because it cannot grow to the size of the buffer beyond `/proc/sys/fs/pipe-max-size`
+    /// without `CAP_SYS_RESOURCE`.
+    #[cfg(target_os = "linux")]
+    fn receive_spliced(&self, buffer: &mut Vec<u8>) -> io::Result<Option<SplicedData>> {
+        let mut request_pipe = self.request_pipe.borrow_mut();
+        if let Some(ref mut pipe) = *request_pipe {
+            match pipe.receive(self.fd, buffer) {
+                Ok(spliced) => {
+                    debug!(
+                        "receive successfully {} byte spliced data",
+                        buffer.len() + spliced.map_or(0, |spliced| spliced.len)
+                    );
+                    return Ok(spliced);
+                }
+                Err(SpliceError::Pipe(err)) => {
+                    info!("splice failed, read requests instead: {}", err);
+                    *request_pipe = None;
+                }
+                Err(SpliceError::Device(err)) => {
+                    if err.raw_os_error() == Some(Errno::EAGAIN as i32) {
+                        debug!("receive found no pending request");
+                    } else {
+                        error!("receive failed, the error is: {:?}", err);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        self.receive(buffer).map(|()| None)
+    }
+
+    /// Start watching the fusermount process of an `auto_unmount` mount
+    #[cfg(target_os = "linux")]
+    fn start(&mut self) {
+        if let Some(auto_unmount) = self.auto_unmount.as_mut() {
+            auto_unmount.watch();
+        }
+    }
+
     /// Returns a sender object for this channel. The sender object can be
     /// used to send to the channel. Multiple sender objects can be used
     /// and they can safely be sent to other threads.
-    pub fn sender(&self) -> ChannelSender {
+    fn sender(&self) -> DeviceSender {
         // Since write/writev syscalls are threadsafe, we can simply create
         // a sender by using the same fd and use it in other threads. Only
         // the channel closes the fd when dropped. If any sender is used after
         // dropping the channel, it'll return an EBADF error.
-        ChannelSender { fd: self.fd }
+        DeviceSender { fd: self.fd }
+    }
+
+    fn unmount(&mut self, mode: UnmountMode) -> io::Result<()> {
+        mount::umount(&self.mountpoint, mode)?;
+        self.mounted = false;
+        #[cfg(target_os = "linux")]
+        {
+            self.auto_unmount = None;
+        }
+        Ok(())
+    }
+
+    fn set_unmounted(&mut self) {
+        self.mounted = false;
     }
 }
 
@@ -122,21 +226,32 @@ impl Drop for Channel {
         // (closing it before unnmount prevents sync unmount deadlock)
         // unsafe { libc::close(self.fd); }
         let _ = unistd::close(self.fd);
-        // Unmount this channel's mount point
-        let _ = unmount(self.mountpoint.as_ref());
+        // Unmount this channel's mount point, unless it was unmounted explicitly
+        if self.mounted {
+            if let Err(e) = unmount(self.mountpoint.as_ref()) {
+                error!("Failed to unmount {}: {}", self.mountpoint.display(), e);
+            }
+        }
     }
 }
 
+/// The sender of replies to the FUSE kernel driver
 #[derive(Clone, Copy, Debug)]
-pub struct ChannelSender {
+pub struct DeviceSender {
     fd: c_int,
 }
 
-impl ChannelSender {
+impl TransportSender for DeviceSender {
     /// Send all data in the slice of slice of bytes in a single write (can block).
-    pub fn send(&self, buffer: &[&[u8]]) -> io::Result<()> {
-        let iovecs: Vec<_> = buffer.iter().map(|d| IoVec::from_slice(d)).collect();
-        let res = uio::writev(self.fd, &iovecs);
+    fn send(&self, buffer: &[&[u8]]) -> io::Result<()> {
+        let res = if buffer.len() <= MAX_REPLY_SLICES {
+            let iovecs: [IoVec<&[u8]>; MAX_REPLY_SLICES] =
+                array::from_fn(|i| IoVec::from_slice(buffer.get(i).copied().unwrap_or(&[])));
+            uio::writev(self.fd, &iovecs[..buffer.len()])
+        } else {
+            let iovecs: Vec<_> = buffer.iter().map(|d| IoVec::from_slice(d)).collect();
+            uio::writev(self.fd, &iovecs)
+        };
         match res {
             Ok(s) => {
                 debug!("send successfully {} byte data", s);
@@ -148,24 +263,35 @@ impl ChannelSender {
             }
         }
     }
-}
 
-impl ReplySender for ChannelSender {
-    fn send(&self, data: &[&[u8]]) {
-        if let Err(err) = ChannelSender::send(self, data) {
-            error!("Failed to send FUSE reply: {}", err);
+    fn send_from_fd(&self, header: &[u8], fd: RawFd, offset: i64, len: usize) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        match splice::send_from_fd(self.fd, header, fd, offset, len) {
+            Ok(()) => {
+                debug!("send successfully {} byte spliced data", header.len() + len);
+                return Ok(());
+            }
+            // e.g. the file does not support splice or the pipe cannot grow, copy instead
+            Err(SpliceError::Pipe(err)) => debug!("splice failed, copy the reply: {}", err),
+            Err(SpliceError::Device(err)) => {
+                error!("send failed, the error is: {:?}", err);
+                return Err(err);
+            }
         }
+        let data = reply::read_from_fd(fd, offset, len)?;
+        self.send(&[header, &data])
     }
 }
 
-/// Unmount an arbitrary mount point
+/// Unmount an arbitrary mount point lazily
 pub fn unmount(mountpoint: &Path) -> io::Result<()> {
-    let res = mount::umount(mountpoint);
-    if res == 0 {
-        Ok(())
-    } else {
-        Err(io::Error::last_os_error())
-    }
+    unmount_with_mode(mountpoint, UnmountMode::Lazy)
+}
+
+/// Unmount an arbitrary mount point with the given mode, a busy mount point fails with
+/// `EBUSY` in `UnmountMode::Normal`
+pub fn unmount_with_mode(mountpoint: &Path, mode: UnmountMode) -> io::Result<()> {
+    mount::umount(mountpoint, mode)
 }
 
 #[cfg(test)]
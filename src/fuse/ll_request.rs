@@ -3,6 +3,7 @@
 //! A request represents information about a filesystem operation the kernel driver wants us to
 //! perform.
 
+use libc::{c_int, EIO, ENOSYS};
 use std::convert::TryFrom;
 use std::ffi::OsStr;
 use std::{error, fmt, mem};
@@ -43,6 +44,34 @@ impl fmt::Display for RequestError {
 
 impl error::Error for RequestError {}
 
+impl RequestError {
+    /// Returns the error code to reply with, `ENOSYS` for unknown operations and `EIO` for
+    /// malformed requests.
+    pub fn errno(&self) -> c_int {
+        match self {
+            RequestError::UnknownOperation(_) => ENOSYS,
+            RequestError::ShortReadHeader(_)
+            | RequestError::ShortRead(..)
+            | RequestError::InsufficientData => EIO,
+        }
+    }
+}
+
+/// Opcodes of requests the kernel expects no reply to, `FUSE_FORGET` and `FUSE_BATCH_FORGET`,
+/// the latter may be unknown to the ABI version in use.
+const NO_REPLY_OPCODES: [u32; 2] = [2, 42];
+
+/// Parses only the header of a request, e.g. to reply to a request that failed to parse.
+/// Returns `None` if there's not enough data for the header.
+pub fn parse_header(data: &[u8]) -> Option<&fuse_in_header> {
+    unsafe { ArgumentIterator::new(data).fetch() }
+}
+
+/// Returns true if the kernel expects a reply to the request with the given header.
+pub fn expects_reply(header: &fuse_in_header) -> bool {
+    !NO_REPLY_OPCODES.contains(&header.opcode)
+}
+
 /// Filesystem operation (and arguments) the kernel driver wants us to perform. The fields of each
 /// variant needs to match the actual arguments the kernel driver sends for the specific operation.
 #[derive(Debug)]
@@ -364,9 +393,17 @@ impl<'a> TryFrom<&'a [u8]> for Request<'a> {
     type Error = RequestError;
 
     fn try_from(data: &'a [u8]) -> Result<Self, Self::Error> {
+        Request::with_spliced(data, 0)
+    }
+}
+
+impl<'a> Request<'a> {
+    /// Parse a request whose last `spliced` bytes are not part of the data, but were left in a
+    /// pipe by a spliced receive, the data of a write request is then empty
+    pub fn with_spliced(data: &'a [u8], spliced: usize) -> Result<Self, RequestError> {
         // Parse a raw packet as sent by the kernel driver into typed data. Every request always
         // begins with a `fuse_in_header` struct followed by arguments depending on the opcode.
-        let data_len = data.len();
+        let data_len = data.len() + spliced;
         let mut data = ArgumentIterator::new(data);
         // Parse header
         let header: &fuse_in_header =
@@ -383,9 +420,7 @@ impl<'a> TryFrom<&'a [u8]> for Request<'a> {
             Operation::parse(&opcode, &mut data).ok_or_else(|| RequestError::InsufficientData)?;
         Ok(Self { header, operation })
     }
-}
 
-impl<'a> Request<'a> {
     /// Returns the unique identifier of this request.
     ///
     /// The FUSE kernel driver assigns a unique id to every concurrent request. This allows to
@@ -699,6 +734,34 @@ mod tests {
         }
     }
 
+    #[test]
+    fn unknown_operation() {
+        let mut data = INIT_REQUEST;
+        data[4..8].copy_from_slice(&999_u32.to_ne_bytes());
+        let err = Request::try_from(&data[..]).unwrap_err();
+        match err {
+            RequestError::UnknownOperation(999) => (),
+            _ => panic!("Unexpected request parsing result"),
+        }
+        assert_eq!(err.errno(), ENOSYS);
+        // the header is still available to reply to the request
+        let header = parse_header(&data).unwrap();
+        assert_eq!(header.unique, 0xdead_beef_baad_f00d);
+        assert!(expects_reply(header));
+    }
+
+    #[test]
+    fn malformed_request() {
+        let err = Request::try_from(&INIT_REQUEST[..48]).unwrap_err();
+        assert_eq!(err.errno(), EIO);
+        assert!(parse_header(&INIT_REQUEST[..48]).is_some());
+        assert!(parse_header(&INIT_REQUEST[..20]).is_none());
+        // forget requests are never replied
+        let mut data = INIT_REQUEST;
+        data[4..8].copy_from_slice(&42_u32.to_ne_bytes());
+        assert!(!expects_reply(parse_header(&data).unwrap()));
+    }
+
     #[test]
     fn init() {
         let req = Request::try_from(&INIT_REQUEST[..]).unwrap();
@@ -0,0 +1,494 @@
+//! Metrics of a session
+//!
+//! The metrics count the requests by operation, the error replies by operation and errno and
+//! the bytes read and written, and keep a histogram of the latency of each operation. A
+//! filesystem may add its own counters and gauges as a metric source. The metrics are
+//! rendered in the Prometheus text format, served on a Unix socket or logged periodically:
+//!
+//! ```text
+//! # HELP fuse_requests_total Requests received by operation
+//! # TYPE fuse_requests_total counter
+//! fuse_requests_total{op="LOOKUP"} 12
+//! # HELP fuse_errors_total Error replies by operation and errno
+//! # TYPE fuse_errors_total counter
+//! fuse_errors_total{op="LOOKUP",errno="ENOENT"} 3
+//! ```
+
+use log::{info, warn};
+use nix::errno::Errno;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Write as _};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::abi::{from_bytes, fuse_opcode, fuse_out_header, fuse_write_out};
+use super::ll_request;
+use super::observer::Observer;
+use super::protocol_log::opcode_name;
+
+/// Upper bounds of the latency histogram buckets in microseconds
+const LATENCY_BUCKETS: [u64; 11] = [
+    10, 50, 100, 500, 1_000, 5_000, 10_000, 50_000, 100_000, 500_000, 1_000_000,
+];
+
+/// How long a client may take to send its request before it gets the bare metrics
+const CLIENT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Type of a metric of a metric source
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MetricKind {
+    /// A value that only increases
+    Counter,
+    /// A value that goes up and down
+    Gauge,
+}
+
+/// A metric of a metric source
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Metric {
+    /// Name of the metric, counters end with `_total`
+    pub name: &'static str,
+    /// Description of the metric
+    pub help: &'static str,
+    /// Type of the metric
+    pub kind: MetricKind,
+    /// Current value of the metric
+    pub value: u64,
+}
+
+/// A source of metrics besides the requests and replies, e.g. the caches of a filesystem
+pub trait MetricSource: fmt::Debug + Send + Sync {
+    /// Return the current values of the metrics
+    fn metrics(&self) -> Vec<Metric>;
+}
+
+/// Latencies of an operation
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Number of latencies in each bucket, the last bucket holds those above all bounds
+    buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    count: u64,
+    sum: Duration,
+}
+
+impl Histogram {
+    fn observe(&mut self, latency: Duration) {
+        let micros = latency.as_micros();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| micros <= u128::from(bound))
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += latency;
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Opcode and arrival time of the requests not replied yet by unique id
+    pending: HashMap<u64, (u32, Instant)>,
+    /// Requests by opcode
+    requests: BTreeMap<u32, u64>,
+    /// Error replies by opcode and errno
+    errors: BTreeMap<(u32, i32), u64>,
+    /// Latencies of the replied requests by opcode
+    latencies: BTreeMap<u32, Histogram>,
+    bytes_read: u64,
+    bytes_written: u64,
+}
+
+/// An observer collecting the metrics of a session
+#[derive(Debug, Default)]
+pub struct Metrics {
+    state: Mutex<State>,
+    sources: Mutex<Vec<Arc<dyn MetricSource>>>,
+}
+
+/// Return the name of an errno, or its number if unknown
+fn errno_name(errno: i32) -> String {
+    match Errno::from_i32(errno) {
+        Errno::UnknownErrno => errno.to_string(),
+        errno => format!("{:?}", errno),
+    }
+}
+
+/// Append the help and type lines of a metric
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+impl Metrics {
+    /// Create metrics without any request
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Add the metrics of the source to the rendered metrics
+    pub fn add_source(&self, source: Arc<dyn MetricSource>) {
+        self.sources.lock().unwrap().push(source);
+    }
+
+    /// Render the metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        // writing to a string never fails, so the results are ignored
+        let mut out = String::new();
+        {
+            let state = self.state.lock().unwrap();
+            header(
+                &mut out,
+                "fuse_requests_total",
+                "Requests received by operation",
+                "counter",
+            );
+            for (&opcode, count) in &state.requests {
+                let _ = writeln!(
+                    out,
+                    "fuse_requests_total{{op=\"{}\"}} {}",
+                    opcode_name(opcode),
+                    count
+                );
+            }
+            header(
+                &mut out,
+                "fuse_errors_total",
+                "Error replies by operation and errno",
+                "counter",
+            );
+            for (&(opcode, errno), count) in &state.errors {
+                let _ = writeln!(
+                    out,
+                    "fuse_errors_total{{op=\"{}\",errno=\"{}\"}} {}",
+                    opcode_name(opcode),
+                    errno_name(errno),
+                    count
+                );
+            }
+            header(
+                &mut out,
+                "fuse_request_duration_seconds",
+                "Latency from receiving a request to sending its reply by operation",
+                "histogram",
+            );
+            for (&opcode, histogram) in &state.latencies {
+                let op = opcode_name(opcode);
+                let mut cumulative = 0;
+                for (bound, count) in LATENCY_BUCKETS.iter().zip(&histogram.buckets) {
+                    cumulative += count;
+                    let _ = writeln!(
+                        out,
+                        "fuse_request_duration_seconds_bucket{{op=\"{}\",le=\"{}\"}} {}",
+                        op,
+                        *bound as f64 / 1_000_000.0,
+                        cumulative
+                    );
+                }
+                let _ = writeln!(
+                    out,
+                    "fuse_request_duration_seconds_bucket{{op=\"{}\",le=\"+Inf\"}} {}",
+                    op, histogram.count
+                );
+                let _ = writeln!(
+                    out,
+                    "fuse_request_duration_seconds_sum{{op=\"{}\"}} {}",
+                    op,
+                    histogram.sum.as_secs_f64()
+                );
+                let _ = writeln!(
+                    out,
+                    "fuse_request_duration_seconds_count{{op=\"{}\"}} {}",
+                    op, histogram.count
+                );
+            }
+            header(
+                &mut out,
+                "fuse_read_bytes_total",
+                "Bytes replied to read requests",
+                "counter",
+            );
+            let _ = writeln!(out, "fuse_read_bytes_total {}", state.bytes_read);
+            header(
+                &mut out,
+                "fuse_written_bytes_total",
+                "Bytes written by write requests",
+                "counter",
+            );
+            let _ = writeln!(out, "fuse_written_bytes_total {}", state.bytes_written);
+        }
+        for source in self.sources.lock().unwrap().iter() {
+            for metric in source.metrics() {
+                let kind = match metric.kind {
+                    MetricKind::Counter => "counter",
+                    MetricKind::Gauge => "gauge",
+                };
+                header(&mut out, metric.name, metric.help, kind);
+                let _ = writeln!(out, "{} {}", metric.name, metric.value);
+            }
+        }
+        out
+    }
+
+    /// Create a Unix socket at the path to serve metrics on, a socket left at the path by an
+    /// earlier run is replaced
+    pub fn bind(path: &Path) -> io::Result<UnixListener> {
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if metadata.file_type().is_socket() {
+                fs::remove_file(path)?;
+            }
+        }
+        UnixListener::bind(path)
+    }
+
+    /// Serve the rendered metrics on the socket from a new thread. HTTP clients such as
+    /// `curl --unix-socket` get an HTTP response, other clients such as `socat` get the bare
+    /// metrics.
+    pub fn serve(self: Arc<Self>, listener: UnixListener) -> io::Result<()> {
+        thread::Builder::new()
+            .name(String::from("metrics"))
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if let Err(e) = stream.and_then(|stream| self.respond(stream)) {
+                        warn!("Failed to serve metrics: {}", e);
+                    }
+                }
+            })?;
+        Ok(())
+    }
+
+    /// Log the rendered metrics at the interval from a new thread, the name tells the
+    /// metrics of several sessions apart
+    pub fn log_every(self: Arc<Self>, name: String, interval: Duration) -> io::Result<()> {
+        thread::Builder::new()
+            .name(String::from("metrics log"))
+            .spawn(move || loop {
+                thread::sleep(interval);
+                info!("metrics of {}:\n{}", name, self.render());
+            })?;
+        Ok(())
+    }
+
+    fn respond(&self, mut stream: UnixStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+        let mut request = [0_u8; 1024];
+        let len = match stream.read(&mut request) {
+            Ok(len) => len,
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                0
+            }
+            Err(e) => return Err(e),
+        };
+        let body = self.render();
+        if request[..len].starts_with(b"GET ") {
+            write!(
+                stream,
+                "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n",
+                body.len()
+            )?;
+        }
+        stream.write_all(body.as_bytes())
+    }
+}
+
+impl Observer for Metrics {
+    fn request(&self, data: &[u8]) {
+        let header = match ll_request::parse_header(data) {
+            Some(header) => header,
+            None => return,
+        };
+        let mut state = self.state.lock().unwrap();
+        *state.requests.entry(header.opcode).or_insert(0) += 1;
+        if ll_request::expects_reply(header) {
+            state
+                .pending
+                .insert(header.unique, (header.opcode, Instant::now()));
+        }
+    }
+
+    fn request_from_fd(&self, data: &[u8], _len: usize) {
+        self.request(data);
+    }
+
+    fn reply(&self, data: &[&[u8]]) {
+        let (header, payload) = match data.split_first() {
+            Some((header, payload)) => (header, payload),
+            None => return,
+        };
+        let len = payload.iter().map(|slice| slice.len()).sum();
+        self.observe_reply(header, len, payload.first().copied());
+    }
+
+    fn reply_from_fd(&self, header: &[u8], len: usize) {
+        self.observe_reply(header, len, None);
+    }
+
+    fn needs_file_data(&self) -> bool {
+        false
+    }
+}
+
+impl Metrics {
+    /// Count a reply with the given header and payload length, the first slice of the
+    /// payload is given if it is in memory
+    fn observe_reply(&self, header: &[u8], len: usize, payload: Option<&[u8]>) {
+        let header: fuse_out_header = match from_bytes(header) {
+            Ok(header) => header,
+            Err(_) => return,
+        };
+        let mut state = self.state.lock().unwrap();
+        let (opcode, start) = match state.pending.remove(&header.unique) {
+            Some(pending) => pending,
+            None => return,
+        };
+        state
+            .latencies
+            .entry(opcode)
+            .or_default()
+            .observe(start.elapsed());
+        if header.error != 0 {
+            *state.errors.entry((opcode, -header.error)).or_insert(0) += 1;
+            return;
+        }
+        if opcode == fuse_opcode::FUSE_READ as u32 {
+            state.bytes_read += len as u64;
+        } else if opcode == fuse_opcode::FUSE_WRITE as u32 {
+            if let Some(Ok(out)) = payload.map(from_bytes::<fuse_write_out>) {
+                state.bytes_written += u64::from(out.size);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Metric, MetricKind, MetricSource, Metrics};
+    use crate::fuse::{Filesystem, MockKernel, ReplyData, ReplyWrite, Request};
+    use libc::ENOENT;
+    use std::ffi::OsStr;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::sync::Arc;
+
+    struct ByteFS;
+
+    impl Filesystem for ByteFS {
+        fn read(
+            &mut self,
+            _req: &Request<'_>,
+            _ino: u64,
+            _fh: u64,
+            _offset: i64,
+            size: u32,
+            reply: ReplyData,
+        ) {
+            reply.data(&vec![0; size as usize]);
+        }
+
+        fn write(
+            &mut self,
+            _req: &Request<'_>,
+            _ino: u64,
+            _fh: u64,
+            _offset: i64,
+            data: &[u8],
+            _flags: u32,
+            reply: ReplyWrite,
+        ) {
+            reply.written(data.len() as u32);
+        }
+    }
+
+    #[derive(Debug)]
+    struct Source;
+
+    impl MetricSource for Source {
+        fn metrics(&self) -> Vec<Metric> {
+            vec![Metric {
+                name: "test_hits_total",
+                help: "Hits of the test",
+                kind: MetricKind::Counter,
+                value: 7,
+            }]
+        }
+    }
+
+    #[test]
+    fn collect_and_render() {
+        let mut kernel = MockKernel::new(ByteFS).unwrap();
+        let metrics = kernel.metrics();
+        metrics.add_source(Arc::new(Source));
+        assert_eq!(kernel.read(2, 0, 0, 100), Ok(vec![0; 100]));
+        assert_eq!(kernel.read(2, 0, 0, 28), Ok(vec![0; 28]));
+        assert_eq!(kernel.write(2, 0, 0, b"hello"), Ok(5));
+        assert_eq!(
+            kernel.lookup(1, OsStr::new("a")).map(|_| ()),
+            Err(libc::ENOSYS)
+        );
+        // the same metrics are returned while the kernel runs
+        assert!(Arc::ptr_eq(&metrics, &kernel.metrics()));
+
+        let text = metrics.render();
+        let lines: Vec<_> = text.lines().collect();
+        for line in &[
+            "# TYPE fuse_requests_total counter",
+            "fuse_requests_total{op=\"LOOKUP\"} 1",
+            "fuse_requests_total{op=\"READ\"} 2",
+            "fuse_requests_total{op=\"WRITE\"} 1",
+            "fuse_errors_total{op=\"LOOKUP\",errno=\"ENOSYS\"} 1",
+            "# TYPE fuse_request_duration_seconds histogram",
+            "fuse_request_duration_seconds_bucket{op=\"READ\",le=\"+Inf\"} 2",
+            "fuse_request_duration_seconds_count{op=\"READ\"} 2",
+            "fuse_read_bytes_total 128",
+            "fuse_written_bytes_total 5",
+            "# TYPE test_hits_total counter",
+            "test_hits_total 7",
+        ] {
+            assert!(lines.contains(line), "{:?} is missing in\n{}", line, text);
+        }
+        // the buckets are cumulative and end with all requests
+        let buckets: Vec<u64> = lines
+            .iter()
+            .filter(|line| line.starts_with("fuse_request_duration_seconds_bucket{op=\"WRITE\""))
+            .map(|line| line.rsplit(' ').next().unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(buckets.len(), 12);
+        assert!(buckets.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(buckets.last(), Some(&1));
+        assert_eq!(super::errno_name(ENOENT), "ENOENT");
+    }
+
+    #[test]
+    fn serve_on_socket() {
+        let dir = std::env::temp_dir().join(format!("fuse_ll_metrics_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("metrics.sock");
+        let metrics = Arc::new(Metrics::new());
+        metrics.add_source(Arc::new(Source));
+        // a stale socket is replaced
+        drop(Metrics::bind(&path).unwrap());
+        let listener = Metrics::bind(&path).unwrap();
+        Arc::clone(&metrics).serve(listener).unwrap();
+
+        let mut http = UnixStream::connect(&path).unwrap();
+        http.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        http.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(response.ends_with("test_hits_total 7\n"));
+
+        let mut bare = UnixStream::connect(&path).unwrap();
+        let mut response = String::new();
+        bare.read_to_string(&mut response).unwrap();
+        assert_eq!(response, metrics.render());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,620 @@
+//! In-process mock of the kernel driver
+//!
+//! A mock kernel builds requests the way the kernel driver does, dispatches them to a
+//! filesystem in the calling thread and decodes the replies, so the logic of a filesystem can
+//! be tested without mounting it.
+
+use libc::{EIO, S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFMT, S_IFSOCK};
+use nix::unistd;
+use std::collections::VecDeque;
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::mem;
+use std::os::raw::c_int;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "abi-7-9")]
+use super::abi::fuse_getattr_in;
+use super::abi::fuse_mknod_in;
+use super::abi::{as_bytes, from_bytes};
+use super::abi::{fuse_attr, fuse_attr_out, fuse_entry_out, fuse_open_out, fuse_write_out};
+use super::abi::{fuse_create_in, fuse_init_in, fuse_mkdir_in, fuse_open_in, fuse_read_in};
+use super::abi::{fuse_dirent, fuse_forget_in, fuse_release_in, fuse_rename_in, fuse_write_in};
+use super::abi::{fuse_flush_in, fuse_fsync_in};
+use super::abi::{fuse_in_header, fuse_opcode, fuse_out_header};
+use super::abi::{FUSE_KERNEL_MINOR_VERSION, FUSE_KERNEL_VERSION};
+use super::metrics::Metrics;
+use super::mount::UnmountMode;
+use super::session::Session;
+use super::transport::{SplicedData, Transport, TransportSender};
+use super::{FileAttr, FileType, Filesystem};
+
+/// The replies sent by the filesystem, oldest first
+pub(super) type Replies = Arc<Mutex<VecDeque<Vec<u8>>>>;
+
+/// A transport without a kernel, requests are passed to the session directly
+#[derive(Debug)]
+pub struct MockTransport {
+    replies: Replies,
+}
+
+/// The sender of replies of the mock transport, which keeps the replies
+#[derive(Debug)]
+pub struct MockSender {
+    replies: Replies,
+}
+
+impl MockTransport {
+    /// Create a transport and return the replies sent over it
+    pub(super) fn new() -> (MockTransport, Replies) {
+        let replies = Replies::default();
+        let transport = MockTransport {
+            replies: Arc::clone(&replies),
+        };
+        (transport, replies)
+    }
+}
+
+impl Transport for MockTransport {
+    type Sender = MockSender;
+
+    fn mountpoint(&self) -> &Path {
+        Path::new("/mock")
+    }
+
+    fn receive(&self, _buffer: &mut Vec<u8>) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENODEV))
+    }
+
+    fn sender(&self) -> MockSender {
+        MockSender {
+            replies: Arc::clone(&self.replies),
+        }
+    }
+
+    fn unmount(&mut self, _mode: UnmountMode) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_unmounted(&mut self) {}
+}
+
+impl TransportSender for MockSender {
+    fn send(&self, data: &[&[u8]]) -> io::Result<()> {
+        self.replies.lock().unwrap().push_back(data.concat());
+        Ok(())
+    }
+}
+
+/// An entry replied to a lookup, mkdir or create request
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MockEntry {
+    /// Inode number
+    pub ino: u64,
+    /// Generation of the inode number
+    pub generation: u64,
+    /// Attributes of the inode
+    pub attr: FileAttr,
+    /// Time the kernel may cache the name
+    pub entry_ttl: Duration,
+    /// Time the kernel may cache the attributes
+    pub attr_ttl: Duration,
+}
+
+/// An entry of a directory replied to a readdir request
+#[derive(Clone, Debug, PartialEq)]
+pub struct MockDirEntry {
+    /// Inode number
+    pub ino: u64,
+    /// Offset of the next entry
+    pub offset: i64,
+    /// Kind of file
+    pub kind: FileType,
+    /// Name of the entry
+    pub name: OsString,
+}
+
+/// A kernel driver in the same process, which sends one request at a time to a filesystem
+/// and returns its reply. All requests are sent with the caller set by `set_caller`, which
+/// defaults to the current process.
+#[derive(Debug)]
+pub struct MockKernel<FS: Filesystem> {
+    session: Session<FS, MockTransport>,
+    replies: Replies,
+    unique: u64,
+    uid: u32,
+    gid: u32,
+    pid: u32,
+}
+
+impl<FS: Filesystem> MockKernel<FS> {
+    /// Create a mock kernel and initialize the filesystem, fails with the error the
+    /// filesystem replied to the init request
+    pub fn new(filesystem: FS) -> Result<MockKernel<FS>, c_int> {
+        let (transport, replies) = MockTransport::new();
+        MockKernel::init(Session::with_transport(filesystem, transport), replies)
+    }
+
+    /// Create a mock kernel like `new`, which records every request and reply from the init
+    /// request on to a trace file, see `Session::trace`
+    pub fn traced(filesystem: FS, path: &Path, description: &str) -> io::Result<MockKernel<FS>> {
+        let (transport, replies) = MockTransport::new();
+        let mut session = Session::with_transport(filesystem, transport);
+        session.trace(path, description)?;
+        MockKernel::init(session, replies).map_err(io::Error::from_raw_os_error)
+    }
+
+    /// Initialize the filesystem of the session
+    fn init(
+        session: Session<FS, MockTransport>,
+        replies: Replies,
+    ) -> Result<MockKernel<FS>, c_int> {
+        let mut kernel = MockKernel {
+            session,
+            replies,
+            unique: 0,
+            uid: unistd::geteuid().as_raw(),
+            gid: unistd::getegid().as_raw(),
+            pid: process::id(),
+        };
+        let arg = fuse_init_in {
+            major: FUSE_KERNEL_VERSION,
+            minor: FUSE_KERNEL_MINOR_VERSION,
+            max_readahead: 128 * 1024,
+            flags: 0,
+        };
+        kernel.call(fuse_opcode::FUSE_INIT, 0, &[as_bytes(&arg)])?;
+        Ok(kernel)
+    }
+
+    /// Return the filesystem
+    pub fn filesystem(&self) -> &FS {
+        &self.session.filesystem
+    }
+
+    /// Return the filesystem for changes between requests
+    pub fn filesystem_mut(&mut self) -> &mut FS {
+        &mut self.session.filesystem
+    }
+
+    /// Collect metrics of the following requests, see `Session::metrics`
+    pub fn metrics(&mut self) -> Arc<Metrics> {
+        self.session.metrics()
+    }
+
+    /// Send the following requests on behalf of the given user, group and process
+    pub fn set_caller(&mut self, uid: u32, gid: u32, pid: u32) {
+        self.uid = uid;
+        self.gid = gid;
+        self.pid = pid;
+    }
+
+    /// Send a request with the raw opcode and argument bytes, return the data of the reply
+    /// or its error, `None` if the filesystem sent no reply
+    pub fn request(
+        &mut self,
+        opcode: u32,
+        nodeid: u64,
+        arg: &[u8],
+    ) -> Option<Result<Vec<u8>, c_int>> {
+        self.request_spliced(opcode, nodeid, arg, None)
+    }
+
+    /// Send a request like `request`, of which the data following the argument was left in
+    /// a pipe by a spliced receive
+    fn request_spliced(
+        &mut self,
+        opcode: u32,
+        nodeid: u64,
+        arg: &[u8],
+        spliced: Option<SplicedData>,
+    ) -> Option<Result<Vec<u8>, c_int>> {
+        self.unique += 1;
+        let spliced_len = spliced.map_or(0, |spliced| spliced.len);
+        let header = fuse_in_header {
+            len: (mem::size_of::<fuse_in_header>() + arg.len() + spliced_len) as u32,
+            opcode,
+            unique: self.unique,
+            nodeid,
+            uid: self.uid,
+            gid: self.gid,
+            pid: self.pid,
+            padding: 0,
+        };
+        let data = [as_bytes(&header), arg].concat();
+        self.session.process_spliced(&data, spliced);
+
+        let reply = self.replies.lock().unwrap().pop_front()?;
+        let out: fuse_out_header = match from_bytes(&reply) {
+            Ok(out) => out,
+            Err(err) => return Some(Err(err)),
+        };
+        assert_eq!(out.unique, self.unique, "reply to another request");
+        assert_eq!(out.len as usize, reply.len(), "wrong length of reply");
+        if out.error != 0 {
+            Some(Err(-out.error))
+        } else {
+            Some(Ok(reply[mem::size_of::<fuse_out_header>()..].to_vec()))
+        }
+    }
+
+    /// Send a request whose argument is made of the given parts, a missing reply is `EIO`
+    fn call(
+        &mut self,
+        opcode: fuse_opcode,
+        nodeid: u64,
+        parts: &[&[u8]],
+    ) -> Result<Vec<u8>, c_int> {
+        self.request(opcode as u32, nodeid, &parts.concat())
+            .unwrap_or(Err(EIO))
+    }
+
+    /// Look up a directory entry by name
+    pub fn lookup(&mut self, parent: u64, name: &OsStr) -> Result<MockEntry, c_int> {
+        let reply = self.call(fuse_opcode::FUSE_LOOKUP, parent, &[&c_name(name)])?;
+        entry_from_bytes(&reply)
+    }
+
+    /// Forget `nlookup` lookups of an inode, the filesystem does not reply
+    pub fn forget(&mut self, ino: u64, nlookup: u64) {
+        let arg = fuse_forget_in { nlookup };
+        let reply = self.request(fuse_opcode::FUSE_FORGET as u32, ino, as_bytes(&arg));
+        assert!(reply.is_none(), "reply to forget");
+    }
+
+    /// Get the attributes of an inode
+    pub fn getattr(&mut self, ino: u64) -> Result<FileAttr, c_int> {
+        #[cfg(feature = "abi-7-9")]
+        let arg: fuse_getattr_in = zeroed();
+        #[cfg(feature = "abi-7-9")]
+        let reply = self.call(fuse_opcode::FUSE_GETATTR, ino, &[as_bytes(&arg)])?;
+        #[cfg(not(feature = "abi-7-9"))]
+        let reply = self.call(fuse_opcode::FUSE_GETATTR, ino, &[])?;
+        let out: fuse_attr_out = from_bytes(&reply)?;
+        Ok(file_attr(&out.attr))
+    }
+
+    /// Create a file node
+    pub fn mknod(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        rdev: u32,
+    ) -> Result<MockEntry, c_int> {
+        let mut arg: fuse_mknod_in = zeroed();
+        arg.mode = mode;
+        arg.rdev = rdev;
+        let reply = self.call(
+            fuse_opcode::FUSE_MKNOD,
+            parent,
+            &[as_bytes(&arg), &c_name(name)],
+        )?;
+        entry_from_bytes(&reply)
+    }
+
+    /// Create a directory
+    pub fn mkdir(&mut self, parent: u64, name: &OsStr, mode: u32) -> Result<MockEntry, c_int> {
+        let mut arg: fuse_mkdir_in = zeroed();
+        arg.mode = mode;
+        let reply = self.call(
+            fuse_opcode::FUSE_MKDIR,
+            parent,
+            &[as_bytes(&arg), &c_name(name)],
+        )?;
+        entry_from_bytes(&reply)
+    }
+
+    /// Remove a file
+    pub fn unlink(&mut self, parent: u64, name: &OsStr) -> Result<(), c_int> {
+        self.call(fuse_opcode::FUSE_UNLINK, parent, &[&c_name(name)])?;
+        Ok(())
+    }
+
+    /// Remove a directory
+    pub fn rmdir(&mut self, parent: u64, name: &OsStr) -> Result<(), c_int> {
+        self.call(fuse_opcode::FUSE_RMDIR, parent, &[&c_name(name)])?;
+        Ok(())
+    }
+
+    /// Rename a directory entry
+    pub fn rename(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+    ) -> Result<(), c_int> {
+        let arg = fuse_rename_in { newdir: newparent };
+        self.call(
+            fuse_opcode::FUSE_RENAME,
+            parent,
+            &[as_bytes(&arg), &c_name(name), &c_name(newname)],
+        )?;
+        Ok(())
+    }
+
+    /// Open a file, return the file handle
+    pub fn open(&mut self, ino: u64, flags: u32) -> Result<u64, c_int> {
+        let arg = fuse_open_in { flags, unused: 0 };
+        let reply = self.call(fuse_opcode::FUSE_OPEN, ino, &[as_bytes(&arg)])?;
+        let out: fuse_open_out = from_bytes(&reply)?;
+        Ok(out.fh)
+    }
+
+    /// Create and open a file, return its entry and the file handle
+    pub fn create(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        flags: u32,
+    ) -> Result<(MockEntry, u64), c_int> {
+        let mut arg: fuse_create_in = zeroed();
+        arg.mode = mode;
+        arg.flags = flags;
+        let reply = self.call(
+            fuse_opcode::FUSE_CREATE,
+            parent,
+            &[as_bytes(&arg), &c_name(name)],
+        )?;
+        let entry = entry_from_bytes(&reply)?;
+        let open: fuse_open_out = from_bytes(&reply[mem::size_of::<fuse_entry_out>()..])?;
+        Ok((entry, open.fh))
+    }
+
+    /// Read up to `size` bytes of an open file at `offset`
+    pub fn read(&mut self, ino: u64, fh: u64, offset: i64, size: u32) -> Result<Vec<u8>, c_int> {
+        let mut arg: fuse_read_in = zeroed();
+        arg.fh = fh;
+        arg.offset = offset as u64;
+        arg.size = size;
+        self.call(fuse_opcode::FUSE_READ, ino, &[as_bytes(&arg)])
+    }
+
+    /// Write data to an open file at `offset`, return the number of bytes written
+    pub fn write(&mut self, ino: u64, fh: u64, offset: i64, data: &[u8]) -> Result<u32, c_int> {
+        let mut arg: fuse_write_in = zeroed();
+        arg.fh = fh;
+        arg.offset = offset as u64;
+        arg.size = data.len() as u32;
+        let reply = self.call(fuse_opcode::FUSE_WRITE, ino, &[as_bytes(&arg), data])?;
+        let out: fuse_write_out = from_bytes(&reply)?;
+        Ok(out.size)
+    }
+
+    /// Write data to an open file at `offset` like `write`, but leave the data in a pipe as a
+    /// spliced receive does, the data must fit into a pipe of 64 KiB
+    pub fn write_spliced(
+        &mut self,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+    ) -> Result<u32, c_int> {
+        let mut arg: fuse_write_in = zeroed();
+        arg.fh = fh;
+        arg.offset = offset as u64;
+        arg.size = data.len() as u32;
+        let (read, write) = unistd::pipe().map_err(|_| EIO)?;
+        let reply = match unistd::write(write, data) {
+            Ok(n) if n == data.len() => {
+                let spliced = SplicedData {
+                    fd: read,
+                    len: data.len(),
+                };
+                self.request_spliced(
+                    fuse_opcode::FUSE_WRITE as u32,
+                    ino,
+                    as_bytes(&arg),
+                    Some(spliced),
+                )
+                .unwrap_or(Err(EIO))
+            }
+            _ => Err(EIO),
+        };
+        let _ = unistd::close(read);
+        let _ = unistd::close(write);
+        let out: fuse_write_out = from_bytes(&reply?)?;
+        Ok(out.size)
+    }
+
+    /// Flush an open file, as on every close of a file descriptor
+    pub fn flush(&mut self, ino: u64, fh: u64) -> Result<(), c_int> {
+        let mut arg: fuse_flush_in = zeroed();
+        arg.fh = fh;
+        self.call(fuse_opcode::FUSE_FLUSH, ino, &[as_bytes(&arg)])?;
+        Ok(())
+    }
+
+    /// Synchronize an open file, only its data if `datasync` is set
+    pub fn fsync(&mut self, ino: u64, fh: u64, datasync: bool) -> Result<(), c_int> {
+        let mut arg: fuse_fsync_in = zeroed();
+        arg.fh = fh;
+        arg.fsync_flags = datasync as u32;
+        self.call(fuse_opcode::FUSE_FSYNC, ino, &[as_bytes(&arg)])?;
+        Ok(())
+    }
+
+    /// Release an open file
+    pub fn release(&mut self, ino: u64, fh: u64, flags: u32) -> Result<(), c_int> {
+        let mut arg: fuse_release_in = zeroed();
+        arg.fh = fh;
+        arg.flags = flags;
+        self.call(fuse_opcode::FUSE_RELEASE, ino, &[as_bytes(&arg)])?;
+        Ok(())
+    }
+
+    /// Open a directory, return the file handle
+    pub fn opendir(&mut self, ino: u64, flags: u32) -> Result<u64, c_int> {
+        let arg = fuse_open_in { flags, unused: 0 };
+        let reply = self.call(fuse_opcode::FUSE_OPENDIR, ino, &[as_bytes(&arg)])?;
+        let out: fuse_open_out = from_bytes(&reply)?;
+        Ok(out.fh)
+    }
+
+    /// Read the entries of an open directory from `offset` that fit in `size` bytes
+    pub fn readdir(
+        &mut self,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+    ) -> Result<Vec<MockDirEntry>, c_int> {
+        let mut arg: fuse_read_in = zeroed();
+        arg.fh = fh;
+        arg.offset = offset as u64;
+        arg.size = size;
+        let reply = self.call(fuse_opcode::FUSE_READDIR, ino, &[as_bytes(&arg)])?;
+        dir_entries_from_bytes(&reply)
+    }
+
+    /// Release an open directory
+    pub fn releasedir(&mut self, ino: u64, fh: u64) -> Result<(), c_int> {
+        let mut arg: fuse_release_in = zeroed();
+        arg.fh = fh;
+        self.call(fuse_opcode::FUSE_RELEASEDIR, ino, &[as_bytes(&arg)])?;
+        Ok(())
+    }
+}
+
+impl<FS: Filesystem> Drop for MockKernel<FS> {
+    fn drop(&mut self) {
+        self.session.destroy();
+    }
+}
+
+/// Return an argument with all fields zero, the fields present depend on the ABI version
+#[allow(unsafe_code)]
+fn zeroed<T>() -> T {
+    // the argument structures consist of integers only
+    unsafe { mem::zeroed() }
+}
+
+/// Return the name terminated by NUL as the kernel sends it
+fn c_name(name: &OsStr) -> Vec<u8> {
+    let mut bytes = name.as_bytes().to_vec();
+    bytes.push(0);
+    bytes
+}
+
+// Linux has mode_t = u32 but macOS has mode_t = u16, requiring a typecast, so silence lint.
+#[allow(trivial_numeric_casts, clippy::unnecessary_cast)]
+/// Returns the file kind of a mode
+fn file_kind(mode: u32) -> FileType {
+    match mode as libc::mode_t & S_IFMT {
+        S_IFIFO => FileType::NamedPipe,
+        S_IFCHR => FileType::CharDevice,
+        S_IFBLK => FileType::BlockDevice,
+        S_IFDIR => FileType::Directory,
+        S_IFLNK => FileType::Symlink,
+        S_IFSOCK => FileType::Socket,
+        _ => FileType::RegularFile,
+    }
+}
+
+fn time(secs: u64, nsecs: u32) -> SystemTime {
+    UNIX_EPOCH + Duration::new(secs, nsecs)
+}
+
+fn file_attr(attr: &fuse_attr) -> FileAttr {
+    FileAttr {
+        ino: attr.ino,
+        size: attr.size,
+        blocks: attr.blocks,
+        atime: time(attr.atime, attr.atimensec),
+        mtime: time(attr.mtime, attr.mtimensec),
+        ctime: time(attr.ctime, attr.ctimensec),
+        #[cfg(target_os = "macos")]
+        crtime: time(attr.crtime, attr.crtimensec),
+        #[cfg(not(target_os = "macos"))]
+        crtime: UNIX_EPOCH,
+        kind: file_kind(attr.mode),
+        perm: (attr.mode & 0o7777) as u16,
+        nlink: attr.nlink,
+        uid: attr.uid,
+        gid: attr.gid,
+        rdev: attr.rdev,
+        #[cfg(target_os = "macos")]
+        flags: attr.flags,
+        #[cfg(not(target_os = "macos"))]
+        flags: 0,
+    }
+}
+
+fn entry_from_bytes(data: &[u8]) -> Result<MockEntry, c_int> {
+    let out: fuse_entry_out = from_bytes(data)?;
+    Ok(MockEntry {
+        ino: out.nodeid,
+        generation: out.generation,
+        attr: file_attr(&out.attr),
+        entry_ttl: Duration::new(out.entry_valid, out.entry_valid_nsec),
+        attr_ttl: Duration::new(out.attr_valid, out.attr_valid_nsec),
+    })
+}
+
+fn dir_entries_from_bytes(mut data: &[u8]) -> Result<Vec<MockDirEntry>, c_int> {
+    let mut entries = Vec::new();
+    while !data.is_empty() {
+        let dirent: fuse_dirent = from_bytes(data)?;
+        let name_start = mem::size_of::<fuse_dirent>();
+        let name_end = name_start + dirent.namelen as usize;
+        // entries are padded to a multiple of 8 bytes
+        let entry_len = (name_end + 7) & !7;
+        if data.len() < name_end {
+            return Err(EIO);
+        }
+        entries.push(MockDirEntry {
+            ino: dirent.ino,
+            offset: dirent.off as i64,
+            kind: file_kind(dirent.typ << 12),
+            name: OsStr::from_bytes(&data[name_start..name_end]).to_os_string(),
+        });
+        data = &data[entry_len.min(data.len())..];
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod test {
+    use super::MockKernel;
+    use crate::fuse::{Filesystem, ReplyAttr, Request};
+    use libc::{ENOENT, ENOSYS};
+    use std::ffi::OsStr;
+
+    struct NullFS;
+
+    impl Filesystem for NullFS {}
+
+    #[test]
+    fn unimplemented_operations() {
+        let mut kernel = MockKernel::new(NullFS).unwrap();
+        assert_eq!(kernel.getattr(1), Err(ENOSYS));
+        assert_eq!(kernel.lookup(1, OsStr::new("file")).err(), Some(ENOSYS));
+        // forget is never replied
+        kernel.forget(1, 1);
+        assert_eq!(kernel.unlink(1, OsStr::new("file")), Err(ENOSYS));
+    }
+
+    struct OneFS;
+
+    impl Filesystem for OneFS {
+        fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+            if ino != 1 {
+                reply.error(ENOENT);
+            }
+            // the reply is dropped for the root, which replies EIO
+        }
+    }
+
+    #[test]
+    fn error_replies() {
+        let mut kernel = MockKernel::new(OneFS).unwrap();
+        assert_eq!(kernel.getattr(2), Err(ENOENT));
+        assert_eq!(kernel.getattr(1), Err(libc::EIO));
+    }
+}
@@ -6,17 +6,25 @@
 
 #![warn(missing_docs, missing_debug_implementations, rust_2018_idioms)]
 
-use libc::ENOSYS;
+use libc::{EIO, ENOSYS};
 // use std::convert::AsRef;
 use std::ffi::OsStr;
 use std::io;
 use std::os::raw::c_int;
+use std::os::unix::io::RawFd;
 use std::path::Path;
 use std::time::SystemTime;
 
 pub use abi::consts;
 pub use abi::FUSE_ROOT_ID;
-pub use channel::unmount;
+pub use async_fs::{AsyncFilesystem, SyncAdapter};
+pub use async_session::AsyncSession;
+pub use channel::{unmount, unmount_with_mode};
+pub use ll_request::RequestError;
+pub use metrics::{Metric, MetricKind, MetricSource, Metrics};
+pub use mock::{MockDirEntry, MockEntry, MockKernel};
+pub use observer::Observer;
+pub use protocol_log::ProtocolLogger;
 #[cfg(target_os = "macos")]
 pub use reply::ReplyXTimes;
 pub use reply::ReplyXattr;
@@ -25,18 +33,30 @@ pub use reply::{
     ReplyLock, ReplyOpen, ReplyStatfs, ReplyStatfsParam, ReplyWrite,
 };
 pub use request::Request;
-pub use session::Session;
+pub use session::{Session, Unmounter};
+pub use trace::{replay, ReplayMismatch, ReplayReport, TraceKind, TraceReader, TraceRecord};
+pub use transport::{read_spliced, SplicedData, Transport, TransportSender};
 // pub use session::{Session, BackgroundSession};
 
-pub use mount::options_validator;
+pub use mount::{options_validator, MountConfig, MountOption, UnmountMode};
 mod abi;
 mod argument;
+mod async_fs;
+mod async_session;
 mod channel;
 mod ll_request;
+mod metrics;
+mod mock;
 mod mount;
+mod observer;
+mod protocol_log;
 mod reply;
 mod request;
 mod session;
+#[cfg(target_os = "linux")]
+mod splice;
+mod trace;
+mod transport;
 
 /// File types
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
@@ -275,6 +295,29 @@ pub trait Filesystem {
         reply.error(ENOSYS);
     }
 
+    /// Write data from a file descriptor.
+    /// Called instead of write if the session received the data of the request into a pipe,
+    /// which `fd` is the read end of. Exactly `size` bytes must be read from it before
+    /// returning, e.g. by splicing them into a file, so they are never copied to user space.
+    /// The default reads them into memory and calls write.
+    #[allow(clippy::too_many_arguments)]
+    fn write_from_fd(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        fd: RawFd,
+        size: u32,
+        flags: u32,
+        reply: ReplyWrite,
+    ) {
+        match transport::read_spliced(fd, size as usize) {
+            Ok(data) => self.write(req, ino, fh, offset, &data, flags, reply),
+            Err(err) => reply.error(err.raw_os_error().unwrap_or(EIO)),
+        }
+    }
+
     /// Flush method.
     /// This is called on each close() of the opened file. Since file descriptors can
     /// be duplicated (dup, dup2, fork), for one open call there may be many flush
@@ -570,6 +613,27 @@ pub fn mount<FS: Filesystem>(
     Session::new(filesystem, mountpoint, options).and_then(|mut se| se.run())
 }
 
+/// Mount the given filesystem to the given mountpoint with typed options. This function
+/// will not return until the filesystem is unmounted.
+pub fn mount2<FS: Filesystem>(
+    filesystem: FS,
+    mountpoint: &Path,
+    options: &[MountOption],
+) -> io::Result<()> {
+    Session::with_options(filesystem, mountpoint, options).and_then(|mut se| se.run())
+}
+
+/// Mount the given asynchronous filesystem to the given mountpoint. This function will not
+/// return until the filesystem is unmounted, the requests are served concurrently on the
+/// calling thread.
+pub fn mount_async<FS: AsyncFilesystem>(
+    filesystem: FS,
+    mountpoint: &Path,
+    options: &[&str],
+) -> io::Result<()> {
+    AsyncSession::new(filesystem, mountpoint, options).and_then(|mut se| se.run())
+}
+
 // /// Mount the given filesystem to the given mountpoint. This function spawns
 // /// a background thread to handle filesystem operations while being mounted
 // /// and therefore returns immediately. The returned handle should be stored
@@ -1,20 +1,37 @@
-use log::{debug, error};
+use log::{debug, error, warn};
+#[cfg(target_os = "macos")]
 use nix::errno::{self, Errno};
 use nix::fcntl::{self, OFlag};
-use nix::sys::stat::{self, FileStat, Mode};
+#[cfg(target_os = "macos")]
+use nix::sys::stat::FileStat;
+use nix::sys::stat::{self, Mode};
 use regex::Regex;
 use std::collections::HashMap;
+#[cfg(target_os = "linux")]
+use std::env;
 use std::ffi::CString;
+#[cfg(target_os = "linux")]
+use std::ffi::OsStr;
+use std::fmt;
 use std::fs;
+use std::io;
 use std::os::raw::c_void;
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::io::RawFd;
 use std::path::Path;
+#[cfg(target_os = "linux")]
+use std::path::PathBuf;
+#[cfg(target_os = "linux")]
+use std::process::{Child, ExitStatus};
 
 use param::*;
 
-pub struct MountOption {
+#[cfg(target_os = "linux")]
+mod fsmount;
+
+pub struct OptionSpec {
     pub name: String,
-    pub parser: fn(&mut FuseMountArgs, &MountOption, &str),
+    pub parser: fn(&mut FuseMountArgs, &OptionSpec, &str),
     pub regex: Regex,
     #[cfg(target_os = "linux")]
     pub flag: Option<u64>,
@@ -32,30 +49,407 @@ fn get_all_options() -> String {
         .join(",")
 }
 
+/// Validate the options given in one `-o` argument
 pub fn options_validator(option: String) -> Result<(), String> {
-    let ret = option
-        .split(',')
-        .collect::<Vec<_>>()
-        .iter()
-        .all(|&op| get_mount_options().iter().any(|x| x.regex.is_match(&op)));
-    if ret {
-        Ok(())
-    } else {
-        Err(format!(
-            "Invalid option \"{}\", valid options: {}",
-            option,
-            get_all_options()
-        ))
-    }
+    MountConfig::parse(&option.split(',').collect::<Vec<_>>()).map(|_| ())
 }
 
-pub fn get_mount_options_map() -> HashMap<String, MountOption> {
+pub fn get_mount_options_map() -> HashMap<String, OptionSpec> {
     get_mount_options()
         .into_iter()
         .map(|op| (op.name.split('=').collect::<Vec<_>>()[0].to_string(), op))
         .collect::<HashMap<_, _>>()
 }
 
+/// How to unmount a filesystem
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UnmountMode {
+    /// Fail with `EBUSY` if the filesystem is in use
+    Normal,
+    /// Detach the filesystem now and clean up once it is not in use anymore
+    Lazy,
+    /// Abort pending requests and unmount even if the filesystem is in use, needs
+    /// `CAP_SYS_ADMIN` on Linux
+    Force,
+}
+
+/// Boolean options as pairs of the enabling and the disabling name, every boolean option can
+/// also be negated by prefixing it with `no`, e.g. `noro` is the same as `rw`
+const BOOL_OPTIONS: [(&str, &str); 12] = [
+    ("ro", "rw"),
+    ("suid", "nosuid"),
+    ("dev", "nodev"),
+    ("exec", "noexec"),
+    ("atime", "noatime"),
+    ("sync", "async"),
+    ("dirsync", "nodirsync"),
+    ("allow_other", "noallow_other"),
+    ("allow_root", "noallow_root"),
+    ("default_permissions", "nodefault_permissions"),
+    ("auto_unmount", "noauto_unmount"),
+    ("debug", "nodebug"),
+];
+
+/// Options of `/etc/fstab` entries, which only matter to mount(8), it passes some of them on
+/// to the mount helper, so they are accepted and ignored
+const FSTAB_OPTIONS: [&str; 10] = [
+    "defaults", "auto", "noauto", "user", "nouser", "users", "owner", "group", "nofail", "_netdev",
+];
+
+/// Return true if the option is only meaningful in `/etc/fstab`, including the `x-*` and
+/// `comment=` options of userspace tools
+fn is_fstab_option(option: &str) -> bool {
+    FSTAB_OPTIONS.contains(&option) || option.starts_with("x-") || option.starts_with("comment=")
+}
+
+/// Return true if the option is supported on this platform
+fn is_supported(option: &str) -> bool {
+    get_mount_options().iter().any(|x| x.regex.is_match(option))
+}
+
+/// Return true if the option is supported on this platform and takes a value
+fn takes_value(name: &str) -> bool {
+    get_mount_options().iter().any(|x| {
+        x.name
+            .strip_prefix(name)
+            .is_some_and(|rest| rest.starts_with('='))
+    })
+}
+
+/// Resolve a boolean option to its enabling name and whether it enables or disables it
+fn resolve_bool_option(name: &str) -> Option<(&'static str, bool)> {
+    BOOL_OPTIONS
+        .iter()
+        .find_map(|&(on, off)| {
+            if name == on {
+                Some((on, true))
+            } else if name == off {
+                Some((on, false))
+            } else {
+                None
+            }
+        })
+        .or_else(|| {
+            name.strip_prefix("no")
+                .and_then(resolve_bool_option)
+                .map(|(on, value)| (on, !value))
+        })
+}
+
+/// Mount options parsed and validated from option strings
+///
+/// Flags not given in the options are `None` and keep the platform default.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MountConfig {
+    /// Mount read-only (`ro`, `rw`)
+    pub read_only: Option<bool>,
+    /// Honor set-user-ID and set-group-ID bits (`suid`, `nosuid`)
+    pub suid: Option<bool>,
+    /// Allow access to device special files (`dev`, `nodev`)
+    pub dev: Option<bool>,
+    /// Allow program execution (`exec`, `noexec`)
+    pub exec: Option<bool>,
+    /// Update access times (`atime`, `noatime`)
+    pub atime: Option<bool>,
+    /// Write synchronously (`sync`, `async`)
+    pub sync: Option<bool>,
+    /// Make directory modifications synchronous (`dirsync`)
+    pub dirsync: Option<bool>,
+    /// Allow all users to access the filesystem (`allow_other`)
+    pub allow_other: bool,
+    /// Allow only root and the owner to access the filesystem (`allow_root`)
+    pub allow_root: bool,
+    /// Let the kernel check permissions (`default_permissions`)
+    pub default_permissions: bool,
+    /// Unmount automatically when the filesystem process exits (`auto_unmount`)
+    pub auto_unmount: bool,
+    /// Log every request and reply of the session to stderr (`debug`)
+    pub debug: bool,
+    /// Name of the mounted filesystem (`fsname=<name>`)
+    pub fsname: Option<String>,
+    /// Subtype of the mounted filesystem (`subtype=<type>`)
+    pub subtype: Option<String>,
+    /// Max size of read requests (`max_read=<bytes>`)
+    pub max_read: Option<u32>,
+    /// User id ranges in the form of `<mount uid>:<backing uid>:<count>` (`uidmap=`)
+    pub uid_map: Vec<String>,
+    /// Group id ranges in the form of `<mount gid>:<backing gid>:<count>` (`gidmap=`)
+    pub gid_map: Vec<String>,
+    /// Options passed to the kernel as given (`MountOption::Custom`)
+    pub custom: Vec<String>,
+}
+
+impl MountConfig {
+    /// Parse options, each of them is a single option without comma, reject unknown,
+    /// malformed, duplicate and conflicting options, ignore `/etc/fstab` only options
+    pub fn parse(options: &[&str]) -> Result<MountConfig, String> {
+        let mut config = MountConfig::default();
+        let mut bool_options: HashMap<&str, (bool, &str)> = HashMap::new();
+        let mut value_options: HashMap<&str, &str> = HashMap::new();
+        for &op in options {
+            if is_fstab_option(op) {
+                debug!("ignore fstab option \"{}\"", op);
+                continue;
+            }
+            let (name, value) = match op.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (op, None),
+            };
+            if let Some((on, enable)) = resolve_bool_option(name) {
+                if value.is_some() {
+                    return Err(format!("option \"{}\" does not take a value", name));
+                }
+                if !is_supported(on) {
+                    return Err(format!(
+                        "option \"{}\" is not supported on this platform",
+                        op
+                    ));
+                }
+                match bool_options.get(on) {
+                    Some(&(prev, prev_op)) if prev != enable => {
+                        return Err(format!(
+                            "conflicting options \"{}\" and \"{}\"",
+                            prev_op, op
+                        ));
+                    }
+                    _ => {
+                        bool_options.insert(on, (enable, op));
+                    }
+                }
+                continue;
+            }
+            let value = match value {
+                Some(v) if !v.is_empty() => v,
+                _ if takes_value(name) => {
+                    return Err(format!("option \"{}\" requires a value", name));
+                }
+                _ => {
+                    return Err(format!(
+                        "unknown option \"{}\", valid options: {}",
+                        op,
+                        get_all_options()
+                    ));
+                }
+            };
+            if !is_supported(op) {
+                return Err(format!("invalid value of option \"{}\"", op));
+            }
+            let parse_u32 = |v: &str| {
+                v.parse::<u32>()
+                    .map_err(|e| format!("invalid value of option \"{}\": {}", op, e))
+            };
+            match name {
+                "uidmap" => config.uid_map.push(value.to_owned()),
+                "gidmap" => config.gid_map.push(value.to_owned()),
+                _ => {
+                    if let Some(prev_op) = value_options.insert(name, op) {
+                        return Err(format!(
+                            "duplicate option \"{}\": \"{}\" and \"{}\"",
+                            name, prev_op, op
+                        ));
+                    }
+                    match name {
+                        "fsname" => config.fsname = Some(value.to_owned()),
+                        "subtype" => config.subtype = Some(value.to_owned()),
+                        "max_read" => config.max_read = Some(parse_u32(value)?),
+                        // the kernel accepts it for block device backed (fuseblk) mounts only
+                        "blksize" => {
+                            return Err(format!(
+                                "option \"{}\" is only supported for block device mounts",
+                                name
+                            ));
+                        }
+                        _ => unreachable!("supported option without field: {}", op),
+                    }
+                }
+            }
+        }
+
+        for (on, (enable, _)) in bool_options {
+            match on {
+                "ro" => config.read_only = Some(enable),
+                "suid" => config.suid = Some(enable),
+                "dev" => config.dev = Some(enable),
+                "exec" => config.exec = Some(enable),
+                "atime" => config.atime = Some(enable),
+                "sync" => config.sync = Some(enable),
+                "dirsync" => config.dirsync = Some(enable),
+                "allow_other" => config.allow_other = enable,
+                "allow_root" => config.allow_root = enable,
+                "default_permissions" => config.default_permissions = enable,
+                "auto_unmount" => config.auto_unmount = enable,
+                "debug" => config.debug = enable,
+                _ => unreachable!("unhandled boolean option: {}", on),
+            }
+        }
+        if config.allow_other && config.allow_root {
+            return Err(String::from(
+                "options \"allow_other\" and \"allow_root\" are mutually exclusive",
+            ));
+        }
+        Ok(config)
+    }
+
+    /// Build the config from typed options, reject duplicate and conflicting options
+    pub fn from_options(options: &[MountOption]) -> Result<MountConfig, String> {
+        let (custom, known): (Vec<_>, Vec<_>) = options
+            .iter()
+            .partition(|op| matches!(op, MountOption::Custom(_)));
+        let known = known.iter().map(ToString::to_string).collect::<Vec<_>>();
+        let mut config = MountConfig::parse(&known.iter().map(String::as_str).collect::<Vec<_>>())?;
+        for op in custom {
+            let op = op.to_string();
+            if op.is_empty() || op.contains(',') {
+                return Err(format!("invalid custom option \"{}\"", op));
+            }
+            config.custom.push(op);
+        }
+        Ok(config)
+    }
+
+    /// Return the typed options, only the options given when parsing are included
+    pub fn to_mount_options(&self) -> Vec<MountOption> {
+        let flags = [
+            (
+                self.read_only,
+                MountOption::ReadOnly,
+                Some(MountOption::ReadWrite),
+            ),
+            (self.suid, MountOption::Suid, Some(MountOption::NoSuid)),
+            (self.dev, MountOption::Dev, Some(MountOption::NoDev)),
+            (self.exec, MountOption::Exec, Some(MountOption::NoExec)),
+            (self.atime, MountOption::Atime, Some(MountOption::NoAtime)),
+            (self.sync, MountOption::Sync, Some(MountOption::Async)),
+            // options without a disabling variant are disabled by default
+            (self.dirsync, MountOption::DirSync, None),
+            (Some(self.allow_other), MountOption::AllowOther, None),
+            (Some(self.allow_root), MountOption::AllowRoot, None),
+            (
+                Some(self.default_permissions),
+                MountOption::DefaultPermissions,
+                None,
+            ),
+            (Some(self.auto_unmount), MountOption::AutoUnmount, None),
+            (Some(self.debug), MountOption::Debug, None),
+        ];
+        let mut options = flags
+            .iter()
+            .cloned()
+            .filter_map(|(value, on, off)| match value {
+                Some(true) => Some(on),
+                Some(false) => off,
+                None => None,
+            })
+            .collect::<Vec<_>>();
+        options.extend(self.fsname.iter().cloned().map(MountOption::FsName));
+        options.extend(self.subtype.iter().cloned().map(MountOption::Subtype));
+        options.extend(self.max_read.iter().cloned().map(MountOption::MaxRead));
+        options.extend(self.uid_map.iter().cloned().map(MountOption::UidMap));
+        options.extend(self.gid_map.iter().cloned().map(MountOption::GidMap));
+        options.extend(self.custom.iter().cloned().map(MountOption::Custom));
+        options
+    }
+
+    /// Return the options in canonical form, only the options given when parsing are included
+    pub fn to_options(&self) -> Vec<String> {
+        self.to_mount_options()
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+}
+
+/// A mount option
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MountOption {
+    /// Mount read-only (`ro`)
+    ReadOnly,
+    /// Mount read-write (`rw`)
+    ReadWrite,
+    /// Honor set-user-ID and set-group-ID bits (`suid`)
+    Suid,
+    /// Ignore set-user-ID and set-group-ID bits (`nosuid`)
+    NoSuid,
+    /// Allow access to device special files (`dev`)
+    Dev,
+    /// Disallow access to device special files (`nodev`)
+    NoDev,
+    /// Allow program execution (`exec`)
+    Exec,
+    /// Disallow program execution (`noexec`)
+    NoExec,
+    /// Update access times (`atime`)
+    Atime,
+    /// Do not update access times (`noatime`)
+    NoAtime,
+    /// Write synchronously (`sync`)
+    Sync,
+    /// Write asynchronously (`async`)
+    Async,
+    /// Make directory modifications synchronous (`dirsync`)
+    DirSync,
+    /// Allow all users to access the filesystem (`allow_other`)
+    AllowOther,
+    /// Allow only root and the owner to access the filesystem (`allow_root`)
+    AllowRoot,
+    /// Let the kernel check permissions (`default_permissions`)
+    DefaultPermissions,
+    /// Unmount automatically when the filesystem process exits (`auto_unmount`)
+    AutoUnmount,
+    /// Log every request and reply of the session to stderr (`debug`)
+    Debug,
+    /// Name of the mounted filesystem (`fsname=<name>`)
+    FsName(String),
+    /// Subtype of the mounted filesystem (`subtype=<type>`)
+    Subtype(String),
+    /// Max size of read requests (`max_read=<bytes>`)
+    MaxRead(u32),
+    /// User id range `<mount uid>:<backing uid>:<count>` (`uidmap=`)
+    UidMap(String),
+    /// Group id range `<mount gid>:<backing gid>:<count>` (`gidmap=`)
+    GidMap(String),
+    /// Option passed to the kernel as given, without validation
+    Custom(String),
+}
+
+impl MountOption {
+    /// Parse options in string form, each of them is a single option without comma
+    pub fn from_strs(options: &[&str]) -> Result<Vec<MountOption>, String> {
+        MountConfig::parse(options).map(|config| config.to_mount_options())
+    }
+}
+
+impl fmt::Display for MountOption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MountOption::ReadOnly => write!(f, "ro"),
+            MountOption::ReadWrite => write!(f, "rw"),
+            MountOption::Suid => write!(f, "suid"),
+            MountOption::NoSuid => write!(f, "nosuid"),
+            MountOption::Dev => write!(f, "dev"),
+            MountOption::NoDev => write!(f, "nodev"),
+            MountOption::Exec => write!(f, "exec"),
+            MountOption::NoExec => write!(f, "noexec"),
+            MountOption::Atime => write!(f, "atime"),
+            MountOption::NoAtime => write!(f, "noatime"),
+            MountOption::Sync => write!(f, "sync"),
+            MountOption::Async => write!(f, "async"),
+            MountOption::DirSync => write!(f, "dirsync"),
+            MountOption::AllowOther => write!(f, "allow_other"),
+            MountOption::AllowRoot => write!(f, "allow_root"),
+            MountOption::DefaultPermissions => write!(f, "default_permissions"),
+            MountOption::AutoUnmount => write!(f, "auto_unmount"),
+            MountOption::Debug => write!(f, "debug"),
+            MountOption::FsName(name) => write!(f, "fsname={}", name),
+            MountOption::Subtype(subtype) => write!(f, "subtype={}", subtype),
+            MountOption::MaxRead(size) => write!(f, "max_read={}", size),
+            MountOption::UidMap(spec) => write!(f, "uidmap={}", spec),
+            MountOption::GidMap(spec) => write!(f, "gidmap={}", spec),
+            MountOption::Custom(option) => write!(f, "{}", option),
+        }
+    }
+}
+
 #[cfg(target_os = "linux")]
 mod param {
     // https://github.com/torvalds/linux/blob/master/include/uapi/linux/mount.h#L11
@@ -63,9 +457,34 @@ mod param {
     pub const MS_RDONLY: u64 = 1; // Mount read-only
     pub const MS_NOSUID: u64 = 2; // Ignore suid and sgid bits
     pub const MS_NODEV: u64 = 4; // Disallow access to device special files
+    pub const MS_NOEXEC: u64 = 8; // Disallow program execution
+    pub const MS_SYNCHRONOUS: u64 = 16; // Writes are synced at once
+    pub const MS_DIRSYNC: u64 = 128; // Directory modifications are synchronous
+    pub const MS_NOATIME: u64 = 1024; // Do not update access times
     pub const MNT_FORCE: i32 = 1; // Force un-mount
+    pub const MNT_DETACH: i32 = 2; // Just detach from the tree
+
+    /// Capability to mount filesystems, from linux/capability.h
+    pub const CAP_SYS_ADMIN: u32 = 21;
+
+    /// Names of the fusermount binary in the order of preference
+    pub const FUSERMOUNT_BINARIES: [&str; 2] = ["fusermount3", "fusermount"];
+
+    /// Mount flags used if not overridden by options, the same as libfuse
+    pub const DEFAULT_MOUNT_FLAGS: u64 = MS_NOSUID | MS_NODEV;
 
-    use super::MountOption;
+    /// Names of mount flags passed to fusermount, which parses the flags itself
+    const MOUNT_FLAG_NAMES: [(u64, &str); 7] = [
+        (MS_RDONLY, "ro"),
+        (MS_NOSUID, "nosuid"),
+        (MS_NODEV, "nodev"),
+        (MS_NOEXEC, "noexec"),
+        (MS_SYNCHRONOUS, "sync"),
+        (MS_DIRSYNC, "dirsync"),
+        (MS_NOATIME, "noatime"),
+    ];
+
+    use super::OptionSpec;
     use regex::Regex;
     fn add_option(options: &Option<String>, option: &str) -> Option<String> {
         match options {
@@ -74,53 +493,120 @@ mod param {
         }
     }
 
-    pub fn get_mount_options() -> Vec<MountOption> {
-        fn parse_flag(args: &mut FuseMountArgs, mount_option: &MountOption, _options: &str) {
+    fn option_value(option: &str) -> &str {
+        option.split_once('=').map_or("", |(_, v)| v)
+    }
+
+    pub fn get_mount_options() -> Vec<OptionSpec> {
+        fn parse_flag(args: &mut FuseMountArgs, mount_option: &OptionSpec, _options: &str) {
             if let Some(flag) = mount_option.flag {
                 args.flags |= flag;
             }
         }
 
-        fn parse_allow_other(args: &mut FuseMountArgs, _mount_option: &MountOption, option: &str) {
+        fn parse_clear_flag(args: &mut FuseMountArgs, mount_option: &OptionSpec, _options: &str) {
+            if let Some(flag) = mount_option.flag {
+                args.flags &= !flag;
+            }
+        }
+
+        fn parse_allow_other(args: &mut FuseMountArgs, _spec: &OptionSpec, option: &str) {
             args.allow_other = 1;
             args.kernel_opts = add_option(&args.kernel_opts, option);
         }
 
-        fn parse_fsname(args: &mut FuseMountArgs, _mount_option: &MountOption, option: &str) {
-            let name = String::from(option.split('=').last().unwrap()); //Safe to use unwrap here, becuase option is always valid.
+        // The kernel only knows allow_other, the session restricts access to root and owner
+        fn parse_allow_root(args: &mut FuseMountArgs, _spec: &OptionSpec, _option: &str) {
+            args.allow_root = 1;
+            args.kernel_opts = add_option(&args.kernel_opts, "allow_other");
+        }
+
+        fn parse_auto_unmount(args: &mut FuseMountArgs, _spec: &OptionSpec, option: &str) {
+            args.auto_unmount = 1;
+            args.fusermount_opts = add_option(&args.fusermount_opts, option);
+        }
+
+        fn parse_kernel_opt(args: &mut FuseMountArgs, _spec: &OptionSpec, option: &str) {
+            args.kernel_opts = add_option(&args.kernel_opts, option);
+        }
+
+        fn parse_max_read(args: &mut FuseMountArgs, _spec: &OptionSpec, option: &str) {
+            // The value always matches \d+, parsing only fails on overflow
+            args.max_read = option_value(option).parse().unwrap_or(u32::MAX);
+            args.kernel_opts = add_option(&args.kernel_opts, option);
+        }
+
+        // Options handled by the filesystem implementation, not passed to the kernel
+        fn parse_fs_opt(_args: &mut FuseMountArgs, _spec: &OptionSpec, _option: &str) {}
+
+        fn parse_fsname(args: &mut FuseMountArgs, _spec: &OptionSpec, option: &str) {
+            let name = String::from(option_value(option));
             args.fsname = Some(name);
             args.fusermount_opts = add_option(&args.fusermount_opts, option);
         }
-        // Safe to use unwrap here, because expression is literal string and always valid.
-        let ro_regex = Regex::new("^ro$").unwrap();
-        let rw_regex = Regex::new("^rw$").unwrap();
-        let allow_other_regex = Regex::new("^allow_other$").unwrap();
-        let fsname_regex = Regex::new(r"^fsname=[^\s]+$").unwrap();
+
+        fn parse_subtype(args: &mut FuseMountArgs, _spec: &OptionSpec, option: &str) {
+            let subtype = String::from(option_value(option));
+            args.subtype = Some(subtype);
+            args.subtype_opt = Some(String::from(option));
+            args.fusermount_opts = add_option(&args.fusermount_opts, option);
+        }
+
+        fn new_option(
+            name: &str,
+            regex: &str,
+            parser: fn(&mut FuseMountArgs, &OptionSpec, &str),
+            flag: Option<u64>,
+        ) -> OptionSpec {
+            OptionSpec {
+                name: String::from(name),
+                parser,
+                // Safe to use unwrap here, because expression is literal string and always valid.
+                regex: Regex::new(regex).unwrap(),
+                flag,
+            }
+        }
+
         vec![
-            MountOption {
-                name: String::from("ro"),
-                parser: parse_flag,
-                regex: ro_regex,
-                flag: Some(MS_RDONLY),
-            },
-            MountOption {
-                name: String::from("rw"),
-                parser: parse_flag,
-                regex: rw_regex,
-                flag: None,
-            },
-            MountOption {
-                name: String::from("allow_other"),
-                parser: parse_allow_other,
-                regex: allow_other_regex,
-                flag: None,
-            },
-            MountOption {
-                name: String::from("fsname=<name>"),
-                parser: parse_fsname,
-                regex: fsname_regex,
-                flag: None,
-            },
+            new_option("ro", "^ro$", parse_flag, Some(MS_RDONLY)),
+            new_option("rw", "^rw$", parse_clear_flag, Some(MS_RDONLY)),
+            new_option("suid", "^suid$", parse_clear_flag, Some(MS_NOSUID)),
+            new_option("nosuid", "^nosuid$", parse_flag, Some(MS_NOSUID)),
+            new_option("dev", "^dev$", parse_clear_flag, Some(MS_NODEV)),
+            new_option("nodev", "^nodev$", parse_flag, Some(MS_NODEV)),
+            new_option("exec", "^exec$", parse_clear_flag, Some(MS_NOEXEC)),
+            new_option("noexec", "^noexec$", parse_flag, Some(MS_NOEXEC)),
+            new_option("sync", "^sync$", parse_flag, Some(MS_SYNCHRONOUS)),
+            new_option("async", "^async$", parse_clear_flag, Some(MS_SYNCHRONOUS)),
+            new_option("dirsync", "^dirsync$", parse_flag, Some(MS_DIRSYNC)),
+            new_option("atime", "^atime$", parse_clear_flag, Some(MS_NOATIME)),
+            new_option("noatime", "^noatime$", parse_flag, Some(MS_NOATIME)),
+            new_option("allow_other", "^allow_other$", parse_allow_other, None),
+            new_option("allow_root", "^allow_root$", parse_allow_root, None),
+            new_option(
+                "default_permissions",
+                "^default_permissions$",
+                parse_kernel_opt,
+                None,
+            ),
+            new_option("auto_unmount", "^auto_unmount$", parse_auto_unmount, None),
+            new_option("debug", "^debug$", parse_fs_opt, None),
+            new_option("fsname=<name>", r"^fsname=[^\s]+$", parse_fsname, None),
+            new_option("subtype=<type>", r"^subtype=[^\s]+$", parse_subtype, None),
+            new_option("max_read=<bytes>", r"^max_read=\d+$", parse_max_read, None),
+            new_option("blksize=<bytes>", r"^blksize=\d+$", parse_kernel_opt, None),
+            new_option(
+                "uidmap=<mount uid>:<backing uid>:<count>",
+                r"^uidmap=\d+:\d+:\d+$",
+                parse_fs_opt,
+                None,
+            ),
+            new_option(
+                "gidmap=<mount gid>:<backing gid>:<count>",
+                r"^gidmap=\d+:\d+:\d+$",
+                parse_fs_opt,
+                None,
+            ),
         ]
     }
 
@@ -128,6 +614,7 @@ mod param {
     #[derive(Debug)]
     pub struct FuseMountArgs {
         allow_other: i32,
+        allow_root: i32,
         flags: u64,
         auto_unmount: i32,
         blkdev: i32,
@@ -142,10 +629,10 @@ mod param {
 
     impl FuseMountArgs {
         pub fn parse(options: &[&str]) -> FuseMountArgs {
-            // TODO: add default arguments
             let mut args = FuseMountArgs {
                 allow_other: 0,
-                flags: 0,
+                allow_root: 0,
+                flags: DEFAULT_MOUNT_FLAGS,
                 auto_unmount: 0,
                 blkdev: 0,
                 fsname: None,
@@ -159,11 +646,104 @@ mod param {
             let mount_options_map = super::get_mount_options_map();
             options.iter().for_each(|op| {
                 let key = op.split('=').collect::<Vec<_>>()[0].to_string();
-                let option = mount_options_map.get(&key).unwrap(); // Safe to use unwrap here, because key always exists
-                (option.parser)(&mut args, &option, &op)
+                match mount_options_map.get(&key) {
+                    Some(option) => (option.parser)(&mut args, &option, &op),
+                    // Options were validated before, unknown ones are custom kernel options
+                    None => args.kernel_opts = add_option(&args.kernel_opts, op),
+                }
             });
             args
         }
+
+        /// Options passed to the kernel in the mount data
+        pub fn kernel_opts(&self) -> Option<&str> {
+            self.kernel_opts.as_deref()
+        }
+
+        /// Flags passed to mount(2)
+        pub fn flags(&self) -> u64 {
+            self.flags
+        }
+
+        /// Name of the mounted filesystem shown in the mount table
+        pub fn fsname(&self) -> Option<&str> {
+            self.fsname.as_deref()
+        }
+
+        /// Filesystem sub type, the mount type becomes `fuse.<subtype>`
+        pub fn subtype(&self) -> Option<&str> {
+            self.subtype.as_deref()
+        }
+
+        /// True if the filesystem should be unmounted when the daemon exits
+        pub fn auto_unmount(&self) -> bool {
+            self.auto_unmount != 0
+        }
+
+        /// Options passed to fusermount, which parses the mount flags itself and passes
+        /// the rest to the kernel
+        pub fn fusermount_opts(&self) -> String {
+            MOUNT_FLAG_NAMES
+                .iter()
+                .filter(|(flag, _)| self.flags & flag != 0)
+                .map(|(_, name)| *name)
+                .chain(self.kernel_opts.as_deref())
+                .chain(self.fusermount_opts.as_deref())
+                .collect::<Vec<_>>()
+                .join(",")
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn default_args() {
+            let args = FuseMountArgs::parse(&[]);
+            assert_eq!(args.flags(), MS_NOSUID | MS_NODEV);
+            assert_eq!(args.kernel_opts(), None);
+            assert_eq!(args.fusermount_opts(), "nosuid,nodev");
+        }
+
+        #[test]
+        fn flag_args() {
+            let args = FuseMountArgs::parse(&["ro", "suid", "dev", "noexec", "noatime", "sync"]);
+            assert_eq!(
+                args.flags(),
+                MS_RDONLY | MS_NOEXEC | MS_NOATIME | MS_SYNCHRONOUS
+            );
+            let args = FuseMountArgs::parse(&["ro", "rw", "sync", "async", "dirsync"]);
+            assert_eq!(args.flags(), DEFAULT_MOUNT_FLAGS | MS_DIRSYNC);
+        }
+
+        #[test]
+        fn option_args() {
+            let args = FuseMountArgs::parse(&[
+                "allow_root",
+                "default_permissions",
+                "max_read=131072",
+                "fsname=memfs",
+                "subtype=fuse_ll",
+                "auto_unmount",
+                "uidmap=0:1000:1",
+                "debug",
+            ]);
+            assert_eq!(args.allow_root, 1);
+            assert!(args.auto_unmount());
+            assert_eq!(args.fsname(), Some("memfs"));
+            assert_eq!(args.subtype(), Some("fuse_ll"));
+            assert_eq!(args.max_read, 131072);
+            assert_eq!(
+                args.kernel_opts(),
+                Some("allow_other,default_permissions,max_read=131072")
+            );
+            assert_eq!(
+                args.fusermount_opts(),
+                "nosuid,nodev,allow_other,default_permissions,max_read=131072,\
+                 fsname=memfs,subtype=fuse_ll,auto_unmount"
+            );
+        }
     }
 }
 
@@ -190,6 +770,7 @@ mod param {
     pub const FUSE_FSSUBTYPE_UNKNOWN: u32 = 0;
     pub const FUSE_MOPT_ALLOW_OTHER: u64 = 0x0000000000000001;
     pub const FUSE_MOPT_DEBUG: u64 = 0x0000000000000040;
+    pub const FUSE_MOPT_DEFAULT_PERMISSIONS: u64 = 0x0000000000000080;
     pub const FUSE_MOPT_FSNAME: u64 = 0x0000000000001000;
     pub const FUSE_MOPT_NO_APPLEXATTR: u64 = 0x0000000000800000;
 
@@ -214,17 +795,17 @@ mod param {
         pub rdev: u32,                        // dev_t for the /dev/osxfuse{n} in question
     }
 
-    use super::MountOption;
+    use super::OptionSpec;
     use regex::Regex;
-    pub fn get_mount_options() -> Vec<MountOption> {
-        fn empty_parser(_args: &mut FuseMountArgs, _mount_option: &MountOption, _option: &str) {}
-        fn parse_fuse_flag(args: &mut FuseMountArgs, mount_option: &MountOption, _option: &str) {
+    pub fn get_mount_options() -> Vec<OptionSpec> {
+        fn empty_parser(_args: &mut FuseMountArgs, _spec: &OptionSpec, _option: &str) {}
+        fn parse_fuse_flag(args: &mut FuseMountArgs, mount_option: &OptionSpec, _option: &str) {
             if let Some(flag) = mount_option.fuse_flag {
                 args.altflags |= flag;
             }
         }
 
-        fn parse_fsname(args: &mut FuseMountArgs, _mount_option: &MountOption, option: &str) {
+        fn parse_fsname(args: &mut FuseMountArgs, _spec: &OptionSpec, option: &str) {
             let name = String::from(option.split('=').last().unwrap()); //Safe to use unwrap here, becuase option is always valid.
             copy_slice(
                 CString::new(name).expect("CString::new failed!").as_bytes(),
@@ -235,36 +816,68 @@ mod param {
         let ro_regex = Regex::new("^ro$").unwrap();
         let rw_regex = Regex::new("^rw$").unwrap();
         let allow_other_regex = Regex::new("^allow_other$").unwrap();
+        let default_permissions_regex = Regex::new("^default_permissions$").unwrap();
         let fsname_regex = Regex::new(r"^fsname=[^\s]+$").unwrap();
+        let uidmap_regex = Regex::new(r"^uidmap=\d+:\d+:\d+$").unwrap();
+        let gidmap_regex = Regex::new(r"^gidmap=\d+:\d+:\d+$").unwrap();
+        let debug_regex = Regex::new("^debug$").unwrap();
         vec![
-            MountOption {
+            OptionSpec {
                 name: String::from("ro"),
                 parser: empty_parser,
                 regex: ro_regex,
                 flag: Some(MNT_RDONLY),
                 fuse_flag: None,
             },
-            MountOption {
+            OptionSpec {
                 name: String::from("rw"),
                 parser: empty_parser,
                 regex: rw_regex,
                 flag: None,
                 fuse_flag: None,
             },
-            MountOption {
+            OptionSpec {
                 name: String::from("allow_other"),
                 parser: parse_fuse_flag,
                 regex: allow_other_regex,
                 flag: None,
                 fuse_flag: Some(FUSE_MOPT_ALLOW_OTHER),
             },
-            MountOption {
+            OptionSpec {
+                name: String::from("default_permissions"),
+                parser: parse_fuse_flag,
+                regex: default_permissions_regex,
+                flag: None,
+                fuse_flag: Some(FUSE_MOPT_DEFAULT_PERMISSIONS),
+            },
+            OptionSpec {
                 name: String::from("fsname=<name>"),
                 parser: parse_fsname,
                 regex: fsname_regex,
                 flag: None,
                 fuse_flag: None,
             },
+            OptionSpec {
+                name: String::from("uidmap=<mount uid>:<backing uid>:<count>"),
+                parser: empty_parser,
+                regex: uidmap_regex,
+                flag: None,
+                fuse_flag: None,
+            },
+            OptionSpec {
+                name: String::from("gidmap=<mount gid>:<backing gid>:<count>"),
+                parser: empty_parser,
+                regex: gidmap_regex,
+                flag: None,
+                fuse_flag: None,
+            },
+            OptionSpec {
+                name: String::from("debug"),
+                parser: empty_parser,
+                regex: debug_regex,
+                flag: None,
+                fuse_flag: None,
+            },
         ]
     }
 
@@ -290,8 +903,11 @@ mod param {
             let mount_options_map = super::get_mount_options_map();
             options.iter().for_each(|op| {
                 let key = op.split('=').collect::<Vec<_>>()[0].to_string();
-                let option = mount_options_map.get(&key).unwrap(); // Safe to use unwrap here, because key always exists
-                (option.parser)(&mut args, &option, &op)
+                match mount_options_map.get(&key) {
+                    Some(option) => (option.parser)(&mut args, &option, &op),
+                    // Options were validated before, custom options are not supported
+                    None => log::warn!("ignoring custom mount option \"{}\" on macOS", op),
+                }
             });
             args
         }
@@ -306,11 +922,8 @@ mod param {
         let mut flag: i32 = 0;
         options.iter().for_each(|&op| {
             let mount_options = get_mount_options();
-            let option = mount_options
-                .iter()
-                .find(|x| x.regex.is_match(&op))
-                .unwrap();
-            if let Some(f) = option.flag {
+            let option = mount_options.iter().find(|x| x.regex.is_match(&op));
+            if let Some(f) = option.and_then(|x| x.flag) {
                 flag |= f;
             }
         });
@@ -319,62 +932,234 @@ mod param {
 }
 
 #[cfg(target_os = "linux")]
-pub fn umount(short_path: &Path) -> i32 {
-    use nix::unistd;
+pub fn umount(mount_point: &Path, mode: UnmountMode) -> io::Result<()> {
     use std::process::Command;
 
-    let mntpnt = short_path.as_os_str();
-
-    if unistd::geteuid().is_root() {
+    if has_sys_admin() {
         // direct umount
-        #[cfg(target_arch = "aarch64")]
-        let result = unsafe { libc::umount2(mntpnt as *const _ as *const u8, MNT_FORCE) };
-        #[cfg(target_arch = "x86_64")]
-        let result =
-            unsafe { libc::umount2(mntpnt as *const _ as *const u8 as *const i8, MNT_FORCE) };
+        let flags = match mode {
+            UnmountMode::Normal => 0,
+            UnmountMode::Lazy => MNT_DETACH,
+            UnmountMode::Force => MNT_FORCE,
+        };
+        let path = CString::new(mount_point.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        #[allow(unsafe_code)]
+        let result = unsafe { libc::umount2(path.as_ptr(), flags) };
+        return if result == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        };
+    }
 
-        result
+    // use fusermount to umount
+    let flag = match mode {
+        UnmountMode::Normal => "-u",
+        UnmountMode::Lazy => "-uz",
+        UnmountMode::Force => {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "forced unmount requires CAP_SYS_ADMIN",
+            ));
+        }
+    };
+    let fusermount = find_fusermount()?;
+    let output = Command::new(&fusermount)
+        .arg(flag)
+        .arg("--")
+        .arg(mount_point.as_os_str())
+        .output()
+        .map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("failed to start {}: {}", fusermount.display(), e),
+            )
+        })?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    debug!("{} failed to umount: {}", fusermount.display(), stderr);
+    // keep the errno, so that callers can tell a busy mount point from other failures
+    if stderr.contains("Device or resource busy") {
+        Err(io::Error::from_raw_os_error(libc::EBUSY))
     } else {
-        // use fusermount to umount
-        let umount_handle = Command::new("fusermount")
-            .arg("-uz") // lazy umount
-            .arg(mntpnt)
-            .output()
-            .expect("fusermount command failed to start");
-        if umount_handle.status.success() {
-            0
-        } else {
-            // should be safe to use unwrap() here
-            let stderr = String::from_utf8(umount_handle.stderr).unwrap();
-            debug!("fusermount failed to umount: {}", stderr);
-            -1
+        Err(fusermount_error(&fusermount, output.status, &stderr))
+    }
+}
+
+/// Return true if the current process has `CAP_SYS_ADMIN` in its effective capabilities,
+/// which allows mount(2) as root or in a user namespace owned by the process
+#[cfg(target_os = "linux")]
+fn has_sys_admin() -> bool {
+    match fs::read_to_string("/proc/self/status") {
+        Ok(status) => parse_cap_eff(&status).is_some_and(|caps| caps & (1 << CAP_SYS_ADMIN) != 0),
+        Err(e) => {
+            debug!("failed to read the capabilities, the error is: {:?}", e);
+            nix::unistd::geteuid().is_root()
         }
     }
 }
 
 #[cfg(target_os = "linux")]
-pub fn mount(mount_point: &Path, options: &[&str]) -> RawFd {
-    use nix::unistd;
+fn parse_cap_eff(status: &str) -> Option<u64> {
+    status
+        .lines()
+        .find_map(|l| l.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+}
 
-    if unistd::geteuid().is_root() {
-        // direct umount
-        direct_mount(mount_point, options)
+/// Return true if the current process runs in a user namespace other than the initial one,
+/// whose uid map covers all ids
+#[cfg(target_os = "linux")]
+fn in_user_namespace() -> bool {
+    fs::read_to_string("/proc/self/uid_map")
+        .map(|map| !is_initial_uid_map(&map))
+        .unwrap_or(false)
+}
+
+#[cfg(target_os = "linux")]
+fn is_initial_uid_map(map: &str) -> bool {
+    map.split_whitespace().collect::<Vec<_>>() == ["0", "0", "4294967295"]
+}
+
+/// Mount the filesystem and return the fd of the FUSE device, with the fusermount process
+/// which unmounts it once the daemon exits if `auto_unmount` is given
+#[cfg(target_os = "linux")]
+pub fn mount(mount_point: &Path, options: &[&str]) -> io::Result<(RawFd, Option<AutoUnmount>)> {
+    // auto_unmount needs fusermount to stay alive and unmount after the daemon exits
+    if has_sys_admin() && !FuseMountArgs::parse(options).auto_unmount() {
+        // direct mount, either as root or as the owner of a user and mount namespace
+        if in_user_namespace() {
+            debug!("mounting {:?} directly in a user namespace", mount_point);
+        }
+        direct_mount(mount_point, options).map(|fd| (fd, None))
     } else {
         // use fusermount to mount
         fuser_mount(mount_point, options)
     }
 }
 
+/// Convert a nix error to an io error, keeping the errno if there is one
+#[cfg(target_os = "linux")]
+fn nix_to_io_error(err: nix::Error, context: &str) -> io::Error {
+    let kind = match err.as_errno() {
+        Some(errno) => io::Error::from_raw_os_error(errno as i32).kind(),
+        None => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, format!("{}: {}", context, err))
+}
+
+/// Find the fusermount binary in `PATH`, `fusermount3` of libfuse 3 is preferred over
+/// `fusermount` of libfuse 2
+#[cfg(target_os = "linux")]
+fn find_fusermount() -> io::Result<PathBuf> {
+    let paths = env::var_os("PATH").unwrap_or_default();
+    FUSERMOUNT_BINARIES
+        .iter()
+        .flat_map(|bin| env::split_paths(&paths).map(move |dir| dir.join(bin)))
+        .find(|path| path.is_file())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("none of {:?} found in PATH", FUSERMOUNT_BINARIES),
+            )
+        })
+}
+
+/// Build an error from the exit status and the error output of fusermount
+#[cfg(target_os = "linux")]
+fn fusermount_error(fusermount: &Path, status: ExitStatus, stderr: &str) -> io::Error {
+    let message = stderr.trim();
+    io::Error::other(if message.is_empty() {
+        format!("{} failed with {}", fusermount.display(), status)
+    } else {
+        format!(
+            "{} failed with {}: {}",
+            fusermount.display(),
+            status,
+            message
+        )
+    })
+}
+
+/// The fusermount process of an `auto_unmount` mount, it unmounts the filesystem once the
+/// socket to it is closed, i.e. when this is dropped or the daemon exits
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+pub struct AutoUnmount {
+    fusermount: PathBuf,
+    /// The end of the socket pair fusermount waits on
+    socket: RawFd,
+    /// The process until it is watched
+    process: Option<Child>,
+}
+
+#[cfg(target_os = "linux")]
+impl AutoUnmount {
+    /// Forward the messages of fusermount and reap it when it exits on another thread.
+    /// Called once the session starts serving, since the thread would not survive
+    /// daemonizing before.
+    pub fn watch(&mut self) {
+        use std::io::{BufRead, BufReader};
+        use std::thread;
+
+        let mut process = match self.process.take() {
+            Some(process) => process,
+            None => return,
+        };
+        let fusermount = self.fusermount.clone();
+        thread::spawn(move || {
+            if let Some(stderr) = process.stderr.take() {
+                BufReader::new(stderr)
+                    .lines()
+                    .map_while(Result::ok)
+                    .for_each(|line| warn!("{}: {}", fusermount.display(), line));
+            }
+            match process.wait() {
+                Ok(status) if !status.success() => {
+                    error!("{} exited with {}", fusermount.display(), status)
+                }
+                Ok(_) => debug!("{} exited", fusermount.display()),
+                // a daemonized process is not the parent of fusermount anymore
+                Err(e) if e.raw_os_error() == Some(libc::ECHILD) => {
+                    debug!("{} exited, reaped by init", fusermount.display())
+                }
+                Err(e) => error!("failed to wait for {}: {}", fusermount.display(), e),
+            }
+        });
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for AutoUnmount {
+    fn drop(&mut self) {
+        // fusermount unmounts the filesystem as soon as it reads EOF from the socket
+        let _ = nix::unistd::close(self.socket);
+    }
+}
+
 #[cfg(target_os = "linux")]
-fn fuser_mount(mount_point: &Path, options: &[&str]) -> RawFd {
+fn fuser_mount(mount_point: &Path, options: &[&str]) -> io::Result<(RawFd, Option<AutoUnmount>)> {
     use nix::cmsg_space;
     use nix::sys::socket::{
         self, AddressFamily, ControlMessageOwned, MsgFlags, SockFlag, SockType,
     };
     use nix::sys::uio::IoVec;
-    use std::process::Command;
+    use nix::unistd;
+    use std::io::Read;
+    use std::process::{Command, Stdio};
 
-    let _args = FuseMountArgs::parse(options);
+    let fusermount = find_fusermount()?;
+    let args = FuseMountArgs::parse(options);
+    let mut mount_opts = args.fusermount_opts();
+    // allow to mount over the source directory itself, libfuse 3 always allows it and
+    // fusermount3 rejects the option
+    if fusermount.file_name() == Some(OsStr::new("fusermount")) {
+        mount_opts.push_str(",nonempty");
+    }
+    debug!("{} opts: {:?}", fusermount.display(), &mount_opts);
 
     let (local, remote) = socket::socketpair(
         AddressFamily::Unix,
@@ -382,119 +1167,197 @@ fn fuser_mount(mount_point: &Path, options: &[&str]) -> RawFd {
         None,
         SockFlag::empty(),
     )
-    .expect("failed to create socket pair");
+    .map_err(|e| nix_to_io_error(e, "failed to create socket pair"))?;
+    // fusermount must not inherit the local end, it unmounts an auto_unmount mount once
+    // the socket is closed
+    let _ = fcntl::fcntl(local, fcntl::FcntlArg::F_SETFD(fcntl::FdFlag::FD_CLOEXEC));
 
-    let mount_handle = Command::new("fusermount")
+    let spawned = Command::new(&fusermount)
         .arg("-o")
-        .arg("nosuid,nodev,noexec,nonempty") // rw,async,noatime,auto_unmount
+        .arg(&mount_opts)
+        .arg("--")
         .arg(mount_point.as_os_str())
         .env("_FUSE_COMMFD", remote.to_string())
-        .output()
-        .expect("fusermount command failed to start");
-
-    assert!(mount_handle.status.success());
+        .stderr(Stdio::piped())
+        .spawn();
+    // only fusermount holds the remote end now, so that receiving fails if it exits early
+    let _ = unistd::close(remote);
+    let mut mount_handle = match spawned {
+        Ok(child) => child,
+        Err(e) => {
+            let _ = unistd::close(local);
+            return Err(io::Error::new(
+                e.kind(),
+                format!("failed to start {}: {}", fusermount.display(), e),
+            ));
+        }
+    };
 
     let mut buf = [0u8; 5];
     let iov = [IoVec::from_mut_slice(&mut buf[..])];
     let mut cmsgspace = cmsg_space!([RawFd; 1]);
-    let msg = socket::recvmsg(local, &iov, Some(&mut cmsgspace), MsgFlags::empty())
-        .expect("failed to receive from fusermount");
-
-    let mut mount_fd = -1;
-    for cmsg in msg.cmsgs() {
-        if let ControlMessageOwned::ScmRights(fd) = cmsg {
-            assert_eq!(fd.len(), 1);
-            mount_fd = fd[0];
-        } else {
-            panic!("unexpected cmsg");
+    let received = socket::recvmsg(local, &iov, Some(&mut cmsgspace), MsgFlags::empty())
+        .map(|msg| {
+            msg.cmsgs()
+                .filter_map(|cmsg| match cmsg {
+                    ControlMessageOwned::ScmRights(fds) => fds.first().cloned(),
+                    _ => None,
+                })
+                .next()
+        })
+        .map_err(|e| nix_to_io_error(e, "failed to receive from fusermount"));
+
+    let mut stderr = mount_handle.stderr.take();
+    let mut read_stderr = || {
+        let mut output = String::new();
+        if let Some(stderr) = stderr.as_mut() {
+            let _ = stderr.read_to_string(&mut output);
         }
-    }
+        output
+    };
+    let mount_fd = match received {
+        Ok(Some(fd)) => fd,
+        // fusermount exits without sending the fd if mounting failed
+        Ok(None) | Err(_) => {
+            let _ = unistd::close(local);
+            let output = read_stderr();
+            let status = mount_handle.wait()?;
+            return Err(match received {
+                Err(e) if status.success() => e,
+                _ => fusermount_error(&fusermount, status, &output),
+            });
+        }
+    };
 
-    mount_fd
+    if args.auto_unmount() {
+        // fusermount stays alive until the socket is closed, then unmounts
+        mount_handle.stderr = stderr;
+        let auto_unmount = AutoUnmount {
+            fusermount,
+            socket: local,
+            process: Some(mount_handle),
+        };
+        Ok((mount_fd, Some(auto_unmount)))
+    } else {
+        let _ = unistd::close(local);
+        let output = read_stderr();
+        let status = mount_handle.wait()?;
+        if !status.success() {
+            let _ = unistd::close(mount_fd);
+            return Err(fusermount_error(&fusermount, status, &output));
+        }
+        Ok((mount_fd, None))
+    }
 }
 
 #[cfg(target_os = "linux")]
-fn direct_mount(mount_point: &Path, options: &[&str]) -> RawFd {
-    use nix::sys::stat::SFlag;
+fn direct_mount(mount_point: &Path, options: &[&str]) -> io::Result<RawFd> {
     use nix::unistd;
 
-    let _args = FuseMountArgs::parse(options);
-    let devpath = Path::new("/dev/fuse");
+    let args = FuseMountArgs::parse(options);
+    let dev_fd = fcntl::open(Path::new("/dev/fuse"), OFlag::O_RDWR, Mode::empty())
+        .map_err(|e| nix_to_io_error(e, "failed to open /dev/fuse"))?;
+    debug!("open fuse device successfully");
 
-    let dev_fd: RawFd;
-    let result = fcntl::open(devpath, OFlag::O_RDWR, Mode::empty());
-    match result {
-        Ok(fd) => {
-            debug!("open fuse device successfully");
-            dev_fd = fd;
-        }
-        Err(e) => {
-            error!("open fuse device failed! {}", e);
-            return -1;
-        }
+    let result = mount_fuse_device(mount_point, &args, dev_fd);
+    if result.is_err() {
+        let _ = unistd::close(dev_fd);
     }
+    result.map(|()| dev_fd)
+}
 
-    let full_path = fs::canonicalize(mount_point).expect("fail to get full path of mount point");
-    let cstr_path = full_path
-        .to_str()
-        .expect("full mount path to string failed");
-
-    let mnt_sb: FileStat;
-    let result = stat::stat(&full_path);
-    match result {
-        Ok(sb) => mnt_sb = sb,
-        Err(e) => {
-            error!("get mount point stat failed! {}", e);
-            return -1;
-        }
-    }
+/// Mount the opened FUSE device to the mount point, with the new mount API if the kernel
+/// supports it, otherwise with mount(2)
+#[cfg(target_os = "linux")]
+fn mount_fuse_device(mount_point: &Path, args: &FuseMountArgs, dev_fd: RawFd) -> io::Result<()> {
+    use nix::sys::stat::SFlag;
+    use nix::unistd;
 
-    let mntpath = CString::new(cstr_path).expect("CString::new failed");
-    let fstype = CString::new("fuse").expect("CString::new failed");
-    let fsname = CString::new("/dev/fuse").expect("CString::new failed");
+    let full_path = fs::canonicalize(mount_point)?;
+    let mnt_sb =
+        stat::stat(&full_path).map_err(|e| nix_to_io_error(e, "failed to get mount point stat"))?;
 
-    let opts = format!(
+    let fsname = args.fsname().unwrap_or("/dev/fuse");
+    let mut opts = format!(
         "fd={},rootmode={:o},user_id={},group_id={}",
         dev_fd,
         mnt_sb.st_mode & SFlag::S_IFMT.bits(),
         unistd::getuid().as_raw(),
         unistd::getgid().as_raw()
     );
-    let opts = CString::new(&*opts).expect("CString::new failed");
+    if let Some(kernel_opts) = args.kernel_opts() {
+        opts.push(',');
+        opts.push_str(kernel_opts);
+    }
     debug!("direct mount opts: {:?}", &opts);
-    unsafe {
-        let result = libc::mount(
+
+    match fsmount::mount(&full_path, fsname, args.subtype(), args.flags(), &opts) {
+        Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => {
+            debug!("new mount API is not supported, fall back to mount(2)");
+        }
+        result => return result,
+    }
+
+    let to_cstring =
+        |s: &str| CString::new(s).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e));
+    let mntpath = to_cstring(&full_path.to_string_lossy())?;
+    let fstype = match args.subtype() {
+        Some(subtype) => format!("fuse.{}", subtype),
+        None => String::from("fuse"),
+    };
+    let fstype = to_cstring(&fstype)?;
+    let fsname = to_cstring(fsname)?;
+    let opts = to_cstring(&opts)?;
+    #[allow(unsafe_code)]
+    let result = unsafe {
+        libc::mount(
             fsname.as_ptr(),
             mntpath.as_ptr(),
             fstype.as_ptr(),
-            MS_NOSUID | MS_NODEV,
+            args.flags(),
             opts.as_ptr() as *const c_void,
-        );
-        if result == 0 {
-            debug!("mount {:?} to {:?} successfully!", mntpath, devpath);
-            dev_fd
-        } else {
-            let e = Errno::from_i32(errno::errno());
-            debug!("errno={}, {:?}", errno::errno(), e);
-            let mount_fail_str = "mount failed!";
-            #[cfg(target_arch = "aarch64")]
-            libc::perror(mount_fail_str.as_ptr());
-            #[cfg(target_arch = "x86_64")]
-            libc::perror(mount_fail_str.as_ptr() as *const i8);
+        )
+    };
+    if result == 0 {
+        debug!("mount {:?} successfully!", mntpath);
+        Ok(())
+    } else {
+        let e = io::Error::last_os_error();
+        debug!("mount {:?} failed, the error is: {:?}", mntpath, e);
+        Err(io::Error::new(e.kind(), format!("mount(2) failed: {}", e)))
+    }
+}
 
-            -1
-        }
+#[cfg(target_os = "macos")]
+pub fn umount(mount_point: &Path, mode: UnmountMode) -> io::Result<()> {
+    // macOS has no lazy unmount, a forced one is the closest
+    let flags = match mode {
+        UnmountMode::Normal => 0,
+        UnmountMode::Lazy | UnmountMode::Force => MNT_FORCE,
+    };
+    let path = CString::new(mount_point.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    #[allow(unsafe_code)]
+    let result = unsafe { libc::unmount(path.as_ptr(), flags) };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
     }
 }
 
 #[cfg(any(target_os = "macos"))]
-pub fn umount(mount_point: &Path) -> i32 {
-    let mntpnt = mount_point.as_os_str();
-    unsafe { libc::unmount(mntpnt as *const _ as *const u8 as *const i8, MNT_FORCE) }
+pub fn mount(mount_point: &Path, options: &[&str]) -> io::Result<RawFd> {
+    let fd = osxfuse_mount(mount_point, options);
+    if fd < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(fd)
+    }
 }
 
-#[cfg(any(target_os = "macos"))]
-pub fn mount(mount_point: &Path, options: &[&str]) -> RawFd {
+#[cfg(target_os = "macos")]
+fn osxfuse_mount(mount_point: &Path, options: &[&str]) -> RawFd {
     let _args = FuseMountArgs::parse(options);
     let devpath = Path::new("/dev/osxfuse1");
     let fd: RawFd;
@@ -607,3 +1470,183 @@ pub fn mount(mount_point: &Path, options: &[&str]) -> RawFd {
         }
     }
 }
+
+#[cfg(test)]
+#[cfg(target_os = "linux")]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        let config = MountConfig::parse(&[
+            "ro",
+            "nosuid",
+            "allow_root",
+            "debug",
+            "fsname=memfs",
+            "max_read=4096",
+            "uidmap=0:1000:1",
+            "uidmap=1:100000:10",
+        ])
+        .unwrap();
+        assert_eq!(config.read_only, Some(true));
+        assert_eq!(config.suid, Some(false));
+        assert_eq!(config.dev, None);
+        assert!(config.allow_root);
+        assert!(config.debug);
+        assert_eq!(config.fsname.as_deref(), Some("memfs"));
+        assert_eq!(config.max_read, Some(4096));
+        assert_eq!(config.uid_map, vec!["0:1000:1", "1:100000:10"]);
+        assert_eq!(
+            config.to_options(),
+            vec![
+                "ro",
+                "nosuid",
+                "allow_root",
+                "debug",
+                "fsname=memfs",
+                "max_read=4096",
+                "uidmap=0:1000:1",
+                "uidmap=1:100000:10",
+            ]
+        );
+    }
+
+    #[test]
+    fn negated_options() {
+        let config =
+            MountConfig::parse(&["noro", "noasync", "noallow_other", "nodirsync"]).unwrap();
+        assert_eq!(config.read_only, Some(false));
+        assert_eq!(config.sync, Some(true));
+        assert!(!config.allow_other);
+        assert_eq!(config.dirsync, Some(false));
+        assert_eq!(config.to_options(), vec!["rw", "sync"]);
+        // repeating the same option is fine
+        assert!(MountConfig::parse(&["ro", "ro", "norw"]).is_ok());
+    }
+
+    #[test]
+    fn fstab_options() {
+        let config = MountConfig::parse(&[
+            "defaults",
+            "_netdev",
+            "nofail",
+            "user",
+            "noauto",
+            "x-systemd.automount",
+            "comment=backup",
+            "allow_other",
+            "auto_unmount",
+        ])
+        .unwrap();
+        assert!(config.allow_other);
+        assert!(config.auto_unmount);
+        assert_eq!(config.to_options(), vec!["allow_other", "auto_unmount"]);
+        assert!(options_validator(String::from("defaults,allow_other,_netdev")).is_ok());
+    }
+
+    #[test]
+    fn conflicting_options() {
+        let err = MountConfig::parse(&["ro", "rw"]).unwrap_err();
+        assert_eq!(err, "conflicting options \"ro\" and \"rw\"");
+        assert!(MountConfig::parse(&["allow_other", "noallow_other"]).is_err());
+        assert!(MountConfig::parse(&["allow_other", "allow_root"]).is_err());
+        assert!(MountConfig::parse(&["sync", "nosync"]).is_err());
+        let err = MountConfig::parse(&["fsname=a", "fsname=b"]).unwrap_err();
+        assert_eq!(
+            err,
+            "duplicate option \"fsname\": \"fsname=a\" and \"fsname=b\""
+        );
+    }
+
+    #[test]
+    fn invalid_options() {
+        assert!(MountConfig::parse(&["foo"])
+            .unwrap_err()
+            .starts_with("unknown option \"foo\""));
+        assert_eq!(
+            MountConfig::parse(&["ro=1"]).unwrap_err(),
+            "option \"ro\" does not take a value"
+        );
+        assert_eq!(
+            MountConfig::parse(&["fsname="]).unwrap_err(),
+            "option \"fsname\" requires a value"
+        );
+        assert_eq!(
+            MountConfig::parse(&["max_read=abc"]).unwrap_err(),
+            "invalid value of option \"max_read=abc\""
+        );
+        assert!(MountConfig::parse(&["max_read=99999999999"]).is_err());
+        assert_eq!(
+            MountConfig::parse(&["blksize=4096"]).unwrap_err(),
+            "option \"blksize\" is only supported for block device mounts"
+        );
+        assert!(options_validator(String::from("ro,blksize=512")).is_err());
+        assert!(options_validator(String::from("ro,rw")).is_err());
+        assert!(options_validator(String::from("ro,nosuid,fsname=memfs")).is_ok());
+    }
+
+    #[test]
+    fn typed_options() {
+        let config = MountConfig::from_options(&[
+            MountOption::ReadOnly,
+            MountOption::AllowOther,
+            MountOption::FsName(String::from("memfs")),
+            MountOption::Custom(String::from("blkdev")),
+        ])
+        .unwrap();
+        assert_eq!(config.read_only, Some(true));
+        assert!(config.allow_other);
+        assert_eq!(config.custom, vec!["blkdev"]);
+        assert_eq!(
+            config.to_options(),
+            vec!["ro", "allow_other", "fsname=memfs", "blkdev"]
+        );
+        assert!(
+            MountConfig::from_options(&[MountOption::ReadOnly, MountOption::ReadWrite]).is_err()
+        );
+        assert!(MountConfig::from_options(&[MountOption::Custom(String::from("a,b"))]).is_err());
+        assert_eq!(
+            MountOption::from_strs(&["noro", "noallow_other", "subtype=fuse_ll"]).unwrap(),
+            vec![
+                MountOption::ReadWrite,
+                MountOption::Subtype(String::from("fuse_ll"))
+            ]
+        );
+        let args = FuseMountArgs::parse(
+            &config
+                .to_options()
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(args.kernel_opts(), Some("allow_other,blkdev"));
+    }
+
+    #[test]
+    fn fusermount_error_message() {
+        use std::os::unix::process::ExitStatusExt;
+        let fusermount = Path::new("/bin/fusermount3");
+        let status = ExitStatus::from_raw(1 << 8);
+        let err = fusermount_error(fusermount, status, "fusermount3: bad option\n");
+        assert_eq!(
+            err.to_string(),
+            "/bin/fusermount3 failed with exit status: 1: fusermount3: bad option"
+        );
+        let err = fusermount_error(fusermount, status, "");
+        assert_eq!(
+            err.to_string(),
+            "/bin/fusermount3 failed with exit status: 1"
+        );
+    }
+
+    #[test]
+    fn capabilities_and_namespaces() {
+        let status = "Name:\tcat\nCapInh:\t0000000000000000\nCapEff:\t000001ffffffffff\n";
+        assert_eq!(parse_cap_eff(status), Some(0x1ff_ffff_ffff));
+        assert_eq!(parse_cap_eff("CapEff:\t0000000000000000\n"), Some(0));
+        assert_eq!(parse_cap_eff("Name:\tcat\n"), None);
+        assert!(is_initial_uid_map("         0          0 4294967295\n"));
+        assert!(!is_initial_uid_map("         0       1000          1\n"));
+    }
+}
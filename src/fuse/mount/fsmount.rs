@@ -0,0 +1,232 @@
+//! Mount backend using the new mount API
+//!
+//! Creates a filesystem context with `fsopen`, sets each option with `fsconfig`, creates a
+//! detached mount with `fsmount` and attaches it with `move_mount`. Unlike mount(2), the kernel
+//! reports which option it rejected and why, the messages are read back from the context.
+
+use libc::{c_char, c_int, c_uint, c_void};
+use log::debug;
+use nix::unistd;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::ptr;
+
+use super::param::{
+    MS_DIRSYNC, MS_NOATIME, MS_NODEV, MS_NOEXEC, MS_NOSUID, MS_RDONLY, MS_SYNCHRONOUS,
+};
+
+// https://github.com/torvalds/linux/blob/master/include/uapi/linux/mount.h
+const FSOPEN_CLOEXEC: c_uint = 1;
+const FSCONFIG_SET_FLAG: c_uint = 0;
+const FSCONFIG_SET_STRING: c_uint = 1;
+const FSCONFIG_CMD_CREATE: c_uint = 6;
+const FSMOUNT_CLOEXEC: c_uint = 1;
+const MOUNT_ATTR_RDONLY: c_uint = 0x0000_0001;
+const MOUNT_ATTR_NOSUID: c_uint = 0x0000_0002;
+const MOUNT_ATTR_NODEV: c_uint = 0x0000_0004;
+const MOUNT_ATTR_NOEXEC: c_uint = 0x0000_0008;
+const MOUNT_ATTR_NOATIME: c_uint = 0x0000_0010;
+const MOVE_MOUNT_F_EMPTY_PATH: c_uint = 0x0000_0004;
+
+/// Mount flags applied to the superblock, set with `fsconfig` by name
+const SUPERBLOCK_FLAGS: [(u64, &str); 3] = [
+    (MS_RDONLY, "ro"),
+    (MS_SYNCHRONOUS, "sync"),
+    (MS_DIRSYNC, "dirsync"),
+];
+
+/// Mount flags applied to the mount, passed to `fsmount`, read-only applies to both
+const MOUNT_ATTRS: [(u64, c_uint); 5] = [
+    (MS_RDONLY, MOUNT_ATTR_RDONLY),
+    (MS_NOSUID, MOUNT_ATTR_NOSUID),
+    (MS_NODEV, MOUNT_ATTR_NODEV),
+    (MS_NOEXEC, MOUNT_ATTR_NOEXEC),
+    (MS_NOATIME, MOUNT_ATTR_NOATIME),
+];
+
+fn cstring(s: &str) -> io::Result<CString> {
+    CString::new(s).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+fn check(result: libc::c_long) -> io::Result<libc::c_long> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+/// A filesystem context created by `fsopen`, closed when dropped
+#[derive(Debug)]
+struct FsContext {
+    fd: RawFd,
+}
+
+impl FsContext {
+    fn open(fstype: &str) -> io::Result<FsContext> {
+        let fstype = cstring(fstype)?;
+        #[allow(unsafe_code)]
+        let fd =
+            check(unsafe { libc::syscall(libc::SYS_fsopen, fstype.as_ptr(), FSOPEN_CLOEXEC) })?;
+        Ok(FsContext { fd: fd as RawFd })
+    }
+
+    fn config(&self, cmd: c_uint, key: Option<&str>, value: Option<&str>) -> io::Result<()> {
+        let key = key.map(cstring).transpose()?;
+        let value = value.map(cstring).transpose()?;
+        let as_ptr = |s: &Option<CString>| s.as_ref().map_or(ptr::null(), |s| s.as_ptr());
+        #[allow(unsafe_code)]
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_fsconfig,
+                self.fd,
+                cmd,
+                as_ptr(&key),
+                as_ptr(&value) as *const c_void,
+                0 as c_int,
+            )
+        };
+        check(result).map(|_| ()).map_err(|e| {
+            let what = match key {
+                Some(key) => format!("option \"{}\"", key.to_string_lossy()),
+                None => String::from("filesystem"),
+            };
+            let messages = self.messages();
+            if messages.is_empty() {
+                io::Error::new(e.kind(), format!("fsconfig() rejected {}: {}", what, e))
+            } else {
+                io::Error::new(
+                    e.kind(),
+                    format!(
+                        "fsconfig() rejected {}: {}: {}",
+                        what,
+                        e,
+                        messages.join("; ")
+                    ),
+                )
+            }
+        })
+    }
+
+    fn set_flag(&self, key: &str) -> io::Result<()> {
+        self.config(FSCONFIG_SET_FLAG, Some(key), None)
+    }
+
+    fn set_string(&self, key: &str, value: &str) -> io::Result<()> {
+        self.config(FSCONFIG_SET_STRING, Some(key), Some(value))
+    }
+
+    /// Read the messages the kernel logged to the context, each of them is prefixed by
+    /// its level, `e` for error, `w` for warning and `i` for information
+    fn messages(&self) -> Vec<String> {
+        let mut messages = Vec::new();
+        let mut buf = [0u8; 4096];
+        while let Ok(size) = unistd::read(self.fd, &mut buf) {
+            if size == 0 {
+                break;
+            }
+            messages.push(String::from_utf8_lossy(&buf[..size]).trim_end().to_owned());
+        }
+        messages
+    }
+
+    /// Create a detached mount of the configured filesystem
+    fn mount(&self, attrs: c_uint) -> io::Result<RawFd> {
+        self.config(FSCONFIG_CMD_CREATE, None, None)?;
+        #[allow(unsafe_code)]
+        let fd =
+            check(unsafe { libc::syscall(libc::SYS_fsmount, self.fd, FSMOUNT_CLOEXEC, attrs) })?;
+        Ok(fd as RawFd)
+    }
+}
+
+impl Drop for FsContext {
+    fn drop(&mut self) {
+        let _ = unistd::close(self.fd);
+    }
+}
+
+/// Attach a detached mount to the mount point
+fn move_mount(mount_fd: RawFd, mount_point: &Path) -> io::Result<()> {
+    let empty = cstring("")?;
+    let target = CString::new(mount_point.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    #[allow(unsafe_code)]
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_move_mount,
+            mount_fd,
+            empty.as_ptr() as *const c_char,
+            libc::AT_FDCWD,
+            target.as_ptr() as *const c_char,
+            MOVE_MOUNT_F_EMPTY_PATH,
+        )
+    };
+    check(result).map(|_| ())
+}
+
+/// Return the `fsmount` attributes of the mount flags
+fn mount_attrs(flags: u64) -> c_uint {
+    MOUNT_ATTRS
+        .iter()
+        .filter(|&&(flag, _)| flags & flag != 0)
+        .fold(0, |attrs, &(_, attr)| attrs | attr)
+}
+
+/// Mount a FUSE filesystem with the new mount API, `data` holds the comma separated options
+/// passed to the kernel, the same as the data of mount(2). Fails with `ENOSYS` on kernels
+/// older than 5.2, which lack the new mount API.
+pub fn mount(
+    mount_point: &Path,
+    source: &str,
+    subtype: Option<&str>,
+    flags: u64,
+    data: &str,
+) -> io::Result<()> {
+    let context = FsContext::open("fuse")?;
+    context.set_string("source", source)?;
+    if let Some(subtype) = subtype {
+        context.set_string("subtype", subtype)?;
+    }
+    for option in data.split(',').filter(|o| !o.is_empty()) {
+        match option.split_once('=') {
+            Some((key, value)) => context.set_string(key, value)?,
+            None => context.set_flag(option)?,
+        }
+    }
+    for &(flag, name) in SUPERBLOCK_FLAGS.iter() {
+        if flags & flag != 0 {
+            context.set_flag(name)?;
+        }
+    }
+
+    let mount_fd = context.mount(mount_attrs(flags))?;
+    let result = move_mount(mount_fd, mount_point);
+    let _ = unistd::close(mount_fd);
+    debug!(
+        "fsmount() of {:?} with data {:?} and flags {:#x} finished: {:?}",
+        mount_point, data, flags, result
+    );
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn flags_to_attrs() {
+        assert_eq!(mount_attrs(0), 0);
+        assert_eq!(
+            mount_attrs(MS_NOSUID | MS_NODEV | MS_RDONLY),
+            MOUNT_ATTR_RDONLY | MOUNT_ATTR_NOSUID | MOUNT_ATTR_NODEV
+        );
+        assert_eq!(
+            mount_attrs(MS_NOEXEC | MS_NOATIME | MS_SYNCHRONOUS),
+            MOUNT_ATTR_NOEXEC | MOUNT_ATTR_NOATIME
+        );
+    }
+}
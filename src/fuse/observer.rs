@@ -0,0 +1,170 @@
+//! Observers of the messages of a session
+//!
+//! An observer sees every raw request before it is dispatched and every reply before it is
+//! sent, e.g. to record, log or count them. Replies may be sent from any thread, so observers
+//! are shared between threads.
+
+use std::fmt;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::Arc;
+
+use super::reply;
+use super::transport::TransportSender;
+
+/// An observer of the requests and replies of a session
+pub trait Observer: fmt::Debug + Send + Sync {
+    /// Observe a request received from the kernel, before it is dispatched
+    fn request(&self, data: &[u8]);
+
+    /// Observe a write request whose last `len` bytes of data were left in a pipe, before it
+    /// is dispatched. Only called if no observer of the session needs the bytes.
+    fn request_from_fd(&self, _data: &[u8], _len: usize) {}
+
+    /// Observe a reply made of the given slices, before it is sent to the kernel. The first
+    /// slice is the header.
+    fn reply(&self, data: &[&[u8]]);
+
+    /// Observe a reply made of the header followed by `len` bytes of a file, before it is
+    /// sent to the kernel. Only called if no observer of the session needs the bytes.
+    fn reply_from_fd(&self, _header: &[u8], _len: usize) {}
+
+    /// Whether the bytes of replies from a file and of write requests are needed, if so they
+    /// are copied into memory and passed to `reply` and `request` instead of `reply_from_fd`
+    /// and `request_from_fd`
+    fn needs_file_data(&self) -> bool {
+        true
+    }
+}
+
+/// A sender passing the replies to observers before sending them, replies from a file are
+/// copied into memory only if an observer needs their bytes
+#[derive(Debug)]
+pub struct ObservingSender<S: TransportSender> {
+    sender: S,
+    observers: Vec<Arc<dyn Observer>>,
+}
+
+impl<S: TransportSender> ObservingSender<S> {
+    /// Pass the replies of the given sender to the observers
+    pub fn new(sender: S, observers: Vec<Arc<dyn Observer>>) -> ObservingSender<S> {
+        ObservingSender { sender, observers }
+    }
+}
+
+impl<S: TransportSender> TransportSender for ObservingSender<S> {
+    fn send(&self, data: &[&[u8]]) -> io::Result<()> {
+        for observer in &self.observers {
+            observer.reply(data);
+        }
+        self.sender.send(data)
+    }
+
+    fn send_from_fd(&self, header: &[u8], fd: RawFd, offset: i64, len: usize) -> io::Result<()> {
+        if self.observers.iter().any(|o| o.needs_file_data()) {
+            let data = reply::read_from_fd(fd, offset, len)?;
+            return self.send(&[header, &data]);
+        }
+        for observer in &self.observers {
+            observer.reply_from_fd(header, len);
+        }
+        self.sender.send_from_fd(header, fd, offset, len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Observer, ObservingSender};
+    use crate::fuse::transport::TransportSender;
+    use std::fs::File;
+    use std::io::{self, Write};
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use std::sync::{Arc, Mutex};
+
+    /// A sender recording how each reply was sent and its length
+    #[derive(Debug, Default)]
+    struct RecordingSender {
+        sent: Arc<Mutex<Vec<(&'static str, usize)>>>,
+    }
+
+    impl TransportSender for RecordingSender {
+        fn send(&self, data: &[&[u8]]) -> io::Result<()> {
+            let len = data.iter().map(|slice| slice.len()).sum();
+            self.sent.lock().unwrap().push(("send", len));
+            Ok(())
+        }
+
+        fn send_from_fd(
+            &self,
+            header: &[u8],
+            _fd: RawFd,
+            _offset: i64,
+            len: usize,
+        ) -> io::Result<()> {
+            self.sent
+                .lock()
+                .unwrap()
+                .push(("send_from_fd", header.len() + len));
+            Ok(())
+        }
+    }
+
+    /// An observer recording the payload lengths of file replies
+    #[derive(Debug)]
+    struct LengthObserver {
+        needs_file_data: bool,
+        lengths: Mutex<Vec<usize>>,
+    }
+
+    impl Observer for LengthObserver {
+        fn request(&self, _data: &[u8]) {}
+
+        fn reply(&self, data: &[&[u8]]) {
+            self.lengths
+                .lock()
+                .unwrap()
+                .push(data[1..].iter().map(|s| s.len()).sum());
+        }
+
+        fn reply_from_fd(&self, _header: &[u8], len: usize) {
+            self.lengths.lock().unwrap().push(len);
+        }
+
+        fn needs_file_data(&self) -> bool {
+            self.needs_file_data
+        }
+    }
+
+    #[test]
+    fn forward_replies_from_fd() {
+        let path = std::env::temp_dir().join(format!("fuse_ll_observer_{}", std::process::id()));
+        File::create(&path)
+            .unwrap()
+            .write_all(b"0123456789")
+            .unwrap();
+        let file = File::open(&path).unwrap();
+
+        for &needs_file_data in &[false, true] {
+            let sender = RecordingSender::default();
+            let sent = Arc::clone(&sender.sent);
+            let observer = Arc::new(LengthObserver {
+                needs_file_data,
+                lengths: Mutex::new(Vec::new()),
+            });
+            let observing =
+                ObservingSender::new(sender, vec![observer.clone() as Arc<dyn Observer>]);
+            observing
+                .send_from_fd(b"head", file.as_raw_fd(), 2, 5)
+                .unwrap();
+            assert_eq!(*observer.lengths.lock().unwrap(), vec![5]);
+            // the bytes are only copied into memory if an observer needs them
+            let how = if needs_file_data {
+                "send"
+            } else {
+                "send_from_fd"
+            };
+            assert_eq!(*sent.lock().unwrap(), vec![(how, 9)]);
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,321 @@
+//! Protocol debug output
+//!
+//! The protocol logger prints one line per request and one per reply in a stable format,
+//! similar to the debug output of libfuse. Request lines start with `>` and hold the unique
+//! id, the caller and the decoded arguments, reply lines start with `<` and hold the unique
+//! id, the operation, the result and the latency:
+//!
+//! ```text
+//! > unique 4, nodeid 1, uid 1000, gid 1000, pid 4242: LOOKUP name "a.txt"
+//! < unique 4, LOOKUP, OK, 12us: nodeid 1220708, generation 0, mode 0o100644, size 6
+//! > unique 6, nodeid 1, uid 1000, gid 1000, pid 4242: LOOKUP name "b.txt"
+//! < unique 6, LOOKUP, ENOENT, 9us
+//! ```
+
+use nix::errno::Errno;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Write};
+use std::mem;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use super::abi::{from_bytes, fuse_opcode, fuse_open_out, fuse_out_header, fuse_write_out};
+use super::abi::{fuse_attr, fuse_attr_out, fuse_entry_out, fuse_init_out};
+use super::ll_request::{self, Request};
+use super::observer::Observer;
+
+/// An observer printing every request and reply of a session
+pub struct ProtocolLogger {
+    /// Opcode and arrival time of the requests not replied yet by unique id
+    pending: Mutex<HashMap<u64, (u32, Instant)>>,
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl fmt::Debug for ProtocolLogger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProtocolLogger")
+            .field("pending", &self.pending)
+            .finish()
+    }
+}
+
+impl ProtocolLogger {
+    /// Print the lines to the writer
+    pub fn new(writer: Box<dyn Write + Send>) -> ProtocolLogger {
+        ProtocolLogger {
+            pending: Mutex::new(HashMap::new()),
+            writer: Mutex::new(writer),
+        }
+    }
+
+    /// Print the lines to stderr
+    pub fn stderr() -> ProtocolLogger {
+        ProtocolLogger::new(Box::new(io::stderr()))
+    }
+
+    fn print(&self, line: fmt::Arguments<'_>) {
+        // the debug output must not disturb the session, so failures are ignored
+        let _ = self
+            .writer
+            .lock()
+            .unwrap()
+            .write_fmt(format_args!("{}\n", line));
+    }
+}
+
+/// Return the name of an opcode without the `FUSE_` prefix
+pub(super) fn opcode_name(opcode: u32) -> String {
+    match fuse_opcode::try_from(opcode) {
+        Ok(opcode) => {
+            let name = format!("{:?}", opcode);
+            name.trim_start_matches("FUSE_").to_owned()
+        }
+        Err(_) => format!("opcode {}", opcode),
+    }
+}
+
+/// Describe the attributes of a reply
+fn attr_summary(attr: &fuse_attr) -> String {
+    format!("mode {:#o}, size {}", attr.mode, attr.size)
+}
+
+/// Describe the data of a successful reply of `len` bytes, the data is empty if the reply is
+/// sent from a file
+fn reply_summary(opcode: u32, data: &[u8], len: usize) -> Option<String> {
+    let opcode = fuse_opcode::try_from(opcode).ok()?;
+    let summary = match opcode {
+        fuse_opcode::FUSE_LOOKUP
+        | fuse_opcode::FUSE_MKNOD
+        | fuse_opcode::FUSE_MKDIR
+        | fuse_opcode::FUSE_SYMLINK
+        | fuse_opcode::FUSE_LINK
+        | fuse_opcode::FUSE_CREATE => {
+            let entry: fuse_entry_out = from_bytes(data).ok()?;
+            let mut summary = format!(
+                "nodeid {}, generation {}, {}",
+                entry.nodeid,
+                entry.generation,
+                attr_summary(&entry.attr)
+            );
+            if let fuse_opcode::FUSE_CREATE = opcode {
+                let open: fuse_open_out =
+                    from_bytes(data.get(mem::size_of::<fuse_entry_out>()..)?).ok()?;
+                summary.push_str(&format!(", fh {}", open.fh));
+            }
+            summary
+        }
+        fuse_opcode::FUSE_GETATTR | fuse_opcode::FUSE_SETATTR => {
+            let out: fuse_attr_out = from_bytes(data).ok()?;
+            format!("ino {}, {}", out.attr.ino, attr_summary(&out.attr))
+        }
+        fuse_opcode::FUSE_OPEN | fuse_opcode::FUSE_OPENDIR => {
+            let out: fuse_open_out = from_bytes(data).ok()?;
+            format!("fh {}, open flags {:#x}", out.fh, out.open_flags)
+        }
+        fuse_opcode::FUSE_WRITE => {
+            let out: fuse_write_out = from_bytes(data).ok()?;
+            format!("size {}", out.size)
+        }
+        fuse_opcode::FUSE_INIT => {
+            let out: fuse_init_out = from_bytes(data).ok()?;
+            format!(
+                "ABI {}.{}, flags {:#x}, max write {}",
+                out.major, out.minor, out.flags, out.max_write
+            )
+        }
+        _ if len > 0 => format!("{} bytes", len),
+        _ => return None,
+    };
+    Some(summary)
+}
+
+impl Observer for ProtocolLogger {
+    fn request(&self, data: &[u8]) {
+        self.log_request(data, 0);
+    }
+
+    fn request_from_fd(&self, data: &[u8], len: usize) {
+        self.log_request(data, len);
+    }
+
+    fn reply(&self, data: &[&[u8]]) {
+        let (header, payload) = match data.split_first() {
+            Some((header, payload)) => (*header, payload),
+            None => (&[][..], &[][..]),
+        };
+        // replies with structures have a single slice of payload
+        let payload = match payload {
+            [] => Cow::Borrowed(&[][..]),
+            [slice] => Cow::Borrowed(*slice),
+            slices => Cow::Owned(slices.concat()),
+        };
+        self.log_reply(header, &payload, payload.len());
+    }
+
+    fn reply_from_fd(&self, header: &[u8], len: usize) {
+        self.log_reply(header, &[], len);
+    }
+
+    fn needs_file_data(&self) -> bool {
+        false
+    }
+}
+
+impl ProtocolLogger {
+    /// Print a request of which the last `spliced` bytes of data were left in a pipe
+    fn log_request(&self, data: &[u8], spliced: usize) {
+        let header = match ll_request::parse_header(data) {
+            Some(header) => header,
+            None => {
+                self.print(format_args!("> short request of {} bytes", data.len()));
+                return;
+            }
+        };
+        if ll_request::expects_reply(header) {
+            self.pending
+                .lock()
+                .unwrap()
+                .insert(header.unique, (header.opcode, Instant::now()));
+        }
+        let caller = format!(
+            "unique {}, nodeid {}, uid {}, gid {}, pid {}",
+            header.unique, header.nodeid, header.uid, header.gid, header.pid
+        );
+        match Request::with_spliced(data, spliced) {
+            Ok(req) => self.print(format_args!("> {}: {}", caller, req.operation())),
+            Err(err) => self.print(format_args!("> {}: {}", caller, err)),
+        }
+    }
+
+    /// Print a reply with the given header and `len` bytes of payload, of which the data is
+    /// in memory
+    fn log_reply(&self, header: &[u8], data: &[u8], len: usize) {
+        let header: fuse_out_header = match from_bytes(header) {
+            Ok(header) => header,
+            Err(_) => {
+                self.print(format_args!(
+                    "< short reply of {} bytes",
+                    header.len() + len
+                ));
+                return;
+            }
+        };
+        let (opcode, latency) = match self.pending.lock().unwrap().remove(&header.unique) {
+            Some((opcode, start)) => (Some(opcode), start.elapsed().as_micros()),
+            None => (None, 0),
+        };
+        let name = opcode.map_or_else(|| String::from("unknown request"), opcode_name);
+        if header.error != 0 {
+            self.print(format_args!(
+                "< unique {}, {}, {:?}, {}us",
+                header.unique,
+                name,
+                Errno::from_i32(-header.error),
+                latency
+            ));
+            return;
+        }
+        match opcode.and_then(|opcode| reply_summary(opcode, data, len)) {
+            Some(summary) => self.print(format_args!(
+                "< unique {}, {}, OK, {}us: {}",
+                header.unique, name, latency, summary
+            )),
+            None => self.print(format_args!(
+                "< unique {}, {}, OK, {}us",
+                header.unique, name, latency
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ProtocolLogger;
+    use crate::fuse::abi::{as_bytes, fuse_in_header, fuse_opcode};
+    use crate::fuse::mock::MockTransport;
+    use crate::fuse::{Filesystem, Session};
+    use std::io::{self, Write};
+    use std::mem;
+    use std::sync::{Arc, Mutex};
+
+    struct NullFS;
+
+    impl Filesystem for NullFS {}
+
+    /// A writer into a buffer shared with the test
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn request(opcode: u32, unique: u64, arg: &[u8]) -> Vec<u8> {
+        let header = fuse_in_header {
+            len: (mem::size_of::<fuse_in_header>() + arg.len()) as u32,
+            opcode,
+            unique,
+            nodeid: 1,
+            uid: 1000,
+            gid: 100,
+            pid: 42,
+            padding: 0,
+        };
+        [as_bytes(&header), arg].concat()
+    }
+
+    #[test]
+    fn log_requests_and_replies() {
+        let output = Output::default();
+        let (transport, _replies) = MockTransport::new();
+        let mut se = Session::with_transport(NullFS, transport);
+        se.observe(Arc::new(ProtocolLogger::new(Box::new(output.clone()))));
+        // major 7, minor 8, max_readahead 4096, no flags
+        let init_arg: Vec<u8> = [7_u32, 8, 4096, 0]
+            .iter()
+            .flat_map(|v| v.to_ne_bytes().to_vec())
+            .collect();
+        se.process(&request(fuse_opcode::FUSE_INIT as u32, 1, &init_arg));
+        se.process(&request(fuse_opcode::FUSE_LOOKUP as u32, 2, b"a.txt\0"));
+        se.process(&request(
+            fuse_opcode::FUSE_FORGET as u32,
+            3,
+            &[1, 0, 0, 0, 0, 0, 0, 0],
+        ));
+        se.process(&request(999, 4, &[]));
+
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        // latencies vary, only the part in front of them is stable
+        let lines: Vec<_> = output
+            .lines()
+            .map(|line| match line.rfind("us") {
+                Some(end) if line.starts_with('<') => {
+                    let start = line[..end].rfind(' ').unwrap();
+                    format!("{}<latency>{}", &line[..start + 1], &line[end + 2..])
+                }
+                _ => line.to_owned(),
+            })
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                "> unique 1, nodeid 1, uid 1000, gid 100, pid 42: INIT kernel ABI 7.8, flags 0x0, max readahead 4096",
+                "< unique 1, INIT, OK, <latency>: ABI 7.8, flags 0x0, max write 16777216",
+                "> unique 2, nodeid 1, uid 1000, gid 100, pid 42: LOOKUP name \"a.txt\"",
+                "< unique 2, LOOKUP, ENOSYS, <latency>",
+                "> unique 3, nodeid 1, uid 1000, gid 100, pid 42: FORGET nlookup 1",
+                "> unique 4, nodeid 1, uid 1000, gid 100, pid 42: Unknown FUSE opcode (999)",
+                "< unique 4, opcode 999, ENOSYS, <latency>",
+            ]
+        );
+    }
+}
@@ -6,14 +6,17 @@
 //! data without cloning the data. A reply *must always* be used (by calling either ok() or
 //! error() exactly once).
 
-use libc::{EIO, S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFREG, S_IFSOCK};
+use libc::{EINVAL, EIO, S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFREG, S_IFSOCK};
 use log::warn;
+use nix::sys::{stat, uio};
 use std::convert::AsRef;
 use std::ffi::OsStr;
 use std::fmt;
+use std::io;
 use std::marker::PhantomData;
 use std::os::raw::c_int;
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
 use std::time::{Duration, SystemTime, SystemTimeError, UNIX_EPOCH};
 use std::{mem, ptr, slice};
 
@@ -26,10 +29,39 @@ use super::abi::{
 };
 use super::{FileAttr, FileType};
 
+/// Max number of slices of a reply sent without allocating, the header and the payload of all
+/// replies built here fit
+pub const MAX_REPLY_SLICES: usize = 4;
+
 /// Generic reply callback to send data
 pub trait ReplySender: Send + 'static {
     /// Send data.
     fn send(&self, data: &[&[u8]]);
+
+    /// Send the header followed by `len` bytes of the file `fd` at `offset`, nothing is sent
+    /// if it fails. The default copies the bytes into memory, the channel splices them.
+    fn send_from_fd(&self, header: &[u8], fd: RawFd, offset: i64, len: usize) -> io::Result<()> {
+        let data = read_from_fd(fd, offset, len)?;
+        self.send(&[header, &data]);
+        Ok(())
+    }
+}
+
+/// Read exactly `len` bytes of the file `fd` at `offset`
+pub fn read_from_fd(fd: RawFd, offset: i64, len: usize) -> io::Result<Vec<u8>> {
+    let mut data = vec![0_u8; len];
+    let mut done = 0;
+    while done < len {
+        match uio::pread(fd, &mut data[done..], offset + done as i64) {
+            // the file was truncated after the length of the reply was decided
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            Ok(n) => done += n,
+            Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => {}
+            Err(nix::Error::Sys(errno)) => return Err(io::Error::from_raw_os_error(errno as i32)),
+            Err(e) => return Err(io::Error::other(e.to_string())),
+        }
+    }
+    Ok(data)
 }
 
 impl fmt::Debug for Box<dyn ReplySender> {
@@ -173,12 +205,47 @@ impl<T> ReplyRaw<T> {
         };
         as_bytes(&header, |headerbytes| {
             let sender = self.sender.take().unwrap();
-            let mut sendbytes = headerbytes.to_vec();
-            sendbytes.extend(bytes);
-            sender.send(&sendbytes);
+            let count = headerbytes.len() + bytes.len();
+            if count <= MAX_REPLY_SLICES {
+                let mut sendbytes: [&[u8]; MAX_REPLY_SLICES] = [&[]; MAX_REPLY_SLICES];
+                sendbytes[..headerbytes.len()].copy_from_slice(headerbytes);
+                sendbytes[headerbytes.len()..count].copy_from_slice(bytes);
+                sender.send(&sendbytes[..count]);
+            } else {
+                let mut sendbytes = headerbytes.to_vec();
+                sendbytes.extend(bytes);
+                sender.send(&sendbytes);
+            }
         });
     }
 
+    /// Reply to a request with `len` bytes of the file `fd` at `offset`, or with the error if
+    /// the bytes cannot be read
+    fn send_from_fd(&mut self, fd: RawFd, offset: i64, len: usize) {
+        assert!(self.sender.is_some());
+        let header = fuse_out_header {
+            len: (mem::size_of::<fuse_out_header>() + len) as u32,
+            error: 0,
+            unique: self.unique,
+        };
+        let res = as_bytes(&header, |headerbytes| {
+            let sender = self.sender.as_ref().unwrap();
+            sender.send_from_fd(headerbytes[0], fd, offset, len)
+        });
+        match res {
+            Ok(()) => {
+                self.sender.take();
+            }
+            Err(err) => {
+                warn!(
+                    "Failed to reply with {} bytes of fd {} at offset {} to operation {}: {}",
+                    len, fd, offset, self.unique, err
+                );
+                self.send(err.raw_os_error().unwrap_or(EIO), &[]);
+            }
+        }
+    }
+
     /// Reply to a request with the given type
     pub fn ok(mut self, data: &T) {
         as_bytes(data, |bytes| {
@@ -254,6 +321,23 @@ impl ReplyData {
         self.reply.send(0, &[data]);
     }
 
+    /// Reply to a request with up to `size` bytes of the file `fd` at `offset`, fewer if the
+    /// file ends before. On Linux, the bytes are spliced to the kernel without copying them.
+    pub fn data_from_fd(mut self, fd: RawFd, offset: i64, size: u32) {
+        if offset < 0 {
+            self.reply.error(EINVAL);
+            return;
+        }
+        match stat::fstat(fd) {
+            Ok(st) => {
+                let len = (st.st_size - offset).clamp(0, i64::from(size));
+                self.reply.send_from_fd(fd, offset, len as usize);
+            }
+            Err(nix::Error::Sys(errno)) => self.reply.error(errno as c_int),
+            Err(_) => self.reply.error(EIO),
+        }
+    }
+
     /// Reply to a request with the given error code
     pub fn error(self, err: c_int) {
         self.reply.error(err);
@@ -809,6 +893,26 @@ mod test {
         reply.error(66);
     }
 
+    #[test]
+    fn reply_many_slices() {
+        // more slices than fit on the stack are sent all the same
+        let sender = AssertSender {
+            expected: vec![
+                vec![
+                    0x15, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde, 0x00,
+                    0x00, 0x00, 0x00,
+                ],
+                vec![0x01],
+                vec![0x02],
+                vec![0x03],
+                vec![0x04],
+                vec![0x05],
+            ],
+        };
+        let mut reply: ReplyRaw<()> = Reply::new(0xdeadbeef, sender);
+        reply.send(0, &[&[0x01], &[0x02], &[0x03], &[0x04], &[0x05]]);
+    }
+
     #[test]
     fn reply_empty() {
         let sender = AssertSender {
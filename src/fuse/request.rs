@@ -5,7 +5,7 @@
 //!
 //! TODO: This module is meant to go away soon in favor of `ll::Request`.
 
-use libc::{EIO, ENOSYS, EPROTO};
+use libc::{c_int, EACCES, EIO, ENOSYS, EPROTO};
 use log::{debug, error, warn};
 use std::convert::TryFrom;
 use std::path::Path;
@@ -13,10 +13,12 @@ use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use super::abi::consts::*;
 use super::abi::*;
-use super::channel::ChannelSender;
-use super::ll_request;
+use super::async_fs::AsyncFilesystem;
+use super::async_session::AsyncSession;
+use super::ll_request::{self, RequestError};
 use super::reply::{Reply, ReplyDirectory, ReplyEmpty, ReplyRaw};
 use super::session::{Session, BUFFER_SIZE, MAX_WRITE_SIZE};
+use super::transport::{ChannelSender, SplicedData, Transport};
 use super::Filesystem;
 
 /// We generally support async reads
@@ -30,167 +32,33 @@ const INIT_FLAGS: u32 = FUSE_ASYNC_READ;
 const INIT_FLAGS: u32 = FUSE_ASYNC_READ | FUSE_CASE_INSENSITIVE | FUSE_VOL_RENAME | FUSE_XTIMES;
 // TODO: Add FUSE_EXPORT_SUPPORT and FUSE_BIG_WRITES (requires ABI 7.10)
 
-/// Request data structure
-#[derive(Debug)]
-pub struct Request<'a> {
-    /// Channel sender for sending the reply
-    ch: ChannelSender,
-    /// Request raw data
-    data: &'a [u8],
-    /// Parsed request
-    pub request: ll_request::Request<'a>,
-}
-
-impl<'a> Request<'a> {
-    /// Create a new request from the given data
-    pub fn new(ch: ChannelSender, data: &'a [u8]) -> Option<Request<'a>> {
-        let request = match ll_request::Request::try_from(data) {
-            Ok(request) => request,
-            Err(err) => {
-                // FIXME: Reply with ENOSYS?
-                error!("{}", err);
-                return None;
-            }
-        };
-
-        Some(Self { ch, data, request })
-    }
-
-    /// Dispatch request to the given filesystem.
-    /// This calls the appropriate filesystem operation method for the
-    /// request and sends back the returned reply to the kernel
-    pub fn dispatch<FS: Filesystem>(&self, se: &mut Session<FS>) {
-        #[cfg(target_os = "macos")]
-        #[inline]
-        fn get_macos_setattr(
-            arg: &fuse_setattr_in,
-        ) -> (
-            Option<SystemTime>,
-            Option<SystemTime>,
-            Option<SystemTime>,
-            Option<u32>,
-        ) {
-            let crtime = match arg.valid & FATTR_CRTIME {
-                0 => None,
-                _ => Some(
-                    match UNIX_EPOCH.checked_add(Duration::new(arg.crtime, arg.crtimensec)) {
-                        Some(crt) => crt,
-                        None => SystemTime::now(),
-                    },
-                ), // _ => Some(UNIX_EPOCH + Duration::new(arg.crtime, arg.crtimensec)),
-            };
-            let chgtime = match arg.valid & FATTR_CHGTIME {
-                0 => None,
-                _ => Some(
-                    match UNIX_EPOCH.checked_add(Duration::new(arg.chgtime, arg.chgtimensec)) {
-                        Some(cht) => cht,
-                        None => SystemTime::now(),
-                    },
-                ), // _ => Some(UNIX_EPOCH + Duration::new(arg.chgtime, arg.chgtimensec)),
-            };
-            let bkuptime = match arg.valid & FATTR_BKUPTIME {
-                0 => None,
-                _ => Some(
-                    match UNIX_EPOCH.checked_add(Duration::new(arg.bkuptime, arg.bkuptimensec)) {
-                        Some(bkt) => bkt,
-                        None => SystemTime::now(),
-                    },
-                ), // _ => Some(UNIX_EPOCH + Duration::new(arg.bkuptime, arg.bkuptimensec)),
-            };
-            let flags = match arg.valid & FATTR_FLAGS {
-                0 => None,
-                _ => Some(arg.flags),
-            };
-            (crtime, chgtime, bkuptime, flags)
-        }
-        #[cfg(target_os = "macos")]
-        #[inline]
-        fn get_position(arg: &fuse_setxattr_in) -> u32 {
-            arg.position
-        }
-        #[cfg(not(target_os = "macos"))]
-        #[inline]
-        fn get_position(_arg: &fuse_setxattr_in) -> u32 {
-            0
-        }
-        debug!("{}", self.request);
-
-        match self.request.operation() {
-            // Filesystem initialization
-            ll_request::Operation::Init { arg } => {
-                debug!("Init args: {:?}", arg);
-                let reply: ReplyRaw<fuse_init_out> = self.reply();
-                // We don't support ABI versions before 7.6
-                if arg.major < 7 || (arg.major == 7 && arg.minor < 6) {
-                    error!("Unsupported FUSE ABI version {}.{}", arg.major, arg.minor);
-                    reply.error(EPROTO);
-                    return;
-                }
-                // Remember ABI version supported by kernel
-                se.proto_major = arg.major;
-                se.proto_minor = arg.minor;
-                // Call filesystem init method and give it a chance to return an error
-                let res = se.filesystem.init(self);
-                if let Err(err) = res {
-                    reply.error(err);
-                    return;
-                }
-                // Reply with our desired version and settings. If the kernel supports a
-                // larger major version, it'll re-send a matching init message. If it
-                // supports only lower major versions, we replied with an error above.
-                let init = fuse_init_out {
-                    major: FUSE_KERNEL_VERSION,
-                    minor: FUSE_KERNEL_MINOR_VERSION,
-                    // max_readahead: arg.max_readahead, // accept any readahead size
-                    max_readahead: if (BUFFER_SIZE as u32) < arg.max_readahead {
-                        BUFFER_SIZE as u32
-                    } else {
-                        arg.max_readahead
-                    }, // TODO: adjust BUFFER_SIZE according to max_readahead
-                    flags: arg.flags & INIT_FLAGS, // use features given in INIT_FLAGS and reported as capable
-                    unused: 0,
-                    max_write: MAX_WRITE_SIZE as u32, // TODO: use a max write size that fits into the session's buffer
-                };
-                debug!(
-                    "INIT response: ABI {}.{}, flags {:#x}, max readahead {}, max write {}",
-                    init.major, init.minor, init.flags, init.max_readahead, init.max_write
-                );
-                se.initialized = true;
-                reply.ok(&init);
-            }
-            // Any operation is invalid before initialization
-            _ if !se.initialized => {
-                warn!("Ignoring FUSE operation before init: {}", self.request);
-                self.reply::<ReplyEmpty>().error(EIO);
-            }
-            // Filesystem destroyed
-            ll_request::Operation::Destroy => {
-                se.filesystem.destroy(self);
-                se.destroyed = true;
-                self.reply::<ReplyEmpty>().ok();
-            }
-            // Any operation is invalid after destroy
-            _ if se.destroyed => {
-                warn!("Ignoring FUSE operation after destroy: {}", self.request);
-                self.reply::<ReplyEmpty>().error(EIO);
-            }
+/// Since ABI 7.14, we report that replies may be spliced to the device and their pages moved,
+/// see `ReplyData::data_from_fd`, and that requests are spliced from the device, see
+/// `Filesystem::write_from_fd`
+#[cfg(all(feature = "abi-7-14", target_os = "linux"))]
+const SPLICE_FLAGS: u32 = FUSE_SPLICE_WRITE | FUSE_SPLICE_MOVE | FUSE_SPLICE_READ;
+#[cfg(not(all(feature = "abi-7-14", target_os = "linux")))]
+const SPLICE_FLAGS: u32 = 0;
 
+/// Call the filesystem method of the operation of a request, the operations handled by the
+/// session itself, init and destroy, are dispatched before. Used by both the synchronous and the
+/// asynchronous dispatch, the latter passes `.await` to await the returned futures.
+macro_rules! dispatch_operation {
+    ($req:ident, $fs:expr $(, .$aw:ident)?) => {
+        match $req.request.operation() {
             ll_request::Operation::Interrupt { .. } => {
                 // TODO: handle FUSE_INTERRUPT
-                self.reply::<ReplyEmpty>().error(ENOSYS);
+                $req.reply::<ReplyEmpty>().error(ENOSYS);
             }
 
             ll_request::Operation::Lookup { name } => {
-                se.filesystem
-                    .lookup(self, self.request.nodeid(), &name, self.reply());
+                $fs.lookup($req, $req.request.nodeid(), &name, $req.reply())$(.$aw)?;
             }
             ll_request::Operation::Forget { arg } => {
-                se.filesystem
-                    .forget(self, self.request.nodeid(), arg.nlookup); // no reply
+                $fs.forget($req, $req.request.nodeid(), arg.nlookup)$(.$aw)?; // no reply
             }
             ll_request::Operation::GetAttr => {
-                se.filesystem
-                    .getattr(self, self.request.nodeid(), self.reply());
+                $fs.getattr($req, $req.request.nodeid(), $req.reply())$(.$aw)?;
             }
             ll_request::Operation::SetAttr { arg } => {
                 let mode = match arg.valid & FATTR_MODE {
@@ -221,22 +89,10 @@ impl<'a> Request<'a> {
                     0 => None,
                     _ => Some(arg.fh),
                 };
-                #[cfg(not(target_os = "macos"))]
-                #[inline]
-                fn get_macos_setattr(
-                    _arg: &fuse_setattr_in,
-                ) -> (
-                    Option<SystemTime>,
-                    Option<SystemTime>,
-                    Option<SystemTime>,
-                    Option<u32>,
-                ) {
-                    (None, None, None, None)
-                }
                 let (crtime, chgtime, bkuptime, flags) = get_macos_setattr(arg);
-                se.filesystem.setattr(
-                    self,
-                    self.request.nodeid(),
+                $fs.setattr(
+                    $req,
+                    $req.request.nodeid(),
                     mode,
                     user_id,
                     group_id,
@@ -248,210 +104,201 @@ impl<'a> Request<'a> {
                     chgtime,
                     bkuptime,
                     flags,
-                    self.reply(),
-                );
+                    $req.reply(),
+                )$(.$aw)?;
             }
             ll_request::Operation::ReadLink => {
-                se.filesystem
-                    .readlink(self, self.request.nodeid(), self.reply());
+                $fs.readlink($req, $req.request.nodeid(), $req.reply())$(.$aw)?;
             }
             ll_request::Operation::MkNod { arg, name } => {
-                se.filesystem.mknod(
-                    self,
-                    self.request.nodeid(),
+                $fs.mknod(
+                    $req,
+                    $req.request.nodeid(),
                     &name,
                     arg.mode,
                     arg.rdev,
-                    self.reply(),
-                );
+                    $req.reply(),
+                )$(.$aw)?;
             }
             ll_request::Operation::MkDir { arg, name } => {
-                se.filesystem
-                    .mkdir(self, self.request.nodeid(), &name, arg.mode, self.reply());
+                $fs.mkdir($req, $req.request.nodeid(), &name, arg.mode, $req.reply())$(.$aw)?;
             }
             ll_request::Operation::Unlink { name } => {
-                se.filesystem
-                    .unlink(self, self.request.nodeid(), &name, self.reply());
+                $fs.unlink($req, $req.request.nodeid(), &name, $req.reply())$(.$aw)?;
             }
             ll_request::Operation::RmDir { name } => {
-                se.filesystem
-                    .rmdir(self, self.request.nodeid(), &name, self.reply());
+                $fs.rmdir($req, $req.request.nodeid(), &name, $req.reply())$(.$aw)?;
             }
             ll_request::Operation::SymLink { name, link } => {
-                se.filesystem.symlink(
-                    self,
-                    self.request.nodeid(),
+                $fs.symlink(
+                    $req,
+                    $req.request.nodeid(),
                     &name,
                     &Path::new(link),
-                    self.reply(),
-                );
+                    $req.reply(),
+                )$(.$aw)?;
             }
             ll_request::Operation::Rename { arg, name, newname } => {
-                se.filesystem.rename(
-                    self,
-                    self.request.nodeid(),
+                $fs.rename(
+                    $req,
+                    $req.request.nodeid(),
                     &name,
                     arg.newdir,
                     &newname,
-                    self.reply(),
-                );
+                    $req.reply(),
+                )$(.$aw)?;
             }
             ll_request::Operation::Link { arg, name } => {
-                se.filesystem.link(
-                    self,
+                $fs.link(
+                    $req,
                     arg.oldnodeid,
-                    self.request.nodeid(),
+                    $req.request.nodeid(),
                     &name,
-                    self.reply(),
-                );
+                    $req.reply(),
+                )$(.$aw)?;
             }
             ll_request::Operation::Open { arg } => {
-                se.filesystem
-                    .open(self, self.request.nodeid(), arg.flags, self.reply());
+                $fs.open($req, $req.request.nodeid(), arg.flags, $req.reply())$(.$aw)?;
             }
             ll_request::Operation::Read { arg } => {
-                se.filesystem.read(
-                    self,
-                    self.request.nodeid(),
+                $fs.read(
+                    $req,
+                    $req.request.nodeid(),
                     arg.fh,
                     arg.offset as i64,
                     arg.size,
-                    self.reply(),
-                );
+                    $req.reply(),
+                )$(.$aw)?;
             }
             ll_request::Operation::Write { arg, data } => {
                 assert_eq!(data.len(), arg.size as usize);
-                se.filesystem.write(
-                    self,
-                    self.request.nodeid(),
+                $fs.write(
+                    $req,
+                    $req.request.nodeid(),
                     arg.fh,
                     arg.offset as i64,
                     data,
                     arg.write_flags,
-                    self.reply(),
-                );
+                    $req.reply(),
+                )$(.$aw)?;
             }
             ll_request::Operation::Flush { arg } => {
-                se.filesystem.flush(
-                    self,
-                    self.request.nodeid(),
+                $fs.flush(
+                    $req,
+                    $req.request.nodeid(),
                     arg.fh,
                     arg.lock_owner,
-                    self.reply(),
-                );
+                    $req.reply(),
+                )$(.$aw)?;
             }
             ll_request::Operation::Release { arg } => {
                 let flush = match arg.release_flags & FUSE_RELEASE_FLUSH {
                     0 => false,
                     _ => true,
                 };
-                se.filesystem.release(
-                    self,
-                    self.request.nodeid(),
+                $fs.release(
+                    $req,
+                    $req.request.nodeid(),
                     arg.fh,
                     arg.flags,
                     arg.lock_owner,
                     flush,
-                    self.reply(),
-                );
+                    $req.reply(),
+                )$(.$aw)?;
             }
             ll_request::Operation::FSync { arg } => {
                 let datasync = match arg.fsync_flags & 1 {
                     0 => false,
                     _ => true,
                 };
-                se.filesystem
-                    .fsync(self, self.request.nodeid(), arg.fh, datasync, self.reply());
+                $fs.fsync($req, $req.request.nodeid(), arg.fh, datasync, $req.reply())$(.$aw)?;
             }
             ll_request::Operation::OpenDir { arg } => {
-                se.filesystem
-                    .opendir(self, self.request.nodeid(), arg.flags, self.reply());
+                $fs.opendir($req, $req.request.nodeid(), arg.flags, $req.reply())$(.$aw)?;
             }
             ll_request::Operation::ReadDir { arg } => {
-                se.filesystem.readdir(
-                    self,
-                    self.request.nodeid(),
+                $fs.readdir(
+                    $req,
+                    $req.request.nodeid(),
                     arg.fh,
                     arg.offset as i64,
-                    ReplyDirectory::new(self.request.unique(), self.ch, arg.size as usize),
-                );
+                    ReplyDirectory::new(
+                        $req.request.unique(),
+                        $req.ch.reply_sender(),
+                        arg.size as usize,
+                    ),
+                )$(.$aw)?;
             }
             ll_request::Operation::ReleaseDir { arg } => {
-                se.filesystem.releasedir(
-                    self,
-                    self.request.nodeid(),
+                $fs.releasedir(
+                    $req,
+                    $req.request.nodeid(),
                     arg.fh,
                     arg.flags,
-                    self.reply(),
-                );
+                    $req.reply(),
+                )$(.$aw)?;
             }
             ll_request::Operation::FSyncDir { arg } => {
                 let datasync = match arg.fsync_flags & 1 {
                     0 => false,
                     _ => true,
                 };
-                se.filesystem
-                    .fsyncdir(self, self.request.nodeid(), arg.fh, datasync, self.reply());
+                $fs.fsyncdir($req, $req.request.nodeid(), arg.fh, datasync, $req.reply())$(.$aw)?;
             }
             ll_request::Operation::StatFs => {
-                se.filesystem
-                    .statfs(self, self.request.nodeid(), self.reply());
+                $fs.statfs($req, $req.request.nodeid(), $req.reply())$(.$aw)?;
             }
             ll_request::Operation::SetXAttr { arg, name, value } => {
                 assert!(value.len() == arg.size as usize);
-                se.filesystem.setxattr(
-                    self,
-                    self.request.nodeid(),
+                $fs.setxattr(
+                    $req,
+                    $req.request.nodeid(),
                     name,
                     value,
                     arg.flags,
                     get_position(arg),
-                    self.reply(),
-                );
+                    $req.reply(),
+                )$(.$aw)?;
             }
             ll_request::Operation::GetXAttr { arg, name } => {
-                se.filesystem
-                    .getxattr(self, self.request.nodeid(), name, arg.size, self.reply());
+                $fs.getxattr($req, $req.request.nodeid(), name, arg.size, $req.reply())$(.$aw)?;
             }
             ll_request::Operation::ListXAttr { arg } => {
-                se.filesystem
-                    .listxattr(self, self.request.nodeid(), arg.size, self.reply());
+                $fs.listxattr($req, $req.request.nodeid(), arg.size, $req.reply())$(.$aw)?;
             }
             ll_request::Operation::RemoveXAttr { name } => {
-                se.filesystem
-                    .removexattr(self, self.request.nodeid(), name, self.reply());
+                $fs.removexattr($req, $req.request.nodeid(), name, $req.reply())$(.$aw)?;
             }
             ll_request::Operation::Access { arg } => {
-                se.filesystem
-                    .access(self, self.request.nodeid(), arg.mask, self.reply());
+                $fs.access($req, $req.request.nodeid(), arg.mask, $req.reply())$(.$aw)?;
             }
             ll_request::Operation::Create { arg, name } => {
-                se.filesystem.create(
-                    self,
-                    self.request.nodeid(),
+                $fs.create(
+                    $req,
+                    $req.request.nodeid(),
                     &name,
                     arg.mode,
                     arg.flags,
-                    self.reply(),
-                );
+                    $req.reply(),
+                )$(.$aw)?;
             }
             ll_request::Operation::GetLk { arg } => {
-                se.filesystem.getlk(
-                    self,
-                    self.request.nodeid(),
+                $fs.getlk(
+                    $req,
+                    $req.request.nodeid(),
                     arg.fh,
                     arg.owner,
                     arg.lk.start,
                     arg.lk.end,
                     arg.lk.typ,
                     arg.lk.pid,
-                    self.reply(),
-                );
+                    $req.reply(),
+                )$(.$aw)?;
             }
             ll_request::Operation::SetLk { arg } => {
-                se.filesystem.setlk(
-                    self,
-                    self.request.nodeid(),
+                $fs.setlk(
+                    $req,
+                    $req.request.nodeid(),
                     arg.fh,
                     arg.owner,
                     arg.lk.start,
@@ -459,13 +306,13 @@ impl<'a> Request<'a> {
                     arg.lk.typ,
                     arg.lk.pid,
                     false,
-                    self.reply(),
-                );
+                    $req.reply(),
+                )$(.$aw)?;
             }
             ll_request::Operation::SetLkW { arg } => {
-                se.filesystem.setlk(
-                    self,
-                    self.request.nodeid(),
+                $fs.setlk(
+                    $req,
+                    $req.request.nodeid(),
                     arg.fh,
                     arg.owner,
                     arg.lk.start,
@@ -473,27 +320,26 @@ impl<'a> Request<'a> {
                     arg.lk.typ,
                     arg.lk.pid,
                     true,
-                    self.reply(),
-                );
+                    $req.reply(),
+                )$(.$aw)?;
             }
             ll_request::Operation::BMap { arg } => {
-                se.filesystem.bmap(
-                    self,
-                    self.request.nodeid(),
+                $fs.bmap(
+                    $req,
+                    $req.request.nodeid(),
                     arg.blocksize,
                     arg.block,
-                    self.reply(),
-                );
+                    $req.reply(),
+                )$(.$aw)?;
             }
 
             #[cfg(target_os = "macos")]
             ll_request::Operation::SetVolName { name } => {
-                se.filesystem.setvolname(self, name, self.reply());
+                $fs.setvolname($req, name, $req.reply())$(.$aw)?;
             }
             #[cfg(target_os = "macos")]
             ll_request::Operation::GetXTimes => {
-                se.filesystem
-                    .getxtimes(self, self.request.nodeid(), self.reply());
+                $fs.getxtimes($req, $req.request.nodeid(), $req.reply())$(.$aw)?;
             }
             #[cfg(target_os = "macos")]
             ll_request::Operation::Exchange {
@@ -501,23 +347,355 @@ impl<'a> Request<'a> {
                 oldname,
                 newname,
             } => {
-                se.filesystem.exchange(
-                    self,
+                $fs.exchange(
+                    $req,
                     arg.olddir,
                     &oldname,
                     arg.newdir,
                     &newname,
                     arg.options,
-                    self.reply(),
+                    $req.reply(),
+                )$(.$aw)?;
+            }
+            ll_request::Operation::Init { .. } | ll_request::Operation::Destroy => {
+                unreachable!("init and destroy are dispatched by the session")
+            }
+        }
+    };
+}
+
+#[cfg(target_os = "macos")]
+fn get_macos_setattr(
+    arg: &fuse_setattr_in,
+) -> (
+    Option<SystemTime>,
+    Option<SystemTime>,
+    Option<SystemTime>,
+    Option<u32>,
+) {
+    let crtime = match arg.valid & FATTR_CRTIME {
+        0 => None,
+        _ => Some(
+            match UNIX_EPOCH.checked_add(Duration::new(arg.crtime, arg.crtimensec)) {
+                Some(crt) => crt,
+                None => SystemTime::now(),
+            },
+        ), // _ => Some(UNIX_EPOCH + Duration::new(arg.crtime, arg.crtimensec)),
+    };
+    let chgtime = match arg.valid & FATTR_CHGTIME {
+        0 => None,
+        _ => Some(
+            match UNIX_EPOCH.checked_add(Duration::new(arg.chgtime, arg.chgtimensec)) {
+                Some(cht) => cht,
+                None => SystemTime::now(),
+            },
+        ), // _ => Some(UNIX_EPOCH + Duration::new(arg.chgtime, arg.chgtimensec)),
+    };
+    let bkuptime = match arg.valid & FATTR_BKUPTIME {
+        0 => None,
+        _ => Some(
+            match UNIX_EPOCH.checked_add(Duration::new(arg.bkuptime, arg.bkuptimensec)) {
+                Some(bkt) => bkt,
+                None => SystemTime::now(),
+            },
+        ), // _ => Some(UNIX_EPOCH + Duration::new(arg.bkuptime, arg.bkuptimensec)),
+    };
+    let flags = match arg.valid & FATTR_FLAGS {
+        0 => None,
+        _ => Some(arg.flags),
+    };
+    (crtime, chgtime, bkuptime, flags)
+}
+
+/// Build the reply to an init request with our desired version and settings. If the kernel supports a
+/// larger major version, it'll re-send a matching init message. If it supports only lower
+/// major versions, the init request was replied with an error before.
+fn init_out(arg: &fuse_init_in) -> fuse_init_out {
+    let init = fuse_init_out {
+        major: FUSE_KERNEL_VERSION,
+        minor: FUSE_KERNEL_MINOR_VERSION,
+        // max_readahead: arg.max_readahead, // accept any readahead size
+        max_readahead: if (BUFFER_SIZE as u32) < arg.max_readahead {
+            BUFFER_SIZE as u32
+        } else {
+            arg.max_readahead
+        }, // TODO: adjust BUFFER_SIZE according to max_readahead
+        flags: arg.flags & (INIT_FLAGS | SPLICE_FLAGS), // use features given in INIT_FLAGS and reported as capable
+        unused: 0,
+        max_write: MAX_WRITE_SIZE as u32, // TODO: use a max write size that fits into the session's buffer
+    };
+    debug!(
+        "INIT response: ABI {}.{}, flags {:#x}, max readahead {}, max write {}",
+        init.major, init.minor, init.flags, init.max_readahead, init.max_write
+    );
+    init
+}
+
+#[cfg(target_os = "macos")]
+fn get_position(arg: &fuse_setxattr_in) -> u32 {
+    arg.position
+}
+#[cfg(not(target_os = "macos"))]
+fn get_position(_arg: &fuse_setxattr_in) -> u32 {
+    0
+}
+#[cfg(not(target_os = "macos"))]
+fn get_macos_setattr(
+    _arg: &fuse_setattr_in,
+) -> (
+    Option<SystemTime>,
+    Option<SystemTime>,
+    Option<SystemTime>,
+    Option<u32>,
+) {
+    (None, None, None, None)
+}
+
+/// The state of a session the dispatch of a request depends on
+#[derive(Clone, Copy, Debug)]
+struct SessionState {
+    initialized: bool,
+    destroyed: bool,
+    allow_root: bool,
+    owner: u32,
+}
+
+/// What is left to do with a request after the checks of the session
+#[derive(Debug)]
+enum Dispatch<'a> {
+    /// Initialize the filesystem
+    Init(&'a fuse_init_in),
+    /// Destroy the filesystem
+    Destroy,
+    /// Call the filesystem method of the operation
+    Operation,
+    /// Nothing, the request was replied already
+    Replied,
+}
+
+/// Request data structure
+#[derive(Debug)]
+pub struct Request<'a> {
+    /// Channel sender for sending the reply
+    ch: ChannelSender,
+    /// Request raw data
+    data: &'a [u8],
+    /// Data of a write request left in a pipe, not part of the raw data
+    spliced: Option<SplicedData>,
+    /// Parsed request
+    pub request: ll_request::Request<'a>,
+}
+
+impl<'a> Request<'a> {
+    /// Create a new request from the given data, fails if the data is no valid request
+    pub fn new(ch: ChannelSender, data: &'a [u8]) -> Result<Request<'a>, RequestError> {
+        let request = ll_request::Request::try_from(data)?;
+        Ok(Self {
+            ch,
+            data,
+            spliced: None,
+            request,
+        })
+    }
+
+    /// Create a new request from the given data and the data of a write request left in a
+    /// pipe by a spliced receive, fails if the data is no valid request
+    pub fn with_spliced(
+        ch: ChannelSender,
+        data: &'a [u8],
+        spliced: SplicedData,
+    ) -> Result<Request<'a>, RequestError> {
+        let request = ll_request::Request::with_spliced(data, spliced.len)?;
+        Ok(Self {
+            ch,
+            data,
+            spliced: Some(spliced),
+            request,
+        })
+    }
+
+    /// Reply to data that failed to parse as a request with `ENOSYS` for unknown operations
+    /// and `EIO` for malformed requests. The reply needs the unique id of the header, so
+    /// nothing is replied if the header is incomplete or the kernel expects no reply.
+    pub fn reply_invalid(ch: ChannelSender, data: &[u8], err: &RequestError) {
+        match ll_request::parse_header(data) {
+            Some(header) if ll_request::expects_reply(header) => {
+                warn!(
+                    "Replying {} to invalid FUSE request {}: {}",
+                    err.errno(),
+                    header.unique,
+                    err
+                );
+                ReplyEmpty::new(header.unique, ch.reply_sender()).error(err.errno());
+            }
+            _ => error!("Ignoring invalid FUSE request: {}", err),
+        }
+    }
+
+    /// Return true if the caller is the given owner or root, or the operation works on an
+    /// already opened file
+    fn is_allowed_for(&self, owner: u32) -> bool {
+        match self.request.operation() {
+            ll_request::Operation::Forget { .. }
+            | ll_request::Operation::Read { .. }
+            | ll_request::Operation::Write { .. }
+            | ll_request::Operation::FSync { .. }
+            | ll_request::Operation::Release { .. }
+            | ll_request::Operation::ReadDir { .. }
+            | ll_request::Operation::FSyncDir { .. }
+            | ll_request::Operation::ReleaseDir { .. }
+            | ll_request::Operation::GetLk { .. }
+            | ll_request::Operation::SetLk { .. }
+            | ll_request::Operation::SetLkW { .. }
+            | ll_request::Operation::Interrupt { .. } => true,
+            _ => self.uid() == owner || self.uid() == 0,
+        }
+    }
+
+    /// Check the request against the state of the session, reply to the requests the session
+    /// must not pass to the filesystem and return what is left to do with the others
+    fn check(&self, state: SessionState) -> Dispatch<'_> {
+        match self.request.operation() {
+            // Filesystem initialization
+            ll_request::Operation::Init { arg } => {
+                debug!("Init args: {:?}", arg);
+                // We don't support ABI versions before 7.6
+                if arg.major < 7 || (arg.major == 7 && arg.minor < 6) {
+                    error!("Unsupported FUSE ABI version {}.{}", arg.major, arg.minor);
+                    self.reply::<ReplyEmpty>().error(EPROTO);
+                    return Dispatch::Replied;
+                }
+                Dispatch::Init(arg)
+            }
+            // Any operation is invalid before initialization
+            _ if !state.initialized => {
+                warn!("Ignoring FUSE operation before init: {}", self.request);
+                self.reply::<ReplyEmpty>().error(EIO);
+                Dispatch::Replied
+            }
+            // Filesystem destroyed
+            ll_request::Operation::Destroy => Dispatch::Destroy,
+            // Any operation is invalid after destroy
+            _ if state.destroyed => {
+                warn!("Ignoring FUSE operation after destroy: {}", self.request);
+                self.reply::<ReplyEmpty>().error(EIO);
+                Dispatch::Replied
+            }
+            // With allow_root, only the owner and root may access the filesystem
+            _ if state.allow_root && !self.is_allowed_for(state.owner) => {
+                warn!(
+                    "Denying FUSE operation of uid={}: {}",
+                    self.uid(),
+                    self.request
                 );
+                self.reply::<ReplyEmpty>().error(EACCES);
+                Dispatch::Replied
+            }
+            _ => Dispatch::Operation,
+        }
+    }
+
+    /// Reply to an init request with the result of the init method of the filesystem, return
+    /// true if the filesystem is initialized
+    fn reply_init(&self, arg: &fuse_init_in, res: Result<(), c_int>) -> bool {
+        let reply: ReplyRaw<fuse_init_out> = self.reply();
+        match res {
+            Ok(()) => {
+                reply.ok(&init_out(arg));
+                true
+            }
+            Err(err) => {
+                reply.error(err);
+                false
+            }
+        }
+    }
+
+    /// Dispatch request to the given filesystem.
+    /// This calls the appropriate filesystem operation method for the
+    /// request and sends back the returned reply to the kernel
+    pub fn dispatch<FS: Filesystem, T: Transport>(&self, se: &mut Session<FS, T>) {
+        debug!("{}", self.request);
+
+        let state = SessionState {
+            initialized: se.initialized,
+            destroyed: se.destroyed,
+            allow_root: se.allow_root,
+            owner: se.owner,
+        };
+        match self.check(state) {
+            Dispatch::Init(arg) => {
+                // Remember ABI version supported by kernel
+                se.proto_major = arg.major;
+                se.proto_minor = arg.minor;
+                // Call filesystem init method and give it a chance to return an error
+                let res = se.filesystem.init(self);
+                if self.reply_init(arg, res) {
+                    se.initialized = true;
+                }
+            }
+            Dispatch::Destroy => {
+                se.filesystem.destroy(self);
+                se.destroyed = true;
+                self.reply::<ReplyEmpty>().ok();
+            }
+            Dispatch::Operation => match (self.request.operation(), self.spliced) {
+                // The data of a spliced write request is read from its pipe
+                (ll_request::Operation::Write { arg, .. }, Some(spliced)) => {
+                    assert_eq!(spliced.len, arg.size as usize);
+                    se.filesystem.write_from_fd(
+                        self,
+                        self.request.nodeid(),
+                        arg.fh,
+                        arg.offset as i64,
+                        spliced.fd,
+                        arg.size,
+                        arg.write_flags,
+                        self.reply(),
+                    );
+                }
+                _ => dispatch_operation!(self, se.filesystem),
+            },
+            Dispatch::Replied => {}
+        }
+    }
+
+    /// Dispatch request to the given asynchronous filesystem, the returned future completes
+    /// once the filesystem replied or passed the reply on
+    pub async fn dispatch_async<FS: AsyncFilesystem>(&self, se: &AsyncSession<FS>) {
+        debug!("{}", self.request);
+
+        let state = SessionState {
+            initialized: se.initialized.get(),
+            destroyed: se.destroyed.get(),
+            allow_root: se.allow_root,
+            owner: se.owner,
+        };
+        match self.check(state) {
+            Dispatch::Init(arg) => {
+                // Remember ABI version supported by kernel
+                se.proto_major.set(arg.major);
+                se.proto_minor.set(arg.minor);
+                // Call filesystem init method and give it a chance to return an error
+                let res = se.filesystem.init(self).await;
+                if self.reply_init(arg, res) {
+                    se.initialized.set(true);
+                }
+            }
+            Dispatch::Destroy => {
+                se.filesystem.destroy(self).await;
+                se.destroyed.set(true);
+                self.reply::<ReplyEmpty>().ok();
             }
+            Dispatch::Operation => dispatch_operation!(self, se.filesystem, .await),
+            Dispatch::Replied => {}
         }
     }
 
     /// Create a reply object for this request that can be passed to the filesystem
     /// implementation and makes sure that a request is replied exactly once
     fn reply<T: Reply>(&self) -> T {
-        Reply::new(self.request.unique(), self.ch)
+        Reply::new(self.request.unique(), self.ch.reply_sender())
     }
 
     /// Returns the unique identifier of this request
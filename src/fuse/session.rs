@@ -7,13 +7,28 @@
 
 use std::io;
 use std::iter;
-use std::path::Path;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::slice;
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+use std::time::Duration;
 // use thread_scoped::{scoped, JoinGuard};
 use libc::{EAGAIN, EINTR, ENODEV, ENOENT};
-use log::info;
+use log::{info, warn};
+use nix::unistd;
 
-use super::channel::Channel;
+use super::abi::{fuse_in_header, fuse_opcode};
+use super::channel::{self, Channel};
+use super::ll_request::RequestError;
+use super::metrics::Metrics;
+use super::mount::{MountConfig, MountOption, UnmountMode};
+use super::observer::{Observer, ObservingSender};
+use super::protocol_log::ProtocolLogger;
 use super::request::Request;
+use super::trace::Tracer;
+use super::transport::{self, ChannelSender, SplicedData, Transport};
 use super::Filesystem;
 
 /// The max size of write requests from the kernel. The absolute minimum is 4k,
@@ -27,11 +42,15 @@ pub const BUFFER_SIZE: usize = MAX_WRITE_SIZE + 4096;
 
 /// The session data structure
 #[derive(Debug)]
-pub struct Session<FS: Filesystem> {
+pub struct Session<FS: Filesystem, T: Transport = Channel> {
     /// Filesystem operation implementations
     pub filesystem: FS,
-    /// Communication channel to the kernel driver
-    ch: Channel,
+    /// Communication channel to the kernel driver, or another transport
+    ch: T,
+    /// Sender of replies over the transport
+    sender: ChannelSender,
+    /// The number of requests not replied yet
+    in_flight: Arc<AtomicUsize>,
     /// FUSE protocol major version
     pub proto_major: u32,
     /// FUSE protocol minor version
@@ -40,25 +59,154 @@ pub struct Session<FS: Filesystem> {
     pub initialized: bool,
     /// True if the filesystem was destroyed (destroy operation done)
     pub destroyed: bool,
+    /// True if only the owner of the session and root may access the filesystem (allow_root)
+    pub allow_root: bool,
+    /// The user who mounted the filesystem
+    pub owner: u32,
+    /// Number of requests with an operation unknown to the ABI in use, replied with `ENOSYS`
+    pub unknown_requests: u64,
+    /// Number of requests too short or with corrupt arguments, replied with `EIO`
+    pub malformed_requests: u64,
+    /// Observers of the requests and replies
+    observers: Vec<Arc<dyn Observer>>,
+    /// Metrics of the requests and replies, once collected
+    metrics: Option<Arc<Metrics>>,
+}
+
+/// A handle to unmount the filesystem of a session from another thread, e.g. on a signal,
+/// after the requests in flight are replied
+#[derive(Clone, Debug)]
+pub struct Unmounter {
+    /// Path of the mounted filesystem
+    mountpoint: PathBuf,
+    /// The number of requests not replied yet, shared with the session
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl Unmounter {
+    pub(crate) fn new(mountpoint: &Path, in_flight: &Arc<AtomicUsize>) -> Unmounter {
+        Unmounter {
+            mountpoint: mountpoint.to_owned(),
+            in_flight: Arc::clone(in_flight),
+        }
+    }
+
+    /// Return path of the mounted filesystem
+    pub fn mountpoint(&self) -> &Path {
+        &self.mountpoint
+    }
+
+    /// Unmount the filesystem, if `drain_timeout` is given, wait up to the timeout for the
+    /// requests not replied yet before unmounting. A busy mount point fails with `EBUSY` in
+    /// `UnmountMode::Normal`.
+    pub fn unmount(&self, mode: UnmountMode, drain_timeout: Option<Duration>) -> io::Result<()> {
+        if let Some(timeout) = drain_timeout {
+            transport::drain(&self.in_flight, timeout);
+        }
+        channel::unmount_with_mode(&self.mountpoint, mode)
+    }
 }
 
 impl<FS: Filesystem> Session<FS> {
-    /// Create a new session by mounting the given filesystem to the given mountpoint
+    /// Create a new session by mounting the given filesystem to the given mountpoint,
+    /// fails with `InvalidInput` if the options are invalid or conflict with each other
     pub fn new(filesystem: FS, mountpoint: &Path, options: &[&str]) -> io::Result<Session<FS>> {
+        let config = MountConfig::parse(options)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Session::with_config(filesystem, mountpoint, config)
+    }
+
+    /// Create a new session by mounting the given filesystem to the given mountpoint with
+    /// typed options, fails with `InvalidInput` if the options conflict with each other
+    pub fn with_options(
+        filesystem: FS,
+        mountpoint: &Path,
+        options: &[MountOption],
+    ) -> io::Result<Session<FS>> {
+        let config = MountConfig::from_options(options)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Session::with_config(filesystem, mountpoint, config)
+    }
+
+    fn with_config(
+        filesystem: FS,
+        mountpoint: &Path,
+        config: MountConfig,
+    ) -> io::Result<Session<FS>> {
         info!("mounting {:?}", mountpoint);
-        Channel::new(mountpoint, options).map(|ch| Session {
+        let ch = Channel::new(mountpoint, &config)?;
+        let mut se = Session::with_transport(filesystem, ch);
+        se.allow_root = config.allow_root;
+        if config.debug {
+            se.observe(Arc::new(ProtocolLogger::stderr()));
+        }
+        Ok(se)
+    }
+}
+
+impl<FS: Filesystem, T: Transport> Session<FS, T> {
+    /// Create a new session serving the given filesystem over the given transport
+    pub fn with_transport(filesystem: FS, transport: T) -> Session<FS, T> {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        Session {
             filesystem,
-            ch,
+            sender: ChannelSender::new(transport.sender(), Arc::clone(&in_flight)),
+            ch: transport,
+            in_flight,
             proto_major: 0,
             proto_minor: 0,
             initialized: false,
             destroyed: false,
-        })
+            allow_root: false,
+            owner: unistd::geteuid().as_raw(),
+            unknown_requests: 0,
+            malformed_requests: 0,
+            observers: Vec::new(),
+            metrics: None,
+        }
     }
 
     /// Return path of the mounted filesystem
     pub fn mountpoint(&self) -> &Path {
-        self.ch.mountpoint().as_ref()
+        self.ch.mountpoint()
+    }
+
+    /// Unmount the filesystem, a busy mount point fails with `EBUSY` in `UnmountMode::Normal`
+    pub fn unmount(&mut self, mode: UnmountMode) -> io::Result<()> {
+        self.ch.unmount(mode)
+    }
+
+    /// Return a handle to unmount the filesystem from another thread while the session runs
+    pub fn unmounter(&self) -> Unmounter {
+        Unmounter::new(self.ch.mountpoint(), &self.in_flight)
+    }
+
+    /// Pass every request and reply of the session to the observer from now on, replies
+    /// from a file and the data of write requests are copied instead of spliced while an
+    /// observer needs their bytes
+    pub fn observe(&mut self, observer: Arc<dyn Observer>) {
+        self.observers.push(observer);
+        let sender = ObservingSender::new(self.ch.sender(), self.observers.clone());
+        self.sender = ChannelSender::new(sender, Arc::clone(&self.in_flight));
+    }
+
+    /// Record every request and reply of the session to a trace file from now on, the trace
+    /// starts with the description of the filesystem, e.g. its options, to replay it with
+    pub fn trace(&mut self, path: &Path, description: &str) -> io::Result<()> {
+        self.observe(Arc::new(Tracer::create(path, description)?));
+        Ok(())
+    }
+
+    /// Collect metrics of every request and reply of the session from now on, the metrics
+    /// are created by the first call and returned again by later calls
+    pub fn metrics(&mut self) -> Arc<Metrics> {
+        if let Some(ref metrics) = self.metrics {
+            return Arc::clone(metrics);
+        }
+        let metrics = Arc::new(Metrics::new());
+        self.observe(Arc::clone(&metrics) as Arc<dyn Observer>);
+        self.metrics = Some(Arc::clone(&metrics));
+        metrics
     }
 
     /// Run the session loop that receives kernel requests and dispatches them to method
@@ -70,17 +218,20 @@ impl<FS: Filesystem> Session<FS> {
         // it is reused immediately after dispatching to conserve memory and allocations.
         // let mut buffer: Vec<u8> = Vec::with_capacity(BUFFER_SIZE);
         let mut buffer: Vec<u8> = iter::repeat(0_u8).take(BUFFER_SIZE).collect();
+        // the data of write requests is left in a pipe unless an observer needs it
+        let splice = !self.observers.iter().any(|o| o.needs_file_data());
+        self.ch.start();
 
         loop {
             // Read the next request from the given channel to kernel driver
             // The kernel driver makes sure that we get exactly one request per read
-            match self.ch.receive(&mut buffer) {
-                Ok(()) => match Request::new(self.ch.sender(), &buffer) {
-                    // Dispatch request
-                    Some(req) => req.dispatch(self),
-                    // Quit loop on illegal request
-                    None => break,
-                },
+            let received = if splice {
+                self.ch.receive_spliced(&mut buffer)
+            } else {
+                self.ch.receive(&mut buffer).map(|()| None)
+            };
+            match received {
+                Ok(spliced) => self.process_spliced(&buffer, spliced),
                 Err(err) => match err.raw_os_error() {
                     // Operation interrupted. Accordingly to FUSE, this is safe to retry
                     Some(ENOENT) => continue,
@@ -89,18 +240,99 @@ impl<FS: Filesystem> Session<FS> {
                     // Explicitly try again
                     Some(EAGAIN) => continue,
                     // Filesystem was unmounted, quit the loop
-                    Some(ENODEV) => break,
+                    Some(ENODEV) => {
+                        self.ch.set_unmounted();
+                        break;
+                    }
                     // Unhandled error
                     _ => return Err(err),
                 },
             }
         }
+        self.destroy();
         Ok(())
     }
+
+    /// Dispatch a single request, an invalid request is replied with an error
+    pub(super) fn process(&mut self, data: &[u8]) {
+        self.process_spliced(data, None);
+    }
+
+    /// Dispatch a single request, whose data may have been left in a pipe, an invalid request
+    /// is replied with an error
+    pub(super) fn process_spliced(&mut self, data: &[u8], spliced: Option<SplicedData>) {
+        for observer in &self.observers {
+            match spliced {
+                Some(spliced) => observer.request_from_fd(data, spliced.len),
+                None => observer.request(data),
+            }
+        }
+        let request = match spliced {
+            Some(spliced) => Request::with_spliced(self.sender.clone(), data, spliced),
+            None => Request::new(self.sender.clone(), data),
+        };
+        match request {
+            // Dispatch request
+            Ok(req) => req.dispatch(self),
+            // Reply an error to an invalid request and keep serving
+            Err(err) => {
+                match err {
+                    RequestError::UnknownOperation(_) => self.unknown_requests += 1,
+                    _ => self.malformed_requests += 1,
+                }
+                Request::reply_invalid(self.sender.clone(), data, &err);
+            }
+        }
+    }
+
+    /// Call destroy of the filesystem if the kernel did not send a destroy request before
+    /// the filesystem was unmounted, which it only does for fuseblk mounts
+    pub(super) fn destroy(&mut self) {
+        if !self.initialized || self.destroyed {
+            return;
+        }
+        let data = destroy_request(self.owner);
+        if let Ok(req) = Request::new(self.sender.clone(), &data) {
+            info!("destroying filesystem after unmount");
+            self.filesystem.destroy(&req);
+        }
+        self.destroyed = true;
+    }
 }
 
-impl<FS: Filesystem> Drop for Session<FS> {
+/// Build a destroy request on behalf of the given owner, for the unmounts after which the
+/// kernel sends none
+pub(super) fn destroy_request(owner: u32) -> Vec<u8> {
+    let header = fuse_in_header {
+        len: mem::size_of::<fuse_in_header>() as u32,
+        opcode: fuse_opcode::FUSE_DESTROY as u32,
+        unique: 0,
+        nodeid: 0,
+        uid: owner,
+        gid: unistd::getegid().as_raw(),
+        pid: process::id(),
+        padding: 0,
+    };
+    #[allow(unsafe_code)]
+    let data = unsafe {
+        slice::from_raw_parts(
+            &header as *const fuse_in_header as *const u8,
+            mem::size_of::<fuse_in_header>(),
+        )
+    };
+    data.to_vec()
+}
+
+impl<FS: Filesystem, T: Transport> Drop for Session<FS, T> {
     fn drop(&mut self) {
+        if self.unknown_requests > 0 || self.malformed_requests > 0 {
+            warn!(
+                "received {} unknown and {} malformed requests while mounted at {}",
+                self.unknown_requests,
+                self.malformed_requests,
+                self.mountpoint().display()
+            );
+        }
         info!("umounted {}", self.mountpoint().display());
     }
 }
@@ -155,3 +387,159 @@ impl<'a> fmt::Debug for BackgroundSession<'a> {
     }
 }
 */
+
+#[cfg(test)]
+mod test {
+    use super::Session;
+    use crate::fuse::abi::{fuse_in_header, fuse_opcode, fuse_out_header};
+    use crate::fuse::mount::UnmountMode;
+    use crate::fuse::{Filesystem, Transport, TransportSender};
+    use libc::{ENODEV, ENOSYS};
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::io;
+    use std::mem;
+    use std::path::Path;
+    use std::sync::atomic::Ordering;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// A transport replaying the given requests and recording the replies
+    #[derive(Debug)]
+    struct Loopback {
+        requests: RefCell<VecDeque<Vec<u8>>>,
+        replies: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    #[derive(Debug)]
+    struct LoopbackSender {
+        replies: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl Transport for Loopback {
+        type Sender = LoopbackSender;
+
+        fn mountpoint(&self) -> &Path {
+            Path::new("/loopback")
+        }
+
+        fn receive(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
+            match self.requests.borrow_mut().pop_front() {
+                Some(request) => {
+                    buffer.clear();
+                    buffer.extend_from_slice(&request);
+                    Ok(())
+                }
+                None => Err(io::Error::from_raw_os_error(ENODEV)),
+            }
+        }
+
+        fn sender(&self) -> LoopbackSender {
+            LoopbackSender {
+                replies: Arc::clone(&self.replies),
+            }
+        }
+
+        fn unmount(&mut self, _mode: UnmountMode) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_unmounted(&mut self) {}
+    }
+
+    impl TransportSender for LoopbackSender {
+        fn send(&self, data: &[&[u8]]) -> io::Result<()> {
+            self.replies.lock().unwrap().push(data.concat());
+            Ok(())
+        }
+    }
+
+    struct NullFS;
+
+    impl Filesystem for NullFS {}
+
+    fn request(opcode: fuse_opcode, unique: u64, arg: &[u8]) -> Vec<u8> {
+        let header = fuse_in_header {
+            len: (mem::size_of::<fuse_in_header>() + arg.len()) as u32,
+            opcode: opcode as u32,
+            unique,
+            nodeid: 1,
+            uid: 0,
+            gid: 0,
+            pid: 0,
+            padding: 0,
+        };
+        #[allow(unsafe_code)]
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                &header as *const fuse_in_header as *const u8,
+                mem::size_of::<fuse_in_header>(),
+            )
+        };
+        [bytes, arg].concat()
+    }
+
+    #[allow(unsafe_code)]
+    fn reply_header(reply: &[u8]) -> fuse_out_header {
+        assert!(reply.len() >= mem::size_of::<fuse_out_header>());
+        unsafe { std::ptr::read_unaligned(reply.as_ptr() as *const fuse_out_header) }
+    }
+
+    #[test]
+    fn run_over_transport() {
+        // major 7, minor 8, max_readahead 4096, no flags
+        let init_arg: Vec<u8> = [7_u32, 8, 4096, 0]
+            .iter()
+            .flat_map(|v| v.to_ne_bytes().to_vec())
+            .collect();
+        let replies = Arc::new(Mutex::new(Vec::new()));
+        let transport = Loopback {
+            requests: RefCell::new(
+                vec![
+                    request(fuse_opcode::FUSE_INIT, 1, &init_arg),
+                    request(fuse_opcode::FUSE_GETATTR, 2, &[]),
+                ]
+                .into(),
+            ),
+            replies: Arc::clone(&replies),
+        };
+        let mut se = Session::with_transport(NullFS, transport);
+        se.run().unwrap();
+        assert!(se.initialized);
+        assert!(se.destroyed);
+
+        let replies = replies.lock().unwrap();
+        assert_eq!(replies.len(), 2);
+        let init = reply_header(&replies[0]);
+        assert_eq!((init.unique, init.error), (1, 0));
+        let getattr = reply_header(&replies[1]);
+        assert_eq!((getattr.unique, getattr.error), (2, -ENOSYS));
+    }
+
+    #[test]
+    fn unmounter_drains_in_flight() {
+        let transport = Loopback {
+            requests: RefCell::new(VecDeque::new()),
+            replies: Arc::new(Mutex::new(Vec::new())),
+        };
+        let se = Session::with_transport(NullFS, transport);
+        let unmounter = se.unmounter();
+        assert_eq!(unmounter.mountpoint(), Path::new("/loopback"));
+
+        // a request replied by another thread while the unmounter waits
+        se.in_flight.fetch_add(1, Ordering::SeqCst);
+        let in_flight = Arc::clone(&se.in_flight);
+        let replier = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+        });
+        let start = Instant::now();
+        // the loopback mount point does not exist, so only the drain succeeds
+        let result = unmounter.unmount(UnmountMode::Lazy, Some(Duration::from_secs(10)));
+        assert!(result.is_err());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(se.in_flight.load(Ordering::SeqCst), 0);
+        replier.join().unwrap();
+    }
+}
@@ -0,0 +1,351 @@
+//! Zero-copy replies and requests
+//!
+//! A reply with data of a file is assembled in a pipe, the header is written to the pipe and
+//! the data spliced from the file behind it, then the whole reply is spliced to the FUSE
+//! device in a single call. The pages of the file are passed by reference, not copied to
+//! user space. Each thread keeps a pipe for its replies.
+//!
+//! The other way round, a request is spliced from the FUSE device into a pipe of the
+//! channel. Its headers are read into memory, the data of a write request stays in the pipe
+//! for the filesystem to splice it on, e.g. into a file.
+
+use log::warn;
+use nix::errno::Errno;
+use nix::fcntl::{self, FcntlArg, OFlag, SpliceFFlags};
+use nix::unistd;
+use std::cell::RefCell;
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+
+use super::abi::{fuse_in_header, fuse_opcode, fuse_write_in};
+use super::ll_request;
+use super::transport::SplicedData;
+
+/// Failure of a spliced reply or request
+#[derive(Debug)]
+pub enum SpliceError {
+    /// The pipe could not be set up, nothing was sent or received, the message may be copied
+    /// instead
+    Pipe(io::Error),
+    /// The reply failed to be sent to the FUSE device, or the request to be received from it
+    Device(io::Error),
+}
+
+fn nix_to_io_error(err: nix::Error) -> io::Error {
+    match err.as_errno() {
+        Some(errno) => io::Error::from_raw_os_error(errno as i32),
+        None => io::Error::other(err.to_string()),
+    }
+}
+
+/// A pipe to assemble replies in
+#[derive(Debug)]
+struct Pipe {
+    read: RawFd,
+    write: RawFd,
+    /// Capacity of the pipe in bytes
+    size: usize,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Pipe> {
+        let (read, write) =
+            unistd::pipe2(OFlag::O_NONBLOCK | OFlag::O_CLOEXEC).map_err(nix_to_io_error)?;
+        Ok(Pipe {
+            read,
+            write,
+            // the default capacity of a pipe, until it is resized
+            size: 16 * 4096,
+        })
+    }
+
+    /// Grow the pipe to hold at least `size` bytes, which fails for unprivileged users
+    /// beyond `/proc/sys/fs/pipe-max-size`
+    fn reserve(&mut self, size: usize) -> io::Result<()> {
+        if size > self.size {
+            let size = fcntl::fcntl(self.write, FcntlArg::F_SETPIPE_SZ(size as i32))
+                .map_err(nix_to_io_error)?;
+            self.size = size as usize;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        let _ = unistd::close(self.read);
+        let _ = unistd::close(self.write);
+    }
+}
+
+thread_local! {
+    /// The pipe of the thread, dropped when a reply left data in it
+    static PIPE: RefCell<Option<Pipe>> = const { RefCell::new(None) };
+}
+
+/// Send the header followed by `len` bytes of the file `fd` at `offset` to the FUSE device
+/// `dev`, the header must hold the total length
+pub fn send_from_fd(
+    dev: RawFd,
+    header: &[u8],
+    fd: RawFd,
+    offset: i64,
+    len: usize,
+) -> Result<(), SpliceError> {
+    PIPE.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let mut pipe = match cell.take() {
+            Some(pipe) => pipe,
+            None => Pipe::new().map_err(SpliceError::Pipe)?,
+        };
+        let res = splice_reply(&mut pipe, dev, header, fd, offset, len);
+        // the pipe is empty after a reply was sent, otherwise its content is undefined
+        if res.is_ok() {
+            *cell = Some(pipe);
+        }
+        res
+    })
+}
+
+fn splice_reply(
+    pipe: &mut Pipe,
+    dev: RawFd,
+    header: &[u8],
+    fd: RawFd,
+    offset: i64,
+    len: usize,
+) -> Result<(), SpliceError> {
+    let total = header.len() + len;
+    pipe.reserve(total).map_err(SpliceError::Pipe)?;
+
+    // the pipe is empty and large enough, so the header is written at once
+    let written =
+        unistd::write(pipe.write, header).map_err(|e| SpliceError::Pipe(nix_to_io_error(e)))?;
+    if written != header.len() {
+        return Err(SpliceError::Pipe(io::Error::from(io::ErrorKind::WriteZero)));
+    }
+    let mut file_offset = offset;
+    let mut spliced = 0;
+    while spliced < len {
+        match fcntl::splice(
+            fd,
+            Some(&mut file_offset),
+            pipe.write,
+            None,
+            len - spliced,
+            SpliceFFlags::SPLICE_F_MOVE,
+        ) {
+            // the file was truncated after the length of the reply was decided
+            Ok(0) => {
+                return Err(SpliceError::Pipe(io::Error::from(
+                    io::ErrorKind::UnexpectedEof,
+                )))
+            }
+            Ok(n) => spliced += n,
+            Err(nix::Error::Sys(Errno::EINTR)) => {}
+            Err(e) => return Err(SpliceError::Pipe(nix_to_io_error(e))),
+        }
+    }
+
+    // the kernel takes a reply in a single write, it fails as a whole
+    match fcntl::splice(
+        pipe.read,
+        None,
+        dev,
+        None,
+        total,
+        SpliceFFlags::SPLICE_F_MOVE,
+    ) {
+        Ok(n) if n == total => Ok(()),
+        Ok(n) => Err(SpliceError::Device(io::Error::other(format!(
+            "spliced {} of {} bytes",
+            n, total
+        )))),
+        Err(e) => Err(SpliceError::Device(nix_to_io_error(e))),
+    }
+}
+
+/// Writes with less data are copied into memory, which takes fewer calls than splicing
+const MIN_SPLICED_WRITE: usize = 4096;
+
+/// A pipe to receive requests in
+#[derive(Debug, Default)]
+pub struct RequestPipe {
+    pipe: Option<Pipe>,
+    /// True if the data of the last request was left in the pipe
+    spliced: bool,
+}
+
+impl RequestPipe {
+    /// Receive a request from the FUSE device `dev` up to the capacity of the buffer, the data
+    /// of a write request is left in the pipe unless it is small, see
+    /// `Transport::receive_spliced`
+    pub fn receive(
+        &mut self,
+        dev: RawFd,
+        buffer: &mut Vec<u8>,
+    ) -> Result<Option<SplicedData>, SpliceError> {
+        // data the filesystem left unread in the pipe is dropped with it
+        if mem::take(&mut self.spliced) {
+            if let Some(ref pipe) = self.pipe {
+                if pipe.has_data().map_err(SpliceError::Pipe)? {
+                    warn!("dropping the data of a write request left unread in its pipe");
+                    self.pipe = None;
+                }
+            }
+        }
+        let pipe = match self.pipe {
+            Some(ref mut pipe) => pipe,
+            None => self.pipe.insert(Pipe::new().map_err(SpliceError::Pipe)?),
+        };
+        // the kernel splices no more than fits into the pipe, but fails to splice less than
+        // the largest request, so the pipe must hold the whole buffer
+        pipe.reserve(buffer.capacity()).map_err(SpliceError::Pipe)?;
+        let len = fcntl::splice(
+            dev,
+            None,
+            pipe.write,
+            None,
+            buffer.capacity(),
+            SpliceFFlags::empty(),
+        )
+        .map_err(|e| SpliceError::Device(nix_to_io_error(e)))?;
+
+        let headers_len = mem::size_of::<fuse_in_header>() + mem::size_of::<fuse_write_in>();
+        buffer.resize(len.min(headers_len), 0);
+        read_pipe(pipe.read, buffer).map_err(SpliceError::Device)?;
+        let is_write = ll_request::parse_header(buffer)
+            .is_some_and(|header| header.opcode == fuse_opcode::FUSE_WRITE as u32);
+        if is_write && len >= headers_len + MIN_SPLICED_WRITE {
+            self.spliced = true;
+            return Ok(Some(SplicedData {
+                fd: pipe.read,
+                len: len - headers_len,
+            }));
+        }
+        let read = buffer.len();
+        buffer.resize(len, 0);
+        read_pipe(pipe.read, &mut buffer[read..]).map_err(SpliceError::Device)?;
+        Ok(None)
+    }
+}
+
+impl Pipe {
+    /// Return whether the pipe holds data
+    fn has_data(&self) -> io::Result<bool> {
+        let mut byte = [0_u8];
+        match unistd::read(self.read, &mut byte) {
+            Ok(n) => Ok(n > 0),
+            Err(nix::Error::Sys(Errno::EAGAIN)) => Ok(false),
+            Err(e) => Err(nix_to_io_error(e)),
+        }
+    }
+}
+
+/// Fill the buffer with the data at the front of the pipe `fd`
+fn read_pipe(fd: RawFd, buffer: &mut [u8]) -> io::Result<()> {
+    let mut done = 0;
+    while done < buffer.len() {
+        match unistd::read(fd, &mut buffer[done..]) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            Ok(n) => done += n,
+            Err(nix::Error::Sys(Errno::EINTR)) => {}
+            Err(e) => return Err(nix_to_io_error(e)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{send_from_fd, RequestPipe, SpliceError};
+    use crate::fuse::abi::{as_bytes, fuse_in_header, fuse_opcode, fuse_write_in};
+    use crate::fuse::ll_request;
+    use crate::fuse::transport::read_spliced;
+    use nix::unistd;
+    use std::fs::File;
+    use std::io::Write;
+    use std::mem;
+    use std::os::unix::io::{AsRawFd, RawFd};
+
+    #[test]
+    fn splice_to_pipe() {
+        let path = std::env::temp_dir().join(format!("fuse_ll_splice_{}", std::process::id()));
+        File::create(&path)
+            .unwrap()
+            .write_all(b"0123456789")
+            .unwrap();
+        let file = File::open(&path).unwrap();
+        // a pipe stands in for the FUSE device
+        let (read, write) = unistd::pipe().unwrap();
+
+        send_from_fd(write, b"head", file.as_raw_fd(), 2, 5).unwrap();
+        let mut buf = [0_u8; 16];
+        let n = unistd::read(read, &mut buf).unwrap();
+        assert_eq!(&buf[..n], b"head23456");
+
+        // reading beyond the end of the file sends nothing
+        match send_from_fd(write, b"head", file.as_raw_fd(), 8, 5) {
+            Err(SpliceError::Pipe(_)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        // the next reply gets a fresh pipe
+        send_from_fd(write, b"next", file.as_raw_fd(), 0, 1).unwrap();
+        let n = unistd::read(read, &mut buf).unwrap();
+        assert_eq!(&buf[..n], b"next0");
+
+        let _ = unistd::close(read);
+        let _ = unistd::close(write);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    /// Write a request to the pipe `dev` standing in for the FUSE device
+    fn send_request(dev: RawFd, opcode: fuse_opcode, arg: &[u8], data: &[u8]) {
+        let header = fuse_in_header {
+            len: (mem::size_of::<fuse_in_header>() + arg.len() + data.len()) as u32,
+            opcode: opcode as u32,
+            unique: 1,
+            nodeid: 1,
+            uid: 0,
+            gid: 0,
+            pid: 0,
+            padding: 0,
+        };
+        let request = [as_bytes(&header), arg, data].concat();
+        assert_eq!(unistd::write(dev, &request).unwrap(), request.len());
+    }
+
+    #[test]
+    fn receive_from_pipe() {
+        let (dev_read, dev_write) = unistd::pipe().unwrap();
+        let mut pipe = RequestPipe::default();
+        let mut buffer = Vec::with_capacity(16 * 4096);
+        let arg = vec![0_u8; mem::size_of::<fuse_write_in>()];
+        let headers_len = mem::size_of::<fuse_in_header>() + arg.len();
+        let data = vec![7_u8; 8192];
+
+        // the data of a write request stays in the pipe
+        send_request(dev_write, fuse_opcode::FUSE_WRITE, &arg, &data);
+        let spliced = pipe.receive(dev_read, &mut buffer).unwrap().unwrap();
+        assert_eq!((buffer.len(), spliced.len), (headers_len, data.len()));
+        assert_eq!(read_spliced(spliced.fd, spliced.len).unwrap(), data);
+
+        // a small write is copied
+        send_request(dev_write, fuse_opcode::FUSE_WRITE, &arg, b"small");
+        assert!(pipe.receive(dev_read, &mut buffer).unwrap().is_none());
+        assert_eq!(&buffer[headers_len..], b"small");
+
+        // data left unread is dropped with its pipe
+        send_request(dev_write, fuse_opcode::FUSE_WRITE, &arg, &data);
+        assert!(pipe.receive(dev_read, &mut buffer).unwrap().is_some());
+        send_request(dev_write, fuse_opcode::FUSE_GETATTR, &[], &[]);
+        assert!(pipe.receive(dev_read, &mut buffer).unwrap().is_none());
+        let header = ll_request::parse_header(&buffer).unwrap();
+        assert_eq!(header.opcode, fuse_opcode::FUSE_GETATTR as u32);
+        assert_eq!(buffer.len(), mem::size_of::<fuse_in_header>());
+
+        let _ = unistd::close(dev_read);
+        let _ = unistd::close(dev_write);
+    }
+}
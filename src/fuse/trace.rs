@@ -0,0 +1,707 @@
+//! Recording and replay of the messages of a session
+//!
+//! A trace is a binary log of every raw request and reply of a session in the order they
+//! happened. It starts with the magic bytes `FUSETRC2` and a description of the traced
+//! filesystem, e.g. its options, as a 32 bit little endian length followed by UTF-8 text.
+//! Then follow records of a kind byte (0 for a request, 1 for a reply), the nanoseconds since
+//! the trace started as a 64 bit and the length of the message as a 32 bit little endian
+//! integer, then the message itself.
+//!
+//! Replaying a trace sends the recorded requests to a filesystem in the recorded order and
+//! compares its replies with the recorded ones, the replies are matched by the unique id of
+//! their requests. Node ids and file handles are chosen by the filesystem, so the ones of the
+//! recorded replies are mapped to the replayed ones in later requests, and the replayed ones
+//! in replies back to the recorded ones. The timestamps of attributes are not compared.
+
+use log::error;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::iter;
+use std::mem;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::abi::fuse_out_header;
+use super::abi::{from_bytes, fuse_dirent, fuse_entry_out, fuse_in_header, fuse_opcode};
+use super::ll_request;
+use super::mock::MockTransport;
+use super::observer::Observer;
+use super::session::Session;
+use super::Filesystem;
+
+/// The magic bytes at the start of a trace
+const MAGIC: &[u8; 8] = b"FUSETRC2";
+
+/// The direction of a traced message
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TraceKind {
+    /// A request from the kernel
+    Request,
+    /// A reply to the kernel
+    Reply,
+}
+
+/// A message of a trace
+#[derive(Clone, Debug)]
+pub struct TraceRecord {
+    /// The direction of the message
+    pub kind: TraceKind,
+    /// Time since the trace started
+    pub time: Duration,
+    /// The raw message, starting with its header
+    pub data: Vec<u8>,
+}
+
+/// A writer of a trace, observing a session, the buffered records are written when it is
+/// dropped
+#[derive(Debug)]
+pub struct Tracer {
+    start: Instant,
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl Tracer {
+    /// Create a trace file starting with the description of the traced filesystem, an
+    /// existing file is truncated
+    pub fn create(path: &Path, description: &str) -> io::Result<Tracer> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&(description.len() as u32).to_le_bytes())?;
+        writer.write_all(description.as_bytes())?;
+        Ok(Tracer {
+            start: Instant::now(),
+            writer: Mutex::new(writer),
+        })
+    }
+
+    /// Append a message made of the given slices to the trace
+    pub fn record(&self, kind: TraceKind, data: &[&[u8]]) -> io::Result<()> {
+        let kind: u8 = match kind {
+            TraceKind::Request => 0,
+            TraceKind::Reply => 1,
+        };
+        let time = self.start.elapsed().as_nanos() as u64;
+        let len = data.iter().map(|d| d.len()).sum::<usize>() as u32;
+        let mut writer = self.writer.lock().unwrap();
+        writer.write_all(&[kind])?;
+        writer.write_all(&time.to_le_bytes())?;
+        writer.write_all(&len.to_le_bytes())?;
+        for d in data {
+            writer.write_all(d)?;
+        }
+        Ok(())
+    }
+}
+
+impl Observer for Tracer {
+    fn request(&self, data: &[u8]) {
+        if let Err(err) = self.record(TraceKind::Request, &[data]) {
+            error!("Failed to record FUSE request: {}", err);
+        }
+    }
+
+    fn reply(&self, data: &[&[u8]]) {
+        if let Err(err) = self.record(TraceKind::Reply, data) {
+            error!("Failed to record FUSE reply: {}", err);
+        }
+    }
+}
+
+/// A reader of the records of a trace
+#[derive(Debug)]
+pub struct TraceReader {
+    reader: BufReader<File>,
+    description: String,
+}
+
+impl TraceReader {
+    /// Open a trace file, fails with `InvalidData` if it is no trace
+    pub fn open(path: &Path) -> io::Result<TraceReader> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0_u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?} is no FUSE trace", path),
+            ));
+        }
+        let mut len = [0_u8; 4];
+        reader.read_exact(&mut len)?;
+        let mut description = vec![0_u8; u32::from_le_bytes(len) as usize];
+        reader.read_exact(&mut description)?;
+        let description = String::from_utf8(description).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid description of trace {:?}", path),
+            )
+        })?;
+        Ok(TraceReader {
+            reader,
+            description,
+        })
+    }
+
+    /// Return the description of the traced filesystem
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<TraceRecord>> {
+        let mut head = [0_u8; 13];
+        // a trace may end after any complete record
+        match self.reader.read_exact(&mut head[..1]) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        self.reader.read_exact(&mut head[1..])?;
+        let kind = match head[0] {
+            0 => TraceKind::Request,
+            1 => TraceKind::Reply,
+            kind => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid kind {} of trace record", kind),
+                ))
+            }
+        };
+        let mut time = [0_u8; 8];
+        time.copy_from_slice(&head[1..9]);
+        let mut len = [0_u8; 4];
+        len.copy_from_slice(&head[9..13]);
+        let mut data = vec![0_u8; u32::from_le_bytes(len) as usize];
+        self.reader.read_exact(&mut data)?;
+        Ok(Some(TraceRecord {
+            kind,
+            time: Duration::from_nanos(u64::from_le_bytes(time)),
+            data,
+        }))
+    }
+}
+
+impl Iterator for TraceReader {
+    type Item = io::Result<TraceRecord>;
+
+    fn next(&mut self) -> Option<io::Result<TraceRecord>> {
+        self.read_record().transpose()
+    }
+}
+
+/// Return the unique id of a request or reply, both headers start with the length followed
+/// by 4 bytes before the unique id
+fn unique_of(data: &[u8]) -> Option<u64> {
+    read_u64(data, 8)
+}
+
+/// Return the error of a reply
+fn error_of(reply: &[u8]) -> i32 {
+    from_bytes::<fuse_out_header>(reply).map_or(0, |header| -header.error)
+}
+
+/// Return the offset of the file handle in the argument of a request, if any
+fn request_fh_offset(opcode: u32) -> Option<usize> {
+    match fuse_opcode::try_from(opcode).ok()? {
+        // fuse_setattr_in starts with valid and padding
+        fuse_opcode::FUSE_SETATTR => Some(8),
+        fuse_opcode::FUSE_READ
+        | fuse_opcode::FUSE_WRITE
+        | fuse_opcode::FUSE_RELEASE
+        | fuse_opcode::FUSE_FSYNC
+        | fuse_opcode::FUSE_FLUSH
+        | fuse_opcode::FUSE_READDIR
+        | fuse_opcode::FUSE_RELEASEDIR
+        | fuse_opcode::FUSE_FSYNCDIR
+        | fuse_opcode::FUSE_GETLK
+        | fuse_opcode::FUSE_SETLK
+        | fuse_opcode::FUSE_SETLKW => Some(0),
+        _ => None,
+    }
+}
+
+/// The offset of the node id in the header of a request
+const NODEID_OFFSET: usize = 16;
+
+/// Return the offset of a node id in the argument of a request besides the header, if any
+fn request_node_offset(opcode: u32) -> Option<usize> {
+    match fuse_opcode::try_from(opcode).ok()? {
+        // newdir of fuse_rename_in and oldnodeid of fuse_link_in
+        fuse_opcode::FUSE_RENAME | fuse_opcode::FUSE_LINK => Some(0),
+        _ => None,
+    }
+}
+
+/// Return the offsets of the new node id and of the attribute in the data of a reply, if any
+fn reply_node_offsets(opcode: u32) -> Option<(Option<usize>, usize)> {
+    // fuse_entry_out starts with 4 u64 and 2 u32 before the attribute
+    const ENTRY_INO_OFFSET: usize = 40;
+    // fuse_attr_out starts with a u64 and 2 u32 before the attribute
+    const ATTR_INO_OFFSET: usize = 16;
+    match fuse_opcode::try_from(opcode).ok()? {
+        fuse_opcode::FUSE_LOOKUP
+        | fuse_opcode::FUSE_MKNOD
+        | fuse_opcode::FUSE_MKDIR
+        | fuse_opcode::FUSE_SYMLINK
+        | fuse_opcode::FUSE_LINK
+        | fuse_opcode::FUSE_CREATE => Some((Some(0), ENTRY_INO_OFFSET)),
+        fuse_opcode::FUSE_GETATTR | fuse_opcode::FUSE_SETATTR => Some((None, ATTR_INO_OFFSET)),
+        _ => None,
+    }
+}
+
+/// The timestamps in `fuse_attr`, from `atime` up to the nanoseconds of the last time
+#[cfg(target_os = "linux")]
+const ATTR_TIMES: Range<usize> = 24..60;
+#[cfg(target_os = "macos")]
+const ATTR_TIMES: Range<usize> = 24..72;
+
+/// Zero the timestamps of the attribute at the offset of the data, if the data is long enough
+fn mask_times(data: &mut [u8], attr_offset: usize) {
+    if let Some(times) = data.get_mut(attr_offset + ATTR_TIMES.start..attr_offset + ATTR_TIMES.end)
+    {
+        times.iter_mut().for_each(|b| *b = 0);
+    }
+}
+
+/// Return the offsets of the inode numbers of the entries of a readdir reply
+fn dirent_ino_offsets(data: &[u8]) -> Vec<usize> {
+    // the name follows the fixed part of the entry, padded to 8 bytes
+    const NAMELEN_OFFSET: usize = 16;
+    let mut offsets = Vec::new();
+    let mut offset = mem::size_of::<fuse_out_header>();
+    while offset + mem::size_of::<fuse_dirent>() <= data.len() {
+        offsets.push(offset);
+        let mut namelen = [0_u8; 4];
+        namelen.copy_from_slice(&data[offset + NAMELEN_OFFSET..offset + NAMELEN_OFFSET + 4]);
+        let len = mem::size_of::<fuse_dirent>() + u32::from_ne_bytes(namelen) as usize;
+        offset += (len + mem::size_of::<u64>() - 1) & !(mem::size_of::<u64>() - 1);
+    }
+    offsets
+}
+
+/// Return the offset of the new file handle in the data of a reply, if any
+fn reply_fh_offset(opcode: u32) -> Option<usize> {
+    match fuse_opcode::try_from(opcode).ok()? {
+        fuse_opcode::FUSE_OPEN | fuse_opcode::FUSE_OPENDIR => Some(0),
+        fuse_opcode::FUSE_CREATE => Some(mem::size_of::<fuse_entry_out>()),
+        _ => None,
+    }
+}
+
+/// Return the u64 at the offset of the data, if the data is long enough
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    let mut value = [0_u8; 8];
+    value.copy_from_slice(data.get(offset..offset + mem::size_of::<u64>())?);
+    Some(u64::from_ne_bytes(value))
+}
+
+/// Overwrite the u64 at the offset of the data, which must be long enough
+fn write_u64(data: &mut [u8], offset: usize, value: u64) {
+    data[offset..offset + mem::size_of::<u64>()].copy_from_slice(&value.to_ne_bytes());
+}
+
+/// A replayed request whose reply differs from the recorded one
+#[derive(Debug)]
+pub struct ReplayMismatch {
+    /// Unique id of the request
+    pub unique: u64,
+    /// Opcode of the request
+    pub opcode: u32,
+    /// Time of the request since the trace started
+    pub time: Duration,
+    /// The recorded reply, if any
+    pub recorded: Option<Vec<u8>>,
+    /// The reply of the replay with the recorded file handle, if any
+    pub replayed: Option<Vec<u8>>,
+}
+
+impl fmt::Display for ReplayMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match fuse_opcode::try_from(self.opcode) {
+            Ok(opcode) => write!(f, "{:?}", opcode)?,
+            Err(_) => write!(f, "opcode {}", self.opcode)?,
+        }
+        write!(f, " request (unique {}) at {:?}: ", self.unique, self.time)?;
+        match (&self.recorded, &self.replayed) {
+            (Some(recorded), Some(replayed)) => {
+                let (recorded_error, replayed_error) = (error_of(recorded), error_of(replayed));
+                if recorded_error != replayed_error {
+                    write!(
+                        f,
+                        "recorded error {}, replayed error {}",
+                        recorded_error, replayed_error
+                    )
+                } else if recorded.len() != replayed.len() {
+                    write!(
+                        f,
+                        "recorded {} bytes, replayed {} bytes",
+                        recorded.len(),
+                        replayed.len()
+                    )
+                } else {
+                    let differ = |(a, b): (&u8, &u8)| a != b;
+                    let pairs = || recorded.iter().zip(replayed.iter());
+                    let first = pairs().position(differ).unwrap_or(0);
+                    let count = pairs().filter(|&pair| differ(pair)).count();
+                    write!(
+                        f,
+                        "{} of {} bytes of the replies differ, the first at byte {}",
+                        count,
+                        recorded.len(),
+                        first
+                    )
+                }
+            }
+            (Some(_), None) => write!(f, "no reply replayed"),
+            (None, Some(_)) => write!(f, "no reply recorded"),
+            (None, None) => write!(f, "no reply"),
+        }
+    }
+}
+
+/// The result of a replay
+#[derive(Debug, Default)]
+pub struct ReplayReport {
+    /// Number of requests replayed
+    pub requests: usize,
+    /// Number of replies equal to the recorded ones
+    pub matched: usize,
+    /// The requests whose replies differ from the recorded ones
+    pub mismatches: Vec<ReplayMismatch>,
+}
+
+/// Send the requests of a trace to the filesystem and compare its replies with the recorded
+/// ones. The filesystem should start from the state the traced one started from, e.g. the same
+/// source directory for a `MemoryFilesystem`.
+pub fn replay<FS: Filesystem>(filesystem: FS, trace: &Path) -> io::Result<ReplayReport> {
+    let records = TraceReader::open(trace)?.collect::<io::Result<Vec<_>>>()?;
+    // the replies of concurrent requests may be recorded in any order
+    let mut recorded = HashMap::new();
+    for record in &records {
+        if record.kind == TraceKind::Reply {
+            if let Some(unique) = unique_of(&record.data) {
+                recorded.insert(unique, record.data.as_slice());
+            }
+        }
+    }
+
+    let (transport, replies) = MockTransport::new();
+    let mut se = Session::with_transport(filesystem, transport);
+    let mut report = ReplayReport::default();
+    // the node ids and file handles of the replay by the recorded ones
+    let mut nodes = HashMap::new();
+    let mut handles = HashMap::new();
+    let in_header_len = mem::size_of::<fuse_in_header>();
+    let out_header_len = mem::size_of::<fuse_out_header>();
+    for record in records.iter().filter(|r| r.kind == TraceKind::Request) {
+        let header = match ll_request::parse_header(&record.data) {
+            Some(header) => header,
+            None => continue,
+        };
+        let (unique, opcode) = (header.unique, header.opcode);
+        let mut request = record.data.clone();
+        let request_node_offset = request_node_offset(opcode).map(|o| in_header_len + o);
+        for offset in iter::once(NODEID_OFFSET).chain(request_node_offset) {
+            if let Some(node) = read_u64(&request, offset).and_then(|node| nodes.get(&node)) {
+                write_u64(&mut request, offset, *node);
+            }
+        }
+        if let Some(offset) = request_fh_offset(opcode).map(|o| in_header_len + o) {
+            if let Some(fh) = read_u64(&request, offset).and_then(|fh| handles.get(&fh)) {
+                write_u64(&mut request, offset, *fh);
+            }
+        }
+        se.process(&request);
+        report.requests += 1;
+
+        let mut replayed = replies.lock().unwrap().pop_front();
+        let mut recorded = recorded.remove(&unique).map(<[u8]>::to_vec);
+        if let (Some(offset), Some(recorded), Some(replayed)) = (
+            reply_fh_offset(opcode).map(|o| out_header_len + o),
+            recorded.as_ref(),
+            replayed.as_mut(),
+        ) {
+            if let (0, 0, Some(recorded_fh), Some(replayed_fh)) = (
+                error_of(recorded),
+                error_of(replayed),
+                read_u64(recorded, offset),
+                read_u64(replayed, offset),
+            ) {
+                handles.insert(recorded_fh, replayed_fh);
+                // the replies are equal if they differ in the file handle only
+                write_u64(replayed, offset, recorded_fh);
+            }
+        }
+        if let (Some(recorded), Some(replayed)) = (recorded.as_mut(), replayed.as_mut()) {
+            if let (0, 0) = (error_of(recorded), error_of(replayed)) {
+                let mut ino_offsets = Vec::new();
+                if let Some((node_offset, attr_offset)) = reply_node_offsets(opcode) {
+                    let node_offset = node_offset.map(|o| out_header_len + o);
+                    if let Some((recorded_node, replayed_node)) = node_offset.and_then(|offset| {
+                        Some((read_u64(recorded, offset)?, read_u64(replayed, offset)?))
+                    }) {
+                        nodes.insert(recorded_node, replayed_node);
+                    }
+                    // the ino of fuse_attr is its first field
+                    let attr_offset = out_header_len + attr_offset;
+                    ino_offsets.extend(node_offset);
+                    ino_offsets.push(attr_offset);
+                    mask_times(recorded, attr_offset);
+                    mask_times(replayed, attr_offset);
+                } else if matches!(fuse_opcode::try_from(opcode), Ok(fuse_opcode::FUSE_READDIR)) {
+                    ino_offsets = dirent_ino_offsets(recorded);
+                }
+                // the replies are equal if they differ in the mapped node ids only
+                for offset in ino_offsets {
+                    if let (Some(recorded_ino), Some(replayed_ino)) =
+                        (read_u64(recorded, offset), read_u64(replayed, offset))
+                    {
+                        if nodes.get(&recorded_ino) == Some(&replayed_ino) {
+                            write_u64(replayed, offset, recorded_ino);
+                        }
+                    }
+                }
+            }
+        }
+        match (recorded, replayed) {
+            (Some(ref recorded), Some(ref replayed)) if recorded == replayed => report.matched += 1,
+            (None, None) => report.matched += 1,
+            (recorded, replayed) => report.mismatches.push(ReplayMismatch {
+                unique,
+                opcode,
+                time: record.time,
+                recorded,
+                replayed,
+            }),
+        }
+    }
+    se.destroy();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{replay, write_u64, TraceKind, TraceReader, NODEID_OFFSET};
+    use crate::fuse::abi::{as_bytes, fuse_in_header, fuse_opcode};
+    use crate::fuse::mock::MockTransport;
+    use crate::fuse::{FileAttr, FileType, Filesystem, ReplyAttr, ReplyEmpty, ReplyEntry};
+    use crate::fuse::{ReplyOpen, Request, Session};
+    use libc::{ENOENT, ENOSYS};
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::mem;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    struct NullFS;
+
+    impl Filesystem for NullFS {}
+
+    struct NoEntFS;
+
+    impl Filesystem for NoEntFS {
+        fn getattr(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyAttr) {
+            reply.error(ENOENT);
+        }
+    }
+
+    /// Hands out file handles counting up from the first one, remembers released handles
+    struct HandleFS {
+        next_fh: u64,
+        released: Arc<Mutex<Vec<u64>>>,
+    }
+
+    impl Filesystem for HandleFS {
+        fn open(&mut self, _req: &Request<'_>, _ino: u64, flags: u32, reply: ReplyOpen) {
+            reply.opened(self.next_fh, flags);
+            self.next_fh += 1;
+        }
+
+        fn release(
+            &mut self,
+            _req: &Request<'_>,
+            _ino: u64,
+            fh: u64,
+            _flags: u32,
+            _lock_owner: u64,
+            _flush: bool,
+            reply: ReplyEmpty,
+        ) {
+            self.released.lock().unwrap().push(fh);
+            reply.ok();
+        }
+    }
+
+    fn request(opcode: fuse_opcode, unique: u64, arg: &[u8]) -> Vec<u8> {
+        let header = fuse_in_header {
+            len: (mem::size_of::<fuse_in_header>() + arg.len()) as u32,
+            opcode: opcode as u32,
+            unique,
+            nodeid: 1,
+            uid: 0,
+            gid: 0,
+            pid: 0,
+            padding: 0,
+        };
+        [as_bytes(&header), arg].concat()
+    }
+
+    /// Hands out node ids counting up from the first one on lookup
+    struct NodeFS {
+        next_ino: u64,
+        inos: Vec<u64>,
+    }
+
+    impl NodeFS {
+        fn attr(ino: u64) -> FileAttr {
+            FileAttr {
+                ino,
+                size: 0,
+                blocks: 0,
+                atime: UNIX_EPOCH,
+                mtime: UNIX_EPOCH,
+                ctime: UNIX_EPOCH,
+                crtime: UNIX_EPOCH,
+                kind: FileType::RegularFile,
+                perm: 0o644,
+                nlink: 1,
+                uid: 0,
+                gid: 0,
+                rdev: 0,
+                flags: 0,
+            }
+        }
+    }
+
+    impl Filesystem for NodeFS {
+        fn lookup(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, reply: ReplyEntry) {
+            self.inos.push(self.next_ino);
+            reply.entry(&Duration::from_secs(1), &NodeFS::attr(self.next_ino), 0);
+            self.next_ino += 1;
+        }
+
+        fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+            if self.inos.contains(&ino) {
+                reply.attr(&Duration::from_secs(1), &NodeFS::attr(ino));
+            } else {
+                reply.error(ENOENT);
+            }
+        }
+    }
+
+    fn init_request() -> Vec<u8> {
+        // major 7, minor 8, max_readahead 4096, no flags
+        let init_arg: Vec<u8> = [7_u32, 8, 4096, 0]
+            .iter()
+            .flat_map(|v| v.to_ne_bytes().to_vec())
+            .collect();
+        request(fuse_opcode::FUSE_INIT, 1, &init_arg)
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let path = std::env::temp_dir().join(format!("fuse_ll_trace_{}", std::process::id()));
+        let (transport, _replies) = MockTransport::new();
+        let mut se = Session::with_transport(NullFS, transport);
+        se.trace(&path, "null 1\n").unwrap();
+        se.process(&init_request());
+        se.process(&request(fuse_opcode::FUSE_GETATTR, 2, &[]));
+        se.process(&request(fuse_opcode::FUSE_FORGET, 3, &1_u64.to_ne_bytes()));
+        drop(se);
+
+        let reader = TraceReader::open(&path).unwrap();
+        assert_eq!(reader.description(), "null 1\n");
+        let records = reader.collect::<Result<Vec<_>, _>>().unwrap();
+        let kinds: Vec<_> = records.iter().map(|r| r.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                TraceKind::Request,
+                TraceKind::Reply,
+                TraceKind::Request,
+                TraceKind::Reply,
+                TraceKind::Request
+            ]
+        );
+        assert!(records[0].time <= records[4].time);
+
+        let report = replay(NullFS, &path).unwrap();
+        assert_eq!((report.requests, report.matched), (3, 3));
+        assert!(report.mismatches.is_empty());
+
+        let report = replay(NoEntFS, &path).unwrap();
+        assert_eq!((report.requests, report.matched), (3, 2));
+        assert_eq!(
+            report.mismatches[0].to_string(),
+            format!(
+                "FUSE_GETATTR request (unique 2) at {:?}: recorded error {}, replayed error {}",
+                records[2].time, ENOSYS, ENOENT
+            )
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replay_file_handles() {
+        let path = std::env::temp_dir().join(format!("fuse_ll_trace_fh_{}", std::process::id()));
+        let released = Arc::new(Mutex::new(Vec::new()));
+        let (transport, _replies) = MockTransport::new();
+        let fs = HandleFS {
+            next_fh: 10,
+            released: Arc::clone(&released),
+        };
+        let mut se = Session::with_transport(fs, transport);
+        se.trace(&path, "").unwrap();
+        se.process(&init_request());
+        // flags O_RDONLY
+        se.process(&request(fuse_opcode::FUSE_OPEN, 2, &[0_u8; 8]));
+        // fh 10, flags, release flags and lock owner
+        let release_arg = [10_u64.to_ne_bytes(), [0_u8; 8], [0_u8; 8]].concat();
+        se.process(&request(fuse_opcode::FUSE_RELEASE, 3, &release_arg));
+        drop(se);
+        assert_eq!(*released.lock().unwrap(), vec![10]);
+
+        // the replayed filesystem hands out other file handles
+        released.lock().unwrap().clear();
+        let fs = HandleFS {
+            next_fh: 20,
+            released: Arc::clone(&released),
+        };
+        let report = replay(fs, &path).unwrap();
+        assert_eq!((report.requests, report.matched), (3, 3));
+        assert_eq!(*released.lock().unwrap(), vec![20]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replay_node_ids() {
+        let path = std::env::temp_dir().join(format!("fuse_ll_trace_node_{}", std::process::id()));
+        let (transport, _replies) = MockTransport::new();
+        let fs = NodeFS {
+            next_ino: 10,
+            inos: Vec::new(),
+        };
+        let mut se = Session::with_transport(fs, transport);
+        se.trace(&path, "").unwrap();
+        se.process(&init_request());
+        se.process(&request(fuse_opcode::FUSE_LOOKUP, 2, b"a\0"));
+        let mut getattr = request(fuse_opcode::FUSE_GETATTR, 3, &[]);
+        write_u64(&mut getattr, NODEID_OFFSET, 10);
+        se.process(&getattr);
+        drop(se);
+
+        // the replayed filesystem hands out other node ids
+        let fs = NodeFS {
+            next_ino: 20,
+            inos: Vec::new(),
+        };
+        let report = replay(fs, &path).unwrap();
+        assert_eq!((report.requests, report.matched), (3, 3));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,202 @@
+//! Transports of the FUSE protocol
+//!
+//! A session receives requests from and sends replies to a transport. The `/dev/fuse` channel
+//! is the transport of a kernel mount, other transports carry the same messages over e.g. a
+//! vhost-user socket, an in-process loopback or a recorded trace.
+
+use log::{error, warn};
+use nix::errno::Errno;
+use nix::unistd;
+use std::fmt;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::mount::UnmountMode;
+use super::reply::{self, ReplySender};
+
+/// A transport of requests and replies
+pub trait Transport: fmt::Debug {
+    /// The sender of replies of the transport
+    type Sender: TransportSender;
+
+    /// Return path of the mounted filesystem
+    fn mountpoint(&self) -> &Path;
+
+    /// Receive one request into the given buffer up to its capacity, may block. Fails with
+    /// `ENODEV` once the filesystem is unmounted, `ENOENT`, `EINTR` and `EAGAIN` are retried.
+    fn receive(&self, buffer: &mut Vec<u8>) -> io::Result<()>;
+
+    /// Receive one request like `receive`, but the data of a write request may be left in a
+    /// pipe instead of copied into the buffer, the buffer then holds the request up to its
+    /// `fuse_write_in` argument. The data must be consumed before the next receive. The
+    /// default receives the whole request into the buffer.
+    fn receive_spliced(&self, buffer: &mut Vec<u8>) -> io::Result<Option<SplicedData>> {
+        self.receive(buffer).map(|()| None)
+    }
+
+    /// Prepare serving requests, called by the session once it starts to run, i.e. after the
+    /// process possibly daemonized. Does nothing by default.
+    fn start(&mut self) {}
+
+    /// Return a sender of replies, which may be used on other threads
+    fn sender(&self) -> Self::Sender;
+
+    /// Unmount the filesystem, a busy mount point fails with `EBUSY` in `UnmountMode::Normal`
+    fn unmount(&mut self, mode: UnmountMode) -> io::Result<()>;
+
+    /// Remember that the filesystem was unmounted from outside, e.g. by `umount`
+    fn set_unmounted(&mut self);
+}
+
+/// The sender of replies of a transport
+pub trait TransportSender: fmt::Debug + Send + Sync + 'static {
+    /// Send all data in the slice of slice of bytes as a single message
+    fn send(&self, data: &[&[u8]]) -> io::Result<()>;
+
+    /// Send the header followed by `len` bytes of the file `fd` at `offset` as a single
+    /// message, nothing is sent if it fails. The default copies the bytes into memory.
+    fn send_from_fd(&self, header: &[u8], fd: RawFd, offset: i64, len: usize) -> io::Result<()> {
+        let data = reply::read_from_fd(fd, offset, len)?;
+        self.send(&[header, &data])
+    }
+}
+
+/// The data of a write request left in a pipe by a spliced receive
+#[derive(Clone, Copy, Debug)]
+pub struct SplicedData {
+    /// The read end of the pipe, which holds nothing but the data
+    pub fd: RawFd,
+    /// The length of the data
+    pub len: usize,
+}
+
+/// Read `len` bytes of data left in the pipe `fd` into memory
+pub fn read_spliced(fd: RawFd, len: usize) -> io::Result<Vec<u8>> {
+    let mut data = vec![0_u8; len];
+    let mut done = 0;
+    while done < len {
+        match unistd::read(fd, &mut data[done..]) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            Ok(n) => done += n,
+            Err(nix::Error::Sys(Errno::EINTR)) => {}
+            Err(nix::Error::Sys(errno)) => return Err(io::Error::from_raw_os_error(errno as i32)),
+            Err(e) => return Err(io::Error::other(e.to_string())),
+        }
+    }
+    Ok(data)
+}
+
+/// A sender of replies over any transport, which counts the requests not replied yet
+#[derive(Clone, Debug)]
+pub struct ChannelSender {
+    sender: Arc<dyn TransportSender>,
+    /// The number of requests not replied yet
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl ChannelSender {
+    /// Create a sender of replies over the transport sender, counting the replies in flight
+    /// with the given counter
+    pub fn new<S: TransportSender>(sender: S, in_flight: Arc<AtomicUsize>) -> ChannelSender {
+        ChannelSender {
+            sender: Arc::new(sender),
+            in_flight,
+        }
+    }
+
+    /// Return a sender for the reply of one request, the request counts as in flight until
+    /// the sender is dropped
+    pub fn reply_sender(&self) -> ReplyChannelSender {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        ReplyChannelSender {
+            sender: self.clone(),
+        }
+    }
+}
+
+impl ReplySender for ChannelSender {
+    fn send(&self, data: &[&[u8]]) {
+        if let Err(err) = self.sender.send(data) {
+            error!("Failed to send FUSE reply: {}", err);
+        }
+    }
+
+    fn send_from_fd(&self, header: &[u8], fd: RawFd, offset: i64, len: usize) -> io::Result<()> {
+        self.sender.send_from_fd(header, fd, offset, len)
+    }
+}
+
+/// A sender for the reply of one request
+#[derive(Debug)]
+pub struct ReplyChannelSender {
+    sender: ChannelSender,
+}
+
+impl ReplySender for ReplyChannelSender {
+    fn send(&self, data: &[&[u8]]) {
+        ReplySender::send(&self.sender, data);
+    }
+
+    fn send_from_fd(&self, header: &[u8], fd: RawFd, offset: i64, len: usize) -> io::Result<()> {
+        self.sender.send_from_fd(header, fd, offset, len)
+    }
+}
+
+impl Drop for ReplyChannelSender {
+    fn drop(&mut self) {
+        self.sender.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The interval to check whether requests in flight are replied
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Wait up to the timeout for the requests not replied yet
+pub fn drain(in_flight: &AtomicUsize, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    while in_flight.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+        thread::sleep(DRAIN_POLL_INTERVAL);
+    }
+    let remaining = in_flight.load(Ordering::SeqCst);
+    if remaining > 0 {
+        warn!(
+            "{} requests are still in flight after {:?}, unmount anyway",
+            remaining, timeout
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ChannelSender, TransportSender};
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[derive(Debug)]
+    struct NullSender;
+
+    impl TransportSender for NullSender {
+        fn send(&self, _data: &[&[u8]]) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn count_in_flight() {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let sender = ChannelSender::new(NullSender, Arc::clone(&in_flight));
+        let first = sender.reply_sender();
+        let second = sender.reply_sender();
+        assert_eq!(in_flight.load(Ordering::SeqCst), 2);
+        drop(first);
+        assert_eq!(in_flight.load(Ordering::SeqCst), 1);
+        drop(second);
+        assert_eq!(in_flight.load(Ordering::SeqCst), 0);
+    }
+}
@@ -1,19 +1,65 @@
-use log::debug;
-use std::ffi::OsStr;
-use std::path::Path;
+use log::{debug, error, info, warn, LevelFilter};
+use nix::sys::signal::{SigSet, Signal};
+use std::env;
+use std::ffi::OsString;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches, ErrorKind, SubCommand};
 
-mod fuse;
-mod memfs;
+use fuse_ll::{fuse, memfs};
 
-use memfs::MemoryFilesystem;
+mod config;
+mod daemon;
 
-fn main() {
-    env_logger::init();
+use config::{Config, Mount, MountEntry};
+use daemon::PidFile;
+use fuse::UnmountMode;
+use memfs::{MemoryFilesystem, MemoryFilesystemConfig};
 
+fn main() {
+    let (args, fake) = mount_helper_args(env::args_os().collect());
     let matches = App::new("Fuse Low Level")
-        .arg(Arg::with_name("mountpoint").required(true).index(1))
+        .arg(
+            Arg::with_name("source")
+                .required_unless("config")
+                .index(1)
+                .help("Directory mirrored by the filesystem"),
+        )
+        .arg(
+            Arg::with_name("mountpoint")
+                .required_unless_one(&["overlay_self", "config"])
+                .index(2)
+                .help("Directory to mount the filesystem on"),
+        )
+        .arg(
+            Arg::with_name("overlay_self")
+                .long("overlay-self")
+                .conflicts_with("mountpoint")
+                .help("Mount the filesystem over the source directory itself"),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("FILE")
+                .help("Serve the mounts described in the TOML configuration file")
+                .takes_value(true)
+                .conflicts_with_all(&[
+                    "source",
+                    "mountpoint",
+                    "overlay_self",
+                    "options",
+                    "switch_credentials",
+                    "trace",
+                    "metrics_socket",
+                    "metrics_interval",
+                    "control_dir",
+                ]),
+        )
         .arg(
             Arg::with_name("options")
                 .short("o")
@@ -24,25 +70,444 @@ fn main() {
                 .validator(fuse::options_validator)
                 .number_of_values(1),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("foreground")
+                .short("f")
+                .long("foreground")
+                .help("Stay in the foreground instead of daemonizing after mounting"),
+        )
+        .arg(
+            Arg::with_name("debug")
+                .short("d")
+                .long("debug")
+                .help("Log debug messages, implies --foreground"),
+        )
+        .arg(
+            Arg::with_name("single_thread")
+                .short("s")
+                .long("single-thread")
+                .help("Accepted for compatibility with libfuse and ignored, the requests of each mount are served in a single thread anyway"),
+        )
+        .arg(
+            Arg::with_name("pidfile")
+                .long("pidfile")
+                .value_name("FILE")
+                .help("Write the process id of the filesystem daemon to the file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("switch_credentials")
+                .long("switch-credentials")
+                .help("Perform mutating operations with the uid and gid of the caller (requires root)"),
+        )
+        .arg(
+            Arg::with_name("trace")
+                .long("trace")
+                .value_name("FILE")
+                .help("Record every request and reply to the file for the replay subcommand")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("metrics_socket")
+                .long("metrics-socket")
+                .value_name("FILE")
+                .help("Serve metrics in the Prometheus text format on a Unix socket at the file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("metrics_interval")
+                .long("metrics-interval")
+                .value_name("SECONDS")
+                .help("Write the metrics to the log at the interval")
+                .takes_value(true)
+                .validator(|secs| {
+                    secs.parse::<f64>()
+                        .map(|_| ())
+                        .map_err(|_| format!("invalid seconds \"{}\"", secs))
+                }),
+        )
+        .arg(
+            Arg::with_name("control_dir")
+                .long("control-dir")
+                .help("Inspect and steer the filesystem through the virtual directory .fuse_ll at the root of the mount"),
+        )
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(
+            SubCommand::with_name("replay")
+                .about("Replay a trace against the source directory with the traced options and compare the replies")
+                .arg(
+                    Arg::with_name("trace")
+                        .required(true)
+                        .index(1)
+                        .help("Trace recorded with --trace"),
+                )
+                .arg(
+                    Arg::with_name("source")
+                        .required(true)
+                        .index(2)
+                        .help("Directory in the state the traced filesystem started from, which the replay modifies"),
+                )
+                .arg(
+                    Arg::with_name("in_place")
+                        .long("in-place")
+                        .help("Confirm that the replayed requests may write to the source directory, replay into a copy to keep it"),
+                ),
+        )
+        .get_matches_from(args);
+
+    let config = matches.value_of("config").map(|path| {
+        Config::load(Path::new(path))
+            .unwrap_or_else(|e| clap::Error::with_description(&e, ErrorKind::InvalidValue).exit())
+    });
+    let debug = matches.is_present("debug");
+    let foreground = debug || matches.is_present("foreground");
+    let logger = || {
+        let mut logger = env_logger::Builder::from_default_env();
+        if debug {
+            logger.filter_level(LevelFilter::Debug);
+        } else if let Some(Ok(Some(level))) = config.as_ref().map(Config::log_level) {
+            logger.filter_level(level);
+        }
+        logger
+    };
+    let control_dir = matches.is_present("control_dir")
+        || config
+            .as_ref()
+            .map_or(false, |config| config.mounts.iter().any(|m| m.control_dir));
+    if control_dir {
+        // the log level may be raised through the control directory, so the logger passes
+        // all records and the max level of the log crate filters them
+        let level = logger().build().filter();
+        logger().filter_level(LevelFilter::Trace).init();
+        log::set_max_level(level);
+    } else {
+        logger().init();
+    }
+    if let Some(matches) = matches.subcommand_matches("replay") {
+        process::exit(replay(matches));
+    }
+    // -s is ignored, each session loop serves the requests of its mount in a single thread
 
-    let mountpoint = OsStr::new(matches.value_of("mountpoint").unwrap()); // safe to use unwrap() here, because mountpoint is required
-    let options: Vec<&str> = match matches.values_of("options") {
-        Some(options) => options.map(|o| o.split(',')).flatten().collect(),
-        None => Vec::new(),
+    let mounts = match config {
+        Some(config) => config.resolve(),
+        None => MountEntry {
+            source: matches.value_of("source").unwrap().into(), // safe to use unwrap() here, because source is required
+            mountpoint: matches.value_of("mountpoint").map(PathBuf::from),
+            overlay_self: matches.is_present("overlay_self"),
+            options: match matches.values_of("options") {
+                Some(options) => options.map(String::from).collect(),
+                None => Vec::new(),
+            },
+            switch_credentials: matches.is_present("switch_credentials"),
+            trace: matches.value_of("trace").map(PathBuf::from),
+            metrics_socket: matches.value_of("metrics_socket").map(PathBuf::from),
+            // safe to use unwrap() here, because the validator parsed the interval
+            metrics_interval: matches
+                .value_of("metrics_interval")
+                .map(|secs| secs.parse().unwrap()),
+            control_dir: matches.is_present("control_dir"),
+            ..MountEntry::default()
+        }
+        .resolve()
+        .map(|mount| vec![mount]),
     };
-    debug!("{:?}", &options);
-    // TODO: add check function for mutual exclusive options
+    let mounts = mounts
+        .unwrap_or_else(|e| clap::Error::with_description(&e, ErrorKind::InvalidValue).exit());
+    debug!("{:?}", &mounts);
+    if fake {
+        return;
+    }
+    // the protocol debug output of -o debug goes to stderr, which a daemon has not
+    let foreground = foreground
+        || mounts
+            .iter()
+            .any(|mount| mount.options.iter().any(|option| option == "debug"));
 
-    let fs = MemoryFilesystem::new(&mountpoint);
-    fuse::mount(fs, Path::new(&mountpoint), &options)
-        .unwrap_or_else(|_| panic!("Couldn't mount filesystem {:?}", mountpoint));
+    // block the signals before mounting, so that only the signal thread receives them
+    let signals = shutdown_signals();
+    signals
+        .thread_block()
+        .unwrap_or_else(|e| panic!("Couldn't block the shutdown signals: {}", e));
+    let mut sessions = Vec::with_capacity(mounts.len());
+    for mount in &mounts {
+        match mount_filesystem(mount) {
+            Ok(session) => sessions.push(session),
+            Err(e) => {
+                error!("Couldn't mount filesystem {:?}: {}", mount.mountpoint, e);
+                // unmount the filesystems mounted so far
+                drop(sessions);
+                process::exit(EXIT_FAILURE);
+            }
+        }
+    }
+
+    // fork after mounting, so the exit status of the parent process reflects the mount result
+    let pidfile = matches.value_of("pidfile").map(Path::new);
+    let pidfile = if foreground {
+        pidfile.map(PidFile::create).transpose()
+    } else {
+        daemon::daemonize(pidfile)
+    };
+    let pidfile = match pidfile {
+        Ok(pidfile) => pidfile,
+        Err(e) => {
+            error!("Couldn't start filesystem daemon: {}", e);
+            drop(sessions);
+            process::exit(EXIT_FAILURE);
+        }
+    };
+    // threads do not survive the fork, so the metrics are exported afterwards, and the
+    // fusermount processes of auto_unmount mounts are watched once the sessions run
+    let sessions = sessions
+        .into_iter()
+        .zip(&mounts)
+        .map(|((mut session, listener), mount)| {
+            export_metrics(&mut session, listener, mount).unwrap_or_else(|e| {
+                error!(
+                    "Couldn't export the metrics of {:?}: {}",
+                    mount.mountpoint, e
+                )
+            });
+            session
+        })
+        .collect::<Vec<_>>();
+    let unmounters = sessions.iter().map(fuse::Session::unmounter).collect();
+    spawn_signal_thread(signals, unmounters);
+
+    // serve each mount in its own thread until all of them are unmounted
+    let handles = sessions
+        .into_iter()
+        .map(|mut session| {
+            thread::spawn(move || {
+                let result = session.run();
+                (session.mountpoint().to_owned(), result)
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut status = EXIT_SUCCESS;
+    for handle in handles {
+        match handle.join() {
+            Ok((_, Ok(()))) => (),
+            Ok((mountpoint, Err(e))) => {
+                error!("Filesystem {:?} failed: {}", mountpoint, e);
+                status = EXIT_FAILURE;
+            }
+            Err(_) => status = EXIT_FAILURE,
+        }
+    }
+    drop(pidfile);
+    process::exit(status);
+}
+
+/// Exit status on clean shutdown, after an unmount or a shutdown signal
+const EXIT_SUCCESS: i32 = 0;
+/// Exit status if mounting or serving the filesystem failed
+const EXIT_FAILURE: i32 = 1;
+
+/// Time to wait for the requests in flight to be replied before unmounting on a signal
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Mount a filesystem, return its session and the socket to serve its metrics on, if any
+fn mount_filesystem(
+    mount: &Mount,
+) -> std::io::Result<(fuse::Session<MemoryFilesystem>, Option<UnixListener>)> {
+    let fs = MemoryFilesystem::with_config(&mount.source, mount.fs_config.clone());
+    let options = mount.options.iter().map(String::as_str).collect::<Vec<_>>();
+    let mut session = fuse::Session::new(fs, &mount.mountpoint, &options)?;
+    if let Some(ref trace) = mount.trace {
+        session.trace(trace, &mount.fs_config.describe())?;
+    }
+    if mount.metrics_socket.is_some() || mount.metrics_interval.is_some() {
+        let metrics = session.metrics();
+        metrics.add_source(session.filesystem.cache_stats());
+    }
+    // the socket is created before forking, so that failures fail the mount
+    let listener = mount
+        .metrics_socket
+        .as_ref()
+        .map(|path| fuse::Metrics::bind(path))
+        .transpose()?;
+    Ok((session, listener))
+}
+
+/// Serve the metrics of a session on the socket and write them to the log at the interval
+/// of the mount, if any
+fn export_metrics(
+    session: &mut fuse::Session<MemoryFilesystem>,
+    listener: Option<UnixListener>,
+    mount: &Mount,
+) -> std::io::Result<()> {
+    if listener.is_none() && mount.metrics_interval.is_none() {
+        return Ok(());
+    }
+    // the metrics were collected since mounting, the same are returned
+    let metrics = session.metrics();
+    if let Some(listener) = listener {
+        Arc::clone(&metrics).serve(listener)?;
+    }
+    if let Some(interval) = mount.metrics_interval {
+        metrics.log_every(mount.mountpoint.display().to_string(), interval)?;
+    }
+    Ok(())
+}
+
+/// Replay a trace against a filesystem of the source directory with the traced options, print
+/// the requests whose replies differ from the recorded ones and return the exit status
+fn replay(matches: &ArgMatches<'_>) -> i32 {
+    let trace = Path::new(matches.value_of_os("trace").unwrap()); // safe, trace is required
+    let source = Path::new(matches.value_of_os("source").unwrap()); // safe, source is required
+    if !matches.is_present("in_place") {
+        error!(
+            "The replay writes to the source {:?}, pass --in-place to replay into it",
+            source
+        );
+        return EXIT_FAILURE;
+    }
+    let config = fuse::TraceReader::open(trace)
+        .map_err(|e| e.to_string())
+        .and_then(|reader| MemoryFilesystemConfig::from_description(reader.description()));
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Couldn't read the options of trace {:?}: {}", trace, e);
+            return EXIT_FAILURE;
+        }
+    };
+    let fs = MemoryFilesystem::with_config(source, config);
+    match fuse::replay(fs, trace) {
+        Ok(report) => {
+            for mismatch in &report.mismatches {
+                println!("{}", mismatch);
+            }
+            println!(
+                "{} requests replayed, {} replies matched, {} differ",
+                report.requests,
+                report.matched,
+                report.mismatches.len()
+            );
+            if report.mismatches.is_empty() {
+                EXIT_SUCCESS
+            } else {
+                EXIT_FAILURE
+            }
+        }
+        Err(e) => {
+            error!("Couldn't replay trace {:?}: {}", trace, e);
+            EXIT_FAILURE
+        }
+    }
+}
+
+/// Translate the arguments of mount(8), which runs the binary installed as
+/// `/sbin/mount.fuse.fuse_ll` for `fuse.fuse_ll` entries of `/etc/fstab` as
+/// `mount.fuse.fuse_ll <source> <mountpoint> [-fnsv] [-o <options>]`. Its `-f` means a fake
+/// mount and `-s` sloppy options, which differ from the flags of the filesystem. The arguments
+/// of other program names are returned as given, mount.fuse(8) runs the binary by its name
+/// with the same `<source> <mountpoint> -o <options>` convention. Also return whether to
+/// only check the arguments without mounting.
+fn mount_helper_args(args: Vec<OsString>) -> (Vec<OsString>, bool) {
+    let is_helper = args
+        .first()
+        .and_then(|arg0| Path::new(arg0).file_name())
+        .is_some_and(|name| name.to_string_lossy().starts_with("mount."));
+    if !is_helper {
+        return (args, false);
+    }
+    let mut fake = false;
+    let mut helper_args = Vec::with_capacity(args.len());
+    for arg in args {
+        let flags = arg.to_str().and_then(|arg| arg.strip_prefix('-'));
+        match flags {
+            Some(flags) if !flags.is_empty() && flags.chars().all(|c| "fnsv".contains(c)) => {
+                fake |= flags.contains('f');
+            }
+            _ => helper_args.push(arg),
+        }
+    }
+    (helper_args, fake)
+}
+
+/// The signals that trigger an orderly unmount
+fn shutdown_signals() -> SigSet {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGINT);
+    signals.add(Signal::SIGTERM);
+    signals.add(Signal::SIGHUP);
+    signals
+}
+
+/// Wait for a shutdown signal in a separate thread and unmount the filesystems after the
+/// requests in flight are replied, which ends the session loops, so that the filesystems are
+/// destroyed and the process exits normally
+fn spawn_signal_thread(signals: SigSet, mut unmounters: Vec<fuse::Unmounter>) {
+    thread::spawn(move || {
+        while !unmounters.is_empty() {
+            let signal = match signals.wait() {
+                Ok(signal) => signal,
+                Err(e) => {
+                    error!("failed to wait for shutdown signals: {}", e);
+                    return;
+                }
+            };
+            info!("received {:?}, unmounting", signal);
+            // keep the mount points failed to unmount for the next signal
+            unmounters.retain(|unmounter| {
+                let mountpoint = unmounter.mountpoint();
+                info!("unmounting {:?}", mountpoint);
+                // fall back to a lazy unmount if the filesystem is still in use
+                let result = unmounter
+                    .unmount(UnmountMode::Normal, Some(DRAIN_TIMEOUT))
+                    .or_else(|e| {
+                        warn!("failed to unmount {:?}: {}, unmount lazily", mountpoint, e);
+                        unmounter.unmount(UnmountMode::Lazy, None)
+                    });
+                match result {
+                    Ok(()) => false,
+                    Err(e) => {
+                        error!("failed to unmount {:?}: {}", mountpoint, e);
+                        true
+                    }
+                }
+            });
+        }
+    });
 }
 
 #[cfg(test)]
 #[allow(clippy::dbg_macro)]
 #[allow(unsafe_code)]
 mod test {
+    #[test]
+    fn test_mount_helper_args() {
+        use std::ffi::OsString;
+        let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+        let plain = args(&["fuse_ll", "-f", "/src", "/mnt"]);
+        assert_eq!(super::mount_helper_args(plain.clone()), (plain, false));
+        assert_eq!(
+            super::mount_helper_args(args(&[
+                "/sbin/mount.fuse.fuse_ll",
+                "/src",
+                "/mnt",
+                "-n",
+                "-sv",
+                "-o",
+                "rw,_netdev"
+            ])),
+            (
+                args(&[
+                    "/sbin/mount.fuse.fuse_ll",
+                    "/src",
+                    "/mnt",
+                    "-o",
+                    "rw,_netdev"
+                ]),
+                false
+            )
+        );
+        let (_, fake) = super::mount_helper_args(args(&["mount.fuse_ll", "/src", "/mnt", "-f"]));
+        assert!(fake);
+    }
+
     #[test]
     fn test_tmp() {
         fn u64fn(u64ref: u64) {
@@ -1,10 +1,15 @@
+#[cfg(target_os = "linux")]
+use crate::fuse::read_spliced;
 use crate::fuse::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
-    ReplyOpen, ReplyWrite, Request, FUSE_ROOT_ID,
+    FileAttr, FileType, Filesystem, Metric, MetricKind, MetricSource, ReplyAttr, ReplyData,
+    ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyWrite, Request, FUSE_ROOT_ID,
 };
-use libc::{EEXIST, EINVAL, ENODATA, ENOENT, ENOTEMPTY};
-use log::{debug, error}; // info, warn
+use libc::{EACCES, EEXIST, EINVAL, EIO, ENODATA, ENOENT, ENOTEMPTY, EPERM, R_OK, W_OK, X_OK};
+use log::{debug, error, warn}; // info
 use nix::dir::{Dir, Entry, Type};
+use nix::errno::Errno;
+#[cfg(target_os = "linux")]
+use nix::fcntl::SpliceFFlags;
 use nix::fcntl::{self, FcntlArg, OFlag};
 use nix::sys::stat::{self, FileStat, Mode, SFlag};
 use nix::sys::uio;
@@ -20,9 +25,19 @@ use std::os::raw::c_int;
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{self, AtomicI64};
+use std::sync::atomic::{self, AtomicI64, AtomicU64};
+use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+mod control;
+mod idmap;
+mod permission;
+
+pub use idmap::{IdMap, IdRange};
+
+use control::{ControlDir, CONTROL_DIR_INO, CONTROL_DIR_NAME};
+use permission::{CredentialGuard, Credentials};
+
 const MY_TTL_SEC: u64 = 1; // TODO: should be a long value, say 1 hour
 const MY_GENERATION: u64 = 1;
 // const MY_DIR_MODE: u16 = 0o755;
@@ -169,6 +184,11 @@ mod util {
         };
         Ok(attr)
     }
+
+    /// Return the error number to reply for a failed system call
+    pub fn errno(err: nix::Error) -> c_int {
+        err.as_errno().map_or(EIO, |errno| errno as c_int)
+    }
 }
 
 #[derive(Debug)]
@@ -198,6 +218,8 @@ struct FileNode {
     fd: RawFd,
     open_count: AtomicI64,
     lookup_count: AtomicI64,
+    /// Bytes written since the file was last flushed or synced
+    dirty_bytes: AtomicU64,
 }
 
 impl Drop for FileNode {
@@ -333,6 +355,24 @@ impl INode {
         }
     }
 
+    /// Return the size of the file data held in memory, directories hold none
+    fn cached_size(&self) -> usize {
+        match self {
+            INode::DIR(_) => 0,
+            INode::FILE(file_node) => file_node.data.borrow().len(),
+        }
+    }
+
+    /// Drop the file data held in memory, it is loaded from disk again when needed,
+    /// which is safe because writes go through to disk
+    fn evict_data(&self) {
+        if let INode::FILE(file_node) = self {
+            let file_data: &mut Vec<u8> = &mut file_node.data.borrow_mut();
+            file_data.clear();
+            file_data.shrink_to_fit();
+        }
+    }
+
     fn inc_lookup_count(&self) -> i64 {
         match self {
             INode::DIR(dir_node) => dir_node.lookup_count.fetch_add(1, atomic::Ordering::SeqCst),
@@ -408,7 +448,7 @@ impl INode {
         child_dir_name: &OsString,
         mode: Mode,
         create_dir: bool,
-    ) -> INode {
+    ) -> Result<INode, c_int> {
         let parent_node = self.helper_get_dir_node();
         let parent = self.get_ino();
 
@@ -418,7 +458,13 @@ impl INode {
                 &PathBuf::from(child_dir_name),
                 mode,
             )
-            .unwrap_or_else(|_| panic!("helper_open_child_dir() failed to create directory name={:?} under parent ino={}", child_dir_name, parent));
+            .map_err(|e| {
+                debug!(
+                    "helper_open_child_dir() failed to create directory name={:?} under parent ino={}, the error is: {:?}",
+                    child_dir_name, parent, e,
+                );
+                util::errno(e)
+            })?;
         }
 
         let child_dir_fd = util::open_dir_at(&parent_node.dir_fd.borrow(), child_dir_name)
@@ -470,14 +516,15 @@ impl INode {
             child_inode.helper_load_dir_data();
         }
 
-        child_inode
+        Ok(child_inode)
     }
 
     fn open_child_dir(&self, child_dir_name: &OsString) -> INode {
         self.helper_open_child_dir(child_dir_name, Mode::empty(), false)
+            .unwrap_or_else(|_| unreachable!("opening a directory never creates it"))
     }
 
-    fn create_child_dir(&self, child_dir_name: &OsString, mode: Mode) -> INode {
+    fn create_child_dir(&self, child_dir_name: &OsString, mode: Mode) -> Result<INode, c_int> {
         self.helper_open_child_dir(child_dir_name, mode, true)
     }
 
@@ -538,7 +585,8 @@ impl INode {
         unsafe {
             file_data.set_len(file_data.capacity());
         }
-        let res = unistd::read(fd, &mut *file_data);
+        // read from the start, the data may be loaded again after being dropped from memory
+        let res = uio::pread(fd, &mut *file_data, 0);
         #[allow(unsafe_code)]
         match res {
             Ok(s) => unsafe {
@@ -577,6 +625,23 @@ impl INode {
         attr
     }
 
+    fn helper_change_owner(&self, uid: u32, gid: u32) -> nix::Result<()> {
+        let raw_fd = match self {
+            INode::DIR(dir_node) => dir_node.dir_fd.borrow().as_raw_fd(),
+            INode::FILE(file_node) => file_node.fd,
+        };
+        // nix 0.17 has no fchown(), call libc directly
+        #[allow(unsafe_code)]
+        let res = unsafe { libc::fchown(raw_fd, uid, gid) };
+        Errno::result(res)?;
+        let attr = self.helper_reload_attribute();
+        match self {
+            INode::DIR(dir_node) => dir_node.attr.set(attr),
+            INode::FILE(file_node) => file_node.attr.set(attr),
+        }
+        Ok(())
+    }
+
     // to open child, parent dir must have been opened
     fn helper_open_child_file(
         &self,
@@ -584,7 +649,7 @@ impl INode {
         oflags: OFlag,
         mode: Mode,
         create_file: bool,
-    ) -> INode {
+    ) -> Result<INode, c_int> {
         let parent_node = self.helper_get_dir_node();
         let parent = self.get_ino();
 
@@ -597,13 +662,14 @@ impl INode {
             oflags,
             mode,
         )
-        .unwrap_or_else(|_| {
-            panic!(
+        .map_err(|e| {
+            debug!(
                 "helper_open_child_file() failed to open a file name={:?}
-                under parent ino={} with oflags: {:?} and mode: {:?}",
-                child_file_name, parent, oflags, mode
-            )
-        });
+                under parent ino={} with oflags: {:?} and mode: {:?}, the error is: {:?}",
+                child_file_name, parent, oflags, mode, e
+            );
+            util::errno(e)
+        })?;
 
         // get new file attribute
         let child_attr = util::read_attr(child_fd).unwrap_or_else(|_| {
@@ -629,7 +695,7 @@ impl INode {
         }
 
         // lookup count and open count are increased to 1 by creation
-        INode::FILE(FileNode {
+        Ok(INode::FILE(FileNode {
             parent: Cell::new(parent),
             name: RefCell::new(child_file_name.clone()),
             attr: Cell::new(child_attr),
@@ -637,14 +703,26 @@ impl INode {
             fd: child_fd,
             open_count: AtomicI64::new(1),
             lookup_count: AtomicI64::new(1),
-        })
+            dirty_bytes: AtomicU64::new(0),
+        }))
     }
 
     fn open_child_file(&self, child_file_name: &OsString, oflags: OFlag) -> INode {
         self.helper_open_child_file(child_file_name, oflags, Mode::empty(), false)
+            .unwrap_or_else(|e| {
+                panic!(
+                    "open_child_file() failed to open a file name={:?} with oflags: {:?}, the error is: {:?}",
+                    child_file_name, oflags, Errno::from_i32(e)
+                )
+            })
     }
 
-    fn create_child_file(&self, child_file_name: &OsString, oflags: OFlag, mode: Mode) -> INode {
+    fn create_child_file(
+        &self,
+        child_file_name: &OsString,
+        oflags: OFlag,
+        mode: Mode,
+    ) -> Result<INode, c_int> {
         self.helper_open_child_file(child_file_name, oflags, mode, true)
     }
 
@@ -711,44 +789,41 @@ impl INode {
             })
     }
 
-    fn unlink_entry(&self, child_name: &OsString) -> DirEntry {
+    fn unlink_entry(&self, child_name: &OsString) -> Result<DirEntry, c_int> {
         let parent_node = self.helper_get_dir_node();
-        let child_entry = self.remove_entry(child_name);
-        // delete from disk and close the handler
-        match child_entry.entry_type {
-            Type::Directory => {
-                unistd::unlinkat(
-                    Some(parent_node.dir_fd.borrow().as_raw_fd()),
-                    &PathBuf::from(child_name),
-                    UnlinkatFlags::RemoveDir,
-                )
-                .unwrap_or_else(|_| {
-                    panic!(
-                        "unlink_entry() failed to delete the file name {:?} from disk",
-                        child_name
-                    )
-                });
-            }
-            Type::File => {
-                unistd::unlinkat(
-                    Some(parent_node.dir_fd.borrow().as_raw_fd()),
-                    &PathBuf::from(child_name),
-                    UnlinkatFlags::NoRemoveDir,
-                )
-                .unwrap_or_else(|_| {
-                    panic!(
-                        "unlink_entry() failed to delete the file name {:?} from disk",
-                        child_name
-                    )
-                });
-            }
+        let entry_type = match self.get_entry(child_name) {
+            Some(child_entry) => child_entry.entry_type,
+            None => panic!(
+                "unlink_entry found fs is inconsistent, the entry of name={:?}
+                is not in directory of name={:?} and ino={}",
+                child_name,
+                self.get_name().as_os_str(),
+                self.get_ino()
+            ),
+        };
+        let unlink_flags = match entry_type {
+            Type::Directory => UnlinkatFlags::RemoveDir,
+            Type::File => UnlinkatFlags::NoRemoveDir,
             _ => panic!(
                 "unlink_entry() found unsupported entry type: {:?}",
-                child_entry.entry_type
+                entry_type
             ),
-        }
+        };
+        // delete from disk first, the entry stays if it fails
+        unistd::unlinkat(
+            Some(parent_node.dir_fd.borrow().as_raw_fd()),
+            &PathBuf::from(child_name),
+            unlink_flags,
+        )
+        .map_err(|e| {
+            debug!(
+                "unlink_entry() failed to delete the file name {:?} from disk, the error is: {:?}",
+                child_name, e
+            );
+            util::errno(e)
+        })?;
 
-        child_entry
+        Ok(self.remove_entry(child_name))
     }
 
     fn is_empty(&self) -> bool {
@@ -799,7 +874,13 @@ impl INode {
         func(&file_node.data.borrow());
     }
 
-    fn write_file(&mut self, fh: u64, offset: i64, data: &[u8], oflags: OFlag) -> usize {
+    fn write_file(
+        &mut self,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        oflags: OFlag,
+    ) -> Result<usize, c_int> {
         let file_node = match self {
             INode::DIR(_) => panic!("write_file() cannot write DirNode"),
             INode::FILE(file_node) => file_node,
@@ -808,6 +889,28 @@ impl INode {
         let ino = attr.ino;
         let file_data = file_node.data.get_mut();
 
+        // write to disk first, the data in memory stays unchanged if it fails
+        let fcntl_oflags = FcntlArg::F_SETFL(oflags);
+        let fd = fh as RawFd;
+        fcntl::fcntl(fd, fcntl_oflags).unwrap_or_else(|_| {
+            panic!(
+                "write_file() failed to set the flags {:?} to file handler {} of ino={}",
+                oflags, fd, ino
+            )
+        });
+        let mut written_size = data.len();
+        if true {
+            // TODO: async write to disk
+            written_size = uio::pwrite(fd, data, offset).map_err(|e| {
+                debug!(
+                    "write_file() failed to write to disk the file of ino={}, the error is: {:?}",
+                    ino, e
+                );
+                util::errno(e)
+            })?;
+            debug_assert_eq!(data.len(), written_size);
+        }
+
         let size_after_write = offset as usize + data.len();
         if file_data.capacity() < size_after_write {
             let before_cap = file_data.capacity();
@@ -845,26 +948,77 @@ impl INode {
         }
         file_data.extend_from_slice(data);
 
+        // update the attribute of the written file
+        attr.size = file_data.len() as u64;
+        let ts = SystemTime::now();
+        attr.mtime = ts;
+
+        Ok(written_size)
+    }
+
+    /// Splice `len` bytes from the pipe `fd` to disk, the data of the file must not be in
+    /// memory, it is read from disk with the written bytes by the next access
+    #[cfg(target_os = "linux")]
+    fn write_file_from_fd(
+        &mut self,
+        fh: u64,
+        offset: i64,
+        fd: RawFd,
+        len: usize,
+        oflags: OFlag,
+    ) -> Result<usize, c_int> {
+        let file_node = match self {
+            INode::DIR(_) => panic!("write_file_from_fd() cannot write DirNode"),
+            INode::FILE(file_node) => file_node,
+        };
+        let attr = file_node.attr.get_mut();
+        let ino = attr.ino;
+        debug_assert!(file_node.data.get_mut().is_empty());
+
         let fcntl_oflags = FcntlArg::F_SETFL(oflags);
-        let fd = fh as RawFd;
-        fcntl::fcntl(fd, fcntl_oflags).unwrap_or_else(|_| {
+        let file_fd = fh as RawFd;
+        fcntl::fcntl(file_fd, fcntl_oflags).unwrap_or_else(|_| {
             panic!(
-                "write_file() failed to set the flags {:?} to file handler {} of ino={}",
-                oflags, fd, ino
+                "write_file_from_fd() failed to set the flags {:?} to file handler {} of ino={}",
+                oflags, file_fd, ino
             )
         });
-        let mut written_size = data.len();
-        if true {
-            // TODO: async write to disk
-            written_size = uio::pwrite(fd, data, offset).expect("write() failed to write to disk");
-            debug_assert_eq!(data.len(), written_size);
+        let mut file_offset = offset;
+        let mut written_size = 0;
+        let mut result = Ok(());
+        while written_size < len {
+            match fcntl::splice(
+                fd,
+                None,
+                file_fd,
+                Some(&mut file_offset),
+                len - written_size,
+                SpliceFFlags::SPLICE_F_MOVE,
+            ) {
+                // the pipe holds less than the request said
+                Ok(0) => {
+                    result = Err(EIO);
+                    break;
+                }
+                Ok(n) => written_size += n,
+                Err(nix::Error::Sys(Errno::EINTR)) => {}
+                Err(e) => {
+                    debug!(
+                        "write_file_from_fd() failed to write to disk the file of ino={}, the error is: {:?}",
+                        ino, e
+                    );
+                    result = Err(util::errno(e));
+                    break;
+                }
+            }
         }
-        // update the attribute of the written file
-        attr.size = file_data.len() as u64;
-        let ts = SystemTime::now();
-        attr.mtime = ts;
 
-        written_size
+        // update the attribute of the written file, also after a partial write
+        if written_size > 0 {
+            attr.size = cmp::max(attr.size, (offset as usize + written_size) as u64);
+            attr.mtime = SystemTime::now();
+        }
+        result.map(|()| written_size)
     }
 
     fn helper_move_file(
@@ -893,15 +1047,302 @@ impl INode {
     }
 }
 
+/// Options of the memory filesystem
+#[derive(Clone, Debug)]
+pub struct MemoryFilesystemConfig {
+    /// The filesystem is mounted with `default_permissions`, the kernel checks permissions
+    /// before sending requests, so the filesystem does not check them again
+    pub default_permissions: bool,
+    /// Perform mutating operations with the filesystem uid and gid of the caller, so that
+    /// ownership, quota and permission checks of the backing filesystem apply to the caller,
+    /// requires the daemon to run as root
+    pub switch_credentials: bool,
+    /// Map of user ids seen through the mount point to user ids of the backing directory
+    pub uid_map: IdMap,
+    /// Map of group ids seen through the mount point to group ids of the backing directory
+    pub gid_map: IdMap,
+    /// How long the kernel may cache file attributes
+    pub attr_ttl: Duration,
+    /// How long the kernel may cache name lookups
+    pub entry_ttl: Duration,
+    /// Max bytes of file data held in memory, the data of closed files is dropped when the
+    /// budget is exceeded, files larger than the budget are read and written on disk while
+    /// their data is not in memory, `None` means no limit
+    pub cache_budget: Option<u64>,
+    /// Serve the virtual control directory `.fuse_ll` at the root of the mount
+    pub control_dir: bool,
+}
+
+impl Default for MemoryFilesystemConfig {
+    fn default() -> MemoryFilesystemConfig {
+        MemoryFilesystemConfig {
+            default_permissions: false,
+            switch_credentials: false,
+            uid_map: IdMap::default(),
+            gid_map: IdMap::default(),
+            attr_ttl: Duration::new(MY_TTL_SEC, 0),
+            entry_ttl: Duration::new(MY_TTL_SEC, 0),
+            cache_budget: None,
+            control_dir: false,
+        }
+    }
+}
+
+impl MemoryFilesystemConfig {
+    /// Describe the options with one `name value` line per option, as shown in the control
+    /// directory and recorded in traces
+    pub fn describe(&self) -> String {
+        let secs = |ttl: Duration| ttl.as_secs_f64();
+        let map = |map: &IdMap| {
+            if map.is_identity() {
+                String::from("none")
+            } else {
+                map.to_string()
+            }
+        };
+        let budget = self
+            .cache_budget
+            .map_or_else(|| String::from("none"), |budget| budget.to_string());
+        format!(
+            "default_permissions {}\nswitch_credentials {}\nuid_map {}\ngid_map {}\n\
+                attr_ttl {}\nentry_ttl {}\ncache_budget {}\ncontrol_dir {}\n",
+            self.default_permissions,
+            self.switch_credentials,
+            map(&self.uid_map),
+            map(&self.gid_map),
+            secs(self.attr_ttl),
+            secs(self.entry_ttl),
+            budget,
+            self.control_dir,
+        )
+    }
+
+    /// Parse options described by `describe`, the options not described keep their default
+    pub fn from_description(description: &str) -> Result<MemoryFilesystemConfig, String> {
+        fn parse_map(value: &str) -> Result<IdMap, String> {
+            let mut map = IdMap::default();
+            if value != "none" {
+                for spec in value.split(',') {
+                    map.add_range(spec)?;
+                }
+            }
+            Ok(map)
+        }
+
+        let mut config = MemoryFilesystemConfig::default();
+        for line in description.lines().filter(|line| !line.trim().is_empty()) {
+            let mut fields = line.splitn(2, ' ');
+            let name = fields.next().unwrap_or_default();
+            let value = fields.next().unwrap_or_default().trim();
+            let invalid = || format!("invalid value \"{}\" of option {}", value, name);
+            let secs = || match value.parse::<f64>() {
+                Ok(secs) if secs.is_finite() && secs >= 0.0 => Ok(Duration::from_secs_f64(secs)),
+                _ => Err(invalid()),
+            };
+            match name {
+                "default_permissions" => {
+                    config.default_permissions = value.parse().map_err(|_| invalid())?
+                }
+                "switch_credentials" => {
+                    config.switch_credentials = value.parse().map_err(|_| invalid())?
+                }
+                "uid_map" => config.uid_map = parse_map(value)?,
+                "gid_map" => config.gid_map = parse_map(value)?,
+                "attr_ttl" => config.attr_ttl = secs()?,
+                "entry_ttl" => config.entry_ttl = secs()?,
+                "cache_budget" if value == "none" => config.cache_budget = None,
+                "cache_budget" => config.cache_budget = Some(value.parse().map_err(|_| invalid())?),
+                "control_dir" => config.control_dir = value.parse().map_err(|_| invalid())?,
+                _ => return Err(format!("unknown option \"{}\"", name)),
+            }
+        }
+        Ok(config)
+    }
+
+    /// Translate the owner of an attribute read from the backing directory to the ids seen
+    /// through the mount point
+    fn attr_to_mount(&self, attr: &FileAttr) -> FileAttr {
+        let mut attr = *attr;
+        attr.uid = self.uid_map.to_mount(attr.uid);
+        attr.gid = self.gid_map.to_mount(attr.gid);
+        attr
+    }
+}
+
+/// Statistics of the file data held in memory, shared with the metrics of the session
+#[derive(Debug, Default)]
+pub struct CacheStats {
+    /// Reads and writes of files whose data is in memory
+    hits: AtomicU64,
+    /// Reads and writes of files whose data is not in memory
+    misses: AtomicU64,
+    /// Files whose data was dropped from memory to fit into the cache budget
+    evictions: AtomicU64,
+    /// Bytes written to files since they were last flushed or synced
+    dirty_bytes: AtomicU64,
+}
+
+impl CacheStats {
+    fn count(counter: &AtomicU64, value: u64) {
+        counter.fetch_add(value, atomic::Ordering::SeqCst);
+    }
+
+    /// Count bytes written to a file
+    fn add_dirty(&self, inode: &INode, bytes: u64) {
+        if let INode::FILE(file_node) = inode {
+            file_node
+                .dirty_bytes
+                .fetch_add(bytes, atomic::Ordering::SeqCst);
+            CacheStats::count(&self.dirty_bytes, bytes);
+        }
+    }
+
+    /// Forget the bytes written to a file, once it was flushed or synced
+    fn clean(&self, inode: &INode) {
+        if let INode::FILE(file_node) = inode {
+            let bytes = file_node.dirty_bytes.swap(0, atomic::Ordering::SeqCst);
+            self.dirty_bytes.fetch_sub(bytes, atomic::Ordering::SeqCst);
+        }
+    }
+}
+
+impl MetricSource for CacheStats {
+    fn metrics(&self) -> Vec<Metric> {
+        let load = |counter: &AtomicU64| counter.load(atomic::Ordering::SeqCst);
+        vec![
+            Metric {
+                name: "memfs_cache_hits_total",
+                help: "Reads and writes of files whose data is in memory",
+                kind: MetricKind::Counter,
+                value: load(&self.hits),
+            },
+            Metric {
+                name: "memfs_cache_misses_total",
+                help: "Reads and writes of files whose data is loaded from disk",
+                kind: MetricKind::Counter,
+                value: load(&self.misses),
+            },
+            Metric {
+                name: "memfs_cache_evictions_total",
+                help: "Files whose data was dropped from memory to fit into the cache budget",
+                kind: MetricKind::Counter,
+                value: load(&self.evictions),
+            },
+            Metric {
+                name: "memfs_dirty_bytes",
+                help: "Bytes written to files since they were last flushed or synced",
+                kind: MetricKind::Gauge,
+                value: load(&self.dirty_bytes),
+            },
+        ]
+    }
+}
+
 pub struct MemoryFilesystem {
     // max_ino: AtomicU64,
     cache: BTreeMap<u64, INode>,
     trash: BTreeSet<u64>,
+    config: MemoryFilesystemConfig,
+    stats: Arc<CacheStats>,
+    control: Option<ControlDir>,
 }
 
 impl MemoryFilesystem {
+    fn helper_check_access(&self, req: &Request<'_>, attr: &FileAttr, mask: u32) -> bool {
+        if self.config.default_permissions {
+            return true;
+        }
+        let cred = Credentials::from_request(req);
+        let allowed = permission::check_access(&self.config.attr_to_mount(attr), &cred, mask);
+        if !allowed {
+            debug!(
+                "helper_check_access() denied access mask={:#o} to ino={} for {:?}",
+                mask, attr.ino, cred,
+            );
+        }
+        allowed
+    }
+
+    fn helper_check_delete(
+        &self,
+        req: &Request<'_>,
+        parent_attr: &FileAttr,
+        child_attr: &FileAttr,
+    ) -> bool {
+        if self.config.default_permissions {
+            return true;
+        }
+        let cred = Credentials::from_request(req);
+        let allowed = permission::check_delete(
+            &self.config.attr_to_mount(parent_attr),
+            &self.config.attr_to_mount(child_attr),
+            &cred,
+        );
+        if !allowed {
+            debug!(
+                "helper_check_delete() denied removing ino={} from parent ino={} for {:?}",
+                child_attr.ino, parent_attr.ino, cred,
+            );
+        }
+        allowed
+    }
+
+    /// Whether the data of a file of the given size does not fit into the cache budget, such
+    /// data is read and written on disk without loading it into memory
+    fn helper_exceeds_cache_budget(&self, size: u64) -> bool {
+        self.config.cache_budget.is_some_and(|budget| size > budget)
+    }
+
+    /// Drop the data of closed files from memory, largest first, until the data held in
+    /// memory fits into the cache budget
+    fn helper_evict_file_data(&self) {
+        if let Some(budget) = self.config.cache_budget {
+            self.helper_evict_file_data_to(budget as usize);
+        }
+    }
+
+    /// Drop the data of closed files from memory, largest first, until the data held in
+    /// memory fits into the given size
+    fn helper_evict_file_data_to(&self, budget: usize) {
+        let mut cached_size: usize = self.cache.values().map(INode::cached_size).sum();
+        if cached_size <= budget {
+            return;
+        }
+        // the open count includes the file handler held by the node itself
+        let mut closed_files: Vec<&INode> = self
+            .cache
+            .values()
+            .filter(|inode| inode.cached_size() > 0 && inode.get_open_count() <= 1)
+            .collect();
+        closed_files.sort_by_key(|inode| cmp::Reverse(inode.cached_size()));
+        for inode in closed_files {
+            if cached_size <= budget {
+                break;
+            }
+            cached_size -= inode.cached_size();
+            inode.evict_data();
+            CacheStats::count(&self.stats.evictions, 1);
+            debug!(
+                "helper_evict_file_data() dropped the data of ino={} from memory",
+                inode.get_ino(),
+            );
+        }
+    }
+
+    fn helper_switch_credentials(&self, req: &Request<'_>) -> Option<CredentialGuard> {
+        if self.config.switch_credentials {
+            Some(CredentialGuard::switch(
+                self.config.uid_map.to_backing(req.uid()),
+                self.config.gid_map.to_backing(req.gid()),
+            ))
+        } else {
+            None
+        }
+    }
+
     fn helper_create_node(
         &mut self,
+        req: &Request<'_>,
         parent: u64,
         node_name: &OsString,
         mode: u32,
@@ -917,6 +1358,11 @@ impl MemoryFilesystem {
                 parent
             )
         });
+        let parent_attr = parent_inode.get_attr();
+        if !self.helper_check_access(req, &parent_attr, (W_OK | X_OK) as u32) {
+            reply.error(EACCES);
+            return;
+        }
         if let Some(occupied) = parent_inode.get_entry(node_name) {
             debug!(
                 "helper_create_node() found the directory of ino={}
@@ -928,15 +1374,15 @@ impl MemoryFilesystem {
         }
         // all checks are passed, ready to create new node
         let m_flags = util::parse_mode(mode);
+        let credential_guard = self.helper_switch_credentials(req);
         let new_ino: u64;
-        let new_inode: INode;
-        match node_kind {
+        let created = match node_kind {
             FileType::Directory => {
                 debug!(
                     "helper_create_node() about to create a directory with name={:?}, mode={:?}",
                     node_name, m_flags,
                 );
-                new_inode = parent_inode.create_child_dir(node_name, m_flags);
+                parent_inode.create_child_dir(node_name, m_flags)
             }
             FileType::RegularFile => {
                 let o_flags = OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_RDWR;
@@ -945,19 +1391,47 @@ impl MemoryFilesystem {
                         create a file with name={:?}, oflags={:?}, mode={:?}",
                     node_name, o_flags, m_flags,
                 );
-                new_inode = parent_inode.create_child_file(node_name, o_flags, m_flags);
+                parent_inode.create_child_file(node_name, o_flags, m_flags)
             }
             _ => panic!(
                 "helper_create_node() found unsupported file type: {:?}",
                 node_kind
             ),
+        };
+        drop(credential_guard);
+        let new_inode = match created {
+            Ok(new_inode) => new_inode,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+        if !self.config.switch_credentials && unistd::geteuid().is_root() {
+            // the new node belongs to the caller instead of the daemon
+            let cred = Credentials::from_request(req);
+            let uid = self.config.uid_map.to_backing(cred.uid);
+            let gid = self.config.gid_map.to_backing(permission::new_node_gid(
+                &self.config.attr_to_mount(&parent_attr),
+                &cred,
+            ));
+            new_inode
+                .helper_change_owner(uid, gid)
+                .unwrap_or_else(|e| {
+                    error!(
+                        "helper_create_node() failed to change the owner of name={:?} to uid={} gid={}, the error is: {:?}",
+                        node_name, uid, gid, e,
+                    )
+                });
         }
         new_ino = new_inode.get_ino();
         let new_attr = new_inode.get_attr();
         self.cache.insert(new_ino, new_inode);
 
-        let ttl = Duration::new(MY_TTL_SEC, 0);
-        reply.entry(&ttl, &new_attr, MY_GENERATION);
+        reply.entry(
+            &self.config.entry_ttl,
+            &self.config.attr_to_mount(&new_attr),
+            MY_GENERATION,
+        );
         debug!(
             "helper_create_node() successfully created the new child name={:?}
                 of ino={} under parent ino={}",
@@ -976,7 +1450,7 @@ impl MemoryFilesystem {
         self.cache.get(&parent_ino).unwrap_or_else(|| panic!("helper_get_parent_inode() failed to find the parent of ino={} for i-node of ino={}", parent_ino, ino))
     }
 
-    fn helper_may_deferred_delete_node(&mut self, ino: u64) {
+    fn helper_may_deferred_delete_node(&mut self, ino: u64) -> Result<(), c_int> {
         let parent_ino: u64;
         let mut deferred_deletion = false;
         {
@@ -990,7 +1464,7 @@ impl MemoryFilesystem {
             let parent_inode = self.helper_get_parent_inode(ino);
             parent_ino = parent_inode.get_ino();
             // remove entry from parent i-node
-            let deleted_entry = parent_inode.unlink_entry(&inode.get_name());
+            let deleted_entry = parent_inode.unlink_entry(&inode.get_name())?;
             debug_assert_eq!(deleted_entry.ino, ino);
             debug_assert_eq!(inode.get_name().as_os_str(), &deleted_entry.name);
             debug_assert!(inode.get_lookup_count() >= 0); // lookup count cannot be negative
@@ -1026,10 +1500,12 @@ impl MemoryFilesystem {
                 inode.get_lookup_count(),
             );
         }
+        Ok(())
     }
 
     fn helper_remove_node(
         &mut self,
+        req: &Request<'_>,
         parent: u64,
         node_name: &OsString,
         node_type: Type,
@@ -1087,26 +1563,41 @@ impl MemoryFilesystem {
                     debug_assert_eq!(parent, child_inode.get_parent_ino());
                     debug_assert_eq!(node_type, child_inode.get_type());
                     debug_assert_eq!(node_kind, child_inode.get_attr().kind);
+                    if !self.helper_check_delete(
+                        req,
+                        &parent_inode.get_attr(),
+                        &child_inode.get_attr(),
+                    ) {
+                        reply.error(EACCES);
+                        return;
+                    }
                 }
             }
         }
         {
             // all checks passed, ready to remove,
             // when deferred deletion, remove entry from directory first
-            self.helper_may_deferred_delete_node(node_ino);
-            reply.ok();
+            let credential_guard = self.helper_switch_credentials(req);
+            let deleted = self.helper_may_deferred_delete_node(node_ino);
+            drop(credential_guard);
+            match deleted {
+                Ok(()) => reply.ok(),
+                Err(errno) => reply.error(errno),
+            }
         }
     }
 
-    pub fn new<P: AsRef<Path>>(mount_point: P) -> MemoryFilesystem {
-        let mount_dir = PathBuf::from(mount_point.as_ref());
-        if !mount_dir.is_dir() {
-            panic!("the input mount path is not a directory");
+    /// Create a filesystem mirroring the source directory, the root directory is opened
+    /// right away, so the source may be mounted over by the filesystem itself
+    pub fn new<P: AsRef<Path>>(source: P) -> MemoryFilesystem {
+        let source_dir = PathBuf::from(source.as_ref());
+        if !source_dir.is_dir() {
+            panic!("the input source path is not a directory");
         }
-        let root_path = fs::canonicalize(&mount_dir).unwrap_or_else(|_| {
+        let root_path = fs::canonicalize(&source_dir).unwrap_or_else(|_| {
             panic!(
-                "failed to convert the mount point {:?} to a full path",
-                mount_dir
+                "failed to convert the source directory {:?} to a full path",
+                source_dir
             )
         });
 
@@ -1115,7 +1606,35 @@ impl MemoryFilesystem {
         cache.insert(FUSE_ROOT_ID, root_inode);
         let trash = BTreeSet::new(); // for deferred deletion
 
-        MemoryFilesystem { cache, trash }
+        MemoryFilesystem {
+            cache,
+            trash,
+            config: MemoryFilesystemConfig::default(),
+            stats: Arc::new(CacheStats::default()),
+            control: None,
+        }
+    }
+
+    pub fn with_config<P: AsRef<Path>>(
+        source: P,
+        config: MemoryFilesystemConfig,
+    ) -> MemoryFilesystem {
+        if config.switch_credentials && !unistd::geteuid().is_root() {
+            warn!(
+                "switching credentials requires running as root, the daemon credentials are used"
+            );
+        }
+        let mut fs = MemoryFilesystem::new(source);
+        if config.control_dir {
+            fs.control = Some(ControlDir::new());
+        }
+        fs.config = config;
+        fs
+    }
+
+    /// Return the statistics of the file data held in memory, to add to the metrics
+    pub fn cache_stats(&self) -> Arc<CacheStats> {
+        Arc::clone(&self.stats)
     }
 }
 
@@ -1125,8 +1644,33 @@ impl Filesystem for MemoryFilesystem {
         Ok(())
     }
 
+    fn destroy(&mut self, req: &Request<'_>) {
+        debug!("destroy(req={:?})", req.request);
+        // flush the written data of all cached files to the backing directory
+        let mut synced = 0;
+        for (ino, inode) in &self.cache {
+            if let INode::FILE(file_node) = inode {
+                match unistd::fsync(file_node.fd) {
+                    Ok(()) => {
+                        self.stats.clean(inode);
+                        synced += 1;
+                    }
+                    Err(e) => error!(
+                        "destroy() failed to sync the file of ino={}, the error is: {:?}",
+                        ino, e
+                    ),
+                }
+            }
+        }
+        debug!("destroy() successfully synced {} files", synced);
+    }
+
     fn getattr(&mut self, req: &Request<'_>, ino: u64, reply: ReplyAttr) {
         debug!("getattr(ino={}, req={:?})", ino, req.request);
+        if self.is_control_ino(ino) {
+            self.control_getattr(ino, reply);
+            return;
+        }
 
         let inode = self.cache.get(&ino).unwrap_or_else(|| {
             panic!(
@@ -1139,8 +1683,7 @@ impl Filesystem for MemoryFilesystem {
             "getattr() cache hit when searching the attribute of ino={}",
             ino,
         );
-        let ttl = Duration::new(MY_TTL_SEC, 0);
-        reply.attr(&ttl, &attr);
+        reply.attr(&self.config.attr_ttl, &self.config.attr_to_mount(&attr));
         debug!(
             "getattr() successfully got the attribute of ino={}, the attr is: {:?}",
             ino, &attr,
@@ -1163,12 +1706,21 @@ impl Filesystem for MemoryFilesystem {
     //     destroy
     fn open(&mut self, req: &Request<'_>, ino: u64, flags: u32, reply: ReplyOpen) {
         debug!("open(ino={}, flags={}, req={:?})", ino, flags, req.request,);
+        if self.is_control_ino(ino) {
+            self.control_open(req, ino, flags, reply);
+            return;
+        }
         let inode = self.cache.get(&ino).unwrap_or_else(|| {
             panic!(
                 "open() found fs is inconsistent, the i-node of ino={} should be in cache",
                 ino
             )
         });
+        let mask = permission::open_flags_to_mask(flags);
+        if !self.helper_check_access(req, &inode.get_attr(), mask) {
+            reply.error(EACCES);
+            return;
+        }
         let o_flags = util::parse_oflag(flags);
         let new_fd = inode.dup_fd(o_flags);
         reply.opened(new_fd as u64, flags);
@@ -1178,6 +1730,19 @@ impl Filesystem for MemoryFilesystem {
         );
     }
 
+    /// The written data went through to the backing file already, so there is nothing to
+    /// flush but the count of dirty bytes
+    fn flush(&mut self, req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        debug!(
+            "flush(ino={}, fh={}, lock_owner={}, req={:?})",
+            ino, fh, lock_owner, req.request,
+        );
+        if let Some(inode) = self.cache.get(&ino) {
+            self.stats.clean(inode);
+        }
+        reply.ok();
+    }
+
     fn release(
         &mut self,
         req: &Request<'_>,
@@ -1192,6 +1757,10 @@ impl Filesystem for MemoryFilesystem {
             "release(ino={}, fh={}, flags={}, lock_owner={}, flush={}, req={:?})",
             ino, fh, flags, lock_owner, flush, req.request,
         );
+        if self.is_control_ino(ino) {
+            self.control_release(fh, reply);
+            return;
+        }
         let inode = self.cache.get(&ino).unwrap_or_else(|| {
             panic!(
                 "release() found fs is inconsistent, the i-node of ino={} should be in cache",
@@ -1201,6 +1770,7 @@ impl Filesystem for MemoryFilesystem {
         if flush {
             // TODO: support flush
         }
+        self.stats.clean(inode);
 
         // close the duplicated dir fd
         unistd::close(fh as RawFd).unwrap_or_else(|_| {
@@ -1215,6 +1785,46 @@ impl Filesystem for MemoryFilesystem {
             "release() successfully closed the file handler {} of ino={}",
             fh, ino,
         );
+        self.helper_evict_file_data();
+    }
+
+    fn fsync(&mut self, req: &Request<'_>, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        debug!(
+            "fsync(ino={}, fh={}, datasync={}, req={:?})",
+            ino, fh, datasync, req.request,
+        );
+        if self.is_control_ino(ino) {
+            reply.ok();
+            return;
+        }
+        let inode = self.cache.get(&ino).unwrap_or_else(|| {
+            panic!(
+                "fsync() found fs is inconsistent, the i-node of ino={} should be in cache",
+                ino
+            )
+        });
+        #[cfg(target_os = "linux")]
+        let synced = if datasync {
+            unistd::fdatasync(fh as RawFd)
+        } else {
+            unistd::fsync(fh as RawFd)
+        };
+        #[cfg(target_os = "macos")]
+        let synced = unistd::fsync(fh as RawFd);
+        match synced {
+            Ok(()) => {
+                self.stats.clean(inode);
+                reply.ok();
+                debug!("fsync() successfully synced the file of ino={}", ino);
+            }
+            Err(e) => {
+                debug!(
+                    "fsync() failed to sync the file of ino={}, the error is: {:?}",
+                    ino, e
+                );
+                reply.error(util::errno(e));
+            }
+        }
     }
 
     fn opendir(&mut self, req: &Request<'_>, ino: u64, flags: u32, reply: ReplyOpen) {
@@ -1222,6 +1832,10 @@ impl Filesystem for MemoryFilesystem {
             "opendir(ino={}, flags={}, req={:?})",
             ino, flags, req.request,
         );
+        if self.is_control_ino(ino) {
+            reply.opened(0, 0);
+            return;
+        }
 
         let inode = self.cache.get(&ino).unwrap_or_else(|| {
             panic!(
@@ -1229,6 +1843,10 @@ impl Filesystem for MemoryFilesystem {
                 ino
             )
         });
+        if !self.helper_check_access(req, &inode.get_attr(), R_OK as u32) {
+            reply.error(EACCES);
+            return;
+        }
         let o_flags = util::parse_oflag(flags);
         let new_fd = inode.dup_fd(o_flags);
 
@@ -1244,6 +1862,10 @@ impl Filesystem for MemoryFilesystem {
             "releasedir(ino={}, fh={}, flags={}, req={:?})",
             ino, fh, flags, req.request,
         );
+        if self.is_control_ino(ino) {
+            reply.ok();
+            return;
+        }
         let inode = self.cache.get(&ino).unwrap_or_else(|| {
             panic!(
                 "releasedir() found fs is inconsistent, the i-node of ino={} should be in cache",
@@ -1278,7 +1900,34 @@ impl Filesystem for MemoryFilesystem {
             "read(ino={}, fh={}, offset={}, size={}, req={:?})",
             ino, fh, offset, size, req.request,
         );
+        if self.is_control_ino(ino) {
+            self.control_read(fh, offset, size, reply);
+            return;
+        }
 
+        let inode = self.cache.get(&ino).unwrap_or_else(|| {
+            panic!(
+                "read() found fs is inconsistent, the i-node of ino={} should be in cache",
+                ino
+            )
+        });
+        if !inode.need_load_data() {
+            CacheStats::count(&self.stats.hits, 1);
+        } else if self.helper_exceeds_cache_budget(inode.get_attr().size) {
+            // the data would be dropped from memory again once the file is closed, so it is
+            // spliced from disk, which is up to date since writes go through to disk
+            CacheStats::count(&self.stats.misses, 1);
+            if (offset as u64) < inode.get_attr().size {
+                debug!(
+                    "read() replies from disk for the file of ino={} larger than the cache budget",
+                    ino
+                );
+                reply.data_from_fd(inode.helper_get_file_node().fd, offset, size);
+                return;
+            }
+        } else {
+            CacheStats::count(&self.stats.misses, 1);
+        }
         let read_helper = |content: &Vec<u8>| {
             if (offset as usize) < content.len() {
                 let read_data = if ((offset + size as i64) as usize) < content.len() {
@@ -1301,12 +1950,6 @@ impl Filesystem for MemoryFilesystem {
             }
         };
 
-        let inode = self.cache.get(&ino).unwrap_or_else(|| {
-            panic!(
-                "read() found fs is inconsistent, the i-node of ino={} should be in cache",
-                ino
-            )
-        });
         inode.read_file(read_helper);
     }
 
@@ -1322,10 +1965,24 @@ impl Filesystem for MemoryFilesystem {
             "readdir(ino={}, fh={}, offset={}, req={:?})",
             ino, fh, offset, req.request,
         );
+        if self.is_control_ino(ino) {
+            self.control_readdir(ino, offset, reply);
+            return;
+        }
 
+        // the control directory hides an entry of its name in the backing root directory
+        let with_control_dir = ino == FUSE_ROOT_ID && self.control.is_some();
         let readdir_helper = |data: &BTreeMap<OsString, DirEntry>| {
             let mut num_child_entries = 0;
-            for (i, (child_name, child_entry)) in data.iter().enumerate().skip(offset as usize) {
+            let entries = data
+                .iter()
+                .filter(|(name, _)| !with_control_dir || name.as_os_str() != CONTROL_DIR_NAME);
+            let mut num_entries = 0;
+            for (i, (child_name, child_entry)) in entries.enumerate() {
+                num_entries = i + 1;
+                if i < offset as usize {
+                    continue;
+                }
                 let child_ino = child_entry.ino;
                 reply.add(
                     child_ino,
@@ -1344,6 +2001,16 @@ impl Filesystem for MemoryFilesystem {
                     ino,
                 );
             }
+            // the control directory follows the entries of the root directory
+            if with_control_dir && offset as usize <= num_entries {
+                reply.add(
+                    CONTROL_DIR_INO,
+                    num_entries as i64 + 1,
+                    FileType::Directory,
+                    CONTROL_DIR_NAME,
+                );
+                num_child_entries += 1;
+            }
             debug!(
                 "readdir() successfully read {} children under the directory of ino={},
                     the reply is: {:?}",
@@ -1367,6 +2034,10 @@ impl Filesystem for MemoryFilesystem {
             "lookup(parent={}, name={:?}, req={:?})",
             parent, child_name, req.request,
         );
+        if self.is_control_entry(parent, name) {
+            self.control_lookup(parent, name, reply);
+            return;
+        }
 
         let ino: u64;
         let child_type: FileType;
@@ -1379,6 +2050,10 @@ impl Filesystem for MemoryFilesystem {
                     parent
                 )
             });
+            if !self.helper_check_access(req, &parent_inode.get_attr(), X_OK as u32) {
+                reply.error(EACCES);
+                return;
+            }
             match parent_inode.get_entry(&child_name) {
                 Some(child_entry) => {
                     ino = child_entry.ino;
@@ -1395,9 +2070,13 @@ impl Filesystem for MemoryFilesystem {
             }
         }
 
+        let config = &self.config;
         let lookup_helper = |attr: &FileAttr| {
-            let ttl = Duration::new(MY_TTL_SEC, 0);
-            reply.entry(&ttl, &attr, MY_GENERATION);
+            reply.entry(
+                &config.entry_ttl,
+                &config.attr_to_mount(attr),
+                MY_GENERATION,
+            );
             debug!(
                 "lookup() successfully found the file name={:?} of ino={}
                     under parent ino={}, the attr is: {:?}",
@@ -1452,6 +2131,10 @@ impl Filesystem for MemoryFilesystem {
             "forget(ino={}, nlookup={}, req={:?})",
             ino, nlookup, req.request,
         );
+        // the control directory is held in memory for the whole session
+        if self.is_control_ino(ino) {
+            return;
+        }
         let current_count: i64;
         {
             let inode = self.cache.get(&ino).unwrap_or_else(|| {
@@ -1530,9 +2213,55 @@ impl Filesystem for MemoryFilesystem {
             flags,
             req.request,
         );
+        if self.is_control_ino(ino) {
+            self.control_setattr(ino, reply);
+            return;
+        }
+
+        if !self.config.default_permissions {
+            let attr = self
+                .cache
+                .get(&ino)
+                .map(INode::get_attr)
+                .unwrap_or_else(|| {
+                    panic!(
+                    "setattr() found fs is inconsistent, the i-node of ino={} should be in cache",
+                    ino
+                )
+                });
+            let attr = self.config.attr_to_mount(&attr);
+            let cred = Credentials::from_request(req);
+            let writable = permission::check_access(&attr, &cred, W_OK as u32);
+            let allowed = (mode.is_none() || permission::check_chmod(&attr, &cred))
+                && (user_id.is_none() && group_id.is_none()
+                    || permission::check_chown(&attr, &cred, user_id, group_id))
+                && (a_time.is_none() && m_time.is_none()
+                    || writable
+                    || permission::check_utimes(&attr, &cred));
+            if !allowed {
+                debug!(
+                    "setattr() denied changing the attribute of ino={} for {:?}",
+                    ino, cred,
+                );
+                reply.error(EPERM);
+                return;
+            }
+            if size.is_some() && !writable {
+                debug!(
+                    "setattr() denied truncating the file of ino={} for {:?}",
+                    ino, cred,
+                );
+                reply.error(EACCES);
+                return;
+            }
+        }
 
+        // the owner is stored as the ids of the backing directory
+        let user_id = user_id.map(|uid| self.config.uid_map.to_backing(uid));
+        let group_id = group_id.map(|gid| self.config.gid_map.to_backing(gid));
+        let config = &self.config;
         let setattr_helper = |attr: &mut FileAttr| {
-            let ttl = Duration::new(MY_TTL_SEC, 0);
+            let ttl = config.attr_ttl;
             let ts = SystemTime::now();
 
             if let Some(b) = mode {
@@ -1564,7 +2293,7 @@ impl Filesystem for MemoryFilesystem {
                 || flags.is_some()
             {
                 attr.ctime = ts; // update ctime, since meta data might change in setattr
-                reply.attr(&ttl, attr);
+                reply.attr(&ttl, &config.attr_to_mount(attr));
                 debug!(
                     "setattr successfully set the attribute of ino={}, the set attr is {:?}",
                     ino, attr,
@@ -1602,8 +2331,12 @@ impl Filesystem for MemoryFilesystem {
             "mknod(parent={}, name={:?}, mode={}, rdev={}, req={:?})",
             parent, file_name, mode, rdev, req.request,
         );
+        if self.is_control_entry(parent, name) {
+            reply.error(EPERM);
+            return;
+        }
 
-        self.helper_create_node(parent, &file_name, mode, Type::File, reply);
+        self.helper_create_node(req, parent, &file_name, mode, Type::File, reply);
     }
 
     fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
@@ -1612,7 +2345,11 @@ impl Filesystem for MemoryFilesystem {
             "unlink(parent={}, name={:?}, req={:?}",
             parent, file_name, req.request,
         );
-        self.helper_remove_node(parent, &file_name, Type::File, reply);
+        if self.is_control_entry(parent, name) {
+            reply.error(EPERM);
+            return;
+        }
+        self.helper_remove_node(req, parent, &file_name, Type::File, reply);
     }
 
     fn mkdir(
@@ -1628,8 +2365,12 @@ impl Filesystem for MemoryFilesystem {
             "mkdir(parent={}, name={:?}, mode={}, req={:?})",
             parent, dir_name, mode, req.request,
         );
+        if self.is_control_entry(parent, name) {
+            reply.error(EPERM);
+            return;
+        }
 
-        self.helper_create_node(parent, &dir_name, mode, Type::Directory, reply);
+        self.helper_create_node(req, parent, &dir_name, mode, Type::Directory, reply);
     }
 
     fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
@@ -1638,12 +2379,16 @@ impl Filesystem for MemoryFilesystem {
             "rmdir(parent={}, name={:?}, req={:?})",
             parent, dir_name, req.request,
         );
-        self.helper_remove_node(parent, &dir_name, Type::Directory, reply);
+        if self.is_control_entry(parent, name) {
+            reply.error(EPERM);
+            return;
+        }
+        self.helper_remove_node(req, parent, &dir_name, Type::Directory, reply);
     }
 
     fn write(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         ino: u64,
         fh: u64,
         offset: i64,
@@ -1661,15 +2406,36 @@ impl Filesystem for MemoryFilesystem {
             flags,
             // req.request,
         );
+        if self.is_control_ino(ino) {
+            self.control_write(ino, data, reply);
+            return;
+        }
 
+        let credential_guard = self.helper_switch_credentials(req);
         let inode = self.cache.get_mut(&ino).unwrap_or_else(|| {
             panic!(
                 "write() found fs is inconsistent, the i-node of ino={} should be in cache",
                 ino
             )
         });
+        // the data may have been dropped from memory, load it before writing to it
+        if inode.need_load_data() {
+            CacheStats::count(&self.stats.misses, 1);
+            inode.helper_load_file_data();
+        } else {
+            CacheStats::count(&self.stats.hits, 1);
+        }
         let o_flags = util::parse_oflag(flags);
-        let written_size = inode.write_file(fh, offset, data, o_flags);
+        let written = inode.write_file(fh, offset, data, o_flags);
+        drop(credential_guard);
+        let written_size = match written {
+            Ok(written_size) => written_size,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+        self.stats.add_dirty(inode, written_size as u64);
         reply.written(written_size as u32);
         debug!(
             "write() successfully wrote {} byte data to file ino={} at offset={},
@@ -1685,6 +2451,83 @@ impl Filesystem for MemoryFilesystem {
         );
     }
 
+    /// Splice the data to disk if the data of the file is not in memory and the written file
+    /// is larger than the cache budget, otherwise copy it into memory like write. Appends are
+    /// copied, since a file in append mode cannot be spliced to.
+    #[cfg(target_os = "linux")]
+    fn write_from_fd(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        fd: RawFd,
+        size: u32,
+        flags: u32,
+        reply: ReplyWrite,
+    ) {
+        debug!(
+            "write_from_fd(ino={}, fh={}, offset={}, data-size={}, flags={})",
+            ino, fh, offset, size, flags,
+        );
+        let o_flags = util::parse_oflag(flags);
+        let uncached = self.cache.get(&ino).is_some_and(|inode| {
+            let size = cmp::max(inode.get_attr().size, offset as u64 + u64::from(size));
+            inode.is_empty() && self.helper_exceeds_cache_budget(size)
+        });
+        if self.is_control_ino(ino) || !uncached || o_flags.contains(OFlag::O_APPEND) {
+            match read_spliced(fd, size as usize) {
+                Ok(data) => self.write(req, ino, fh, offset, &data, flags, reply),
+                Err(err) => reply.error(err.raw_os_error().unwrap_or(EIO)),
+            }
+            return;
+        }
+
+        let credential_guard = self.helper_switch_credentials(req);
+        let inode = self.cache.get_mut(&ino).unwrap_or_else(|| {
+            panic!(
+                "write_from_fd() found fs is inconsistent, the i-node of ino={} should be in cache",
+                ino
+            )
+        });
+        CacheStats::count(&self.stats.misses, 1);
+        let written = inode.write_file_from_fd(fh, offset, fd, size as usize, o_flags);
+        drop(credential_guard);
+        match written {
+            Ok(written_size) => {
+                self.stats.add_dirty(inode, written_size as u64);
+                reply.written(written_size as u32);
+                debug!(
+                    "write_from_fd() successfully spliced {} byte data to file ino={} at offset={}",
+                    written_size, ino, offset,
+                );
+            }
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: u32, reply: ReplyEmpty) {
+        debug!(
+            "access(ino={}, mask={:#o}, req={:?})",
+            ino, mask, req.request,
+        );
+        if self.is_control_ino(ino) {
+            self.control_access(req, ino, mask, reply);
+            return;
+        }
+        let inode = self.cache.get(&ino).unwrap_or_else(|| {
+            panic!(
+                "access() found fs is inconsistent, the i-node of ino={} should be in cache",
+                ino
+            )
+        });
+        if self.helper_check_access(req, &inode.get_attr(), mask) {
+            reply.ok();
+        } else {
+            reply.error(EACCES);
+        }
+    }
+
     /// Rename a file
     /// The filesystem must return -EINVAL for any unsupported or
     /// unknown flags. Currently the following flags are implemented:
@@ -1710,6 +2553,11 @@ impl Filesystem for MemoryFilesystem {
             "rename(old parent={}, old name={:?}, new parent={}, new name={:?}, req={:?})",
             parent, old_name, new_parent, os_newname, req.request,
         );
+        // the entries of the control directory are fixed
+        if self.is_control_entry(parent, name) || self.is_control_entry(new_parent, newname) {
+            reply.error(EPERM);
+            return;
+        }
 
         // let old_entry_ino: u64;
         // let mut need_to_replace = false;
@@ -1744,6 +2592,21 @@ impl Filesystem for MemoryFilesystem {
             }
 
             let new_parent_inode = self.cache.get(&new_parent).unwrap_or_else(|| panic!("rename() found fs is inconsistent, new parent i-node of ino={} should be in cache", new_parent));
+            let child_attr = parent_inode
+                .get_entry(&old_name)
+                .and_then(|old_entry| self.cache.get(&old_entry.ino))
+                .map(INode::get_attr)
+                .unwrap(); // safe to use unwrap() here, checked above
+            if !self.helper_check_delete(req, &parent_inode.get_attr(), &child_attr)
+                || !self.helper_check_access(
+                    req,
+                    &new_parent_inode.get_attr(),
+                    (W_OK | X_OK) as u32,
+                )
+            {
+                reply.error(EACCES);
+                return;
+            }
             if let Some(replace_entry) = new_parent_inode.get_entry(&os_newname) {
                 debug_assert_eq!(&os_newname, &replace_entry.name);
                 // replaced_node_ino = replace_entry.ino;
@@ -1769,6 +2632,21 @@ impl Filesystem for MemoryFilesystem {
 
             let old_entry = parent_inode.get_entry(&old_name).unwrap();
             let child_inode = self.cache.get(&old_entry.ino).unwrap();
+
+            // move child on disk first, the cache stays unchanged if it fails
+            let credential_guard = self.helper_switch_credentials(req);
+            let moved =
+                INode::helper_move_file(&parent_inode, &old_name, &new_parent_inode, newname);
+            drop(credential_guard);
+            if let Err(e) = moved {
+                debug!(
+                    "rename() failed to move the old file name={:?} of ino={} under old parent ino={}
+                        to the new file name={:?} under new parent ino={}, the error is: {:?}",
+                    old_name, old_entry.ino, parent, newname, new_parent, e,
+                );
+                reply.error(util::errno(e));
+                return;
+            }
             child_inode.set_parent_ino(new_parent_inode.get_ino());
             child_inode.set_name(os_newname.clone());
 
@@ -1782,9 +2660,6 @@ impl Filesystem for MemoryFilesystem {
             //     debug_assert_eq!(replaced_entry.ino, replaced_node_ino);
             //     debug_assert_eq!(os_newname, replaced_entry.name);
             // } else {
-            // move child on disk
-            INode::helper_move_file(&parent_inode, &old_name, &new_parent_inode, newname).unwrap_or_else(|_| panic!("rename() failed to move the old file name={:?} of ino={} under old parent ino={}
-                    to the new file name={:?} under new parent ino={}", old_name, old_entry.ino, parent, newname, new_parent));
             debug!(
                 "rename() moved on disk the old file name={:?} of ino={} under old parent ino={}
                     to the new file name={:?} ino={} under new parent ino={}",
@@ -1814,7 +2689,445 @@ impl Filesystem for MemoryFilesystem {
     }
 }
 
+#[cfg(test)]
 mod test {
+    use super::control::CONTROL_DIR_INO;
+    use super::{MemoryFilesystem, MemoryFilesystemConfig};
+    use crate::fuse::{replay, FileType, MetricSource, MockKernel, FUSE_ROOT_ID};
+    use libc::{EACCES, EINVAL, ENOENT, ENOTEMPTY, EPERM};
+    use libc::{O_RDONLY, O_RDWR, O_WRONLY, S_IFDIR, S_IFREG};
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    /// Create an empty source directory for a test
+    fn source_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("fuse_ll_{}_{}", name, std::process::id()));
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_mock_file_io() {
+        let source = source_dir("mock_file_io");
+        let mut kernel = MockKernel::new(MemoryFilesystem::new(&source)).unwrap();
+
+        let dir = kernel
+            .mkdir(FUSE_ROOT_ID, OsStr::new("dir"), S_IFDIR | 0o755)
+            .unwrap();
+        assert_eq!(dir.attr.kind, FileType::Directory);
+        assert_eq!(dir.attr.perm, 0o755);
+        let file = kernel
+            .mknod(dir.ino, OsStr::new("file"), S_IFREG | 0o644, 0)
+            .unwrap();
+        assert_eq!(file.attr.kind, FileType::RegularFile);
+        assert_eq!(file.attr.size, 0);
+
+        let fh = kernel.open(file.ino, O_RDWR as u32).unwrap();
+        assert_eq!(kernel.write(file.ino, fh, 0, b"0123456789").unwrap(), 10);
+        assert_eq!(kernel.read(file.ino, fh, 2, 5).unwrap(), b"23456");
+        assert_eq!(kernel.read(file.ino, fh, 8, 5).unwrap(), b"89");
+        kernel.release(file.ino, fh, O_RDWR as u32).unwrap();
+        assert_eq!(kernel.getattr(file.ino).unwrap().size, 10);
+        assert_eq!(fs::read(source.join("dir/file")).unwrap(), b"0123456789");
+
+        let entry = kernel.lookup(dir.ino, OsStr::new("file")).unwrap();
+        assert_eq!(entry.ino, file.ino);
+        assert_eq!(
+            kernel.lookup(dir.ino, OsStr::new("missing")).err(),
+            Some(ENOENT)
+        );
+
+        drop(kernel);
+        fs::remove_dir_all(&source).unwrap();
+    }
+
+    #[test]
+    fn test_config_description() {
+        let mut config = MemoryFilesystemConfig {
+            default_permissions: true,
+            attr_ttl: Duration::from_millis(500),
+            cache_budget: Some(4096),
+            ..MemoryFilesystemConfig::default()
+        };
+        config.uid_map.add_range("0:1000:1").unwrap();
+        config.uid_map.add_range("1:100000:65536").unwrap();
+
+        let description = config.describe();
+        assert!(description.contains("uid_map 0:1000:1,1:100000:65536\ngid_map none\n"));
+        let parsed = MemoryFilesystemConfig::from_description(&description).unwrap();
+        assert_eq!(parsed.describe(), description);
+        assert_eq!(parsed.attr_ttl, Duration::from_millis(500));
+
+        assert!(MemoryFilesystemConfig::from_description("attr_ttl -1\n").is_err());
+        assert!(MemoryFilesystemConfig::from_description("unknown 1\n").is_err());
+    }
+
+    #[test]
+    fn test_mock_switched_credentials() {
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+        let source = source_dir("mock_switched_credentials");
+        fs::write(source.join("a.txt"), "content").unwrap();
+        // the kernel checks permissions, so only the backing filesystem denies the caller
+        let config = MemoryFilesystemConfig {
+            default_permissions: true,
+            switch_credentials: true,
+            ..MemoryFilesystemConfig::default()
+        };
+        let mut kernel = MockKernel::new(MemoryFilesystem::with_config(&source, config)).unwrap();
+        let file = kernel.lookup(FUSE_ROOT_ID, OsStr::new("a.txt")).unwrap();
+
+        // the source directory belongs to root and is not writable by others
+        kernel.set_caller(1000, 1000, 42);
+        assert_eq!(
+            kernel
+                .mknod(FUSE_ROOT_ID, OsStr::new("new"), S_IFREG | 0o644, 0)
+                .err(),
+            Some(EACCES)
+        );
+        assert_eq!(
+            kernel
+                .mkdir(FUSE_ROOT_ID, OsStr::new("dir"), S_IFDIR | 0o755)
+                .err(),
+            Some(EACCES)
+        );
+        assert_eq!(
+            kernel.unlink(FUSE_ROOT_ID, OsStr::new("a.txt")).err(),
+            Some(EACCES)
+        );
+        assert_eq!(
+            kernel
+                .rename(
+                    FUSE_ROOT_ID,
+                    OsStr::new("a.txt"),
+                    FUSE_ROOT_ID,
+                    OsStr::new("b.txt")
+                )
+                .err(),
+            Some(EACCES)
+        );
+
+        // nothing changed in memory after the failures
+        kernel.set_caller(0, 0, 42);
+        let entry = kernel.lookup(FUSE_ROOT_ID, OsStr::new("a.txt")).unwrap();
+        assert_eq!(entry.ino, file.ino);
+        assert_eq!(
+            kernel.lookup(FUSE_ROOT_ID, OsStr::new("new")).err(),
+            Some(ENOENT)
+        );
+        assert_eq!(
+            kernel.lookup(FUSE_ROOT_ID, OsStr::new("b.txt")).err(),
+            Some(ENOENT)
+        );
+        kernel.unlink(FUSE_ROOT_ID, OsStr::new("a.txt")).unwrap();
+        assert!(!source.join("a.txt").exists());
+
+        drop(kernel);
+        fs::remove_dir_all(&source).unwrap();
+    }
+
+    #[test]
+    fn test_mock_cache_stats() {
+        let source = source_dir("mock_cache_stats");
+        // without a budget, the data of a file is dropped from memory once it is closed
+        let config = MemoryFilesystemConfig {
+            cache_budget: Some(0),
+            ..MemoryFilesystemConfig::default()
+        };
+        let fs = MemoryFilesystem::with_config(&source, config);
+        let stats = fs.cache_stats();
+        let mut kernel = MockKernel::new(fs).unwrap();
+        let values = || -> Vec<u64> { stats.metrics().iter().map(|m| m.value).collect() };
+
+        let file = kernel
+            .mknod(FUSE_ROOT_ID, OsStr::new("file"), S_IFREG | 0o644, 0)
+            .unwrap();
+        let fh = kernel.open(file.ino, O_RDWR as u32).unwrap();
+        kernel.write(file.ino, fh, 0, b"0123456789").unwrap();
+        kernel.read(file.ino, fh, 0, 4).unwrap();
+        // hits, misses, evictions and dirty bytes
+        assert_eq!(values(), vec![2, 0, 0, 10]);
+        kernel.release(file.ino, fh, O_RDWR as u32).unwrap();
+        assert_eq!(values(), vec![2, 0, 1, 0]);
+
+        let fh = kernel.open(file.ino, O_RDWR as u32).unwrap();
+        assert_eq!(kernel.read(file.ino, fh, 0, 4).unwrap(), b"0123");
+        kernel.write(file.ino, fh, 10, b"abc").unwrap();
+        assert_eq!(values(), vec![2, 2, 1, 3]);
+        kernel.fsync(file.ino, fh, false).unwrap();
+        assert_eq!(values(), vec![2, 2, 1, 0]);
+        kernel.write(file.ino, fh, 13, b"de").unwrap();
+        kernel.flush(file.ino, fh).unwrap();
+        assert_eq!(values(), vec![3, 2, 1, 0]);
+        kernel.write(file.ino, fh, 15, b"f").unwrap();
+        kernel.fsync(file.ino, fh, true).unwrap();
+        kernel.release(file.ino, fh, O_RDWR as u32).unwrap();
+        assert_eq!(values(), vec![4, 2, 2, 0]);
+        assert_eq!(fs::read(source.join("file")).unwrap(), b"0123456789abcdef");
+
+        // the files still open are synced when the filesystem is destroyed
+        let fh = kernel.open(file.ino, O_WRONLY as u32).unwrap();
+        kernel.write(file.ino, fh, 16, b"g").unwrap();
+        assert_eq!(values()[3], 1);
+        drop(kernel);
+        assert_eq!(values()[3], 0);
+        fs::remove_dir_all(&source).unwrap();
+    }
+
+    #[test]
+    fn test_mock_spliced_write() {
+        let source = source_dir("mock_spliced_write");
+        let config = MemoryFilesystemConfig {
+            cache_budget: Some(4096),
+            ..MemoryFilesystemConfig::default()
+        };
+        let fs = MemoryFilesystem::with_config(&source, config);
+        let stats = fs.cache_stats();
+        let mut kernel = MockKernel::new(fs).unwrap();
+        let values = || -> Vec<u64> { stats.metrics().iter().map(|m| m.value).collect() };
+
+        let file = kernel
+            .mknod(FUSE_ROOT_ID, OsStr::new("file"), S_IFREG | 0o644, 0)
+            .unwrap();
+        let fh = kernel.open(file.ino, O_RDWR as u32).unwrap();
+        // the data of the file is not in memory and exceeds the cache budget, so it is
+        // spliced to disk and read from there
+        let data = vec![b'a'; 8192];
+        assert_eq!(kernel.write_spliced(file.ino, fh, 0, &data).unwrap(), 8192);
+        assert_eq!(fs::read(source.join("file")).unwrap(), data);
+        assert_eq!(kernel.getattr(file.ino).unwrap().size, 8192);
+        assert_eq!(kernel.read(file.ino, fh, 8190, 4).unwrap(), b"aa");
+        // hits, misses, evictions and dirty bytes
+        assert_eq!(values(), vec![0, 2, 0, 8192]);
+
+        // once a write loaded the data into memory, it is copied there
+        kernel.write(file.ino, fh, 8192, b"bb").unwrap();
+        assert_eq!(kernel.write_spliced(file.ino, fh, 8194, b"cc").unwrap(), 2);
+        assert_eq!(kernel.read(file.ino, fh, 8190, 8).unwrap(), b"aabbcc");
+        assert_eq!(values(), vec![2, 3, 0, 8196]);
+        kernel.release(file.ino, fh, O_RDWR as u32).unwrap();
+        assert_eq!(
+            fs::read(source.join("file")).unwrap()[8190..],
+            b"aabbcc"[..]
+        );
+
+        // the data of a file within the cache budget is copied into memory
+        let small = kernel
+            .mknod(FUSE_ROOT_ID, OsStr::new("small"), S_IFREG | 0o644, 0)
+            .unwrap();
+        let fh = kernel.open(small.ino, O_RDWR as u32).unwrap();
+        assert_eq!(kernel.write_spliced(small.ino, fh, 0, b"small").unwrap(), 5);
+        kernel.release(small.ino, fh, O_RDWR as u32).unwrap();
+        let fh = kernel.open(small.ino, O_RDONLY as u32).unwrap();
+        assert_eq!(kernel.read(small.ino, fh, 0, 8).unwrap(), b"small");
+        kernel.release(small.ino, fh, O_RDONLY as u32).unwrap();
+        // the large file was dropped from memory when it was closed
+        assert_eq!(values(), vec![4, 3, 1, 0]);
+
+        drop(kernel);
+        fs::remove_dir_all(&source).unwrap();
+    }
+
+    #[test]
+    fn test_mock_control_dir() {
+        let source = source_dir("mock_control_dir");
+        fs::write(source.join("a.txt"), "content").unwrap();
+        fs::write(source.join(".fuse_ll"), "hidden").unwrap();
+        let config = MemoryFilesystemConfig {
+            control_dir: true,
+            ..MemoryFilesystemConfig::default()
+        };
+        let mut kernel = MockKernel::new(MemoryFilesystem::with_config(&source, config)).unwrap();
+        let read_all = |kernel: &mut MockKernel<MemoryFilesystem>, name: &str| {
+            let ino = kernel
+                .lookup(CONTROL_DIR_INO, OsStr::new(name))
+                .unwrap()
+                .ino;
+            let fh = kernel.open(ino, O_RDONLY as u32).unwrap();
+            let content = kernel.read(ino, fh, 0, 4096).unwrap();
+            kernel.release(ino, fh, O_RDONLY as u32).unwrap();
+            String::from_utf8(content).unwrap()
+        };
+
+        // the control directory follows the entries of the root and hides the backing one
+        let dir = kernel.lookup(FUSE_ROOT_ID, OsStr::new(".fuse_ll")).unwrap();
+        assert_eq!(dir.ino, CONTROL_DIR_INO);
+        assert_eq!(dir.attr.kind, FileType::Directory);
+        let fh = kernel.opendir(FUSE_ROOT_ID, 0).unwrap();
+        let entries = kernel.readdir(FUSE_ROOT_ID, fh, 0, 4096).unwrap();
+        kernel.releasedir(FUSE_ROOT_ID, fh).unwrap();
+        let names: Vec<_> = entries.iter().map(|e| (e.name.clone(), e.ino)).collect();
+        let a = kernel.lookup(FUSE_ROOT_ID, OsStr::new("a.txt")).unwrap();
+        assert_eq!(
+            names,
+            vec![
+                ("a.txt".into(), a.ino),
+                (".fuse_ll".into(), CONTROL_DIR_INO)
+            ]
+        );
+        let fh = kernel.opendir(CONTROL_DIR_INO, 0).unwrap();
+        let entries = kernel.readdir(CONTROL_DIR_INO, fh, 0, 4096).unwrap();
+        kernel.releasedir(CONTROL_DIR_INO, fh).unwrap();
+        let names: Vec<_> = entries.iter().map(|e| e.name.clone()).collect();
+        assert_eq!(
+            names,
+            vec!["stats", "cache", "options", "drop_caches", "log_level"]
+        );
+
+        // the written data is held in memory
+        let b = kernel
+            .mknod(FUSE_ROOT_ID, OsStr::new("b.txt"), S_IFREG | 0o644, 0)
+            .unwrap();
+        let fh = kernel.open(b.ino, O_RDWR as u32).unwrap();
+        kernel.write(b.ino, fh, 0, b"content").unwrap();
+        kernel.release(b.ino, fh, O_RDWR as u32).unwrap();
+        let stats = read_all(&mut kernel, "stats");
+        assert!(stats.contains("hits 1\n"), "{}", stats);
+        assert!(stats.contains("dirty_bytes 0\n"), "{}", stats);
+        assert!(stats.contains("cached_bytes 7\n"), "{}", stats);
+        let cache = read_all(&mut kernel, "cache");
+        assert!(
+            cache.contains(&format!("{} file 1 7 \"b.txt\"", b.ino)),
+            "{}",
+            cache
+        );
+        assert!(read_all(&mut kernel, "options").contains("cache_budget none\n"));
+
+        // the data of the closed file is dropped, other values are refused
+        let drop_caches = kernel
+            .lookup(CONTROL_DIR_INO, OsStr::new("drop_caches"))
+            .unwrap();
+        let fh = kernel.open(drop_caches.ino, O_WRONLY as u32).unwrap();
+        assert_eq!(kernel.write(drop_caches.ino, fh, 0, b"1\n"), Ok(2));
+        assert_eq!(kernel.write(drop_caches.ino, fh, 0, b"2\n"), Err(EINVAL));
+        kernel
+            .release(drop_caches.ino, fh, O_WRONLY as u32)
+            .unwrap();
+        let stats = read_all(&mut kernel, "stats");
+        assert!(stats.contains("evictions 1\n"), "{}", stats);
+        assert!(stats.contains("cached_bytes 0\n"), "{}", stats);
+
+        let log_level = kernel
+            .lookup(CONTROL_DIR_INO, OsStr::new("log_level"))
+            .unwrap();
+        let previous = log::max_level();
+        let fh = kernel.open(log_level.ino, O_WRONLY as u32).unwrap();
+        assert_eq!(kernel.write(log_level.ino, fh, 0, b"warn\n"), Ok(5));
+        assert_eq!(kernel.write(log_level.ino, fh, 0, b"loud"), Err(EINVAL));
+        kernel.release(log_level.ino, fh, O_WRONLY as u32).unwrap();
+        assert_eq!(read_all(&mut kernel, "log_level"), "warn\n");
+        log::set_max_level(previous);
+
+        // the entries are fixed and the backing directory is untouched
+        let stats = kernel.lookup(CONTROL_DIR_INO, OsStr::new("stats")).unwrap();
+        assert_eq!(kernel.write(stats.ino, 0, 0, b"1"), Err(EPERM));
+        assert_eq!(
+            kernel
+                .mkdir(CONTROL_DIR_INO, OsStr::new("dir"), S_IFDIR | 0o755)
+                .err(),
+            Some(EPERM)
+        );
+        assert_eq!(
+            kernel.unlink(FUSE_ROOT_ID, OsStr::new(".fuse_ll")),
+            Err(EPERM)
+        );
+        assert_eq!(
+            kernel.lookup(CONTROL_DIR_INO, OsStr::new("missing")).err(),
+            Some(ENOENT)
+        );
+        assert_eq!(fs::read(source.join(".fuse_ll")).unwrap(), b"hidden");
+
+        drop(kernel);
+        fs::remove_dir_all(&source).unwrap();
+    }
+
+    #[test]
+    fn test_mock_trace_replay() {
+        let source = source_dir("mock_trace_replay");
+        fs::write(source.join("old.txt"), "content").unwrap();
+        let trace = source.with_extension("trace");
+        let fs = MemoryFilesystem::new(&source);
+        let mut kernel = MockKernel::traced(fs, &trace, "").unwrap();
+
+        let old = kernel.lookup(FUSE_ROOT_ID, OsStr::new("old.txt")).unwrap();
+        let fh = kernel.open(old.ino, O_RDONLY as u32).unwrap();
+        assert_eq!(kernel.read(old.ino, fh, 0, 4096).unwrap(), b"content");
+        kernel.release(old.ino, fh, O_RDONLY as u32).unwrap();
+        let new = kernel
+            .mknod(FUSE_ROOT_ID, OsStr::new("new.txt"), S_IFREG | 0o644, 0)
+            .unwrap();
+        let fh = kernel.open(new.ino, O_WRONLY as u32).unwrap();
+        kernel.write(new.ino, fh, 0, b"new content").unwrap();
+        kernel.release(new.ino, fh, O_WRONLY as u32).unwrap();
+        kernel.getattr(new.ino).unwrap();
+        let fh = kernel.opendir(FUSE_ROOT_ID, 0).unwrap();
+        assert_eq!(kernel.readdir(FUSE_ROOT_ID, fh, 0, 4096).unwrap().len(), 2);
+        kernel.releasedir(FUSE_ROOT_ID, fh).unwrap();
+        // the replay starts from the same source directory
+        kernel.unlink(FUSE_ROOT_ID, OsStr::new("new.txt")).unwrap();
+        drop(kernel);
+
+        // the timestamps differ in the replay, and the inode number of the new file as long
+        // as another file holds the freed one
+        let hold = source.with_extension("hold");
+        fs::write(&hold, "").unwrap();
+        let report = replay(MemoryFilesystem::new(&source), &trace).unwrap();
+        let mismatches: Vec<_> = report.mismatches.iter().map(|m| m.to_string()).collect();
+        assert_eq!(mismatches, Vec::<String>::new());
+        assert_eq!(report.matched, report.requests);
+
+        fs::remove_file(&hold).unwrap();
+        fs::remove_file(&trace).unwrap();
+        fs::remove_dir_all(&source).unwrap();
+    }
+
+    #[test]
+    fn test_mock_directories() {
+        let source = source_dir("mock_directories");
+        fs::write(source.join("old.txt"), "content").unwrap();
+        let mut kernel = MockKernel::new(MemoryFilesystem::new(&source)).unwrap();
+
+        let dir = kernel
+            .mkdir(FUSE_ROOT_ID, OsStr::new("dir"), S_IFDIR | 0o755)
+            .unwrap();
+        // the kernel looks up a name before renaming it
+        kernel.lookup(FUSE_ROOT_ID, OsStr::new("old.txt")).unwrap();
+        kernel
+            .rename(
+                FUSE_ROOT_ID,
+                OsStr::new("old.txt"),
+                dir.ino,
+                OsStr::new("new.txt"),
+            )
+            .unwrap();
+        assert!(source.join("dir/new.txt").exists());
+        assert!(!source.join("old.txt").exists());
+
+        let fh = kernel.opendir(dir.ino, 0).unwrap();
+        let entries = kernel.readdir(dir.ino, fh, 0, 4096).unwrap();
+        kernel.releasedir(dir.ino, fh).unwrap();
+        let names: Vec<_> = entries.iter().map(|e| e.name.clone()).collect();
+        assert_eq!(names, vec!["new.txt"]);
+        assert_eq!(entries[0].kind, FileType::RegularFile);
+
+        assert_eq!(
+            kernel.rmdir(FUSE_ROOT_ID, OsStr::new("dir")),
+            Err(ENOTEMPTY)
+        );
+        kernel.unlink(dir.ino, OsStr::new("new.txt")).unwrap();
+        kernel.rmdir(FUSE_ROOT_ID, OsStr::new("dir")).unwrap();
+        assert!(!source.join("dir").exists());
+
+        drop(kernel);
+        fs::remove_dir_all(&source).unwrap();
+    }
+
     #[test]
     fn test_libc_renameat() {
         use nix::dir::Dir;
@@ -0,0 +1,339 @@
+//! Virtual control directory
+//!
+//! An optional directory `.fuse_ll` at the root of the mount inspects and steers the running
+//! filesystem. It is held in memory only and never touches the backing directory, its i-nodes
+//! use numbers at the top of the range, which backing filesystems do not hand out. An entry
+//! of the same name in the backing root directory is hidden by it.
+//!
+//! The read-only files `stats`, `cache` and `options` show the cache statistics, the i-nodes
+//! in memory and the options of the filesystem. Writing `1` to `drop_caches` drops the data
+//! of all closed files from memory, writing a level such as `debug` to `log_level` changes
+//! the log level of the daemon. The content of a file is taken when it is opened.
+
+use crate::fuse::consts::FOPEN_DIRECT_IO;
+use crate::fuse::{
+    FileAttr, FileType, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen,
+    ReplyWrite, Request,
+};
+use libc::{c_int, EACCES, EINVAL, ENOENT, EPERM};
+use log::{info, LevelFilter};
+use nix::dir::Type;
+use nix::unistd;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fmt::Write;
+use std::str;
+use std::sync::atomic;
+use std::time::SystemTime;
+
+use super::permission::{self, Credentials};
+use super::{INode, MemoryFilesystem, FUSE_ROOT_ID, MY_GENERATION};
+
+/// Name of the control directory in the root directory
+pub const CONTROL_DIR_NAME: &str = ".fuse_ll";
+
+/// I-node number of the control directory, the numbers from it on are reserved
+pub const CONTROL_DIR_INO: u64 = u64::MAX - 0xff;
+
+/// A file of the control directory
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ControlFile {
+    Stats,
+    Cache,
+    Options,
+    DropCaches,
+    LogLevel,
+}
+
+const CONTROL_FILES: [ControlFile; 5] = [
+    ControlFile::Stats,
+    ControlFile::Cache,
+    ControlFile::Options,
+    ControlFile::DropCaches,
+    ControlFile::LogLevel,
+];
+
+impl ControlFile {
+    fn name(self) -> &'static str {
+        match self {
+            ControlFile::Stats => "stats",
+            ControlFile::Cache => "cache",
+            ControlFile::Options => "options",
+            ControlFile::DropCaches => "drop_caches",
+            ControlFile::LogLevel => "log_level",
+        }
+    }
+
+    fn ino(self) -> u64 {
+        CONTROL_DIR_INO + 1 + self as u64
+    }
+
+    fn from_ino(ino: u64) -> Option<ControlFile> {
+        CONTROL_FILES.iter().copied().find(|file| file.ino() == ino)
+    }
+
+    fn from_name(name: &OsStr) -> Option<ControlFile> {
+        CONTROL_FILES
+            .iter()
+            .copied()
+            .find(|file| OsStr::new(file.name()) == name)
+    }
+
+    fn writable(self) -> bool {
+        match self {
+            ControlFile::DropCaches | ControlFile::LogLevel => true,
+            ControlFile::Stats | ControlFile::Cache | ControlFile::Options => false,
+        }
+    }
+}
+
+/// State of the control directory
+#[derive(Debug)]
+pub struct ControlDir {
+    /// Time the directory was created, all its times are this one
+    created: SystemTime,
+    /// Content of the open files by file handle
+    handles: BTreeMap<u64, Vec<u8>>,
+    next_fh: u64,
+}
+
+impl ControlDir {
+    pub fn new() -> ControlDir {
+        ControlDir {
+            created: SystemTime::now(),
+            handles: BTreeMap::new(),
+            next_fh: 1,
+        }
+    }
+
+    /// Return the attribute of the directory or one of its files, owned by the daemon
+    fn attr(&self, file: Option<ControlFile>) -> FileAttr {
+        let (ino, kind, perm, nlink) = match file {
+            None => (CONTROL_DIR_INO, FileType::Directory, 0o555, 2),
+            Some(file) if file.writable() => (file.ino(), FileType::RegularFile, 0o644, 1),
+            Some(file) => (file.ino(), FileType::RegularFile, 0o444, 1),
+        };
+        FileAttr {
+            ino,
+            // the files are opened with direct I/O, so the kernel reads them to the end
+            size: 0,
+            blocks: 0,
+            atime: self.created,
+            mtime: self.created,
+            ctime: self.created,
+            crtime: self.created,
+            kind,
+            perm,
+            nlink,
+            uid: unistd::geteuid().as_raw(),
+            gid: unistd::getegid().as_raw(),
+            rdev: 0,
+            flags: 0,
+        }
+    }
+}
+
+/// Return whether the i-node number is reserved for the control directory
+fn is_reserved(ino: u64) -> bool {
+    ino >= CONTROL_DIR_INO
+}
+
+impl MemoryFilesystem {
+    /// Return whether the i-node belongs to the control directory
+    pub(super) fn is_control_ino(&self, ino: u64) -> bool {
+        self.control.is_some() && is_reserved(ino)
+    }
+
+    /// Return whether the name refers to the control directory or an entry in it
+    pub(super) fn is_control_entry(&self, parent: u64, name: &OsStr) -> bool {
+        self.control.is_some()
+            && ((parent == FUSE_ROOT_ID && name == CONTROL_DIR_NAME) || is_reserved(parent))
+    }
+
+    fn control_attr(&self, ino: u64) -> Option<FileAttr> {
+        let control = self.control.as_ref()?;
+        match ControlFile::from_ino(ino) {
+            Some(file) => Some(control.attr(Some(file))),
+            None if ino == CONTROL_DIR_INO => Some(control.attr(None)),
+            None => None,
+        }
+    }
+
+    fn control_check_access(&self, req: &Request<'_>, attr: &FileAttr, mask: u32) -> bool {
+        self.config.default_permissions
+            || permission::check_access(attr, &Credentials::from_request(req), mask)
+    }
+
+    pub(super) fn control_lookup(&self, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let ino = if parent == CONTROL_DIR_INO {
+            ControlFile::from_name(name).map(ControlFile::ino)
+        } else if name == CONTROL_DIR_NAME {
+            Some(CONTROL_DIR_INO)
+        } else {
+            None
+        };
+        match ino.and_then(|ino| self.control_attr(ino)) {
+            Some(attr) => reply.entry(&self.config.entry_ttl, &attr, MY_GENERATION),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    pub(super) fn control_getattr(&self, ino: u64, reply: ReplyAttr) {
+        match self.control_attr(ino) {
+            Some(attr) => reply.attr(&self.config.attr_ttl, &attr),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    /// Reply the unchanged attribute to a change, e.g. truncating a file before writing to it
+    pub(super) fn control_setattr(&self, ino: u64, reply: ReplyAttr) {
+        match ControlFile::from_ino(ino) {
+            Some(file) if file.writable() => self.control_getattr(ino, reply),
+            _ => reply.error(EPERM),
+        }
+    }
+
+    pub(super) fn control_access(&self, req: &Request<'_>, ino: u64, mask: u32, reply: ReplyEmpty) {
+        match self.control_attr(ino) {
+            Some(attr) if self.control_check_access(req, &attr, mask) => reply.ok(),
+            Some(_) => reply.error(EACCES),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    pub(super) fn control_readdir(&self, ino: u64, offset: i64, mut reply: ReplyDirectory) {
+        if ino != CONTROL_DIR_INO {
+            reply.error(ENOENT);
+            return;
+        }
+        for (i, file) in CONTROL_FILES.iter().enumerate().skip(offset as usize) {
+            if reply.add(file.ino(), i as i64 + 1, FileType::RegularFile, file.name()) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    pub(super) fn control_open(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        flags: u32,
+        reply: ReplyOpen,
+    ) {
+        let (file, attr) = match (ControlFile::from_ino(ino), self.control_attr(ino)) {
+            (Some(file), Some(attr)) => (file, attr),
+            _ => {
+                reply.error(EPERM);
+                return;
+            }
+        };
+        if !self.control_check_access(req, &attr, permission::open_flags_to_mask(flags)) {
+            reply.error(EACCES);
+            return;
+        }
+        let content = self.control_content(file).into_bytes();
+        let control = self.control.as_mut().unwrap(); // safe, the control attribute exists
+        let fh = control.next_fh;
+        control.next_fh += 1;
+        control.handles.insert(fh, content);
+        reply.opened(fh, FOPEN_DIRECT_IO);
+    }
+
+    pub(super) fn control_read(&self, fh: u64, offset: i64, size: u32, reply: ReplyData) {
+        let content = match self.control.as_ref().and_then(|c| c.handles.get(&fh)) {
+            Some(content) => content,
+            None => {
+                reply.error(EINVAL);
+                return;
+            }
+        };
+        let start = (offset as usize).min(content.len());
+        let end = start.saturating_add(size as usize).min(content.len());
+        reply.data(&content[start..end]);
+    }
+
+    pub(super) fn control_write(&self, ino: u64, data: &[u8], reply: ReplyWrite) {
+        let file = match ControlFile::from_ino(ino) {
+            Some(file) if file.writable() => file,
+            _ => {
+                reply.error(EPERM);
+                return;
+            }
+        };
+        match self.control_apply(file, data) {
+            Ok(()) => reply.written(data.len() as u32),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    pub(super) fn control_release(&mut self, fh: u64, reply: ReplyEmpty) {
+        if let Some(control) = self.control.as_mut() {
+            control.handles.remove(&fh);
+        }
+        reply.ok();
+    }
+
+    /// Render the content of a file
+    fn control_content(&self, file: ControlFile) -> String {
+        // writing to a string never fails, so the results are ignored
+        let mut out = String::new();
+        match file {
+            ControlFile::Stats => {
+                let stats = &self.stats;
+                let load = |counter: &atomic::AtomicU64| counter.load(atomic::Ordering::SeqCst);
+                let _ = writeln!(out, "hits {}", load(&stats.hits));
+                let _ = writeln!(out, "misses {}", load(&stats.misses));
+                let _ = writeln!(out, "evictions {}", load(&stats.evictions));
+                let _ = writeln!(out, "dirty_bytes {}", load(&stats.dirty_bytes));
+                let _ = writeln!(out, "inodes {}", self.cache.len());
+                let cached_bytes: usize = self.cache.values().map(INode::cached_size).sum();
+                let _ = writeln!(out, "cached_bytes {}", cached_bytes);
+            }
+            ControlFile::Cache => {
+                let _ = writeln!(out, "ino type lookups cached_bytes name");
+                for (ino, inode) in &self.cache {
+                    let kind = match inode.get_type() {
+                        Type::Directory => "dir",
+                        _ => "file",
+                    };
+                    let _ = writeln!(
+                        out,
+                        "{} {} {} {} {:?}",
+                        ino,
+                        kind,
+                        inode.get_lookup_count(),
+                        inode.cached_size(),
+                        inode.get_name().as_os_str(),
+                    );
+                }
+            }
+            ControlFile::Options => out.push_str(&self.config.describe()),
+            ControlFile::DropCaches => (),
+            ControlFile::LogLevel => {
+                let level = log::max_level().to_string().to_lowercase();
+                let _ = writeln!(out, "{}", level);
+            }
+        }
+        out
+    }
+
+    /// Apply a write to a writable file, the written value may end with a newline
+    fn control_apply(&self, file: ControlFile, data: &[u8]) -> Result<(), c_int> {
+        let value = str::from_utf8(data).map_err(|_| EINVAL)?.trim();
+        match file {
+            ControlFile::DropCaches if value == "1" => {
+                self.helper_evict_file_data_to(0);
+                info!("dropped the data of all closed files from memory");
+                Ok(())
+            }
+            ControlFile::LogLevel => {
+                let level: LevelFilter = value.parse().map_err(|_| EINVAL)?;
+                log::set_max_level(level);
+                info!("changed the log level to {}", level);
+                Ok(())
+            }
+            _ => Err(EINVAL),
+        }
+    }
+}
@@ -0,0 +1,186 @@
+//! User and group id mapping
+//!
+//! Translates the owner of i-nodes between the ids seen through the mount point and the ids
+//! stored in the backing directory. A map consists of ranges in the form of
+//! `<mount id>:<backing id>:<count>`. Like in a user namespace, ids not covered by any range
+//! are mapped to the overflow id, so that no two ids map to the same one. Without any range,
+//! every id is mapped to itself.
+
+use std::fmt;
+
+/// The id unmapped ids are mapped to, `nobody` and `nogroup` like the default of
+/// `/proc/sys/kernel/overflowuid` and `overflowgid`
+pub const OVERFLOW_ID: u32 = 65534;
+
+/// A range of consecutive ids mapped from the mount point to the backing directory
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct IdRange {
+    /// The first id seen through the mount point
+    pub mount_id: u32,
+    /// The first id stored in the backing directory
+    pub backing_id: u32,
+    /// The number of ids in the range
+    pub count: u32,
+}
+
+impl IdRange {
+    /// Parse a range in the form of `<mount id>:<backing id>:<count>`
+    pub fn parse(spec: &str) -> Result<IdRange, String> {
+        let fields = spec
+            .split(':')
+            .map(|f| f.parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("invalid id range \"{}\": {}", spec, e))?;
+        if fields.len() != 3 {
+            return Err(format!(
+                "invalid id range \"{}\", expect <mount id>:<backing id>:<count>",
+                spec
+            ));
+        }
+        let range = IdRange {
+            mount_id: fields[0],
+            backing_id: fields[1],
+            count: fields[2],
+        };
+        if range.count == 0
+            || range.mount_id.checked_add(range.count - 1).is_none()
+            || range.backing_id.checked_add(range.count - 1).is_none()
+        {
+            return Err(format!("invalid id range \"{}\", count out of range", spec));
+        }
+        Ok(range)
+    }
+}
+
+/// A user or group id map, the identity map if no range is given
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct IdMap {
+    ranges: Vec<IdRange>,
+}
+
+impl IdMap {
+    /// Add a range in the form of `<mount id>:<backing id>:<count>` to the map,
+    /// ranges must not overlap on either side
+    pub fn add_range(&mut self, spec: &str) -> Result<(), String> {
+        let range = IdRange::parse(spec)?;
+        let overlap = |a: u32, b: u32, count: u32, other_count: u32| {
+            a <= b.saturating_add(other_count - 1) && b <= a.saturating_add(count - 1)
+        };
+        if let Some(r) = self.ranges.iter().find(|r| {
+            overlap(range.mount_id, r.mount_id, range.count, r.count)
+                || overlap(range.backing_id, r.backing_id, range.count, r.count)
+        }) {
+            return Err(format!(
+                "id range \"{}\" overlaps with \"{}\"",
+                spec,
+                IdMap { ranges: vec![*r] }
+            ));
+        }
+        self.ranges.push(range);
+        Ok(())
+    }
+
+    /// Return true if no range is given
+    pub fn is_identity(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Translate an id stored in the backing directory to the id seen through the mount point,
+    /// the overflow id if no range covers it
+    pub fn to_mount(&self, backing_id: u32) -> u32 {
+        if self.is_identity() {
+            return backing_id;
+        }
+        self.ranges
+            .iter()
+            .find(|r| backing_id >= r.backing_id && backing_id - r.backing_id < r.count)
+            .map_or(OVERFLOW_ID, |r| r.mount_id + (backing_id - r.backing_id))
+    }
+
+    /// Translate an id seen through the mount point to the id stored in the backing directory,
+    /// the overflow id if no range covers it
+    pub fn to_backing(&self, mount_id: u32) -> u32 {
+        if self.is_identity() {
+            return mount_id;
+        }
+        self.ranges
+            .iter()
+            .find(|r| mount_id >= r.mount_id && mount_id - r.mount_id < r.count)
+            .map_or(OVERFLOW_ID, |r| r.backing_id + (mount_id - r.mount_id))
+    }
+}
+
+impl fmt::Display for IdMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ranges = self
+            .ranges
+            .iter()
+            .map(|r| format!("{}:{}:{}", r.mount_id, r.backing_id, r.count))
+            .collect::<Vec<_>>();
+        write!(f, "{}", ranges.join(","))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_range() {
+        assert_eq!(
+            IdRange::parse("0:1000:1").unwrap(),
+            IdRange {
+                mount_id: 0,
+                backing_id: 1000,
+                count: 1
+            }
+        );
+        assert!(IdRange::parse("0:1000").is_err());
+        assert!(IdRange::parse("0:1000:0").is_err());
+        assert!(IdRange::parse("0:a:1").is_err());
+        assert!(IdRange::parse("4294967295:0:2").is_err());
+    }
+
+    #[test]
+    fn identity_map() {
+        let map = IdMap::default();
+        assert!(map.is_identity());
+        assert_eq!(map.to_mount(1000), 1000);
+        assert_eq!(map.to_backing(0), 0);
+    }
+
+    #[test]
+    fn map_ranges() {
+        let mut map = IdMap::default();
+        map.add_range("0:1000:1").unwrap();
+        map.add_range("1:100000:65536").unwrap();
+        assert_eq!(map.to_mount(1000), 0);
+        assert_eq!(map.to_backing(0), 1000);
+        assert_eq!(map.to_mount(100005), 6);
+        assert_eq!(map.to_backing(6), 100005);
+        // ids outside of any range are mapped to the overflow id
+        assert_eq!(map.to_mount(65537 + 100000), OVERFLOW_ID);
+        assert_eq!(map.to_backing(70000), OVERFLOW_ID);
+        assert_eq!(map.to_string(), "0:1000:1,1:100000:65536");
+    }
+
+    #[test]
+    fn unmapped_ids() {
+        let mut map = IdMap::default();
+        map.add_range("0:1000:1").unwrap();
+        // the unmapped ids do not collide with the mapped ones
+        assert_eq!(map.to_mount(1000), 0);
+        assert_eq!(map.to_mount(0), OVERFLOW_ID);
+        assert_eq!(map.to_backing(0), 1000);
+        assert_eq!(map.to_backing(1000), OVERFLOW_ID);
+    }
+
+    #[test]
+    fn overlapped_ranges() {
+        let mut map = IdMap::default();
+        map.add_range("0:1000:10").unwrap();
+        assert!(map.add_range("5:2000:1").is_err());
+        assert!(map.add_range("20:1009:1").is_err());
+        assert!(map.add_range("10:1010:1").is_ok());
+    }
+}
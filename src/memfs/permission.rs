@@ -0,0 +1,334 @@
+//! Permission checking on behalf of the caller
+//!
+//! Evaluates the owner, group and other permission bits of an i-node against the credentials
+//! of the process that issued a request, the same way the kernel does when the filesystem is
+//! mounted with `default_permissions`.
+
+use crate::fuse::{FileAttr, FileType, Request};
+use libc::{R_OK, S_ISGID, S_ISVTX, W_OK, X_OK};
+use log::debug;
+use std::fs;
+
+// mode_t is u32 on Linux but u16 on macOS, permission bits always fit in u16
+#[allow(clippy::unnecessary_cast)]
+const SET_GROUP_ID_BIT: u16 = S_ISGID as u16;
+#[allow(clippy::unnecessary_cast)]
+const STICKY_BIT: u16 = S_ISVTX as u16;
+
+/// The credentials of the process that issued a request
+#[derive(Debug)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+    pub groups: Vec<u32>,
+}
+
+impl Credentials {
+    /// Build the credentials of a request, supplementary groups are read from
+    /// `/proc/<pid>/status` of the calling process
+    pub fn from_request(req: &Request<'_>) -> Credentials {
+        Credentials {
+            uid: req.uid(),
+            gid: req.gid(),
+            groups: supplementary_groups(req.pid()),
+        }
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+
+    pub fn in_group(&self, gid: u32) -> bool {
+        self.gid == gid || self.groups.contains(&gid)
+    }
+}
+
+/// Read the supplementary groups of a process, return empty groups if the process
+/// already exited or the request was issued by the kernel itself (pid 0)
+fn supplementary_groups(pid: u32) -> Vec<u32> {
+    if pid == 0 {
+        return Vec::new();
+    }
+    match fs::read_to_string(format!("/proc/{}/status", pid)) {
+        Ok(status) => parse_groups(&status),
+        Err(e) => {
+            debug!(
+                "supplementary_groups() failed to read the status of pid={}, the error is: {:?}",
+                pid, e
+            );
+            Vec::new()
+        }
+    }
+}
+
+fn parse_groups(status: &str) -> Vec<u32> {
+    status
+        .lines()
+        .find(|l| l.starts_with("Groups:"))
+        .map(|l| {
+            l["Groups:".len()..]
+                .split_whitespace()
+                .filter_map(|g| g.parse::<u32>().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Check the access `mask` (a combination of `R_OK`, `W_OK` and `X_OK`) of the
+/// credentials to the i-node with the given attribute
+pub fn check_access(attr: &FileAttr, cred: &Credentials, mask: u32) -> bool {
+    let mask = mask & (R_OK | W_OK | X_OK) as u32;
+    if cred.is_root() {
+        // root may read and write everything, but only execute files that are
+        // executable by anyone
+        return mask & X_OK as u32 == 0
+            || attr.kind == FileType::Directory
+            || attr.perm & 0o111 != 0;
+    }
+    let perm = u32::from(attr.perm);
+    let granted = if cred.uid == attr.uid {
+        (perm >> 6) & 0o7
+    } else if cred.in_group(attr.gid) {
+        (perm >> 3) & 0o7
+    } else {
+        perm & 0o7
+    };
+    granted & mask == mask
+}
+
+/// Check whether the credentials may remove or rename a child from the parent directory,
+/// which requires write and search permission on the directory and, if the sticky bit is
+/// set on the directory, ownership of either the directory or the child
+pub fn check_delete(parent_attr: &FileAttr, child_attr: &FileAttr, cred: &Credentials) -> bool {
+    if !check_access(parent_attr, cred, (W_OK | X_OK) as u32) {
+        return false;
+    }
+    if parent_attr.perm & STICKY_BIT == 0 || cred.is_root() {
+        return true;
+    }
+    cred.uid == parent_attr.uid || cred.uid == child_attr.uid
+}
+
+/// Check whether the credentials may change the mode of the i-node
+pub fn check_chmod(attr: &FileAttr, cred: &Credentials) -> bool {
+    cred.is_root() || cred.uid == attr.uid
+}
+
+/// Check whether the credentials may change the owner and group of the i-node,
+/// only root may give away a file, the owner may only change the group to one it
+/// is a member of
+pub fn check_chown(
+    attr: &FileAttr,
+    cred: &Credentials,
+    uid: Option<u32>,
+    gid: Option<u32>,
+) -> bool {
+    if cred.is_root() {
+        return true;
+    }
+    if let Some(u) = uid {
+        if u != attr.uid {
+            return false;
+        }
+    }
+    match gid {
+        Some(g) if g != attr.gid => cred.uid == attr.uid && cred.in_group(g),
+        _ => true,
+    }
+}
+
+/// Check whether the credentials may set the timestamps of the i-node to arbitrary values
+pub fn check_utimes(attr: &FileAttr, cred: &Credentials) -> bool {
+    cred.is_root() || cred.uid == attr.uid
+}
+
+/// Return the access mask needed to open a file with the given open flags
+pub fn open_flags_to_mask(flags: u32) -> u32 {
+    let flags = flags as i32;
+    let mut mask = match flags & libc::O_ACCMODE {
+        libc::O_RDONLY => R_OK,
+        libc::O_WRONLY => W_OK,
+        _ => R_OK | W_OK,
+    };
+    if flags & libc::O_TRUNC != 0 {
+        mask |= W_OK;
+    }
+    mask as u32
+}
+
+/// Return the group a new child of the directory should belong to, new children
+/// inherit the group of a set-group-ID directory
+pub fn new_node_gid(parent_attr: &FileAttr, cred: &Credentials) -> u32 {
+    if parent_attr.perm & SET_GROUP_ID_BIT != 0 {
+        parent_attr.gid
+    } else {
+        cred.gid
+    }
+}
+
+/// Switch the filesystem uid and gid of the current thread to the credentials of a caller,
+/// so that ownership, quota and permission checks of the backing filesystem apply to the
+/// caller. The previous filesystem uid and gid are restored when the guard is dropped.
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+pub struct CredentialGuard {
+    old_uid: u32,
+    old_gid: u32,
+}
+
+#[cfg(target_os = "linux")]
+impl CredentialGuard {
+    pub fn switch(uid: u32, gid: u32) -> CredentialGuard {
+        // switch gid first, since switching fsuid away from root drops the capability to
+        // switch fsgid
+        #[allow(unsafe_code)]
+        let (old_gid, old_uid) = unsafe { (libc::setfsgid(gid), libc::setfsuid(uid)) };
+        debug!(
+            "CredentialGuard::switch() switched fsuid from {} to {} and fsgid from {} to {}",
+            old_uid, uid, old_gid, gid,
+        );
+        CredentialGuard {
+            old_uid: old_uid as u32,
+            old_gid: old_gid as u32,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for CredentialGuard {
+    fn drop(&mut self) {
+        #[allow(unsafe_code)]
+        unsafe {
+            libc::setfsuid(self.old_uid);
+            libc::setfsgid(self.old_gid);
+        }
+    }
+}
+
+/// macOS has no per-thread filesystem credentials, the guard does nothing
+#[cfg(target_os = "macos")]
+#[derive(Debug)]
+pub struct CredentialGuard;
+
+#[cfg(target_os = "macos")]
+impl CredentialGuard {
+    pub fn switch(_uid: u32, _gid: u32) -> CredentialGuard {
+        CredentialGuard
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn attr(kind: FileType, perm: u16, uid: u32, gid: u32) -> FileAttr {
+        FileAttr {
+            ino: 2,
+            size: 0,
+            blocks: 0,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind,
+            perm,
+            nlink: 1,
+            uid,
+            gid,
+            rdev: 0,
+            flags: 0,
+        }
+    }
+
+    fn cred(uid: u32, gid: u32, groups: Vec<u32>) -> Credentials {
+        Credentials { uid, gid, groups }
+    }
+
+    #[test]
+    fn parse_status_groups() {
+        let status = "Name:\tcat\nUid:\t1000\t1000\t1000\t1000\nGroups:\t4 24 1000 \nNgid:\t0\n";
+        assert_eq!(parse_groups(status), vec![4, 24, 1000]);
+        assert!(parse_groups("Name:\tcat\n").is_empty());
+        assert!(parse_groups("Groups:\t\n").is_empty());
+    }
+
+    #[test]
+    fn owner_group_other_bits() {
+        let file = attr(FileType::RegularFile, 0o640, 1000, 100);
+        let (r, w, x) = (R_OK as u32, W_OK as u32, X_OK as u32);
+        assert!(check_access(&file, &cred(1000, 1000, vec![]), r | w));
+        assert!(!check_access(&file, &cred(1000, 1000, vec![]), x));
+        assert!(check_access(&file, &cred(1001, 100, vec![]), r));
+        assert!(!check_access(&file, &cred(1001, 100, vec![]), w));
+        assert!(check_access(&file, &cred(1001, 1001, vec![100]), r));
+        assert!(!check_access(&file, &cred(1001, 1001, vec![]), r));
+    }
+
+    #[test]
+    fn root_access() {
+        let file = attr(FileType::RegularFile, 0o600, 1000, 1000);
+        let dir = attr(FileType::Directory, 0o700, 1000, 1000);
+        let root = cred(0, 0, vec![]);
+        assert!(check_access(&file, &root, (R_OK | W_OK) as u32));
+        assert!(!check_access(&file, &root, X_OK as u32));
+        assert!(check_access(&dir, &root, (R_OK | W_OK | X_OK) as u32));
+    }
+
+    #[test]
+    fn sticky_directory() {
+        let dir = attr(FileType::Directory, 0o1777, 0, 0);
+        let child = attr(FileType::RegularFile, 0o644, 1000, 1000);
+        assert!(check_delete(&dir, &child, &cred(1000, 1000, vec![])));
+        assert!(!check_delete(&dir, &child, &cred(1001, 1001, vec![])));
+        assert!(check_delete(&dir, &child, &cred(0, 0, vec![])));
+    }
+
+    #[test]
+    fn chown_rules() {
+        let file = attr(FileType::RegularFile, 0o644, 1000, 1000);
+        let owner = cred(1000, 1000, vec![100]);
+        assert!(check_chown(&file, &owner, None, Some(100)));
+        assert!(!check_chown(&file, &owner, None, Some(200)));
+        assert!(!check_chown(&file, &owner, Some(1001), None));
+        assert!(check_chown(&file, &owner, Some(1000), None));
+        assert!(!check_chown(
+            &file,
+            &cred(1001, 100, vec![]),
+            None,
+            Some(100)
+        ));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn switch_fs_credentials() {
+        // setfsuid() with an invalid id only returns the current fsuid
+        fn current() -> (i32, i32) {
+            unsafe { (libc::setfsuid(u32::MAX), libc::setfsgid(u32::MAX)) }
+        }
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+        let before = current();
+        {
+            let _guard = CredentialGuard::switch(1000, 1001);
+            assert_eq!(current(), (1000, 1001));
+        }
+        assert_eq!(current(), before);
+    }
+
+    #[test]
+    fn open_mask() {
+        assert_eq!(open_flags_to_mask(libc::O_RDONLY as u32), R_OK as u32);
+        assert_eq!(open_flags_to_mask(libc::O_WRONLY as u32), W_OK as u32);
+        assert_eq!(
+            open_flags_to_mask(libc::O_RDWR as u32),
+            (R_OK | W_OK) as u32
+        );
+        assert_eq!(
+            open_flags_to_mask((libc::O_RDONLY | libc::O_TRUNC) as u32),
+            (R_OK | W_OK) as u32
+        );
+    }
+}
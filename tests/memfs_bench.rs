@@ -1,7 +1,15 @@
 use log::info; // debug, error, warn
+use nix::sys::stat;
 use std::env;
 use std::ffi::OsString;
+use std::fs::{self, File};
+use std::os::unix::io::AsRawFd;
 use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use fuse_ll::fuse;
+use fuse_ll::memfs::{MemoryFilesystem, MemoryFilesystemConfig};
 
 mod test_util;
 use test_util::DEFAULT_MOUNT_DIR;
@@ -22,3 +30,67 @@ fn bench_test() {
 
     test_util::teardown(&mount_dir, th);
 }
+
+/// Print the throughput of an operation repeated `repeat_times` times
+fn report(name: &str, repeat_times: usize, elapsed: Duration) {
+    println!(
+        "{}: {} ops in {:?}, {:.0} ops/s",
+        name,
+        repeat_times,
+        elapsed,
+        repeat_times as f64 / elapsed.as_secs_f64()
+    );
+}
+
+/// Measure getattr and lookup requests, each of them is a small reply, run with
+/// `cargo test --release --test memfs_bench bench_metadata -- --nocapture`
+#[test]
+fn bench_metadata() {
+    let mount_dir = Path::new("../fuse_bench_metadata");
+    if mount_dir.exists() {
+        let _ = fuse::unmount(mount_dir);
+        fs::remove_dir_all(mount_dir).unwrap();
+    }
+    fs::create_dir_all(mount_dir).unwrap();
+    let abs_mount_path = fs::canonicalize(mount_dir).unwrap();
+    let file_count = 100;
+    for i in 0..file_count {
+        File::create(abs_mount_path.join(format!("file{}", i))).unwrap();
+    }
+
+    // the kernel caches neither attributes nor names, so every call reaches the filesystem
+    let config = MemoryFilesystemConfig {
+        attr_ttl: Duration::from_secs(0),
+        entry_ttl: Duration::from_secs(0),
+        ..MemoryFilesystemConfig::default()
+    };
+    let fs = MemoryFilesystem::with_config(&abs_mount_path, config);
+    let mountpoint = abs_mount_path.clone();
+    let th = thread::spawn(move || {
+        fuse::mount(fs, &mountpoint, &[])
+            .unwrap_or_else(|_| panic!("Couldn't mount filesystem: {:?}", mountpoint));
+    });
+    thread::sleep(Duration::new(2, 0));
+
+    let repeat_times: usize = 100_000;
+    let file = File::open(abs_mount_path.join("file0")).unwrap();
+    let begin = Instant::now();
+    for _ in 0..repeat_times {
+        stat::fstat(file.as_raw_fd()).unwrap();
+    }
+    report("getattr", repeat_times, begin.elapsed());
+    drop(file);
+
+    let paths: Vec<_> = (0..file_count)
+        .map(|i| abs_mount_path.join(format!("file{}", i)))
+        .collect();
+    let begin = Instant::now();
+    for i in 0..repeat_times {
+        stat::lstat(&paths[i % file_count]).unwrap();
+    }
+    report("lookup", repeat_times, begin.elapsed());
+
+    fuse::unmount(&abs_mount_path).unwrap();
+    th.join().unwrap();
+    fs::remove_dir_all(&abs_mount_path).unwrap();
+}
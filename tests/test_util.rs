@@ -35,7 +35,6 @@ pub fn setup(mount_dir: &Path) -> JoinHandle<()> {
         //"-o",
         //"debug",
         "fsname=fuse_rs_demo",
-        "ro",
         "allow_other",
     ]
     .iter()
@@ -0,0 +1,60 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// Return true if unprivileged user and mount namespaces can be created
+fn unshare_available() -> bool {
+    Command::new("unshare")
+        .args(&["-Urm", "true"])
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+#[test]
+fn test_mount_in_user_namespace() {
+    if !unshare_available() || !Path::new("/dev/fuse").exists() {
+        println!("skip test_mount_in_user_namespace, user namespaces or FUSE not available");
+        return;
+    }
+
+    let mount_dir = Path::new("../fuse_userns_test");
+    if mount_dir.exists() {
+        fs::remove_dir_all(mount_dir).unwrap();
+    }
+    fs::create_dir_all(mount_dir).unwrap();
+    let abs_mount_path = fs::canonicalize(mount_dir).unwrap();
+
+    // the mount disappears together with the namespace when the shell exits
+    let script = format!(
+        r#"
+        "{bin}" -f --overlay-self "{dir}" &
+        pid=$!
+        for i in $(seq 50); do
+            grep -q " {dir} .* - fuse " /proc/self/mountinfo && break
+            sleep 0.1
+        done
+        grep " {dir} .* - fuse " /proc/self/mountinfo || exit 1
+        echo -n 0123456789ABCDEF > "{dir}/userns.txt" || exit 2
+        content=$(cat "{dir}/userns.txt")
+        rm "{dir}/userns.txt" || exit 3
+        kill $pid
+        test "$content" = 0123456789ABCDEF
+        "#,
+        bin = env!("CARGO_BIN_EXE_fuse_ll"),
+        dir = abs_mount_path.display(),
+    );
+    let output = Command::new("unshare")
+        .args(&["-Urm", "sh", "-c", &script])
+        .output()
+        .unwrap();
+    fs::remove_dir_all(&abs_mount_path).unwrap();
+
+    assert!(
+        output.status.success(),
+        "mount in user namespace failed with {}, stdout: {}, stderr: {}",
+        output.status,
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr),
+    );
+}